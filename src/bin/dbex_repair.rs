@@ -0,0 +1,41 @@
+// Repairs the database in the directory given as the only argument (see DBex::repair)
+// and reports what it did. Exits with 1 if any table had to be quarantined, since its
+// entries may be lost, and with 2 if the repair itself failed.
+use std::env;
+use std::process::ExitCode;
+use dbex::DBex;
+use dbex::options::DBexOptions;
+
+fn main() -> ExitCode {
+    let Some(data_dir) = env::args().nth(1) else {
+        eprintln!("usage: dbex_repair <data dir>");
+        return ExitCode::from(2);
+    };
+
+    let report = match DBex::repair(&data_dir, DBexOptions::default()) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("repair of {} failed: {}", data_dir, err);
+            return ExitCode::from(2);
+        }
+    };
+
+    if report.manifest_rebuilt {
+        println!("rebuilt the manifest from the tables on disk");
+    }
+    println!("replayed {} WAL entries", report.wal_entries_replayed);
+    for table in &report.quarantined {
+        println!("quarantined {}: {}", table.data_path.display(), table.reason);
+        match &table.key_range {
+            Some((min_key, max_key)) => println!("  lost keys from {} to {}", min_key.escape_ascii(), max_key.escape_ascii()),
+            None => println!("  lost keys in an unknown range"),
+        }
+    }
+
+    if report.quarantined.is_empty() {
+        println!("{} is healthy", data_dir);
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
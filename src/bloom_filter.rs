@@ -0,0 +1,165 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use xxhash_rust::xxh3::xxh3_64;
+
+// Hashes keys for Bloom filters. A table's filter records the id of the hasher that
+// built it, so the same hasher can be found again when the table is reopened: ids below
+// 128 are reserved for the built-in hashers, custom ones pick an id from 128 up.
+pub trait BloomHasher: Debug + Send + Sync {
+    fn id(&self) -> u8;
+    fn hash(&self, key: &[u8]) -> u64;
+}
+
+// The default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xxh3Hasher;
+
+impl BloomHasher for Xxh3Hasher {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        xxh3_64(key)
+    }
+}
+
+// Used by tables written before filters recorded their hasher
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fnv1aHasher;
+
+impl BloomHasher for Fnv1aHasher {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        fnv1a(key)
+    }
+}
+
+// The hasher with `id`: a built-in one, or `custom` if its id matches
+pub fn resolve_hasher(id: u8, custom: Option<&Arc<dyn BloomHasher>>) -> Option<Arc<dyn BloomHasher>> {
+    match id {
+        0 => Some(Arc::new(Fnv1aHasher)),
+        1 => Some(Arc::new(Xxh3Hasher)),
+        _ => custom.filter(|custom| custom.id() == id).cloned(),
+    }
+}
+
+// Bloom filter over the keys (or key prefixes) of a single SSTable.
+// A miss means the key is definitely absent, a hit means it may be present.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    hasher: Arc<dyn BloomHasher>,
+}
+
+impl PartialEq for BloomFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits == other.bits
+            && self.num_bits == other.num_bits
+            && self.num_hashes == other.num_hashes
+            && self.hasher.id() == other.hasher.id()
+    }
+}
+
+impl BloomFilter {
+    // Sized for `expected_keys` entries at roughly a 1% false positive rate
+    pub fn new(expected_keys: usize, hasher: Arc<dyn BloomHasher>) -> Self {
+        let bits_per_key = 10;
+        let num_bits = (expected_keys.max(1) * bits_per_key) as u64;
+
+        BloomFilter {
+            bits: vec![0u64; num_bits.div_ceil(64) as usize],
+            num_bits,
+            // ln(2) * bits_per_key rounds to 7 probes
+            num_hashes: 7,
+            hasher,
+        }
+    }
+
+    // Sized for `expected_keys` entries at about `false_positive_rate` (between 0 and 1):
+    // -ln(p) / ln(2)^2 bits per key, probed ln(2) times as many times
+    pub fn with_false_positive_rate(expected_keys: usize, false_positive_rate: f64, hasher: Arc<dyn BloomHasher>) -> Self {
+        let false_positive_rate = false_positive_rate.clamp(1e-9, 0.5);
+        let bits_per_key = -false_positive_rate.ln() / (2f64.ln() * 2f64.ln());
+        let num_bits = (expected_keys.max(1) as f64 * bits_per_key).ceil() as u64;
+
+        BloomFilter {
+            bits: vec![0u64; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes: (bits_per_key * 2f64.ln()).round().max(1.0) as u32,
+            hasher,
+        }
+    }
+
+    pub fn hasher(&self) -> &Arc<dyn BloomHasher> {
+        &self.hasher
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in bit_positions(self.hasher.hash(key), self.num_hashes, self.num_bits) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        bit_positions(self.hasher.hash(key), self.num_hashes, self.num_bits)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // [num_hashes: u32][num_bits: u64][bit words: u64 * n]. The hasher's id is stored by
+    // the caller.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.num_hashes.to_be_bytes());
+        out.extend_from_slice(&self.num_bits.to_be_bytes());
+        for word in &self.bits {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    // Returns the filter and the number of bytes consumed, or None if `bytes` is malformed
+    pub fn decode(bytes: &[u8], hasher: Arc<dyn BloomHasher>) -> Option<(Self, usize)> {
+        let num_hashes = u32::from_be_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let num_bits = u64::from_be_bytes(bytes.get(4..12)?.try_into().ok()?);
+        if num_bits == 0 {
+            return None;
+        }
+
+        let num_words = num_bits.div_ceil(64) as usize;
+        let words_end = 12usize.checked_add(num_words.checked_mul(8)?)?;
+        let bits = bytes.get(12..words_end)?
+            .chunks_exact(8)
+            .map(|word| u64::from_be_bytes(word.try_into().unwrap()))
+            .collect();
+
+        Some((BloomFilter { bits, num_bits, num_hashes, hasher }, words_end))
+    }
+}
+
+// Double hashing: probe i lands on h1 + i * h2
+fn bit_positions(h1: u64, num_hashes: u32, num_bits: u64) -> impl Iterator<Item = u64> {
+    let h2 = mix(h1) | 1;
+    (0..num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// splitmix64 finalizer, used to derive an independent second hash
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,184 @@
+use std::fmt::{self, Debug};
+use std::io;
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::stream::raw::CParameter;
+use crate::options::Compression;
+
+// Codec sidecar: [kind: u8][level: i32][dictionary len: u32][dictionary]
+const CODEC_ZSTD: u8 = 1;
+const CODEC_ZSTD_DICTIONARY: u8 = 2;
+const CODEC_LZ4: u8 = 3;
+
+// Dictionary training needs a handful of samples to find anything worth sharing, and
+// gains little from more than a few thousand
+const MIN_TRAINING_SAMPLES: usize = 8;
+const MAX_TRAINING_SAMPLES: usize = 4096;
+
+// Custom processing of stored values (encryption, an application codec, ...): on_write
+// runs on every value an SSTable writes, after compression, and on_read undoes it. Each
+// table records the id of the transform its values went through, and is only read back
+// with a transform of the same id.
+pub trait ValueTransform: Debug + Send + Sync {
+    fn id(&self) -> u8;
+    fn on_write(&self, value: &[u8]) -> Vec<u8>;
+    fn on_read(&self, stored: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+// Compresses the values of a single SSTable. Values are stored as
+// [uncompressed len: u32][frame], where the frame is a zstd frame, optionally against a
+// dictionary shared by the table, or an LZ4 block.
+pub struct ValueCodec {
+    level: i32,
+    dictionary: Option<Vec<u8>>,
+    engine: Engine,
+}
+
+enum Engine {
+    Zstd { compressor: Compressor<'static>, decompressor: Decompressor<'static> },
+    // LZ4 blocks keep no state between values
+    Lz4,
+}
+
+impl fmt::Debug for ValueCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.engine {
+            Engine::Zstd { .. } => "zstd",
+            Engine::Lz4 => "lz4",
+        };
+        f.debug_struct("ValueCodec")
+            .field("kind", &kind)
+            .field("level", &self.level)
+            .field("dictionary_len", &self.dictionary.as_ref().map(Vec::len))
+            .finish()
+    }
+}
+
+// A zstd block decompresses to at most 128 KiB and takes at least 3 bytes (its header),
+// which bounds what a frame of a given length can decompress to
+const MAX_BLOCK_LEN: usize = 128 * 1024;
+const MIN_BLOCK_HEADER_LEN: usize = 3;
+
+// An LZ4 sequence expands to at most 255 bytes per input byte (a run of length bytes),
+// plus the literals of the last one
+const MAX_LZ4_RATIO: usize = 255;
+const LZ4_LAST_LITERALS: usize = 16;
+
+fn max_decompressed_len(engine: &Engine, frame_len: usize) -> usize {
+    match engine {
+        Engine::Zstd { .. } => (frame_len / MIN_BLOCK_HEADER_LEN).saturating_mul(MAX_BLOCK_LEN),
+        Engine::Lz4 => frame_len.saturating_mul(MAX_LZ4_RATIO).saturating_add(LZ4_LAST_LITERALS),
+    }
+}
+
+impl ValueCodec {
+    fn new(level: i32, dictionary: Option<Vec<u8>>) -> io::Result<Self> {
+        let (mut compressor, decompressor) = match &dictionary {
+            Some(dictionary) => (Compressor::with_dictionary(level, dictionary)?, Decompressor::with_dictionary(dictionary)?),
+            None => (Compressor::new(level)?, Decompressor::new()?),
+        };
+        // The length prefix already records the size and the table records the dictionary,
+        // so drop both from every frame header
+        compressor.set_parameter(CParameter::ContentSizeFlag(false))?;
+        compressor.set_parameter(CParameter::DictIdFlag(false))?;
+        Ok(ValueCodec { level, dictionary, engine: Engine::Zstd { compressor, decompressor } })
+    }
+
+    fn lz4() -> Self {
+        ValueCodec { level: 0, dictionary: None, engine: Engine::Lz4 }
+    }
+
+    // Codec for a table about to be written from `values`. For ZstdDictionary a dictionary
+    // is trained on an evenly spread sample of them; if there are too few values or
+    // training fails, the values are compressed on their own instead.
+    pub fn train(compression: Compression, values: &[&[u8]]) -> Option<Self> {
+        match compression {
+            Compression::None => None,
+            Compression::Lz4 => Some(Self::lz4()),
+            Compression::Zstd { level } => Self::new(level, None).ok(),
+            Compression::ZstdDictionary { level, max_dict_len } => {
+                let step = values.len().div_ceil(MAX_TRAINING_SAMPLES).max(1);
+                let samples: Vec<&[u8]> = values.iter().step_by(step).copied().collect();
+                let dictionary = (samples.len() >= MIN_TRAINING_SAMPLES)
+                    .then(|| zstd::dict::from_samples(&samples, max_dict_len).ok())
+                    .flatten();
+                Self::new(level, dictionary).ok()
+            }
+        }
+    }
+
+    // Codec for a table whose values can't be sampled up front (compaction output, bulk
+    // loads), reusing `dictionary` from an existing table when there is one
+    pub fn reuse(compression: Compression, dictionary: Option<&[u8]>) -> Option<Self> {
+        match compression {
+            Compression::None => None,
+            Compression::Lz4 => Some(Self::lz4()),
+            Compression::Zstd { level } => Self::new(level, None).ok(),
+            Compression::ZstdDictionary { level, .. } => Self::new(level, dictionary.map(<[u8]>::to_vec)).ok(),
+        }
+    }
+
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
+    }
+
+    pub fn compress(&mut self, value: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = (value.len() as u32).to_be_bytes().to_vec();
+        match &mut self.engine {
+            Engine::Zstd { compressor, .. } => out.extend_from_slice(&compressor.compress(value)?),
+            Engine::Lz4 => out.extend_from_slice(&lz4_flex::block::compress(value)),
+        }
+        Ok(out)
+    }
+
+    pub fn decompress(&mut self, stored: &[u8]) -> io::Result<Vec<u8>> {
+        let (len_bytes, frame) = stored.split_at_checked(4)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "compressed value is missing its length"))?;
+        let value_len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        // The output buffer is allocated at the recorded length, so a garbled one mustn't
+        // ask for more than the frame could possibly hold
+        if value_len > max_decompressed_len(&self.engine, frame.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed value's length is larger than its frame can hold"));
+        }
+
+        let value = match &mut self.engine {
+            Engine::Zstd { decompressor, .. } => decompressor.decompress(frame, value_len)?,
+            Engine::Lz4 => lz4_flex::block::decompress(frame, value_len)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        };
+        if value.len() != value_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed value has the wrong length"));
+        }
+        Ok(value)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match (&self.engine, &self.dictionary) {
+            (Engine::Lz4, _) => CODEC_LZ4,
+            (Engine::Zstd { .. }, Some(_)) => CODEC_ZSTD_DICTIONARY,
+            (Engine::Zstd { .. }, None) => CODEC_ZSTD,
+        };
+        let dictionary = self.dictionary.as_deref().unwrap_or_default();
+
+        let mut out = vec![kind];
+        out.extend_from_slice(&self.level.to_be_bytes());
+        out.extend_from_slice(&(dictionary.len() as u32).to_be_bytes());
+        out.extend_from_slice(dictionary);
+        out
+    }
+
+    // Returns None if `bytes` is malformed
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let kind = *bytes.first()?;
+        let level = i32::from_be_bytes(bytes.get(1..5)?.try_into().ok()?);
+        let dictionary_len = u32::from_be_bytes(bytes.get(5..9)?.try_into().ok()?) as usize;
+        let dictionary = bytes.get(9..9usize.checked_add(dictionary_len)?)?;
+
+        let dictionary = match kind {
+            CODEC_LZ4 => return Some(Self::lz4()),
+            CODEC_ZSTD => None,
+            CODEC_ZSTD_DICTIONARY => Some(dictionary.to_vec()),
+            _ => return None,
+        };
+        Self::new(level, dictionary).ok()
+    }
+}
@@ -0,0 +1,159 @@
+// Crash consistency checking, built with the crash-test feature. Replays a sequence of
+// operations, "crashes" part way through by copying the data directory as the OS sees it
+// (so anything still sitting in a userspace buffer is lost) and tearing the last write,
+// then recovers from the copy and checks what came back.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use crate::error::DbexError;
+use crate::DBex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CrashOp {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    // Acknowledges every earlier operation as durable
+    Flush,
+}
+
+// What one simulated crash recovered
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashOutcome {
+    // Operations acknowledged before the crash
+    pub crash_after: usize,
+    // Bytes cut off the end of the WAL
+    pub wal_bytes_torn: u64,
+    // Whether the crash hit the next operation, a flush, while it was writing its SSTable
+    pub torn_flush: bool,
+    // Length of the prefix of `ops` the recovered data matches
+    pub recovered_ops: usize,
+}
+
+type State = BTreeMap<Vec<u8>, Vec<u8>>;
+
+impl DBex {
+    // Runs `trials` crashes at points chosen from `seed`, half of them during a flush if
+    // `ops` has any, each against a fresh database under `path`. After recovery the data
+    // has to match the first N operations for some N no smaller than the last
+    // acknowledged flush; otherwise this fails with DbexError::Corruption describing the
+    // crash.
+    pub fn crash_consistency_test<P: AsRef<Path>>(path: P, ops: &[CrashOp], trials: usize, seed: u64) -> Result<Vec<CrashOutcome>, DbexError> {
+        let path = path.as_ref();
+        let live_path = path.join("live");
+        let crashed_path = path.join("crashed");
+        let mut rng = seed | 1;
+
+        let prefix_states = Self::prefix_states(ops);
+        let flush_indexes: Vec<usize> = (0..ops.len()).filter(|&idx| ops[idx] == CrashOp::Flush).collect();
+        let mut outcomes = Vec::with_capacity(trials);
+        for trial in 0..trials {
+            // Flushes are rare among the ops but have the most to get wrong, so every other
+            // crash lands in the middle of one
+            let crash_after = match flush_indexes.len() {
+                flushes if flushes > 0 && trial % 2 == 1 => flush_indexes[(next_random(&mut rng) % flushes as u64) as usize],
+                _ => (next_random(&mut rng) % (ops.len() as u64 + 1)) as usize,
+            };
+            fs::remove_dir_all(path).ok();
+
+            let mut db = DBex::try_open(&live_path)?;
+            for op in &ops[..crash_after] {
+                db.apply_crash_op(op)?;
+            }
+            copy_dir(&live_path, &crashed_path)?;
+
+            let torn_flush = ops.get(crash_after) == Some(&CrashOp::Flush);
+            let mut wal_bytes_torn = 0;
+            if torn_flush {
+                // The new table's files reached the disk partially and the manifest was
+                // never updated, so recovery has to ignore them and replay the WAL
+                let before: BTreeSet<_> = fs::read_dir(crashed_path.join("ss_tables"))?
+                    .map(|entry| entry.map(|entry| entry.file_name()))
+                    .collect::<Result<_, _>>()?;
+                db.flush()?;
+                for entry in fs::read_dir(live_path.join("ss_tables"))? {
+                    let entry = entry?;
+                    if before.contains(&entry.file_name()) {
+                        continue;
+                    }
+                    let torn_path = crashed_path.join("ss_tables").join(entry.file_name());
+                    fs::copy(entry.path(), &torn_path)?;
+                    let len = fs::metadata(&torn_path)?.len();
+                    OpenOptions::new().write(true).open(&torn_path)?.set_len(next_random(&mut rng) % (len + 1))?;
+                }
+            } else {
+                let wal_path = crashed_path.join("wals").join("cur.wal");
+                let len = fs::metadata(&wal_path)?.len();
+                wal_bytes_torn = next_random(&mut rng) % (len.min(256) + 1);
+                OpenOptions::new().write(true).open(&wal_path)?.set_len(len - wal_bytes_torn)?;
+            }
+            drop(db);
+
+            let mut recovered = DBex::try_open(&crashed_path)?;
+            let state: State = recovered.live_range(&[], None)?.collect();
+            drop(recovered);
+
+            let durable = ops[..crash_after].iter().rposition(|op| *op == CrashOp::Flush).map_or(0, |idx| idx + 1);
+            let recovered_ops = (durable..=crash_after).rev()
+                .find(|&prefix_len| prefix_states[prefix_len] == state)
+                .ok_or_else(|| DbexError::Corruption(format!(
+                    "crash after {} ops (wal torn by {} bytes, torn flush: {}) recovered {} keys matching no prefix of at least {} ops",
+                    crash_after, wal_bytes_torn, torn_flush, state.len(), durable,
+                )))?;
+
+            outcomes.push(CrashOutcome { crash_after, wal_bytes_torn, torn_flush, recovered_ops });
+        }
+
+        fs::remove_dir_all(path).ok();
+        Ok(outcomes)
+    }
+
+    fn apply_crash_op(&mut self, op: &CrashOp) -> Result<(), DbexError> {
+        match op {
+            CrashOp::Insert(key, value) => self.insert(key.clone(), value.clone()),
+            CrashOp::Remove(key) => self.remove(key),
+            CrashOp::Flush => self.flush().map(|_| ()),
+        }
+    }
+
+    // The expected contents after each prefix of `ops`, from empty to all of them
+    fn prefix_states(ops: &[CrashOp]) -> Vec<State> {
+        let mut state = State::new();
+        let mut states = vec![state.clone()];
+        for op in ops {
+            match op {
+                CrashOp::Insert(key, value) => {
+                    state.insert(key.clone(), value.clone());
+                }
+                CrashOp::Remove(key) => {
+                    state.remove(key);
+                }
+                CrashOp::Flush => {}
+            }
+            states.push(state.clone());
+        }
+        states
+    }
+}
+
+// xorshift64; crash points only need to be spread out and reproducible from the seed
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), DbexError> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
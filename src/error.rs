@@ -0,0 +1,104 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum DbexError {
+    Io(io::Error),
+    // A write was attempted through a handle opened with DBex::open_read_only
+    ReadOnly,
+    // Another writer holds the lock file of this data directory
+    Locked(PathBuf),
+    // On-disk data failed validation (bad checksum, truncated file, ...)
+    Corruption(String),
+    // bulk_load saw this key more than once under DuplicateKeys::Error
+    DuplicateKey(Vec<u8>),
+    // bulk_load input wasn't sorted; this key came after a larger one
+    UnsortedInput(Vec<u8>),
+    // A panic caught at the API boundary (see DBexOptions::catch_panics)
+    Internal(String),
+    // recover_to_lsn needs the history kept by DBexOptions::archive_wal
+    WalNotArchived,
+    // An insert's value was longer than DBexOptions::max_value_size
+    ValueTooLarge { len: usize, max: usize },
+    // A ShardedDb was opened with a layout other than the one its directory was created
+    // with, which is given here
+    ShardLayoutMismatch { shard_count: usize, prefix_len: Option<usize> },
+    // An operation passed to write_batch that it can't apply, e.g. an insert without a value
+    InvalidBatch(String),
+    // insert_with_ttl was called with a transaction open, which can't stage expiries
+    TtlInTransaction,
+    // compare_and_swap was called with a transaction open; staged writes have no LSN to
+    // compare against or return until commit
+    CasInTransaction,
+}
+
+impl fmt::Display for DbexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbexError::Io(err) => write!(f, "io error: {}", err),
+            DbexError::ReadOnly => write!(f, "database was opened read-only"),
+            DbexError::Locked(path) => write!(f, "database locked: {} is held by another writer", path.display()),
+            DbexError::Corruption(msg) => write!(f, "corruption: {}", msg),
+            DbexError::DuplicateKey(key) => write!(f, "duplicate key in bulk load: {:?}", key),
+            DbexError::UnsortedInput(key) => write!(f, "bulk load input is not sorted at key {:?}", key),
+            DbexError::Internal(msg) => write!(f, "internal error: {}", msg),
+            DbexError::WalNotArchived => write!(f, "point-in-time recovery needs the archive_wal option"),
+            DbexError::ValueTooLarge { len, max } => write!(f, "value of {} bytes exceeds max_value_size of {}", len, max),
+            DbexError::ShardLayoutMismatch { shard_count, prefix_len: Some(len) } => write!(f, "data directory is split into {} shards by {}-byte key prefix", shard_count, len),
+            DbexError::ShardLayoutMismatch { shard_count, prefix_len: None } => write!(f, "data directory is split into {} shards by whole key", shard_count),
+            DbexError::InvalidBatch(msg) => write!(f, "invalid batch: {}", msg),
+            DbexError::TtlInTransaction => write!(f, "inserts with a TTL can't be made inside a transaction"),
+            DbexError::CasInTransaction => write!(f, "compare-and-swap can't be made inside a transaction"),
+        }
+    }
+}
+
+impl std::error::Error for DbexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbexError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Returned by DBex::compare_and_swap
+#[derive(Debug)]
+pub enum CasError {
+    // The key's LSN didn't match the expected one. `current_lsn` is what lsn_of
+    // returned, None if the key is absent.
+    Conflict { current_lsn: Option<u64> },
+    Dbex(DbexError),
+}
+
+impl fmt::Display for CasError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CasError::Conflict { current_lsn: Some(lsn) } => write!(f, "compare-and-swap conflict: key is at lsn {}", lsn),
+            CasError::Conflict { current_lsn: None } => write!(f, "compare-and-swap conflict: key has no lsn"),
+            CasError::Dbex(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CasError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CasError::Dbex(err) => Some(err),
+            CasError::Conflict { .. } => None,
+        }
+    }
+}
+
+impl From<DbexError> for CasError {
+    fn from(err: DbexError) -> Self {
+        CasError::Dbex(err)
+    }
+}
+
+impl From<io::Error> for DbexError {
+    fn from(err: io::Error) -> Self {
+        DbexError::Io(err)
+    }
+}
@@ -0,0 +1,160 @@
+// Conversions that let integer and fixed-size keys be passed straight into DBex
+// lookups without building a Vec<u8> for each call.
+//
+// Integers are encoded big-endian so byte order matches numeric order. Signed
+// integers additionally flip the sign bit so negative values sort first.
+
+// Borrowed or stack-allocated key bytes, used by read and delete paths
+pub trait AsKeyBytes {
+    type Bytes: AsRef<[u8]>;
+
+    fn key_bytes(self) -> Self::Bytes;
+}
+
+impl<'a, T: AsRef<[u8]> + ?Sized> AsKeyBytes for &'a T {
+    type Bytes = &'a [u8];
+
+    fn key_bytes(self) -> &'a [u8] {
+        self.as_ref()
+    }
+}
+
+impl AsKeyBytes for u32 {
+    type Bytes = [u8; 4];
+
+    fn key_bytes(self) -> [u8; 4] {
+        self.to_be_bytes()
+    }
+}
+
+impl AsKeyBytes for u64 {
+    type Bytes = [u8; 8];
+
+    fn key_bytes(self) -> [u8; 8] {
+        self.to_be_bytes()
+    }
+}
+
+// Always 8 bytes, regardless of the platform's pointer width
+impl AsKeyBytes for usize {
+    type Bytes = [u8; 8];
+
+    fn key_bytes(self) -> [u8; 8] {
+        (self as u64).to_be_bytes()
+    }
+}
+
+impl AsKeyBytes for i32 {
+    type Bytes = [u8; 4];
+
+    fn key_bytes(self) -> [u8; 4] {
+        ((self as u32) ^ (1 << 31)).to_be_bytes()
+    }
+}
+
+impl AsKeyBytes for i64 {
+    type Bytes = [u8; 8];
+
+    fn key_bytes(self) -> [u8; 8] {
+        ((self as u64) ^ (1 << 63)).to_be_bytes()
+    }
+}
+
+// Owned key bytes, used by insert since the memtable has to own its keys
+pub trait IntoKey {
+    fn into_key(self) -> Vec<u8>;
+}
+
+impl IntoKey for Vec<u8> {
+    fn into_key(self) -> Vec<u8> {
+        self
+    }
+}
+
+impl<K: AsKeyBytes> IntoKey for K {
+    fn into_key(self) -> Vec<u8> {
+        self.key_bytes().as_ref().to_vec()
+    }
+}
+
+// Component encoding for CompositeKey: every 0x00 byte inside a component is escaped as
+// 0x00 0xFF and each component ends with 0x00 0x01. The terminator sorts below every
+// other byte a component can continue with, so a shorter component sorts before any
+// component it is a prefix of and the encoded keys order component by component.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+// Builds a single key out of several components (e.g. tenant, then timestamp) that sorts
+// the same way as comparing the components in turn, whatever bytes they contain
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompositeKey {
+    bytes: Vec<u8>,
+}
+
+impl CompositeKey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Appends a component. Integers are encoded as for plain keys, so they sort numerically.
+    pub fn push<K: AsKeyBytes>(mut self, component: K) -> Self {
+        for &byte in component.key_bytes().as_ref() {
+            self.bytes.push(byte);
+            if byte == ESCAPE {
+                self.bytes.push(ESCAPED_ZERO);
+            }
+        }
+        self.bytes.extend_from_slice(&[ESCAPE, TERMINATOR]);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    // Splits an encoded key back into its components. Returns None if `bytes` wasn't
+    // produced by CompositeKey.
+    pub fn decode(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let mut components = Vec::new();
+        let mut component = Vec::new();
+        let mut bytes = bytes.iter();
+
+        while let Some(&byte) = bytes.next() {
+            if byte != ESCAPE {
+                component.push(byte);
+                continue;
+            }
+            match *bytes.next()? {
+                ESCAPED_ZERO => component.push(ESCAPE),
+                TERMINATOR => components.push(std::mem::take(&mut component)),
+                _ => return None,
+            }
+        }
+
+        // A trailing component without its terminator means the key was truncated
+        component.is_empty().then_some(components)
+    }
+}
+
+impl AsRef<[u8]> for CompositeKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<CompositeKey> for Vec<u8> {
+    fn from(key: CompositeKey) -> Vec<u8> {
+        key.bytes
+    }
+}
+
+impl IntoKey for CompositeKey {
+    fn into_key(self) -> Vec<u8> {
+        self.bytes
+    }
+}
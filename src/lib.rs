@@ -1,313 +1,2588 @@
+pub mod bloom_filter;
+pub mod compression;
+#[cfg(feature = "crash-test")]
+pub mod crash_test;
+pub mod error;
+pub mod key;
+pub mod manifest;
 pub mod memtable;
+mod merge;
+pub mod options;
+pub mod sharded;
+pub mod snapshot;
 pub mod ss_table;
+pub mod stats;
+pub mod storage;
 pub mod write_ahead_log;
 pub mod utils;
 
 
-use std::cmp::Reverse;
-use std::collections::BinaryHeap;
-use std::fs;
-use std::io::{Seek, SeekFrom};
-use std::mem::take;
+use std::cmp::{Ordering, Reverse};
+use std::io::{ErrorKind, Read};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::mem::{replace, take};
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 // src/lib.rs
+use crate::compression::ValueCodec;
+use crate::error::{CasError, DbexError};
+use crate::key::{AsKeyBytes, IntoKey};
+use crate::manifest::Manifest;
 use crate::memtable::MemTable;
-use crate::ss_table::SSTable;
-use crate::utils::Operation::{Delete, Insert};
-use crate::write_ahead_log::WriteAheadLog;
+use crate::merge::{EntrySource, MergeIter};
+use crate::snapshot::{Snapshot, TablePin};
+use crate::options::{Compression, DBexOptions, DuplicateKeys, KeyHint, MergeConflict, SyncPolicy};
+use crate::ss_table::{owning_data_path, table_number, KeyVersion, SSTable, ValueRef};
+use crate::stats::{BackupReport, CompactionStats, DBexStats, FlushStats, MaintenanceReport, MemoryStats, QuarantinedTable, RecoveryStats, RepairReport, VerifyStats};
+use crate::storage::{LocalStorage, Storage, StorageLock};
+use crate::utils::{prefix_end, unix_millis, Operation, RateLimiter};
+use crate::write_ahead_log::{expiring_value, LoggedWrite, WriteAheadLog};
+
+// One scanned source's entries in key order, None marking a tombstone
+type ScanRun = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+// Live key-value pairs in key order
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+// Every version of one key, newest source first
+type Versions = Vec<(ReadSource, Option<Vec<u8>>)>;
+
+// Summary of the SSTable produced by a flush
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushInfo {
+    pub min_key: Vec<u8>,
+    pub max_key: Vec<u8>,
+    pub entry_count: u64,
+    pub size_bytes: u64,
+}
+
+// Physical layout of one SSTable, as reported by DBex::list_sstables
+#[derive(Debug, Clone, PartialEq)]
+pub struct SSTableInfo {
+    pub data_path: PathBuf,
+    pub index_path: PathBuf,
+    pub min_key: Vec<u8>,
+    pub max_key: Vec<u8>,
+    // Stored entries, tombstones included
+    pub entry_count: u64,
+    pub size_bytes: u64,
+    pub level: usize,
+    // LSN of the next write when the table was created
+    pub created_lsn: u64,
+    // Every file of the table, data and index first
+    pub files: Vec<PathBuf>,
+}
+
+// Where a copy of a key was found
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadSource {
+    // Staged by the open transaction, not yet committed
+    Transaction,
+    Memtable,
+    ImmutableMemtable,
+    SSTable { level: usize, data_path: PathBuf },
+}
+
+// What DBex::get_status found for a key
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyStatus {
+    Present(Vec<u8>),
+    // The newest copy is a tombstone (or an expired value)
+    Deleted,
+    // No source holds the key, or compaction has dropped its tombstone
+    Absent,
+}
+
+// Lifecycle of a memtable once it stops taking writes. It stays readable through
+// every state until its SSTable is installed in the manifest, and only then is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemTableState {
+    // Only the active memtable exists
+    Active,
+    // Frozen and waiting to be flushed; new writes go to a fresh active memtable
+    Frozen,
+    // Its SSTable is being written
+    Flushing,
+    // Its SSTable is installed, so it is about to be dropped
+    Flushed,
+}
+
+// A memtable holding this many bytes of keys and values is flushed
+const MEMTABLE_FLUSH_BYTES: usize = 64 * 1024 * 1024;
+
+// Levels when DBexOptions::level_count is None: L0, L1 and L2
+const DEFAULT_LEVEL_COUNT: usize = 3;
+
+// Keys estimate_live_data_size samples from each SSTable
+const LIVE_DATA_SAMPLE_KEYS: usize = 64;
 
 pub struct DBex {
     memtable: MemTable,
     immutable_memtable: Option<MemTable>,
-    l0_ss_tables: Vec<SSTable>,
-    l1_ss_tables: Vec<SSTable>,
-    l2_ss_tables: Vec<SSTable>,
-    write_ahead_log: WriteAheadLog,
-    is_in_txn: bool,
-    record_count: u64,
+    immutable_state: MemTableState,
+    // L0 first. Each level holds its tables oldest to newest, and every level but the
+    // last is compacted into the next once it holds more than 10.
+    levels: Vec<Vec<SSTable>>,
+    // None for read-only handles, which never write a WAL
+    write_ahead_log: Option<WriteAheadLog>,
+    // Writes staged since start_txn (None for a remove), applied by commit_txn. Only
+    // this handle's reads see them until then.
+    txn: Option<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+    // Number of live keys, once len has counted them; from then on every insert and remove
+    // checks whether its key was live to keep it exact. None until len is first called,
+    // and again after writes that add keys in bulk (bulk_load, ingest_sstable, ...).
+    record_count: Option<u64>,
     lsn: u64,
+    options: DBexOptions,
+    data_dir: PathBuf,
+    read_only: bool,
+    // Set by set_compaction_paused; levels may then grow past their thresholds
+    compaction_paused: bool,
+    // Exclusive advisory lock on `<data_dir>/LOCK`, released when the handle is dropped
+    _lock_file: Option<StorageLock>,
+    storage: Arc<dyn Storage>,
+    // Number of SSTables actually read by scans, for observing Bloom pruning
+    ss_tables_touched: u64,
+    // Tables skipped at open because they failed validation
+    corrupt_ss_tables: Vec<PathBuf>,
+    stats: DBexStats,
+    // Number the next new SSTable is named after. Only ever increases, and is persisted in
+    // the manifest, so file names follow creation order regardless of the clock.
+    next_table_number: u64,
+    // Writes with an LSN below this are on disk, in a synced WAL or a flushed SSTable
+    durable_lsn: u64,
+    // Pins of the tables open snapshots read, by data path (see retire_ss_table)
+    table_pins: Mutex<HashMap<PathBuf, Weak<TablePin>>>,
+}
+
+// Keeps DBex shareable behind a Mutex or RwLock; fails to compile if a field stops being Send + Sync
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<DBex>();
+};
+
+impl Default for DBex {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DBex {
+    // Panics if `db_data` can't be opened; try_new reports the error instead
     pub fn new() -> Self {
-        fs::create_dir_all("db_data/wals").unwrap();
-        fs::create_dir_all("db_data/ss_tables").unwrap();
-        DBex {
+        Self::try_new().unwrap()
+    }
+
+    pub fn try_new() -> Result<Self, DbexError> {
+        Self::try_with_options(DBexOptions::default())
+    }
+
+    pub fn with_options(options: DBexOptions) -> Self {
+        Self::try_with_options(options).unwrap()
+    }
+
+    pub fn try_with_options(options: DBexOptions) -> Result<Self, DbexError> {
+        Self::try_open_with_options("db_data", options)
+    }
+
+    // Default options tuned for `hint` (see DBexOptions::with_key_hint); to combine a hint
+    // with a path or other options, open with DBexOptions::default().with_key_hint(hint)
+    pub fn with_key_hint(hint: KeyHint) -> Self {
+        Self::with_options(DBexOptions::default().with_key_hint(hint))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+        Self::open_with_options(path, DBexOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: DBexOptions) -> Self {
+        Self::try_open_with_options(path, options).unwrap()
+    }
+
+    pub fn try_open<P: AsRef<Path>>(path: P) -> Result<Self, DbexError> {
+        Self::try_open_with_options(path, DBexOptions::default())
+    }
+
+    // Fails with DbexError::Locked if another writer already has this directory open.
+    // Tables are loaded from the manifest, and unless the previous writer shut down
+    // through close(), the WAL is replayed into the memtable.
+    pub fn try_open_with_options<P: AsRef<Path>>(path: P, options: DBexOptions) -> Result<Self, DbexError> {
+        let data_dir = path.as_ref().to_path_buf();
+        let storage = options.storage.clone().unwrap_or_else(|| Arc::new(LocalStorage));
+        storage.create_dir_all(&data_dir.join("wals"))?;
+        storage.create_dir_all(&data_dir.join("ss_tables"))?;
+        let lock_file = Self::acquire_lock(storage.as_ref(), &data_dir)?;
+
+        // A missing manifest next to existing tables means it was lost, not that the
+        // database is new: rebuild the levels from the files rather than orphan them
+        let loaded_manifest = Manifest::load(storage.as_ref(), &data_dir)?;
+        let manifest_rebuilt = loaded_manifest.is_none() && !Self::ss_table_data_paths(&storage, &data_dir)?.is_empty();
+        let manifest = loaded_manifest.unwrap_or_default();
+        // Also past any table the manifest doesn't know about, such as one left behind by
+        // a crash, so a new table can never take its name
+        let next_table_number = Self::ss_table_data_paths(&storage, &data_dir)?.iter()
+            .filter_map(|data_path| table_number(data_path))
+            .max()
+            .map_or(0, |number| number + 1)
+            .max(manifest.next_table_number);
+        let level_count = Self::level_count_of(&options);
+        let (mut levels, corrupt_ss_tables) = match manifest_rebuilt {
+            true => Self::scan_ss_tables(&storage, &data_dir, level_count)?,
+            false => Self::load_levels(&storage, &data_dir, &manifest, level_count)?,
+        };
+        // The lost manifest also held the next LSN; the tables' own LSNs are the best guess
+        let tables_next_lsn = match manifest_rebuilt {
+            true => levels.iter_mut()
+                .flatten()
+                .filter_map(SSTable::max_lsn)
+                .max()
+                .map_or(0, |lsn| lsn + 1),
+            false => 0,
+        };
+
+        let mut db = DBex {
+            memtable: Self::new_memtable(&options),
+            immutable_memtable: None,
+            immutable_state: MemTableState::Active,
+            levels,
+            write_ahead_log: Some(WriteAheadLog::new(storage.clone(), &data_dir.join("wals"), options.wal_coalesce_window, options.wal_record_format)?),
+            txn: None,
+            record_count: None,
+            lsn: manifest.next_lsn.max(tables_next_lsn).max(options.start_lsn),
+            options,
+            data_dir,
+            read_only: false,
+            compaction_paused: false,
+            _lock_file: Some(lock_file),
+            storage,
+            ss_tables_touched: 0,
+            corrupt_ss_tables,
+            stats: DBexStats::default(),
+            next_table_number,
+            durable_lsn: 0,
+            table_pins: Mutex::default(),
+        };
+        for ss_table in db.levels.iter_mut().flatten() {
+            Self::apply_table_options(&db.options, ss_table);
+            // When a table came about is lost with the manifest, so count it as new
+            if manifest_rebuilt {
+                ss_table.set_created_lsn(db.lsn);
+            }
+            // Fail now rather than on every read of a table we can't decode
+            ss_table.check_value_transform()?;
+        }
+
+        let wal_entries_replayed = if manifest.clean_shutdown { 0 } else { db.replay_wal()? };
+        // Everything replayed was read back from disk
+        db.durable_lsn = db.lsn;
+        db.stats.recovery = RecoveryStats {
+            clean_shutdown: manifest.clean_shutdown,
+            wal_entries_replayed,
+            manifest_rebuilt,
+        };
+
+        // From here on, a crash counts as an unclean shutdown
+        db.write_manifest(false)?;
+        Ok(db)
+    }
+
+    // Opens the tables listed in `manifest`, level by level. Tables that fail validation
+    // are left out and returned separately. So is a table missing a file, but only if
+    // its writes can be replayed from the WAL instead (see wal_covers); otherwise its
+    // data is gone and opening fails with DbexError::Corruption.
+    // Makes at least `level_count` levels, and more if the manifest lists tables deeper
+    // than that, so a database opened with fewer levels than it was written with keeps
+    // all of its tables
+    fn load_levels(storage: &Arc<dyn Storage>, data_dir: &Path, manifest: &Manifest, level_count: usize) -> Result<(Vec<Vec<SSTable>>, Vec<PathBuf>), DbexError> {
+        let mut levels: Vec<Vec<SSTable>> = Vec::new();
+        levels.resize_with(level_count.max(manifest.levels.len()), Vec::new);
+        let mut corrupt_ss_tables = Vec::new();
+        for (level_number, (level, file_names)) in levels.iter_mut().zip(&manifest.levels).enumerate() {
+            for (table, file_name) in file_names.iter().enumerate() {
+                let data_path = data_dir.join("ss_tables").join(file_name);
+                // Tables from before creation LSNs were recorded existed by next_lsn
+                let created_lsn = manifest.created_lsns.get(level_number)
+                    .and_then(|lsns| lsns.get(table))
+                    .copied()
+                    .unwrap_or(manifest.next_lsn);
+                match SSTable::open(storage.clone(), &data_path) {
+                    Ok(mut ss_table) => {
+                        ss_table.set_created_lsn(created_lsn);
+                        level.push(ss_table);
+                    }
+                    Err(DbexError::Corruption(_)) => corrupt_ss_tables.push(data_path),
+                    Err(DbexError::Io(err)) if err.kind() == ErrorKind::NotFound => {
+                        if level_number != 0 || !Self::wal_covers(storage.as_ref(), data_dir, manifest, created_lsn)? {
+                            return Err(DbexError::Corruption(format!(
+                                "{}: table listed in the manifest is missing a file ({})", data_path.display(), err
+                            )));
+                        }
+                        log::warn!("{}: table is missing a file, recovering its writes from the WAL", data_path.display());
+                        corrupt_ss_tables.push(data_path);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok((levels, corrupt_ss_tables))
+    }
+
+    // Whether the WAL still holds the writes of the L0 table created at `created_lsn`.
+    // The WAL is only cleared once a flush's table is in the manifest, so if it still
+    // holds writes from before the table was created, the crash came between the two and
+    // replay brings them back. Compaction output never qualifies: it lands below L0, or
+    // when coalescing L0, right after a clear. A table bulk loaded or ingested into L0
+    // while unflushed writes were logged can't be told apart from a flushed one, though,
+    // and is skipped the same way.
+    fn wal_covers(storage: &dyn Storage, data_dir: &Path, manifest: &Manifest, created_lsn: u64) -> Result<bool, DbexError> {
+        let wal_path = data_dir.join("wals").join("cur.wal");
+        if manifest.clean_shutdown || !storage.exists(&wal_path) {
+            return Ok(false);
+        }
+        let wal_entries = WriteAheadLog::read_file(storage, &wal_path, 0)?;
+        Ok(wal_entries.first().is_some_and(|wal_entry| wal_entry.lsn() < created_lsn))
+    }
+
+    // Reapplies every logged write to the memtable and returns how many there were. The
+    // memtable is flushed whenever the replay_batch_size option or the usual size limit
+    // is reached. Once replay has produced a table, the rest is flushed too and the WAL
+    // cleared, so a later crash doesn't replay the same entries again.
+    fn replay_wal(&mut self) -> Result<u64, DbexError> {
+        let Some(write_ahead_log) = self.write_ahead_log.as_mut() else {
+            return Ok(0);
+        };
+
+        let wal_entries = write_ahead_log.recover()?;
+        let replayed = wal_entries.len() as u64;
+        let mut applied_since_flush = 0;
+        let mut flushed = false;
+        for wal_entry in wal_entries {
+            let entry_lsn = wal_entry.lsn();
+            self.lsn = self.lsn.max(entry_lsn + 1);
+            match wal_entry.into_write() {
+                Some((key, Some(value), None)) => self.memtable.insert_with_lsn(key, value, entry_lsn),
+                Some((key, Some(value), Some(expires_at))) => self.memtable.insert_with_expiry(key, value, entry_lsn, expires_at),
+                Some((key, None, _)) => self.memtable.remove_with_lsn(&key, entry_lsn),
+                None => {}
+            }
+
+            applied_since_flush += 1;
+
+            let batch_full = self.options.replay_batch_size
+                .is_some_and(|batch_size| applied_since_flush >= batch_size);
+            if batch_full || self.memtable.size_byte() >= MEMTABLE_FLUSH_BYTES {
+                applied_since_flush = 0;
+                flushed = true;
+                self.freeze_memtable()?;
+                self.flush_immutable_memtable()?;
+                self.compact_if_needed()?;
+            }
+        }
+        if flushed {
+            self.flush_unguarded()?;
+        }
+        Ok(replayed)
+    }
+
+    // Commits the current set of tables. The ss_tables directory is fsynced first, so the
+    // entries of table files created since the last commit are on disk before a manifest
+    // naming them is; without it a power failure could leave the manifest pointing at
+    // files that never made it into the directory. Skipped with SyncPolicy::None, which
+    // doesn't sync the table files either.
+    fn write_manifest(&self, clean_shutdown: bool) -> Result<(), DbexError> {
+        if self.options.sync_policy != SyncPolicy::None {
+            self.storage.sync_dir(&self.ss_table_dir())?;
+        }
+        self.manifest(clean_shutdown).write(self.storage.as_ref(), &self.data_dir)
+    }
+
+    fn manifest(&self, clean_shutdown: bool) -> Manifest {
+        let file_names = |level: &Vec<SSTable>| level.iter()
+            .filter_map(|ss_table| ss_table.data_path().file_name())
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .collect();
+
+        Manifest {
+            clean_shutdown,
+            next_lsn: self.lsn,
+            levels: self.levels.iter().map(file_names).collect(),
+            next_table_number: self.next_table_number,
+            created_lsns: self.levels.iter()
+                .map(|level| level.iter().map(SSTable::created_lsn).collect())
+                .collect(),
+        }
+    }
+
+    fn acquire_lock(storage: &dyn Storage, data_dir: &Path) -> Result<StorageLock, DbexError> {
+        let lock_path = data_dir.join("LOCK");
+        storage.try_lock(&lock_path)?.ok_or(DbexError::Locked(lock_path))
+    }
+
+    // Opens the SSTables under `path` for reads only. No WAL or lock is created and every
+    // write (insert, remove, flush, purge, ...) returns DbexError::ReadOnly, so any
+    // number of read-only handles can sit alongside the single writer.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, DbexError> {
+        let data_dir = path.as_ref().to_path_buf();
+
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage);
+
+        let manifest = Manifest::load(storage.as_ref(), &data_dir)?;
+        let (levels, corrupt_ss_tables) = match &manifest {
+            Some(manifest) => Self::load_levels(&storage, &data_dir, manifest, DEFAULT_LEVEL_COUNT)?,
+            None => Self::scan_ss_tables(&storage, &data_dir, DEFAULT_LEVEL_COUNT)?,
+        };
+        let lsn = manifest.map_or(0, |manifest| manifest.next_lsn);
+
+        Ok(DBex {
             memtable: MemTable::new(),
             immutable_memtable: None,
-            l0_ss_tables: Vec::new(),
-            l1_ss_tables: Vec::new(),
-            l2_ss_tables: Vec::new(),
-            write_ahead_log: WriteAheadLog::new(),
-            is_in_txn: false,
-            record_count: 0,
-            lsn: 0,
+            immutable_state: MemTableState::Active,
+            levels,
+            write_ahead_log: None,
+            txn: None,
+            record_count: None,
+            lsn,
+            options: DBexOptions::default(),
+            data_dir,
+            read_only: true,
+            compaction_paused: false,
+            _lock_file: None,
+            storage,
+            ss_tables_touched: 0,
+            corrupt_ss_tables,
+            stats: DBexStats::default(),
+            next_table_number: 0,
+            durable_lsn: lsn,
+            table_pins: Mutex::default(),
+        })
+    }
+
+    // Without a manifest there are no level assignments to go on, so they are rebuilt from
+    // the tables' key ranges. A table that overlaps no other can't shadow or be shadowed,
+    // so it goes to the last level, the one with no table limit. The rest stay in L0 in
+    // creation order, which keeps newer entries winning, and compaction sorts them out
+    // from there.
+    fn scan_ss_tables(storage: &Arc<dyn Storage>, data_dir: &Path, level_count: usize) -> Result<(Vec<Vec<SSTable>>, Vec<PathBuf>), DbexError> {
+        let mut ss_tables = Vec::new();
+        let mut corrupt_ss_tables = Vec::new();
+        for data_path in Self::ss_table_data_paths(storage, data_dir)? {
+            match SSTable::open(storage.clone(), &data_path) {
+                Ok(ss_table) => ss_tables.push(ss_table),
+                Err(DbexError::Corruption(_)) => corrupt_ss_tables.push(data_path),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let overlaps_another = |idx: usize, ss_table: &SSTable| ss_tables.iter().enumerate().any(|(other_idx, other)| {
+            other_idx != idx && other.min_key() <= ss_table.max_key() && other.max_key() >= ss_table.min_key()
+        });
+        let in_l0: Vec<bool> = ss_tables.iter().enumerate().map(|(idx, ss_table)| overlaps_another(idx, ss_table)).collect();
+        let mut levels: Vec<Vec<SSTable>> = Vec::new();
+        levels.resize_with(level_count, Vec::new);
+        for (ss_table, in_l0) in ss_tables.into_iter().zip(in_l0) {
+            levels[if in_l0 { 0 } else { level_count - 1 }].push(ss_table);
+        }
+        Ok((levels, corrupt_ss_tables))
+    }
+
+    // Data files in the ss_tables directory, oldest to newest, whether listed in the
+    // manifest or not
+    fn ss_table_data_paths(storage: &Arc<dyn Storage>, data_dir: &Path) -> Result<Vec<PathBuf>, DbexError> {
+        let mut data_paths: Vec<PathBuf> = storage.list(&data_dir.join("ss_tables"))?
+            .into_iter()
+            .filter(|data_path| data_path.extension().is_some_and(|ext| ext == "db"))
+            .collect();
+        // Table numbers follow creation order, so this is oldest to newest
+        data_paths.sort_by_key(|data_path| table_number(data_path));
+        Ok(data_paths)
+    }
+
+    // Data paths of tables that were found on open but rejected as corrupt or incomplete
+    pub fn corrupt_ss_tables(&self) -> &[PathBuf] {
+        &self.corrupt_ss_tables
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), DbexError> {
+        if self.read_only {
+            return Err(DbexError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn new_memtable(options: &DBexOptions) -> MemTable {
+        if options.monotonic_keys {
+            return MemTable::monotonic();
+        }
+        match options.memtable_size_hint {
+            Some(expected_entries) => MemTable::with_size_hint(expected_entries),
+            None => MemTable::new(),
+        }
+    }
+
+    fn ss_table_dir(&self) -> PathBuf {
+        self.data_dir.join("ss_tables")
+    }
+
+    fn new_ss_table(&mut self) -> Result<SSTable, DbexError> {
+        let number = self.take_table_number();
+        let mut ss_table = SSTable::numbered(self.storage.clone(), &self.ss_table_dir(), number, self.options.prefix_bloom_len)?;
+        ss_table.set_created_lsn(self.lsn);
+        Ok(self.with_table_options(ss_table))
+    }
+
+    // Writes the index of a table whose entries are all written and syncs it. On failure
+    // the table's files are removed.
+    fn seal_ss_table(mut ss_table: SSTable, index: &[(Vec<u8>, u64)], sync_policy: SyncPolicy) -> Result<SSTable, DbexError> {
+        match ss_table.write_index(index).and_then(|_| ss_table.sync(sync_policy)) {
+            Ok(()) => Ok(ss_table),
+            Err(err) => {
+                ss_table.delete_files();
+                Err(err)
+            }
+        }
+    }
+
+    fn take_table_number(&mut self) -> u64 {
+        self.next_table_number += 1;
+        self.next_table_number - 1
+    }
+
+    fn with_table_options(&self, mut ss_table: SSTable) -> SSTable {
+        Self::apply_table_options(&self.options, &mut ss_table);
+        ss_table
+    }
+
+    // The level_count option, with its default and lower bound applied
+    fn level_count_of(options: &DBexOptions) -> usize {
+        options.level_count.unwrap_or(DEFAULT_LEVEL_COUNT).max(2)
+    }
+
+    // Applies the bloom_hasher, value_transform, sparse_index_interval and
+    // false_positive_rate options, if set, and the verify_checksums option to a new or
+    // just opened table
+    fn apply_table_options(options: &DBexOptions, ss_table: &mut SSTable) {
+        if let Some(false_positive_rate) = options.false_positive_rate {
+            ss_table.set_false_positive_rate(false_positive_rate);
+        }
+        if let Some(interval) = options.sparse_index_interval {
+            ss_table.set_sparse_index_interval(interval);
+        }
+        if let Some(bloom_hasher) = &options.bloom_hasher {
+            ss_table.set_bloom_hasher(bloom_hasher.clone());
+        }
+        if let Some(value_transform) = &options.value_transform {
+            ss_table.set_value_transform(value_transform.clone());
+        }
+        ss_table.set_verify_checksums(options.verify_checksums);
+    }
+
+    // The options the database was opened with, including any a key hint filled in
+    pub fn options(&self) -> &DBexOptions {
+        &self.options
+    }
+
+    pub fn memtable(&self) -> &MemTable {
+        &self.memtable
+    }
+
+    // Runs `op`, turning a panic into DbexError::Internal when the catch_panics option is
+    // set. The handle is assumed to still be usable afterwards, which holds for the
+    // unwraps this is meant to catch: they fail on I/O before in-memory state changes.
+    fn guard<T>(&mut self, op: impl FnOnce(&mut Self) -> Result<T, DbexError>) -> Result<T, DbexError> {
+        if !self.options.catch_panics {
+            return op(self);
+        }
+
+        match panic::catch_unwind(AssertUnwindSafe(|| op(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Err(DbexError::Internal(message))
+            }
+        }
+    }
+
+    pub fn insert<K: IntoKey>(&mut self, key: K, value: Vec<u8>) -> Result<(), DbexError> {
+        let key = key.into_key();
+        self.guard(|db| db.insert_unguarded(key, value))
+    }
+
+    // Like insert, but from `ttl` on the key reads as removed. The expiry is an absolute
+    // time, kept with the entry through flushes and compactions (and across restarts, so
+    // time spent closed counts towards it). An expired entry still hides older values of
+    // the key, like a tombstone, until compaction drops it. Expiry is checked against the
+    // system clock, at millisecond resolution. len counts keys as of when it last counted
+    // them, so it can still include one that has expired since.
+    pub fn insert_with_ttl<K: IntoKey>(&mut self, key: K, value: Vec<u8>, ttl: Duration) -> Result<(), DbexError> {
+        let key = key.into_key();
+        let expires_at = unix_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX));
+        self.guard(|db| db.insert_expiring(key, value, Some(expires_at)))
+    }
+
+    fn insert_unguarded(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), DbexError> {
+        self.insert_expiring(key, value, None)
+    }
+
+    fn insert_expiring(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<(), DbexError> {
+        self.check_writable()?;
+        if let Some(max) = self.options.max_value_size.filter(|&max| value.len() > max) {
+            return Err(DbexError::ValueTooLarge { len: value.len(), max });
+        }
+        if let Some(txn) = self.txn.as_mut() {
+            if expires_at.is_some() {
+                return Err(DbexError::TtlInTransaction);
+            }
+            txn.insert(key, Some(value));
+            return Ok(());
+        }
+
+        let newly_live = self.record_count.is_some() && !self.is_live(&key)?;
+
+        if let Some(write_ahead_log) = self.write_ahead_log.as_mut() {
+            match expires_at {
+                Some(expires_at) => write_ahead_log.write(Operation::InsertWithExpiry, self.lsn, Some(key.clone()), Some(expiring_value(expires_at, &value)))?,
+                None => write_ahead_log.write(Operation::Insert, self.lsn, Some(key.clone()), Some(value.clone()))?,
+            }
+        }
+        // Advanced before a possible flush, which records it in the manifest
+        self.lsn += 1;
+        self.sync_logged_write()?;
+
+        match expires_at {
+            Some(expires_at) => self.memtable.insert_with_expiry(key, value, self.lsn - 1, expires_at),
+            None => self.memtable.insert_with_lsn(key, value, self.lsn - 1),
+        }
+        self.stats.entries_written += 1;
+        if let (Some(record_count), true) = (self.record_count.as_mut(), newly_live) {
+            *record_count += 1;
+        }
+        // Expiring takes no write to keep the count in step with, so it's redone on demand
+        if expires_at.is_some() {
+            self.record_count = None;
+        }
+
+        if self.memtable.size_byte() >= MEMTABLE_FLUSH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Inserts a value of `len` bytes read from `reader` without ever holding all of it in
+    // memory, for large blobs. The value skips the memtable and WAL and goes straight into
+    // an L0 SSTable of its own, synced and installed in the manifest before this returns.
+    // If a memtable holds an earlier write of `key`, the memtables are flushed first so the
+    // new table is the newest source of the key. Compression and value transforms need
+    // whole values, so with either configured the value is read into memory and inserted
+    // as usual. Fails with an UnexpectedEof I/O error if `reader` ends early.
+    pub fn insert_reader<K: IntoKey, R: Read>(&mut self, key: K, mut reader: R, len: usize) -> Result<(), DbexError> {
+        let key = key.into_key();
+        self.guard(|db| db.insert_reader_unguarded(key, &mut reader, len))
+    }
+
+    fn insert_reader_unguarded(&mut self, key: Vec<u8>, reader: &mut dyn Read, len: usize) -> Result<(), DbexError> {
+        self.check_writable()?;
+        if let Some(max) = self.options.max_value_size.filter(|&max| len > max) {
+            return Err(DbexError::ValueTooLarge { len, max });
+        }
+        // A length of u32::MAX marks a tombstone on disk
+        if len >= u32::MAX as usize {
+            return Err(DbexError::ValueTooLarge { len, max: u32::MAX as usize - 1 });
+        }
+
+        // A transaction stages the value in memory like any other
+        if self.txn.is_some() || self.options.value_transform.is_some() || self.options.compression != Compression::None {
+            let mut value = Vec::with_capacity(len);
+            reader.take(len as u64).read_to_end(&mut value)?;
+            if value.len() < len {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            return self.insert_unguarded(key, value);
+        }
+
+        let newly_live = self.record_count.is_some() && !self.is_live(&key)?;
+        let in_memtables = [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter()
+            .flatten()
+            .any(|table| table.get_entry(&key).is_some());
+        if in_memtables {
+            self.flush_unguarded()?;
+        }
+
+        let mut ss_table = self.new_ss_table()?;
+        ss_table.record_lsn(0, self.lsn);
+        if let Err(err) = ss_table.write_entry_from(reader, len as u32) {
+            ss_table.delete_files();
+            return Err(err);
+        }
+        let ss_table = Self::seal_ss_table(ss_table, &[(key, 0)], self.options.sync_policy)?;
+        self.lsn += 1;
+        self.levels[0].push(ss_table);
+
+        if let Err(err) = self.write_manifest(false) {
+            if let Some(ss_table) = self.levels[0].pop() {
+                ss_table.delete_files();
+            }
+            return Err(err);
+        }
+        self.stats.entries_written += 1;
+        if let (Some(record_count), true) = (self.record_count.as_mut(), newly_live) {
+            *record_count += 1;
+        }
+        self.compact_if_needed()
+    }
+
+    // With the sync_wal_writes option, makes the write just logged durable before the
+    // memtable sees it
+    fn sync_logged_write(&mut self) -> Result<(), DbexError> {
+        if !self.options.sync_wal_writes {
+            return Ok(());
+        }
+        self.sync()
+    }
+
+    // Writes `new_value` only if `key` was last written at `expected_lsn`, or with None,
+    // only if the key is absent, and returns the LSN of the write. Keys whose value came
+    // in without an LSN (see lsn_of) can't be matched until a plain insert gives them one.
+    // Fails with DbexError::CasInTransaction while a transaction is open, since staged
+    // writes only get their LSNs at commit.
+    pub fn compare_and_swap<K: IntoKey>(&mut self, key: K, expected_lsn: Option<u64>, new_value: Vec<u8>) -> Result<u64, CasError> {
+        self.check_writable()?;
+        if self.txn.is_some() {
+            return Err(DbexError::CasInTransaction.into());
+        }
+        let key = key.into_key();
+
+        let current_lsn = self.lsn_of(key.as_slice())?;
+        let matches = match expected_lsn {
+            Some(expected_lsn) => current_lsn == Some(expected_lsn),
+            None => self.find_borrowed(key.as_slice())?.is_none(),
+        };
+        if !matches {
+            return Err(CasError::Conflict { current_lsn });
+        }
+
+        let lsn = self.lsn;
+        self.insert(key, new_value)?;
+        Ok(lsn)
+    }
+
+    // Returns the value of `key`, or if it has none, stores the one `init` makes and
+    // returns that. `init` only runs when the key is absent. The check and the insert
+    // happen within one call on the handle, which writers share behind a lock, so of two
+    // threads racing to initialize a key one computes and stores the value and the
+    // other gets it back.
+    pub fn get_or_insert_with<K: IntoKey, F>(&mut self, key: K, init: F) -> Result<Vec<u8>, DbexError>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        self.check_writable()?;
+        let key = key.into_key();
+
+        if let Some(value) = self.find_borrowed(key.as_slice())? {
+            return Ok(value.to_vec());
+        }
+        let value = init();
+        self.insert(key, value.clone())?;
+        Ok(value)
+    }
+
+    // Appends `suffix` to the current value of `key`, treating a missing key as empty.
+    // The read-modify-write lands as a single insert, so it consumes one LSN.
+    pub fn append<K: IntoKey>(&mut self, key: K, suffix: &[u8]) -> Result<(), DbexError> {
+        self.check_writable()?;
+        let key = key.into_key();
+
+        let mut value = self.lookup(&key)?.unwrap_or_default();
+        value.extend_from_slice(suffix);
+        self.insert(key, value)
+    }
+
+    pub fn remove<K: AsKeyBytes>(&mut self, key: K) -> Result<(), DbexError> {
+        let key = key.key_bytes().as_ref().to_vec();
+        self.guard(|db| db.remove_unguarded(key))
+    }
+
+    fn remove_unguarded(&mut self, key: Vec<u8>) -> Result<(), DbexError> {
+        self.check_writable()?;
+        if let Some(txn) = self.txn.as_mut() {
+            txn.insert(key, None);
+            return Ok(());
+        }
+        // Only a key that was live leaves the count, never one missing or already removed
+        let was_live = self.record_count.is_some() && self.is_live(&key)?;
+
+        if let Some(write_ahead_log) = self.write_ahead_log.as_mut() {
+            write_ahead_log.write(Operation::Delete, self.lsn, Some(key.clone()), None)?;
+        }
+
+        self.lsn += 1;
+        self.sync_logged_write()?;
+
+        self.memtable.remove_with_lsn(&key, self.lsn - 1);
+        self.stats.entries_deleted += 1;
+
+        if let (Some(record_count), true) = (self.record_count.as_mut(), was_live) {
+            *record_count -= 1;
+        }
+        Ok(())
+    }
+
+    // Removes every key in `keys` as one unit and returns how many of them had a live value.
+    // The deletes go through write_batch, so they're logged between transaction markers
+    // and the WAL is synced once, after the last of them, and a crash leaves either all
+    // of them or none to replay. Each key still takes its own LSN. Inside a transaction
+    // they're staged like any other write.
+    pub fn remove_many(&mut self, keys: &[Vec<u8>]) -> Result<usize, DbexError> {
+        self.guard(|db| db.remove_many_unguarded(keys))
+    }
+
+    fn remove_many_unguarded(&mut self, keys: &[Vec<u8>]) -> Result<usize, DbexError> {
+        self.check_writable()?;
+
+        // Counted before anything changes, so a failed read leaves the batch undone
+        let mut seen = HashSet::new();
+        let mut removed = 0;
+        for key in keys {
+            if seen.insert(key.as_slice()) && self.find_borrowed(key.as_slice())?.is_some() {
+                removed += 1;
+            }
+        }
+
+        // write_batch gives up on the live key count, but here it's known
+        let record_count = self.record_count;
+        let ops = keys.iter().map(|key| (Operation::Delete, key.clone(), None)).collect();
+        self.write_batch_unguarded(ops)?;
+        if self.txn.is_none() {
+            self.record_count = record_count.map(|record_count| record_count - removed as u64);
+        }
+        Ok(removed)
+    }
+
+    // The LSN the next insert or remove will be logged with. Every write takes the next
+    // one; writes staged by a transaction take theirs, in key order, when it commits.
+    // LSNs keep increasing across restarts, so one is never handed out twice.
+    pub fn current_lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    // Writes logged with an LSN below this are durable: the WAL holding them has been
+    // synced, or they've been flushed to SSTables. Trails current_lsn while writes sit
+    // in WAL buffers, and catches up on sync, flush or remove_many.
+    pub fn durable_lsn(&self) -> u64 {
+        self.durable_lsn
+    }
+
+    // Pushes every logged write to disk, making all of them durable
+    pub fn sync(&mut self) -> Result<(), DbexError> {
+        self.check_writable()?;
+        if let Some(write_ahead_log) = self.write_ahead_log.as_mut() {
+            write_ahead_log.sync()?;
+        }
+        self.durable_lsn = self.lsn;
+        Ok(())
+    }
+
+    // Every stored copy of `key`, newest first, with None for tombstones. find only
+    // returns the first of these; this is meant for debugging reads and compaction.
+    pub fn get_all_versions<K: AsKeyBytes>(&mut self, key: K) -> Result<Versions, DbexError> {
+        let key_bytes = key.key_bytes();
+        let key = key_bytes.as_ref();
+        let mut versions = Vec::new();
+
+        if let Some(value) = self.txn.as_ref().and_then(|txn| txn.get(key)) {
+            versions.push((ReadSource::Transaction, value.clone()));
+        }
+        if let Some(value) = self.memtable.get_entry(key) {
+            versions.push((ReadSource::Memtable, value.clone()));
+        }
+        if let Some(ref table) = self.immutable_memtable {
+            if let Some(value) = table.get_entry(key) {
+                versions.push((ReadSource::ImmutableMemtable, value.clone()));
+            }
+        }
+
+        for (level, tables) in self.levels.iter_mut().enumerate() {
+            for ss_table in tables.iter_mut().rev() {
+                if !ss_table.covers(key) {
+                    continue;
+                }
+                if let Some(value) = ss_table.get_entry(key)? {
+                    let data_path = ss_table.data_path().clone();
+                    versions.push((ReadSource::SSTable { level, data_path: data_path.clone() }, value));
+                    // Kept by compaction under the versions_to_keep option; a damaged
+                    // sidecar only hides them
+                    for (_, value) in ss_table.older_versions(key).unwrap_or_default() {
+                        versions.push((ReadSource::SSTable { level, data_path: data_path.clone() }, value));
+                    }
+                }
+            }
+        }
+
+        Ok(versions)
+    }
+
+    // find, but with the catch_panics option a panic surfaces as DbexError::Internal
+    pub fn try_find<K: AsKeyBytes>(&mut self, key: K) -> Result<Option<Vec<u8>>, DbexError> {
+        let key_bytes = key.key_bytes();
+        self.guard(|db| db.find(key_bytes.as_ref()))
+    }
+
+    // The live value of `key`. Fails if reading an SSTable does, e.g. with
+    // DbexError::Corruption for a damaged entry, rather than reading it as missing.
+    pub fn find<K: AsKeyBytes>(&mut self, key: K) -> Result<Option<Vec<u8>>, DbexError> {
+        let key_bytes = key.key_bytes();
+        let value = self.lookup(key_bytes.as_ref())?;
+
+        self.stats.reads_served += 1;
+        match value {
+            Some(_) => self.stats.read_hits += 1,
+            None => self.stats.read_misses += 1,
+        }
+        Ok(value)
+    }
+
+    // Like find, but tells a removed key from one that was never written. A tombstone
+    // only lasts until compaction drops it, after which the key reads as Absent again.
+    pub fn get_status<K: AsKeyBytes>(&mut self, key: K) -> Result<KeyStatus, DbexError> {
+        let key_bytes = key.key_bytes();
+        let status = match self.lookup_entry(key_bytes.as_ref())? {
+            Some(Some(value)) => KeyStatus::Present(value),
+            Some(None) => KeyStatus::Deleted,
+            None => KeyStatus::Absent,
+        };
+
+        self.stats.reads_served += 1;
+        match status {
+            KeyStatus::Present(_) => self.stats.read_hits += 1,
+            _ => self.stats.read_misses += 1,
+        }
+        Ok(status)
+    }
+
+    // find without counting towards the read stats, for reads made on a write's behalf
+    fn lookup(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DbexError> {
+        Ok(self.lookup_entry(key)?.flatten())
+    }
+
+    // The newest entry of `key`, with Some(None) for a tombstone. Sources are searched
+    // newest first and the first entry of the key decides, so a tombstone hides whatever
+    // older tables still hold for it.
+    fn lookup_entry(&mut self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>, DbexError> {
+        // 0. Writes staged by an open transaction
+        if let Some(value) = self.txn.as_ref().and_then(|txn| txn.get(key)) {
+            return Ok(Some(value.clone()));
+        }
+
+        // 1. The active memtable, then the frozen one (if being flushed)
+        for table in [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter().flatten() {
+            if let Some(value) = table.get_entry(key) {
+                return Ok(Some(value.clone()));
+            }
+        }
+
+        // 2. SSTables level by level. Tables are pushed as they're created, so the newest
+        // of each level is at the back
+        for ss_table in self.levels.iter_mut().flat_map(|tables| tables.iter_mut().rev()) {
+            if !ss_table.covers(key) {
+                continue;
+            }
+            if let Some(value) = ss_table.get(key, self.options.index_cache_len)? {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)  // Not found
+    }
+
+    // Like find, but a value found in an uncompressed SSTable is returned as a view into
+    // the table's memory-mapped data file instead of being copied into a Vec. The
+    // returned value stays readable even if compaction deletes the table meanwhile.
+    // Memtable hits are still copied.
+    pub fn find_borrowed<K: AsKeyBytes>(&mut self, key: K) -> Result<Option<ValueRef>, DbexError> {
+        let key_bytes = key.key_bytes();
+        let key = key_bytes.as_ref();
+
+        if let Some(value) = self.txn.as_ref().and_then(|txn| txn.get(key)) {
+            return Ok(value.clone().map(ValueRef::Owned));
+        }
+        for table in [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter().flatten() {
+            if let Some(value) = table.get_entry(key) {
+                return Ok(value.clone().map(ValueRef::Owned));
+            }
+        }
+
+        for ss_table in self.levels.iter_mut().flat_map(|tables| tables.iter_mut().rev()) {
+            if !ss_table.covers(key) {
+                continue;
+            }
+            if let Some(value) = ss_table.get_mapped(key, self.options.index_cache_len)? {
+                return Ok(value);
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Number of live keys. The first call counts them with a pass over every table's index
+    // (see range_keys); after that the count is kept up to date by the writes themselves.
+    pub fn len(&mut self) -> Result<u64, DbexError> {
+        // The count is of committed keys, so with a transaction open it's done afresh
+        if self.txn.is_some() {
+            return Ok(self.live_keys_in(&[], None)?.count() as u64);
+        }
+        if let Some(record_count) = self.record_count {
+            return Ok(record_count);
+        }
+        let record_count = self.live_keys_in(&[], None)?.count() as u64;
+        self.record_count = Some(record_count);
+        Ok(record_count)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, DbexError> {
+        Ok(self.len()? == 0)
+    }
+
+    fn is_live(&mut self, key: &[u8]) -> Result<bool, DbexError> {
+        Ok(self.find_borrowed(key)?.is_some())
+    }
+
+    // LSN of the write that gave `key` its current value, or None if the key doesn't exist
+    // or its value came in without one (bulk_load, ingest_sstable of an unlogged table).
+    // A write staged by the open transaction has none until it commits.
+    // Every overwrite moves it forward, so it works as a version for compare-and-set.
+    pub fn lsn_of<K: AsKeyBytes>(&mut self, key: K) -> Result<Option<u64>, DbexError> {
+        let key_bytes = key.key_bytes();
+        let key = key_bytes.as_ref();
+
+        if self.txn.as_ref().is_some_and(|txn| txn.contains_key(key)) {
+            return Ok(None);
+        }
+
+        for table in [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter().flatten() {
+            if let Some(value) = table.get_entry(key) {
+                return Ok(value.as_ref().and(table.lsn_of(key)));
+            }
+        }
+
+        for ss_table in self.levels.iter_mut().flat_map(|tables| tables.iter_mut().rev()) {
+            if !ss_table.covers(key) {
+                continue;
+            }
+            if let Some(offset) = ss_table.offset_of(key)? {
+                if ss_table.is_tombstone_at(offset)? {
+                    return Ok(None);
+                }
+                return Ok(ss_table.lsn_at(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    // find for every key in `keys`, returning the values in the same order. The keys are
+    // sorted and each SSTable's index is walked once for all the keys its range covers,
+    // rather than once per key.
+    pub fn multi_get<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, DbexError> {
+        let mut sorted_keys: Vec<&[u8]> = keys.iter().map(AsRef::as_ref).collect();
+        sorted_keys.sort_unstable();
+        sorted_keys.dedup();
+
+        // None until the newest copy of the key is found; Some(None) for a tombstone
+        let mut entries: Vec<Option<Option<Vec<u8>>>> = sorted_keys.iter()
+            .map(|key| {
+                let staged = self.txn.as_ref().and_then(|txn| txn.get(*key).cloned());
+                staged.or_else(|| {
+                    [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter()
+                        .flatten()
+                        .find_map(|table| table.get_entry(key).cloned())
+                })
+            })
+            .collect();
+
+        for ss_table in self.levels.iter_mut().flat_map(|tables| tables.iter_mut().rev()) {
+            let unresolved: Vec<usize> = (0..sorted_keys.len())
+                .filter(|&idx| entries[idx].is_none() && ss_table.covers(sorted_keys[idx]))
+                .collect();
+            if unresolved.is_empty() {
+                continue;
+            }
+
+            let table_keys: Vec<&[u8]> = unresolved.iter().map(|&idx| sorted_keys[idx]).collect();
+            for (idx, entry) in unresolved.into_iter().zip(ss_table.get_entries(&table_keys)?) {
+                entries[idx] = entry;
+            }
+        }
+
+        Ok(keys.iter()
+            .map(|key| {
+                let idx = sorted_keys.binary_search(&key.as_ref()).unwrap();
+                entries[idx].clone().flatten()
+            })
+            .collect())
+    }
+
+    // Takes a point-in-time view for reads that mustn't see later writes, e.g. a report
+    // made of several queries. The memtables are copied and every SSTable is reopened
+    // and pinned, so compaction leaves its files in place until the snapshot is dropped.
+    pub fn snapshot(&self) -> Result<Snapshot, DbexError> {
+        let mut table_pins = self.table_pins.lock().unwrap();
+        table_pins.retain(|_, pin| pin.strong_count() > 0);
+
+        let mut ss_tables = Vec::new();
+        let mut pins = Vec::new();
+        for ss_table in self.levels.iter().flat_map(|tables| tables.iter().rev()) {
+            let data_path = ss_table.data_path();
+            let pin = table_pins.get(data_path).and_then(Weak::upgrade).unwrap_or_else(|| {
+                let pin = Arc::new(TablePin::new(self.storage.clone(), data_path.clone()));
+                table_pins.insert(data_path.clone(), Arc::downgrade(&pin));
+                pin
+            });
+            pins.push(pin);
+            let mut snapshot_table = SSTable::open(self.storage.clone(), data_path)?;
+            Self::apply_table_options(&self.options, &mut snapshot_table);
+            ss_tables.push(snapshot_table);
+        }
+
+        let memtables = [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter().flatten().cloned().collect();
+        Ok(Snapshot::new(self.lsn, memtables, ss_tables, pins, self.options.index_cache_len, self.options.read_ahead))
+    }
+
+    // Deletes the files of a table no level holds any more, unless a snapshot still reads
+    // it; then the snapshot's pin deletes them once the last snapshot is dropped
+    fn retire_ss_table(&self, ss_table: SSTable) {
+        let pin = self.table_pins.lock().unwrap().get(ss_table.data_path()).and_then(Weak::upgrade);
+        match pin {
+            Some(pin) => pin.retire(),
+            None => ss_table.delete_files(),
+        }
+    }
+
+    fn is_pinned(&self, data_path: &Path) -> bool {
+        self.table_pins.lock().unwrap().get(data_path).is_some_and(|pin| pin.strong_count() > 0)
+    }
+
+    // Checks every SSTable for corruption. A table is first checked against the CRCs its
+    // index footer records, which only streams its files; only tables whose checksums
+    // don't match, or that predate them, get the full SSTable::verify pass to find out
+    // what's wrong. Fails with DbexError::Corruption on the first damaged table.
+    pub fn verify(&mut self) -> Result<VerifyStats, DbexError> {
+        let mut verify_stats = VerifyStats::default();
+        for ss_table in self.levels.iter_mut().flatten() {
+            Self::verify_table(ss_table, &mut verify_stats)?;
+        }
+        Ok(verify_stats)
+    }
+
+    fn verify_table(ss_table: &mut SSTable, verify_stats: &mut VerifyStats) -> Result<(), DbexError> {
+        verify_stats.tables_checked += 1;
+        let checksums_match = ss_table.checksums_match()?;
+        if checksums_match == Some(true) {
+            return Ok(());
+        }
+
+        verify_stats.deep_scans += 1;
+        ss_table.verify()?;
+        // Damage verify can't see, such as a changed byte inside a value
+        if checksums_match == Some(false) {
+            return Err(DbexError::Corruption(format!("{}: checksum mismatch", ss_table.data_path().display())));
+        }
+        Ok(())
+    }
+
+    // Brings a damaged database at `path` back to a state that opens cleanly: a manifest
+    // that can't be read is set aside and rebuilt from the tables, the WAL is replayed,
+    // and every table that open rejects or verify fails on is moved into
+    // `<path>/quarantine/` and dropped from the levels. Ends with a clean close, so the
+    // replayed writes are in SSTables. Fails, changing nothing further, on errors other
+    // than corruption, e.g. if another writer holds the database.
+    pub fn repair<P: AsRef<Path>>(path: P, options: DBexOptions) -> Result<RepairReport, DbexError> {
+        let data_dir = path.as_ref().to_path_buf();
+        let storage = options.storage.clone().unwrap_or_else(|| Arc::new(LocalStorage));
+        let quarantine_dir = data_dir.join("quarantine");
+        storage.create_dir_all(&quarantine_dir)?;
+
+        let mut manifest_quarantined = false;
+        if let Err(DbexError::Corruption(_)) = Manifest::load(storage.as_ref(), &data_dir) {
+            storage.rename(&data_dir.join("MANIFEST"), &quarantine_dir.join("MANIFEST"))?;
+            manifest_quarantined = true;
+        }
+
+        let mut db = Self::try_open_with_options(&data_dir, options)?;
+        let mut report = RepairReport {
+            manifest_rebuilt: manifest_quarantined || db.stats.recovery.manifest_rebuilt,
+            wal_entries_replayed: db.stats.recovery.wal_entries_replayed,
+            quarantined: Vec::new(),
+        };
+
+        // Tables open turned away, whose key ranges are out of reach
+        for data_path in take(&mut db.corrupt_ss_tables) {
+            let reason = match SSTable::open(storage.clone(), &data_path) {
+                Err(err) => err.to_string(),
+                Ok(_) => "rejected at open".to_string(),
+            };
+            SSTable::move_files(storage.as_ref(), &data_path, &quarantine_dir)?;
+            report.quarantined.push(QuarantinedTable { data_path, reason, key_range: None });
+        }
+
+        let mut corrupt = Vec::new();
+        for level in db.levels.iter_mut() {
+            let mut kept = Vec::new();
+            for mut ss_table in take(level) {
+                match Self::verify_table(&mut ss_table, &mut VerifyStats::default()) {
+                    Ok(()) => kept.push(ss_table),
+                    Err(DbexError::Corruption(reason)) => corrupt.push((ss_table, reason)),
+                    Err(err) => return Err(err),
+                }
+            }
+            *level = kept;
+        }
+        for (ss_table, reason) in corrupt {
+            let data_path = ss_table.data_path().clone();
+            let key_range = Some((ss_table.min_key().clone(), ss_table.max_key().clone()));
+            drop(ss_table);
+            SSTable::move_files(storage.as_ref(), &data_path, &quarantine_dir)?;
+            report.quarantined.push(QuarantinedTable { data_path, reason, key_range });
+        }
+
+        db.write_manifest(false)?;
+        db.close()?;
+        Ok(report)
+    }
+
+    // Index repositionings across the current SSTables, for checking how many seeks lookups cost
+    pub fn index_seeks(&self) -> u64 {
+        self.levels.iter().flatten().map(SSTable::index_seeks).sum()
+    }
+
+    // Returns every live key/value pair whose key starts with `prefix`, in key order. That
+    // is the range from `prefix` up to prefix_end(prefix), with tables the prefix Bloom
+    // filter rules out skipped.
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Pairs, DbexError> {
+        let end = prefix_end(prefix);
+        let read_ahead = self.options.read_ahead;
+        let mut touched = 0;
+
+        // Sources from oldest to newest
+        let mut sources: Vec<ScanRun> = self.ss_tables_overlapping(prefix, end.as_deref())
+            .filter(|ss_table| ss_table.may_contain_prefix(prefix))
+            .map(|ss_table| {
+                touched += 1;
+                ss_table.scan_range(prefix, end.as_deref(), read_ahead)
+            })
+            .collect::<Result<_, _>>()?;
+        self.ss_tables_touched += touched;
+        let memtable_run = |table: &MemTable| -> ScanRun {
+            table.range(prefix, end.as_deref()).map(|(key, value)| (key.clone(), value.clone())).collect()
+        };
+        if let Some(ref table) = self.immutable_memtable {
+            sources.push(memtable_run(table));
+        }
+        sources.push(memtable_run(&self.memtable));
+        if let Some(txn) = self.txn.as_ref() {
+            let range = (Bound::Included(prefix), end.as_deref().map_or(Bound::Unbounded, Bound::Excluded));
+            sources.push(txn.range::<[u8], _>(range).map(|(key, value)| (key.clone(), value.clone())).collect());
+        }
+
+        // Drop keys whose newest entry is a tombstone
+        Ok(Self::merge_sources(sources).into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect())
+    }
+
+    // Returns the live pairs of the tenant whose keys all start with `tenant`, in key order.
+    // With the strip_tenant_prefix option the keys come back without that prefix.
+    pub fn scan_tenant(&mut self, tenant: &[u8]) -> Result<Pairs, DbexError> {
+        let mut pairs = self.scan_prefix(tenant)?;
+        if self.options.strip_tenant_prefix {
+            for (key, _) in pairs.iter_mut() {
+                key.drain(..tenant.len());
+            }
+        }
+        Ok(pairs)
+    }
+
+    // Returns the live key/value pairs with `start <= key < end`, in key order
+    pub fn range(&mut self, start: &[u8], end: &[u8]) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError> {
+        self.live_range(start, Some(end))
+    }
+
+    // Like range, but with `end` included: returns the live pairs with `start <= key <= end`
+    pub fn scan(&mut self, start: &[u8], end: &[u8]) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError> {
+        // The first key after `end` in byte order is `end` followed by a zero byte
+        let mut exclusive_end = end.to_vec();
+        exclusive_end.push(0);
+        self.live_range(start, Some(&exclusive_end))
+    }
+
+    // Like range, yielding only the pairs `pred` accepts. The predicate runs on each source
+    // before the merge, and a value it rejects is dropped on the spot: its entry turns into
+    // a tombstone, which still hides older entries of the key but carries no value. So
+    // memtable values that don't match are never copied, and table values that don't match
+    // never reach the merge.
+    pub fn scan_with_filter<F>(&mut self, start: &[u8], end: &[u8], pred: F) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        self.filtered_range(start, Some(end), pred)
+    }
+
+    // Like range, with no upper bound if `end` is None
+    fn live_range(&mut self, start: &[u8], end: Option<&[u8]>) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError> {
+        self.filtered_range(start, end, |_, _| true)
+    }
+
+    fn filtered_range(&mut self, start: &[u8], end: Option<&[u8]>, pred: impl Fn(&[u8], &[u8]) -> bool) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError> {
+        // Sources from oldest to newest
+        let read_ahead = self.options.read_ahead;
+        let mut sources: Vec<ScanRun> = self.ss_tables_overlapping(start, end)
+            .map(|ss_table| {
+                Ok(ss_table.scan_range(start, end, read_ahead)?
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = value.filter(|value| pred(&key, value));
+                        (key, value)
+                    })
+                    .collect())
+            })
+            .collect::<Result<_, DbexError>>()?;
+        let memtable_run = |table: &MemTable| -> ScanRun {
+            table.range(start, end)
+                .map(|(key, value)| (key.clone(), value.as_ref().filter(|value| pred(key, value)).cloned()))
+                .collect()
+        };
+        if let Some(ref table) = self.immutable_memtable {
+            sources.push(memtable_run(table));
+        }
+        sources.push(memtable_run(&self.memtable));
+        if let Some(txn) = self.txn.as_ref() {
+            let range = (Bound::Included(start), end.map_or(Bound::Unbounded, Bound::Excluded));
+            sources.push(txn.range::<[u8], _>(range)
+                .map(|(key, value)| (key.clone(), value.as_ref().filter(|value| pred(key, value)).cloned()))
+                .collect());
         }
+
+        Ok(Self::merge_sources(sources).into_iter().filter_map(|(key, value)| value.map(|value| (key, value))))
     }
 
-    pub fn memtable(&self) -> &MemTable {
-        &self.memtable
+    // Merges sorted `sources`, given oldest to newest, into one sorted run where the newest
+    // entry of each key wins. Sources whose keys don't overlap any other's (the usual case
+    // below L0) are concatenated as they are; only overlapping groups go through a map.
+    pub(crate) fn merge_sources(sources: Vec<ScanRun>) -> ScanRun {
+        let mut sources: Vec<(usize, ScanRun)> = sources.into_iter()
+            .enumerate()
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect();
+        sources.sort_by(|(_, a), (_, b)| a[0].0.cmp(&b[0].0));
+
+        let mut merged = Vec::with_capacity(sources.iter().map(|(_, entries)| entries.len()).sum());
+        let mut sources = sources.into_iter().peekable();
+        while let Some(first) = sources.next() {
+            // Gather every source that starts before the group so far ends
+            let mut group_end = first.1.last().unwrap().0.clone();
+            let mut group = vec![first];
+            while let Some(next) = sources.next_if(|(_, entries)| entries[0].0 <= group_end) {
+                group_end = group_end.max(next.1.last().unwrap().0.clone());
+                group.push(next);
+            }
+
+            if group.len() == 1 {
+                merged.append(&mut group[0].1);
+                continue;
+            }
+            group.sort_by_key(|(age, _)| *age);
+            let mut overlapping: BTreeMap<Vec<u8>, Option<Vec<u8>>> = BTreeMap::new();
+            for (_, entries) in group {
+                overlapping.extend(entries);
+            }
+            merged.extend(overlapping);
+        }
+        merged
     }
 
-    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+    // Every live key/value pair in key order, e.g. for exporting the whole database. Unlike
+    // range, nothing is collected up front: each memtable and SSTable is walked lazily and
+    // merged as the iterator advances, so memory stays bounded however large the database.
+    // A table that fails to read ends the iteration with its error.
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), DbexError>> + '_ {
+        // Sources from oldest to newest
+        let mut sources: Vec<EntrySource> = Vec::new();
+        for ss_table in self.levels.iter_mut().rev().flatten() {
+            sources.push(Box::new(ss_table.entries()));
+        }
+        let owned = |(key, value): (&Vec<u8>, &Option<Vec<u8>>)| Ok((key.clone(), value.clone()));
+        if let Some(ref table) = self.immutable_memtable {
+            sources.push(Box::new(table.iter().map(owned)));
+        }
+        sources.push(Box::new(self.memtable.iter().map(owned)));
+        if let Some(txn) = self.txn.as_ref() {
+            sources.push(Box::new(txn.iter().map(owned)));
+        }
+
+        MergeIter::new(sources)
+    }
 
-        // self.write_ahead_log.write(Insert, self.lsn.clone(), Some(key.clone()), Some(value.clone()));
+    // Returns only the live keys with `start <= key < end`, in key order. SSTable values
+    // are never read, only their tombstone markers.
+    pub fn range_keys(&mut self, start: &[u8], end: &[u8]) -> Result<impl Iterator<Item = Vec<u8>>, DbexError> {
+        self.live_keys_in(start, Some(end))
+    }
 
-        self.memtable.insert(key, value);
+    // Like range_keys, with no upper bound if `end` is None
+    fn live_keys_in(&mut self, start: &[u8], end: Option<&[u8]>) -> Result<impl Iterator<Item = Vec<u8>>, DbexError> {
+        let mut merged: BTreeMap<Vec<u8>, bool> = BTreeMap::new();
 
-        if self.memtable.size_byte() >= 64 * 1024 * 1024  {
-            self.flush();
+        for ss_table in self.ss_tables_overlapping(start, end) {
+            merged.extend(ss_table.scan_range_keys(start, end)?);
+        }
+        if let Some(ref table) = self.immutable_memtable {
+            merged.extend(table.range(start, end).map(|(k, v)| (k.clone(), v.is_some())));
+        }
+        merged.extend(self.memtable.range(start, end).map(|(k, v)| (k.clone(), v.is_some())));
+        if let Some(txn) = self.txn.as_ref() {
+            let range = (Bound::Included(start), end.map_or(Bound::Unbounded, Bound::Excluded));
+            merged.extend(txn.range::<[u8], _>(range).map(|(k, v)| (k.clone(), v.is_some())));
         }
 
-        self.record_count += 1;
-        self.lsn += 1;
+        Ok(merged.into_iter().filter_map(|(key, is_live)| is_live.then_some(key)))
     }
 
-    pub fn remove(&mut self, key: &Vec<u8>) {
-        let key = key.to_vec();
+    // Keys whose newest entry is a tombstone that compaction hasn't reclaimed yet, in key
+    // order, counting removes staged by the open transaction. Like range_keys, only
+    // tombstone markers are read. Meant for auditing the delete backlog, since it reads
+    // every table.
+    pub fn iter_tombstones(&mut self) -> Result<impl Iterator<Item = Vec<u8>>, DbexError> {
+        let mut merged: BTreeMap<Vec<u8>, bool> = BTreeMap::new();
+
+        for ss_table in self.ss_tables_overlapping(&[], None) {
+            merged.extend(ss_table.scan_range_keys(&[], None)?);
+        }
+        if let Some(ref table) = self.immutable_memtable {
+            merged.extend(table.iter().map(|(k, v)| (k.clone(), v.is_some())));
+        }
+        merged.extend(self.memtable.iter().map(|(k, v)| (k.clone(), v.is_some())));
+        if let Some(txn) = self.txn.as_ref() {
+            merged.extend(txn.iter().map(|(k, v)| (k.clone(), v.is_some())));
+        }
 
-        // self.write_ahead_log.write(Delete, self.lsn.clone(), Some(key.clone()), None);
+        Ok(merged.into_iter().filter_map(|(key, is_live)| (!is_live).then_some(key)))
+    }
 
-        self.memtable.remove(&key);
+    // SSTables whose key range intersects [start, end), ordered oldest to newest
+    fn ss_tables_overlapping<'a>(&'a mut self, start: &'a [u8], end: Option<&'a [u8]>) -> impl Iterator<Item = &'a mut SSTable> + 'a {
+        self.levels.iter_mut().rev().flatten()
+            .filter(move |ss_table| {
+                ss_table.max_key().as_slice() >= start
+                    && end.is_none_or(|end| ss_table.min_key().as_slice() < end)
+            })
+    }
 
-        self.record_count -= 1;
-        self.lsn += 1;
+    // How many SSTables scans have had to read, i.e. weren't pruned by key range or Bloom filter
+    pub fn ss_tables_touched(&self) -> u64 {
+        self.ss_tables_touched
+    }
+
+    // Returns a summary of the new L0 SSTable, or None if the memtable was empty. Flushed
+    // entries move from the active memtable to the frozen one and on to an L0 table, and
+    // stay readable at every step. Once it returns, the table's files, their entries in
+    // the ss_tables directory and the manifest naming the table are all on disk (unless
+    // sync_policy is SyncPolicy::None), so the flushed writes survive a power failure.
+    // Sharing a DBex between threads goes through a lock around the whole handle, so a
+    // reader never sees the swap half done.
+    pub fn flush(&mut self) -> Result<Option<FlushInfo>, DbexError> {
+        self.guard(|db| db.flush_unguarded())
     }
 
-    pub fn find(&mut self, key: &Vec<u8>) -> Option<Vec<u8>> {
-        // 1. Check active MemTable (RAM)
-        if let Some(value) = self.memtable.get(key) {
-            return Some(value.clone());
+    fn flush_unguarded(&mut self) -> Result<Option<FlushInfo>, DbexError> {
+        self.check_writable()?;
+        if self.memtable.is_empty() && self.immutable_memtable.is_none() {
+            return Ok(None);
         }
 
-        // 2. Check immutable MemTable (if being flushed)
-        if let Some(ref table) = self.immutable_memtable {
-            if let Some(value) = table.get(key) {
-                return Some(value.clone());
+        // A memtable frozen earlier is older than the active one, so it goes first
+        let mut flush_info = self.flush_immutable_memtable()?;
+        if self.options.direct_flush {
+            flush_info = self.flush_active_memtable()?.or(flush_info);
+        } else if self.freeze_memtable()? {
+            flush_info = self.flush_immutable_memtable()?;
+        }
+
+        // The WAL holds both memtables' writes, so it can only be cleared now that neither
+        // needs replaying
+        self.retire_wal()?;
+
+        self.compact_if_needed()?;
+        Ok(flush_info)
+    }
+
+    // Everything a background maintenance tick would do, for callers that run it on their
+    // own schedule: flushes the memtables, runs pending compactions, clears the WAL once
+    // its entries are all in SSTables and removes orphaned table files. Checks are cheap,
+    // so calling it when there's nothing to do costs little.
+    pub fn maintain(&mut self) -> Result<MaintenanceReport, DbexError> {
+        self.guard(|db| db.maintain_unguarded())
+    }
+
+    fn maintain_unguarded(&mut self) -> Result<MaintenanceReport, DbexError> {
+        self.check_writable()?;
+        let compactions = self.stats.compactions;
+
+        // A flush retires the WAL and compacts on its own
+        let flushed = self.flush_unguarded()?.is_some();
+        let mut wal_truncated = flushed && self.write_ahead_log.is_some();
+        if !flushed {
+            let wal_pending = match self.write_ahead_log.as_ref() {
+                Some(write_ahead_log) => !write_ahead_log.is_empty()?,
+                None => false,
+            };
+            if wal_pending && self.memtable.is_empty() && self.immutable_memtable.is_none() {
+                self.retire_wal()?;
+                wal_truncated = true;
             }
+            self.compact_if_needed()?;
         }
+        Ok(MaintenanceReport {
+            flushed,
+            compactions: self.stats.compactions - compactions,
+            wal_truncated,
+            orphaned_tables_removed: self.remove_orphaned_tables()?,
+        })
+    }
 
-        // 3. Check Pre Compacted SSTables (newest to oldest)
-        for ss_table in &mut self.l0_ss_tables {
-            let min_key = ss_table.min_key();
-            let max_key = ss_table.max_key();
+    // Removes the files of tables on disk that no level holds, leaving out corrupt ones,
+    // which are kept for repair. Returns how many tables there were.
+    fn remove_orphaned_tables(&mut self) -> Result<usize, DbexError> {
+        let known: HashSet<&Path> = self.levels.iter()
+            .flatten()
+            .map(|ss_table| ss_table.data_path().as_path())
+            .chain(self.corrupt_ss_tables.iter().map(PathBuf::as_path))
+            .collect();
+        let orphans: HashSet<PathBuf> = self.storage.list(&self.ss_table_dir())?.iter()
+            .filter_map(|path| owning_data_path(path))
+            .filter(|data_path| !known.contains(data_path.as_path()) && !self.is_pinned(data_path))
+            .collect();
+        for data_path in &orphans {
+            SSTable::remove_files(self.storage.as_ref(), data_path)?;
+        }
+        Ok(orphans.len())
+    }
 
-            if key >= min_key && key <= max_key {
-                if let Some(value) = ss_table.get(key) {
-                    return Some(value);
-                }
+    // Holds off compaction, e.g. to keep it out of a benchmark's measurement window. Flushes
+    // still add tables, so levels may grow past their thresholds while paused; resuming
+    // compacts whatever backlog built up before returning.
+    pub fn set_compaction_paused(&mut self, paused: bool) -> Result<(), DbexError> {
+        self.compaction_paused = paused;
+        if paused || self.read_only {
+            return Ok(());
+        }
+        self.guard(|db| db.compact_if_needed())
+    }
+
+    pub fn is_compaction_paused(&self) -> bool {
+        self.compaction_paused
+    }
+
+    fn compact_if_needed(&mut self) -> Result<(), DbexError> {
+        if self.compaction_paused {
+            return Ok(());
+        }
+        self.coalesce_tiny_tables()?;
+        // Each level but the last is merged into the next once it holds too many tables,
+        // shallowest first so a level can fill and spill in the same pass
+        for level in 0..self.levels.len() - 1 {
+            if self.levels[level].len() > 10 {
+                self.compact_level(level)?;
             }
         }
+        Ok(())
+    }
 
-        // 4. Check Compacted SSTables (Traverse the tree structure)
-        for ss_table in &mut self.l1_ss_tables {
-            let min_key = ss_table.min_key();
-            let max_key = ss_table.max_key();
+    // Stops writes to the current memtable and starts a fresh one. The frozen memtable is
+    // still read until flush writes it out. Returns false (and does nothing) if the memtable
+    // is empty or an earlier frozen memtable hasn't been flushed yet.
+    pub fn freeze_memtable(&mut self) -> Result<bool, DbexError> {
+        self.check_writable()?;
+        if self.memtable.is_empty() || self.immutable_memtable.is_some() {
+            return Ok(false);
+        }
 
-            if key >= min_key && key <= max_key {
-                if let Some(value) = ss_table.get(key) {
-                    return Some(value);
-                }
+        self.immutable_memtable = Some(replace(&mut self.memtable, Self::new_memtable(&self.options)));
+        self.immutable_state = MemTableState::Frozen;
+        Ok(true)
+    }
+
+    pub fn memtable_state(&self) -> MemTableState {
+        self.immutable_state
+    }
+
+    // Writes the frozen memtable to a new L0 SSTable, records it in the manifest, and
+    // only then drops it. On failure it stays frozen, and readable, for the next flush.
+    fn flush_immutable_memtable(&mut self) -> Result<Option<FlushInfo>, DbexError> {
+        if self.immutable_memtable.is_none() {
+            return Ok(None);
+        }
+        self.immutable_state = MemTableState::Flushing;
+
+        let flush_info = match self.write_l0_table(false) {
+            Ok(flush_info) => flush_info,
+            Err(err) => {
+                self.immutable_state = MemTableState::Frozen;
+                return Err(err);
             }
+        };
+        self.immutable_state = MemTableState::Flushed;
+
+        self.immutable_memtable = None;
+        self.immutable_state = MemTableState::Active;
+        Ok(Some(flush_info))
+    }
+
+    // The direct_flush path: writes the active memtable to a new L0 SSTable and only then
+    // starts a fresh one, without freezing it in between. On failure its entries stay in
+    // the active memtable, which keeps taking writes, for the next flush.
+    fn flush_active_memtable(&mut self) -> Result<Option<FlushInfo>, DbexError> {
+        if self.memtable.is_empty() {
+            return Ok(None);
         }
+        let flush_info = self.write_l0_table(true)?;
+        self.memtable = Self::new_memtable(&self.options);
+        Ok(Some(flush_info))
+    }
 
-        for ss_table in &mut self.l2_ss_tables {
-            let min_key = ss_table.min_key();
-            let max_key = ss_table.max_key();
+    // Writes the active memtable if `active`, the frozen one otherwise, to a new L0 table
+    // and installs it in the manifest. On failure the table is removed again.
+    fn write_l0_table(&mut self, active: bool) -> Result<FlushInfo, DbexError> {
+        let start = Instant::now();
+        let mut ss_table = self.new_ss_table()?;
+        let table = if active { &self.memtable } else { self.immutable_memtable.as_ref().unwrap() };
+        let values: Vec<&[u8]> = table.iter().filter_map(|(_, value)| value.as_deref()).collect();
+        ss_table.set_codec(ValueCodec::train(self.options.compression, &values));
+        if let Err(err) = ss_table.load_from_memtable(table, self.options.sync_policy) {
+            ss_table.delete_files();
+            return Err(err);
+        }
+        let duration = start.elapsed();
+        let flush_info = FlushInfo {
+            min_key: ss_table.min_key().clone(),
+            max_key: ss_table.max_key().clone(),
+            entry_count: ss_table.entry_count(),
+            size_bytes: ss_table.size_bytes(),
+        };
+        self.levels[0].push(ss_table);
 
-            if key >= min_key && key <= max_key {
-                if let Some(value) = ss_table.get(key) {
-                    return Some(value);
-                }
+        if let Err(err) = self.write_manifest(false) {
+            // Keep serving the entries from memory; the next flush writes them again
+            if let Some(ss_table) = self.levels[0].pop() {
+                ss_table.delete_files();
+            }
+            return Err(err);
+        }
+        self.stats.flush.record(duration);
+        Ok(flush_info)
+    }
+
+    // Deletes every entry like truncate, and with them whatever repair and ingest_stream
+    // left in the data directory. The directory layout and the lock stay, so the handle
+    // goes on working as an empty database.
+    pub fn purge(&mut self) -> Result<(), DbexError> {
+        self.truncate()?;
+        for dir_name in ["quarantine", "ingest"] {
+            let dir = self.data_dir.join(dir_name);
+            if self.storage.exists(&dir) {
+                self.storage.remove_dir_all(&dir)?;
             }
         }
+        self.record_count = None;
+        Ok(())
+    }
 
-        None  // Not found
+    // Drops the WAL's entries once they are all in SSTables, or with archive_wal set, moves
+    // them into a segment of the archive named after the next LSN, so segment names sort
+    // in write order
+    fn retire_wal(&mut self) -> Result<(), DbexError> {
+        // Only called once both memtables are in SSTables
+        self.durable_lsn = self.lsn;
+        let Some(write_ahead_log) = self.write_ahead_log.as_mut() else {
+            return Ok(());
+        };
+        if self.options.archive_wal {
+            let archive_dir = self.data_dir.join("wal_archive");
+            self.storage.create_dir_all(&archive_dir)?;
+            write_ahead_log.archive(&archive_dir.join(format!("wal_{:020}.wal", self.lsn)))?;
+        } else {
+            write_ahead_log.clear()?;
+        }
+        Ok(())
     }
 
-    pub fn flush(&mut self) {
-        // Move current memtable to immutable
-        self.immutable_memtable = Some(std::mem::replace(&mut self.memtable, MemTable::new()));
+    // Rebuilds the database from the archived and current WAL as it was just before the
+    // write logged with `lsn`, discarding everything from there on, and returns how many
+    // writes were kept. The archive has to go back to the database's creation, and writes
+    // that bypass the WAL (bulk_load, ingest_sstable, ...) aren't part of it. LSNs are
+    // never reused, so later writes continue after the discarded ones. If this is
+    // interrupted, calling it again with the same `lsn` finishes the job.
+    pub fn recover_to_lsn(&mut self, lsn: u64) -> Result<u64, DbexError> {
+        self.check_writable()?;
+        if !self.options.archive_wal {
+            return Err(DbexError::WalNotArchived);
+        }
+        let Some(write_ahead_log) = self.write_ahead_log.as_mut() else {
+            return Err(DbexError::ReadOnly);
+        };
+
+        write_ahead_log.sync()?;
+        let archive_dir = self.data_dir.join("wal_archive");
+        let mut segments = if self.storage.exists(&archive_dir) { self.storage.list(&archive_dir)? } else { Vec::new() };
+        segments.sort();
+        let mut wal_entries = Vec::new();
+        for segment in &segments {
+            wal_entries.extend(WriteAheadLog::read_file(self.storage.as_ref(), segment, 0)?);
+        }
+        wal_entries.extend(write_ahead_log.read(0)?);
+
+        // (lsn, (key, value, expiry)), with no value for a remove
+        let kept: Vec<(u64, LoggedWrite)> = wal_entries.into_iter()
+            .filter(|wal_entry| wal_entry.lsn() < lsn)
+            .filter_map(|wal_entry| {
+                let entry_lsn = wal_entry.lsn();
+                wal_entry.into_write().map(|write| (entry_lsn, write))
+            })
+            .collect();
 
-        // Flush the immutable one
-        if let Some(ref table) = self.immutable_memtable {
-            let mut ss_table = SSTable::new();
-            ss_table.load_from_memtable(&table);
-            self.l0_ss_tables.push(ss_table);
+        // The kept history goes into the current WAL before anything is removed, so an
+        // interrupted recovery still has all of it
+        write_ahead_log.clear()?;
+        for (entry_lsn, (key, value, expires_at)) in &kept {
+            let (operation, value) = match (value, expires_at) {
+                (Some(value), Some(expires_at)) => (Operation::InsertWithExpiry, Some(expiring_value(*expires_at, value))),
+                (Some(value), None) => (Operation::Insert, Some(value.clone())),
+                (None, _) => (Operation::Delete, None),
+            };
+            write_ahead_log.write(operation, *entry_lsn, Some(key.clone()), value)?;
+        }
+        write_ahead_log.sync()?;
+        for segment in &segments {
+            self.storage.remove(segment)?;
+        }
+
+        // The manifest stops listing the tables before their files go
+        let ss_tables: Vec<SSTable> = self.levels.iter_mut().flat_map(take).collect();
+        self.write_manifest(false)?;
+        for ss_table in ss_tables {
+            self.retire_ss_table(ss_table);
+        }
+        self.memtable = Self::new_memtable(&self.options);
+        self.immutable_memtable = None;
+        self.immutable_state = MemTableState::Active;
+        // The kept history can write a key more than once, so it's counted again on demand
+        self.record_count = None;
+
+        let kept_len = kept.len() as u64;
+        for (entry_lsn, (key, value, expires_at)) in kept {
+            match (value, expires_at) {
+                (Some(value), Some(expires_at)) => self.memtable.insert_with_expiry(key, value, entry_lsn, expires_at),
+                (Some(value), None) => self.memtable.insert_with_lsn(key, value, entry_lsn),
+                (None, _) => self.memtable.remove_with_lsn(&key, entry_lsn),
+            }
+            if self.memtable.size_byte() >= MEMTABLE_FLUSH_BYTES {
+                self.freeze_memtable()?;
+                self.flush_immutable_memtable()?;
+            }
         }
+        self.flush_unguarded()?;
+        self.retire_wal()?;
+        self.write_manifest(false)?;
+        Ok(kept_len)
+    }
+
+    // Removes every entry but leaves the database open and usable, like SQL TRUNCATE.
+    // Only this database's own tables, WAL and WAL archive are touched. LSNs carry on
+    // from where they were.
+    pub fn truncate(&mut self) -> Result<(), DbexError> {
+        self.check_writable()?;
+
+        // The empty manifest is written as a clean shutdown first, so a crash from here on
+        // skips replaying the WAL that's about to be cleared and comes back empty
+        let ss_tables: Vec<SSTable> = self.levels.iter_mut().flat_map(take).collect();
+        self.write_manifest(true)?;
 
-        // Clear it after flush
+        self.memtable = Self::new_memtable(&self.options);
         self.immutable_memtable = None;
+        self.immutable_state = MemTableState::Active;
+        if let Some(write_ahead_log) = self.write_ahead_log.as_mut() {
+            write_ahead_log.clear()?;
+        }
+        let archive_dir = self.data_dir.join("wal_archive");
+        if self.storage.exists(&archive_dir) {
+            self.storage.remove_dir_all(&archive_dir)?;
+        }
+        self.write_manifest(false)?;
+
+        for ss_table in ss_tables {
+            self.retire_ss_table(ss_table);
+        }
+        self.record_count = Some(0);
+        self.ss_tables_touched = 0;
+        self.stats.reset_operation_counts();
+        Ok(())
+    }
+
+    // Flushes the memtable and makes sure every SSTable, the WAL and the manifest are on
+    // disk, regardless of the sync policy
+    pub fn flush_all_levels_to_disk(&mut self) -> Result<(), DbexError> {
+        self.check_writable()?;
+        self.flush()?;
+
+        for ss_table in self.levels.iter().flatten() {
+            ss_table.sync_to_disk()?;
+        }
+        if let Some(write_ahead_log) = self.write_ahead_log.as_mut() {
+            write_ahead_log.sync()?;
+        }
+        self.write_manifest(false)
+    }
+
+    // Shuts down cleanly: flushes everything, marks the manifest so the next open can skip
+    // WAL replay, and releases the lock file
+    pub fn close(mut self) -> Result<(), DbexError> {
+        self.flush_all_levels_to_disk()?;
+        self.write_manifest(true)
+    }
+
+    // Links an externally produced SSTable (e.g. flushed by another DBex) into this
+    // database without rewriting it, and returns the level it was placed in (see place_ss_table).
+    // Unless trust_ingested_tables is set, a table that fails SSTable::verify is rejected
+    // with DbexError::Corruption before anything is linked.
+    pub fn ingest_sstable<P: AsRef<Path>>(&mut self, data_path: P) -> Result<usize, DbexError> {
+        self.check_writable()?;
+
+        if !self.options.trust_ingested_tables {
+            self.with_table_options(SSTable::open(self.storage.clone(), data_path.as_ref())?).verify()?;
+        }
+
+        let number = self.take_table_number();
+        let ss_table = SSTable::import(self.storage.clone(), data_path.as_ref(), &self.ss_table_dir(), number)?;
+        let mut ss_table = self.with_table_options(ss_table);
+        ss_table.set_created_lsn(self.lsn);
+        if let Err(err) = ss_table.check_value_transform() {
+            ss_table.delete_files();
+            return Err(err);
+        }
+        // Ingested keys may already have been live
+        self.record_count = None;
+        self.place_ss_table(ss_table)
+    }
+
+    // Ingests a table sent by SSTable::stream_to, e.g. from another database. The files are
+    // staged under `<data_dir>/ingest` and then handled like ingest_sstable; the staged copy
+    // is removed whether or not the ingest succeeds.
+    pub fn ingest_stream<R: Read>(&mut self, reader: &mut R) -> Result<usize, DbexError> {
+        self.check_writable()?;
+
+        let staging_dir = self.data_dir.join("ingest");
+        self.storage.create_dir_all(&staging_dir)?;
+        let staged = SSTable::read_stream(self.storage.clone(), reader, &staging_dir.join("ss_table_stream.db"))?;
+        let result = self.ingest_sstable(staged.data_path());
+        staged.delete_files();
+        result
+    }
+
+    // Writes `entries`, which must be sorted by key, straight into a new SSTable instead of
+    // going through the memtable, and returns the number of entries written. Repeated keys
+    // are resolved by the bulk_load_duplicates option. The table is placed like an ingested one.
+    pub fn bulk_load<I>(&mut self, entries: I) -> Result<usize, DbexError>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.check_writable()?;
+
+        let mut ss_table = self.new_ss_table()?;
+        ss_table.set_codec(ValueCodec::reuse(self.options.compression, None));
+        let index = match Self::write_sorted_entries(&mut ss_table, entries, self.options.bulk_load_duplicates) {
+            Ok(index) => index,
+            Err(err) => {
+                ss_table.delete_files();
+                return Err(err);
+            }
+        };
+        if index.is_empty() {
+            ss_table.delete_files();
+            return Ok(0);
+        }
+
+        let ss_table = Self::seal_ss_table(ss_table, &index, self.options.sync_policy)?;
+        self.place_ss_table(ss_table)?;
+
+        // Loaded keys may already have been live
+        self.record_count = None;
+        Ok(index.len())
+    }
+
+    // Copies every live key/value pair of `other` into this database as a single bulk-loaded
+    // table, and returns how many were copied. Keys live in both are resolved by the
+    // merge_conflict option; `other` is left unchanged.
+    pub fn merge_from(&mut self, other: &mut DBex) -> Result<usize, DbexError> {
+        self.check_writable()?;
+
+        let incoming = other.live_range(&[], None)?;
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = match self.options.merge_conflict {
+            // The loaded table is newer than everything here, so it would shadow our
+            // values: walk both sorted streams and leave out the keys we already have
+            MergeConflict::KeepSelf => {
+                let mut existing = self.live_range(&[], None)?.map(|(key, _)| key).peekable();
+                incoming
+                    .filter(|(key, _)| {
+                        while existing.next_if(|existing_key| existing_key < key).is_some() {}
+                        existing.peek() != Some(key)
+                    })
+                    .collect()
+            }
+            MergeConflict::KeepOther => incoming.collect(),
+        };
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        // Keys are unique and sorted, so the duplicate policy never comes into play
+        self.bulk_load(entries)
+    }
+
+    // Writes the values of `entries` to `ss_table` and returns the index to build for them
+    fn write_sorted_entries<I>(ss_table: &mut SSTable, entries: I, duplicates: DuplicateKeys) -> Result<Vec<(Vec<u8>, u64)>, DbexError>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut index = Vec::new();
+        let mut offset = 0u64;
+        // Held back until the next key shows up, since a duplicate may still replace it
+        let mut pending: Option<(Vec<u8>, Vec<u8>)> = None;
+
+        for (key, value) in entries {
+            if let Some((pending_key, pending_value)) = pending.as_mut() {
+                match key.cmp(pending_key) {
+                    Ordering::Less => return Err(DbexError::UnsortedInput(key)),
+                    Ordering::Equal => {
+                        match duplicates {
+                            DuplicateKeys::Error => return Err(DbexError::DuplicateKey(key)),
+                            DuplicateKeys::KeepLast => *pending_value = value,
+                            DuplicateKeys::KeepFirst => {}
+                        }
+                        continue;
+                    }
+                    Ordering::Greater => {}
+                }
+            }
 
-        // Check if pre_compact_ss_tables is too big now
-        if self.l0_ss_tables.len() > 10 {
-            self.compact_l0()
+            if let Some((pending_key, pending_value)) = pending.replace((key, value)) {
+                index.push((pending_key, offset));
+                offset += ss_table.write_entry(&Some(pending_value))?;
+            }
         }
-        // Check if pre_compact_ss_tables is too big now
-        if self.l1_ss_tables.len() > 10 {
-            self.compact_l1()
+
+        if let Some((pending_key, pending_value)) = pending {
+            index.push((pending_key, offset));
+            ss_table.write_entry(&Some(pending_value))?;
         }
+        Ok(index)
     }
 
-    // Delete all SSTables associated with the DB
-    pub fn purge(&mut self) {
-        for ss_table in &mut self.l0_ss_tables {
-            fs::remove_dir_all("db_data/").ok();
+    // The table's data counts as newer than everything already stored: it goes to the
+    // deepest level where neither that level nor any level above it overlaps its key
+    // range, otherwise to L0. Memtable entries in its range are flushed first.
+    // Returns the level it was placed in.
+    fn place_ss_table(&mut self, ss_table: SSTable) -> Result<usize, DbexError> {
+        let min_key = ss_table.min_key().clone();
+        let max_key = ss_table.max_key().clone();
+
+        let overlaps_memtable = |memtable: &MemTable| memtable
+            .range(&min_key, None)
+            .next()
+            .is_some_and(|(key, _)| key <= &max_key);
+        if overlaps_memtable(&self.memtable) || self.immutable_memtable.as_ref().is_some_and(overlaps_memtable) {
+            self.flush()?;
+        }
+
+        let overlaps = |level: &[SSTable]| level.iter().any(|table| {
+            table.min_key() <= &max_key && table.max_key() >= &min_key
+        });
+
+        let mut target_level = 0;
+        for (level, tables) in self.levels.iter().enumerate() {
+            if overlaps(tables) {
+                break;
+            }
+            target_level = level;
         }
-        self.l0_ss_tables.clear();
-        self.l1_ss_tables.clear();
-        self.l2_ss_tables.clear();
-        self.record_count = 0;
+
+        self.levels[target_level].push(ss_table);
+        self.write_manifest(false)?;
+        Ok(target_level)
     }
 
+    // Stages the inserts and removes that follow, up to commit_txn or rollback_txn, instead
+    // of applying them. Reads through this handle see the staged writes. Does nothing if a
+    // transaction is already open.
     pub fn start_txn(&mut self) {
-        self.is_in_txn = true;
+        self.txn.get_or_insert_with(BTreeMap::new);
+    }
+
+    // Applies the staged writes all at once. They're logged between StartTxn and CommitTxn
+    // markers, so recovery replays either all of them or, if the commit marker never made
+    // it to disk, none. On failure the transaction stays open with its writes staged.
+    pub fn commit_txn(&mut self) -> Result<(), DbexError> {
+        self.check_writable()?;
+        let Some(staged) = self.txn.take() else {
+            return Ok(());
+        };
+        if staged.is_empty() {
+            return Ok(());
+        }
+        if let Err(err) = self.apply_txn(&staged) {
+            self.txn = Some(staged);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Discards the staged writes and closes the transaction
+    pub fn rollback_txn(&mut self) {
+        self.txn = None;
+    }
+
+    pub fn in_txn(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    fn apply_txn(&mut self, staged: &BTreeMap<Vec<u8>, Option<Vec<u8>>>) -> Result<(), DbexError> {
+        // Checked before anything changes, so a failed read leaves the count as it was
+        let (mut added, mut removed) = (0, 0);
+        if self.record_count.is_some() {
+            for (key, value) in staged {
+                match (self.is_live(key)?, value.is_some()) {
+                    (false, true) => added += 1,
+                    (true, false) => removed += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        let writes = staged.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+        self.apply_batch(writes)?;
+        if let Some(record_count) = self.record_count.as_mut() {
+            *record_count = *record_count + added - removed;
+        }
+        Ok(())
+    }
+
+    // Applies inserts and removes (given as Operation::Insert with a value and
+    // Operation::Delete) in order, as one unit: they're logged in a single run framed by
+    // StartTxn and CommitTxn markers and the WAL is synced once, so recovery replays all
+    // of them or none. Much cheaper per write than insert for loading many rows; the
+    // whole batch goes into the memtable before it can be flushed, so very large loads
+    // are best split into batches of a few hundred thousand.
+    pub fn write_batch(&mut self, ops: Vec<(Operation, Vec<u8>, Option<Vec<u8>>)>) -> Result<(), DbexError> {
+        self.guard(|db| db.write_batch_unguarded(ops))
+    }
+
+    fn write_batch_unguarded(&mut self, ops: Vec<(Operation, Vec<u8>, Option<Vec<u8>>)>) -> Result<(), DbexError> {
+        self.check_writable()?;
+        let mut writes = Vec::with_capacity(ops.len());
+        for (idx, (operation, key, value)) in ops.into_iter().enumerate() {
+            match (operation, value) {
+                (Operation::Insert, Some(value)) => {
+                    if let Some(max) = self.options.max_value_size.filter(|&max| value.len() > max) {
+                        return Err(DbexError::ValueTooLarge { len: value.len(), max });
+                    }
+                    writes.push((key, Some(value)));
+                }
+                (Operation::Delete, _) => writes.push((key, None)),
+                (Operation::Insert, None) => {
+                    return Err(DbexError::InvalidBatch(format!("operation {} is an insert without a value", idx)));
+                }
+                (marker, _) => {
+                    return Err(DbexError::InvalidBatch(format!("operation {} is a {:?} marker, which write_batch adds itself", idx, marker)));
+                }
+            }
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        if let Some(txn) = self.txn.as_mut() {
+            txn.extend(writes);
+            return Ok(());
+        }
+
+        // Keeping the live key count exact would cost a lookup per write
+        self.record_count = None;
+        self.apply_batch(writes)
+    }
+
+    // Logs `writes` as one batch (see WriteAheadLog::write_batch) and applies them to
+    // the memtable, which is flushed only afterwards, once the whole batch is in
+    fn apply_batch(&mut self, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<(), DbexError> {
+        let first_lsn = self.lsn;
+        if let Some(write_ahead_log) = self.write_ahead_log.as_mut() {
+            write_ahead_log.write_batch(first_lsn, &writes)?;
+        }
+        self.lsn += writes.len() as u64;
+        if self.write_ahead_log.is_some() {
+            self.durable_lsn = self.lsn;
+        }
+
+        for (lsn, (key, value)) in (first_lsn..).zip(writes) {
+            match value {
+                Some(value) => {
+                    self.memtable.insert_with_lsn(key, value, lsn);
+                    self.stats.entries_written += 1;
+                }
+                None => {
+                    self.memtable.remove_with_lsn(&key, lsn);
+                    self.stats.entries_deleted += 1;
+                }
+            }
+        }
+
+        if self.memtable.size_byte() >= MEMTABLE_FLUSH_BYTES {
+            self.flush_unguarded()?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> DBexStats {
+        self.stats.clone()
     }
 
-    pub fn commit_txn(&mut self) {
-        // Write to WAL or some shit
-        // then flush or some shit
-        self.flush();
-        self.is_in_txn = false;
+    // The flush part of stats, without copying the rest
+    pub fn flush_stats(&self) -> FlushStats {
+        self.stats.flush.clone()
     }
 
     pub fn cnt_of_l0_ss_tables(&self) -> usize {
-        self.l0_ss_tables.len()
+        self.cnt_of_ss_tables_in_level(0)
     }
 
     pub fn cnt_of_l1_ss_tables(&self) -> usize {
-        self.l1_ss_tables.len()
+        self.cnt_of_ss_tables_in_level(1)
     }
 
     pub fn cnt_of_l2_ss_tables(&self) -> usize {
-        self.l2_ss_tables.len()
+        self.cnt_of_ss_tables_in_level(2)
     }
 
-    fn compact_l0(&mut self) {
-        // take() Takes ownership of pre_compact tables (leaves empty Vec behind)
-        let mut tables_to_compact: Vec<SSTable> = take(&mut self.l0_ss_tables);
-        let mut new_ss_table = SSTable::new();
-        let mut new_ss_table_offset = 0;
-        let mut new_indexes = Vec::new();
+    pub fn cnt_of_ss_tables_in_level(&self, level: usize) -> usize {
+        self.levels.get(level).map_or(0, Vec::len)
+    }
 
-        let mut min_vals = BinaryHeap::new();
+    // Number of levels, L0 included; see DBexOptions::level_count
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
 
-        for (ss_table_idx, ss_table) in tables_to_compact.iter_mut().enumerate() {
-            ss_table.index_reader_mut().seek(SeekFrom::Start(0)).unwrap();
-            let (stored_key, data_file_offset) = match ss_table.get_next_key_in_index_file() {
-                Some(data) => data,
-                None => {
-                    panic!("Error Empty SSTable found. SSTable index: {}, data path: {:?}, index path: {:?}",
-                        ss_table_idx,
-                        ss_table.data_path(),
-                        ss_table.index_path()
-                    );
-                }
-            };
-            min_vals.push(Reverse((stored_key, ss_table_idx, data_file_offset)));
+    // Estimated bytes of the keys and values reads can still see. The bytes on disk (see
+    // count_in_level) also count overwritten copies, tombstones not yet compacted away and
+    // file overhead, so comparing the two shows the space amplification. Memtables are
+    // counted exactly. Each SSTable adds its entry count times the average live size of
+    // keys sampled from its sparse index, a sampled key counting only if the table holds
+    // the newest copy of it and that copy is a value. The newest copies of all sampled
+    // keys are found together, like multi_get does, walking each table's index once.
+    // Staged transaction writes aren't counted.
+    pub fn estimate_live_data_size(&mut self) -> Result<u64, DbexError> {
+        let mut live_bytes: u64 = self.memtable.iter()
+            .filter_map(|(key, value)| value.as_ref().map(|value| (key.len() + value.len()) as u64))
+            .sum();
+        if let Some(table) = &self.immutable_memtable {
+            live_bytes += table.iter()
+                .filter(|(key, _)| self.memtable.get_entry(key).is_none())
+                .filter_map(|(key, value)| value.as_ref().map(|value| (key.len() + value.len()) as u64))
+                .sum::<u64>();
         }
 
-        let mut last_seen_key: Option<Vec<u8>> = None;
+        // Newest first, the order reads search them in
+        let positions: Vec<(usize, usize)> = self.levels.iter().enumerate()
+            .flat_map(|(level, tables)| (0..tables.len()).rev().map(move |idx| (level, idx)))
+            .collect();
+        let samples: Vec<Vec<Vec<u8>>> = positions.iter()
+            .map(|&(level, idx)| self.levels[level][idx].sample_keys(LIVE_DATA_SAMPLE_KEYS))
+            .collect();
+        let mut sorted_keys: Vec<&[u8]> = samples.iter().flatten().map(Vec::as_slice).collect();
+        sorted_keys.sort_unstable();
+        sorted_keys.dedup();
 
-        while !min_vals.is_empty() {
-            let Reverse((
-                    stored_key,
-                    ss_table_idx,
-                    data_file_offset
-                )) = min_vals.pop().unwrap();
+        // Where the newest copy of each key is (None for a memtable, otherwise the table's
+        // place in `positions`) and its value's length, None for a tombstone
+        let mut newest: Vec<Option<(Option<usize>, Option<usize>)>> = sorted_keys.iter()
+            .map(|key| {
+                [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter()
+                    .flatten()
+                    .find_map(|table| table.get_entry(key))
+                    .map(|value| (None, value.as_ref().map(Vec::len)))
+            })
+            .collect();
+        for (position, &(level, idx)) in positions.iter().enumerate() {
+            let ss_table = &mut self.levels[level][idx];
+            let unresolved: Vec<usize> = (0..sorted_keys.len())
+                .filter(|&key_idx| newest[key_idx].is_none() && ss_table.covers(sorted_keys[key_idx]))
+                .collect();
+            if unresolved.is_empty() {
+                continue;
+            }
+            let table_keys: Vec<&[u8]> = unresolved.iter().map(|&key_idx| sorted_keys[key_idx]).collect();
+            for (key_idx, entry) in unresolved.into_iter().zip(ss_table.get_entries(&table_keys)?) {
+                newest[key_idx] = entry.map(|value| (Some(position), value.as_ref().map(Vec::len)));
+            }
+        }
 
-            if last_seen_key.as_ref() == Some(&stored_key) {
+        for (position, sample) in samples.iter().enumerate() {
+            if sample.is_empty() {
                 continue;
             }
-            last_seen_key = Some(stored_key.clone());
+            let sampled_live_bytes: u64 = sample.iter()
+                .filter_map(|key| {
+                    let key_idx = sorted_keys.binary_search(&key.as_slice()).unwrap();
+                    match newest[key_idx] {
+                        Some((Some(holder), Some(value_len))) if holder == position => Some((key.len() + value_len) as u64),
+                        _ => None,
+                    }
+                })
+                .sum();
+            let (level, idx) = positions[position];
+            live_bytes += self.levels[level][idx].entry_count() * sampled_live_bytes / sample.len() as u64;
+        }
+        Ok(live_bytes)
+    }
 
-            let ss_table = tables_to_compact.get_mut(ss_table_idx).unwrap();
+    // Current size of the memtables, sparse indexes and caches, computed from their
+    // contents on each call
+    pub fn memory_usage(&self) -> MemoryStats {
+        let memtable_bytes = [Some(&self.memtable), self.immutable_memtable.as_ref()].into_iter()
+            .flatten()
+            .map(|table| table.size_byte() as u64)
+            .sum();
+        let ss_tables = || self.levels.iter().flatten();
+        MemoryStats {
+            memtable_bytes,
+            sparse_index_bytes: ss_tables().map(SSTable::sparse_index_bytes).sum(),
+            cache_bytes: ss_tables().map(SSTable::cache_bytes).sum(),
+        }
+    }
+
+    // (tables, entries, bytes on disk) in `level`, taken from table metadata. Entries
+    // count every stored copy, tombstones included. Levels past the last are empty.
+    pub fn count_in_level(&self, level: usize) -> (usize, u64, u64) {
+        let Some(tables) = self.levels.get(level) else {
+            return (0, 0, 0);
+        };
+
+        let entries = tables.iter().map(SSTable::entry_count).sum();
+        let bytes = tables.iter().map(SSTable::size_bytes).sum();
+        (tables.len(), entries, bytes)
+    }
+
+    // Every SSTable, level by level and oldest first within a level
+    pub fn list_sstables(&self) -> Vec<SSTableInfo> {
+        self.levels.iter().enumerate()
+            .flat_map(|(level, tables)| tables.iter().map(move |ss_table| SSTableInfo {
+                data_path: ss_table.data_path().clone(),
+                index_path: ss_table.index_path().clone(),
+                min_key: ss_table.min_key().clone(),
+                max_key: ss_table.max_key().clone(),
+                entry_count: ss_table.entry_count(),
+                size_bytes: ss_table.size_bytes(),
+                level,
+                created_lsn: ss_table.created_lsn(),
+                files: ss_table.files(),
+            }))
+            .collect()
+    }
+
+    // The tables created at or after `lsn`, e.g. the checkpoint_lsn of the last backup:
+    // flushed, compacted, bulk loaded or ingested since. Those are all an incremental
+    // backup has to copy, since tables never change once written. A table created just
+    // as the checkpoint was taken can show up in both passes.
+    pub fn iter_since_checkpoint(&self, lsn: u64) -> impl Iterator<Item = SSTableInfo> {
+        self.list_sstables().into_iter().filter(move |info| info.created_lsn >= lsn)
+    }
+
+    // Copies the database into `dest`, which can then be opened like any data directory.
+    // The memtables are flushed first, so the copy holds every write so far. With
+    // `since_lsn`, the checkpoint_lsn of an earlier backup into `dest`, only the tables
+    // created since are copied; tables already in `dest` are never copied again.
+    pub fn backup<P: AsRef<Path>>(&mut self, dest: P, since_lsn: Option<u64>) -> Result<BackupReport, DbexError> {
+        self.flush()?;
+        let dest = dest.as_ref();
+        let ss_table_dest = dest.join("ss_tables");
+        self.storage.create_dir_all(&ss_table_dest)?;
 
-            let value = ss_table.read_value_at_offset(data_file_offset);
-            if value.is_none() {
+        let mut report = BackupReport { checkpoint_lsn: self.lsn, ..BackupReport::default() };
+        for info in self.iter_since_checkpoint(since_lsn.unwrap_or(0)) {
+            let targets: Vec<(PathBuf, PathBuf)> = info.files.iter()
+                .filter_map(|file| Some((file.clone(), ss_table_dest.join(file.file_name()?))))
+                .collect();
+            // A data file is only ever copied last, so its presence means the table is complete
+            if self.storage.exists(&targets[0].1) {
                 continue;
             }
+            for (file, target) in targets.iter().rev() {
+                report.bytes_copied += self.storage.copy(file, target)?;
+            }
+            report.tables_copied.push(info.data_path);
+        }
+        self.storage.sync_dir(&ss_table_dest)?;
 
-            new_ss_table.write_entry(&value);
-            new_indexes.push((stored_key.clone(), new_ss_table_offset));
+        // Written last and with a clean shutdown, so the copy opens without a WAL
+        self.manifest(true).write(self.storage.as_ref(), dest)?;
+        Ok(report)
+    }
 
-            let value_len = value.unwrap().len();
-            new_ss_table_offset += 4 + value_len as u64;
+    // Merges each run of two or more adjacent L0 tables below coalesce_below_bytes into a
+    // single table in the run's place, so L0 stays ordered oldest to newest. Older tables
+    // may still hold the keys, so tombstones are kept.
+    fn coalesce_tiny_tables(&mut self) -> Result<(), DbexError> {
+        let Some(size_floor) = self.options.coalesce_below_bytes else {
+            return Ok(());
+        };
 
-            let next_stored_key = ss_table.get_next_key_in_index_file();
-            if next_stored_key.is_none() {
+        let mut start = 0;
+        while start < self.levels[0].len() {
+            let run_len = self.levels[0][start..].iter()
+                .take_while(|ss_table| ss_table.size_bytes() < size_floor)
+                .count();
+            if run_len < 2 {
+                start += run_len.max(1);
                 continue;
             }
-            let (next_stored_key, next_data_file_offset) = next_stored_key.unwrap();
 
-            min_vals.push(Reverse((next_stored_key, ss_table_idx, next_data_file_offset)));
+            let mut tables_to_compact: Vec<SSTable> = self.levels[0].drain(start..start + run_len).collect();
+            match self.merge_ss_tables(&mut tables_to_compact, false) {
+                Ok(new_ss_table) => {
+                    let merged = new_ss_table.is_some() as usize;
+                    self.levels[0].splice(start..start, new_ss_table);
+                    start += merged;
+                    self.retire_compacted(tables_to_compact)?;
+                }
+                Err(err) => {
+                    self.levels[0].splice(start..start, tables_to_compact);
+                    return Err(err);
+                }
+            }
         }
-
-        new_ss_table.write_index(&new_indexes);
-        self.l1_ss_tables.push(new_ss_table);
+        Ok(())
     }
 
-    fn compact_l1(&mut self) {
+    // Merges every table in `level` into one table appended to the level below
+    fn compact_level(&mut self, level: usize) -> Result<(), DbexError> {
         // take() Takes ownership of pre_compact tables (leaves empty Vec behind)
-        let mut tables_to_compact: Vec<SSTable> = take(&mut self.l1_ss_tables);
-        let mut new_ss_table = SSTable::new();
-        let mut new_ss_table_offset = 0;
+        let mut tables_to_compact: Vec<SSTable> = take(&mut self.levels[level]);
+        // Tombstones must survive while an older level could still hold the key
+        let drop_tombstones = self.levels[level + 1..].iter().all(Vec::is_empty);
+
+        match self.merge_ss_tables(&mut tables_to_compact, drop_tombstones) {
+            Ok(new_ss_table) => {
+                self.levels[level + 1].extend(new_ss_table);
+                self.retire_compacted(tables_to_compact)
+            }
+            Err(err) => {
+                // Leave the inputs in place so nothing is lost
+                self.levels[level] = tables_to_compact;
+                Err(err)
+            }
+        }
+    }
+
+    // Deletes the inputs of a finished compaction. The manifest is rewritten first, so it
+    // never points at files that are gone.
+    fn retire_compacted(&mut self, compacted: Vec<SSTable>) -> Result<(), DbexError> {
+        self.write_manifest(false)?;
+        for ss_table in compacted {
+            self.retire_ss_table(ss_table);
+        }
+        Ok(())
+    }
+
+    // The copy of `key` at `offset` in `ss_table`, followed by the older versions the table
+    // kept behind it, newest first
+    fn versions_at(ss_table: &mut SSTable, key: &[u8], offset: u64) -> Result<Vec<KeyVersion>, DbexError> {
+        let value = ss_table.try_read_value_at_offset(offset)?;
+        let mut versions = vec![(ss_table.lsn_at(offset), value)];
+        versions.extend(ss_table.older_versions(key)?);
+        Ok(versions)
+    }
+
+    // K-way merges `tables_to_compact` (ordered oldest to newest) into a single table,
+    // keeping the newest value of each key and, with the versions_to_keep option, older
+    // versions behind it. The inputs are left for the caller to delete;
+    // on error the partial output is removed. Returns None if nothing survived the merge.
+    fn merge_ss_tables(&mut self, tables_to_compact: &mut [SSTable], drop_tombstones: bool) -> Result<Option<SSTable>, DbexError> {
+        let start = Instant::now();
+        let mut compaction_stats = CompactionStats {
+            input_tables: tables_to_compact.len() as u64,
+            bytes_read: tables_to_compact.iter().map(|ss_table| ss_table.size_bytes()).sum(),
+            ..CompactionStats::default()
+        };
+
+        let mut new_ss_table = self.new_ss_table()?;
+        let dictionary = tables_to_compact.iter().rev().find_map(|ss_table| ss_table.dictionary());
+        new_ss_table.set_codec(ValueCodec::reuse(self.options.compression, dictionary));
+        let mut new_ss_table_offset: u64 = 0;
         let mut new_indexes = Vec::new();
+        let mut rate_limiter = RateLimiter::new(self.options.compaction_bytes_per_sec);
 
+        // Min-heap on key; for equal keys the newest table (highest index) pops first
         let mut min_vals = BinaryHeap::new();
 
         for (ss_table_idx, ss_table) in tables_to_compact.iter_mut().enumerate() {
-            ss_table.index_reader_mut().seek(SeekFrom::Start(0)).unwrap();
-            let (stored_key, data_file_offset) = match ss_table.get_next_key_in_index_file() {
-                Some(data) => data,
-                None => {
-                    panic!("Error Empty SSTable found. SSTable index: {}, data path: {:?}, index path: {:?}",
-                           ss_table_idx,
-                           ss_table.data_path(),
-                           ss_table.index_path()
-                    );
+            let first_entry = ss_table.seek_index(0).and_then(|_| ss_table.try_next_key_in_index_file());
+            let (stored_key, data_file_offset) = match first_entry {
+                Ok(Some(data)) => data,
+                Err(err) => {
+                    new_ss_table.delete_files();
+                    return Err(err);
+                }
+                Ok(None) => {
+                    new_ss_table.delete_files();
+                    return Err(DbexError::Corruption(format!("{}: table has no entries", ss_table.data_path().display())));
                 }
             };
-            min_vals.push(Reverse((stored_key, ss_table_idx, data_file_offset)));
+            min_vals.push(Reverse((stored_key, Reverse(ss_table_idx), data_file_offset)));
         }
 
         let mut last_seen_key: Option<Vec<u8>> = None;
+        let versions_to_keep = self.options.versions_to_keep.unwrap_or(1).max(1);
+        // Versions of last_seen_key kept so far, including the newest; None once the
+        // newest was a tombstone that got dropped
+        let mut kept_versions: Option<usize> = None;
 
-        while !min_vals.is_empty() {
-            let Reverse((
-                            stored_key,
-                            ss_table_idx,
-                            data_file_offset
-                        )) = min_vals.pop().unwrap();
+        while let Some(Reverse((stored_key, Reverse(ss_table_idx), data_file_offset))) = min_vals.pop() {
+            let ss_table = tables_to_compact.get_mut(ss_table_idx).unwrap();
 
-            if last_seen_key.as_ref() == Some(&stored_key) {
-                continue;
+            // Always advance this table, whether or not its entry is kept. A torn entry
+            // fails the merge, which would otherwise drop the rest of the table's keys.
+            match ss_table.try_next_key_in_index_file() {
+                Ok(Some((next_stored_key, next_data_file_offset))) => {
+                    min_vals.push(Reverse((next_stored_key, Reverse(ss_table_idx), next_data_file_offset)));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    new_ss_table.delete_files();
+                    return Err(err);
+                }
             }
-            last_seen_key = Some(stored_key.clone());
 
-            let ss_table = tables_to_compact.get_mut(ss_table_idx).unwrap();
+            // Each index entry is [key len: u32][key][offset: u64], charged once as it's
+            // read. Writing the output index at the end isn't charged again.
+            rate_limiter.consume(12 + stored_key.len() as u64);
 
-            let value = ss_table.read_value_at_offset(data_file_offset);
-            if value.is_none() {
+            if last_seen_key.as_ref() == Some(&stored_key) {
+                // An older copy, kept as one of the key's versions while there's room.
+                // Equal keys pop newest table first, and each copy brings along the
+                // versions its table kept, so they're recorded newest first.
+                let room = kept_versions.map_or(0, |kept| versions_to_keep.saturating_sub(kept));
+                if room == 0 {
+                    compaction_stats.duplicates_dropped += 1;
+                    continue;
+                }
+                let versions = match Self::versions_at(ss_table, &stored_key, data_file_offset) {
+                    Ok(versions) => versions,
+                    Err(err) => {
+                        new_ss_table.delete_files();
+                        return Err(err);
+                    }
+                };
+                compaction_stats.duplicates_dropped += versions.len().saturating_sub(room) as u64;
+                for (lsn, value) in versions.into_iter().take(room) {
+                    rate_limiter.consume(4 + value.as_ref().map_or(0, |value| value.len() as u64));
+                    new_ss_table.record_older_version(stored_key.clone(), lsn, value);
+                    kept_versions = kept_versions.map(|kept| kept + 1);
+                }
                 continue;
             }
 
-            new_ss_table.write_entry(&value);
-            new_indexes.push((stored_key.clone(), new_ss_table_offset));
-
-            let value_len = value.unwrap().len();
-            new_ss_table_offset += 4 + value_len as u64;
+            // A corrupt length must fail the compaction rather than silently become a tombstone
+            let value = match ss_table.try_read_value_at_offset(data_file_offset) {
+                Ok(value) => value,
+                Err(err) => {
+                    new_ss_table.delete_files();
+                    return Err(err);
+                }
+            };
+            last_seen_key = Some(stored_key.clone());
+            rate_limiter.consume(4 + value.as_ref().map_or(0, |value| value.len() as u64));
 
-            let next_stored_key = ss_table.get_next_key_in_index_file();
-            if next_stored_key.is_none() {
+            if value.is_none() && drop_tombstones {
+                compaction_stats.tombstones_dropped += 1;
+                kept_versions = None;
                 continue;
             }
-            let (next_stored_key, next_data_file_offset) = next_stored_key.unwrap();
 
-            min_vals.push(Reverse((next_stored_key, ss_table_idx, next_data_file_offset)));
+            kept_versions = Some(1);
+            if versions_to_keep > 1 {
+                let older_versions = match ss_table.older_versions(&stored_key) {
+                    Ok(older_versions) => older_versions,
+                    Err(err) => {
+                        new_ss_table.delete_files();
+                        return Err(err);
+                    }
+                };
+                compaction_stats.duplicates_dropped += older_versions.len().saturating_sub(versions_to_keep - 1) as u64;
+                for (lsn, value) in older_versions.into_iter().take(versions_to_keep - 1) {
+                    new_ss_table.record_older_version(stored_key.clone(), lsn, value);
+                    kept_versions = kept_versions.map(|kept| kept + 1);
+                }
+            }
+
+            if let Some(lsn) = ss_table.lsn_at(data_file_offset) {
+                new_ss_table.record_lsn(new_ss_table_offset, lsn);
+            }
+            // Expired entries read as tombstones above; the rest keep counting down
+            let expiry = match ss_table.expiry_at(data_file_offset) {
+                Ok(expiry) => expiry,
+                Err(err) => {
+                    new_ss_table.delete_files();
+                    return Err(err);
+                }
+            };
+            if let (Some(expires_at), Some(_)) = (expiry, &value) {
+                new_ss_table.record_expiry(new_ss_table_offset, expires_at);
+            }
+            let entry_size = match new_ss_table.write_entry(&value) {
+                Ok(entry_size) => entry_size,
+                Err(err) => {
+                    new_ss_table.delete_files();
+                    return Err(err);
+                }
+            };
+            rate_limiter.consume(entry_size);
+            let next_offset = new_ss_table_offset.checked_add(entry_size)
+                .filter(|next_offset| *next_offset == new_ss_table.data_len());
+            let Some(next_offset) = next_offset else {
+                let data_path = new_ss_table.data_path().clone();
+                new_ss_table.delete_files();
+                return Err(DbexError::Corruption(format!(
+                    "{}: compaction output offset diverged from the data file length", data_path.display()
+                )));
+            };
+            new_indexes.push((stored_key, new_ss_table_offset));
+            new_ss_table_offset = next_offset;
         }
 
-        new_ss_table.write_index(&new_indexes);
-        self.l2_ss_tables.push(new_ss_table);
+        let new_ss_table = if new_indexes.is_empty() {
+            new_ss_table.delete_files();
+            None
+        } else {
+            let new_ss_table = Self::seal_ss_table(new_ss_table, &new_indexes, self.options.sync_policy)?;
+            compaction_stats.bytes_written = new_ss_table.size_bytes();
+            Some(new_ss_table)
+        };
+
+        compaction_stats.duration = start.elapsed();
+        compaction_stats.throttled = rate_limiter.slept();
+        self.stats.record_compaction(compaction_stats);
+
+        Ok(new_ss_table)
     }
-}
\ No newline at end of file
+}
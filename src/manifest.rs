@@ -0,0 +1,133 @@
+use std::path::Path;
+use crate::error::DbexError;
+use crate::options::SyncPolicy;
+use crate::storage::Storage;
+
+// [magic: u32][clean_shutdown: u8][next lsn: u64][level count: u32]
+// per level: [table count: u32], per table: [file name len: u32][file name]
+// [next table number: u64, missing from older manifests]
+// [created lsn: u64 per table in level order, missing from older manifests]
+// [crc32 of everything before it: u32]
+const MANIFEST_MAGIC: u32 = 0x4442584D; // "DBXM"
+
+// Which SSTables make up each level, and whether the last writer shut down cleanly.
+// Rewritten whenever the set of tables changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    // Set only by DBex::close, after the memtable was flushed and the WAL emptied
+    pub clean_shutdown: bool,
+    // LSN of the next write at the time the manifest was written. The WAL is emptied on
+    // flush, so this is what keeps LSNs increasing across restarts.
+    pub next_lsn: u64,
+    // Data file names relative to the ss_tables directory, each level oldest to newest
+    pub levels: Vec<Vec<String>>,
+    // Number the next SSTable is named after (see SSTable::numbered)
+    pub next_table_number: u64,
+    // Creation LSN of each table in `levels`, laid out the same way. Empty for manifests
+    // written before it was recorded.
+    pub created_lsns: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    // Returns None if `data_dir` has no manifest yet
+    pub fn load(storage: &dyn Storage, data_dir: &Path) -> Result<Option<Self>, DbexError> {
+        let manifest_path = data_dir.join("MANIFEST");
+        let bytes = match storage.read(&manifest_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Self::decode(&bytes)
+            .map(Some)
+            .ok_or_else(|| DbexError::Corruption(format!("{}: malformed manifest", manifest_path.display())))
+    }
+
+    // Writes to a temporary file and renames it over the old manifest, so a crash leaves
+    // either the old or the new manifest in place, never a torn one
+    pub fn write(&self, storage: &dyn Storage, data_dir: &Path) -> Result<(), DbexError> {
+        let tmp_path = data_dir.join("MANIFEST.tmp");
+        let tmp_file = storage.create(&tmp_path)?;
+        tmp_file.write(&self.encode())?;
+        tmp_file.sync(SyncPolicy::SyncAll)?;
+        storage.rename(&tmp_path, &data_dir.join("MANIFEST"))?;
+        // Persist the rename itself
+        storage.sync_dir(data_dir)?;
+        Ok(())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MANIFEST_MAGIC.to_be_bytes());
+        out.push(self.clean_shutdown as u8);
+        out.extend_from_slice(&self.next_lsn.to_be_bytes());
+        out.extend_from_slice(&(self.levels.len() as u32).to_be_bytes());
+        for level in &self.levels {
+            out.extend_from_slice(&(level.len() as u32).to_be_bytes());
+            for file_name in level {
+                out.extend_from_slice(&(file_name.len() as u32).to_be_bytes());
+                out.extend_from_slice(file_name.as_bytes());
+            }
+        }
+        out.extend_from_slice(&self.next_table_number.to_be_bytes());
+        for created_lsn in self.created_lsns.iter().flatten() {
+            out.extend_from_slice(&created_lsn.to_be_bytes());
+        }
+        out.extend_from_slice(&crc32fast::hash(&out).to_be_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (body, crc_bytes) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+        if crc32fast::hash(body) != u32::from_be_bytes(crc_bytes.try_into().ok()?) {
+            return None;
+        }
+
+        let mut pos = 0;
+        let read_u32 = |pos: &mut usize| -> Option<u32> {
+            let value = u32::from_be_bytes(body.get(*pos..*pos + 4)?.try_into().ok()?);
+            *pos += 4;
+            Some(value)
+        };
+
+        if read_u32(&mut pos)? != MANIFEST_MAGIC {
+            return None;
+        }
+        let clean_shutdown = *body.get(pos)? != 0;
+        pos += 1;
+        let next_lsn = u64::from_be_bytes(body.get(pos..pos + 8)?.try_into().ok()?);
+        pos += 8;
+
+        let level_count = read_u32(&mut pos)?;
+        let mut levels = Vec::new();
+        for _ in 0..level_count {
+            let table_count = read_u32(&mut pos)?;
+            let mut level = Vec::new();
+            for _ in 0..table_count {
+                let name_len = read_u32(&mut pos)? as usize;
+                let name = body.get(pos..pos.checked_add(name_len)?)?;
+                pos += name_len;
+                level.push(String::from_utf8(name.to_vec()).ok()?);
+            }
+            levels.push(level);
+        }
+
+        let rest = body.get(pos..)?;
+        if rest.is_empty() {
+            return Some(Manifest { clean_shutdown, next_lsn, levels, ..Manifest::default() });
+        }
+        let (next_table_number, rest) = rest.split_at_checked(8)?;
+        let next_table_number = u64::from_be_bytes(next_table_number.try_into().ok()?);
+
+        let mut created_lsns = Vec::new();
+        if !rest.is_empty() {
+            let mut lsns = rest.chunks_exact(8).map(|lsn| u64::from_be_bytes(lsn.try_into().unwrap()));
+            if rest.len() != 8 * levels.iter().map(Vec::len).sum::<usize>() {
+                return None;
+            }
+            created_lsns = levels.iter().map(|level| lsns.by_ref().take(level.len()).collect()).collect();
+        }
+
+        Some(Manifest { clean_shutdown, next_lsn, levels, next_table_number, created_lsns })
+    }
+}
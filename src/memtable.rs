@@ -1,44 +1,242 @@
 use std::collections::BTreeMap;
+use std::ops::Bound;
 
+use crate::utils::unix_millis;
+
+// Above this many entries a sorted vector's O(n) inserts cost more than the
+// BTreeMap's per-node allocations, so the memtable switches over
+pub const SORTED_VEC_MAX_ENTRIES: usize = 4096;
+
+// A value (None for a tombstone) and the LSN it was written at, if it came from a logged
+// write, and when it expires, if it was written with a TTL
+#[derive(Clone)]
+struct Slot {
+    value: Option<Vec<u8>>,
+    lsn: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+const TOMBSTONE: &Option<Vec<u8>> = &None;
+
+impl Slot {
+    // The value, or a tombstone once it has expired, so it still hides older copies
+    fn live_value(&self) -> &Option<Vec<u8>> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= unix_millis() => TOMBSTONE,
+            _ => &self.value,
+        }
+    }
+}
+
+type Entry = (Vec<u8>, Slot);
+
+// (key, value, LSN, expiry) as iter_with_metadata yields them
+pub type EntryWithMetadata<'a> = (&'a Vec<u8>, &'a Option<Vec<u8>>, Option<u64>, Option<u64>);
+
+// Small memtables keep their entries in a sorted vector and binary search it;
+// anything larger (or of unknown size) uses a BTreeMap
+#[derive(Clone)]
+enum Entries {
+    SortedVec(Vec<Entry>),
+    BTree(BTreeMap<Vec<u8>, Slot>),
+}
+
+#[derive(Clone)]
 pub struct MemTable {
-    data: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    data: Entries,
     size_bytes: usize,  // Track size
+    // Keys are expected in ascending order (see monotonic)
+    monotonic: bool,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable {
     pub fn new() -> Self {
         MemTable{
-            data: BTreeMap::new(),
+            data: Entries::BTree(BTreeMap::new()),
+            size_bytes: 0,
+            monotonic: false,
+        }
+    }
+
+    // Starts out as a sorted vector if `expected_entries` is small enough
+    pub fn with_size_hint(expected_entries: usize) -> Self {
+        if expected_entries > SORTED_VEC_MAX_ENTRIES {
+            return Self::new();
+        }
+
+        MemTable{
+            data: Entries::SortedVec(Vec::with_capacity(expected_entries)),
+            size_bytes: 0,
+            monotonic: false,
+        }
+    }
+
+    // For keys written in ascending order, e.g. timestamps or sequence numbers. A sorted
+    // vector appends those without shifting anything, so it stays one past
+    // SORTED_VEC_MAX_ENTRIES until a key arrives out of order.
+    pub fn monotonic() -> Self {
+        MemTable{
+            data: Entries::SortedVec(Vec::new()),
             size_bytes: 0,
+            monotonic: true,
         }
     }
 
+    pub fn is_sorted_vec(&self) -> bool {
+        matches!(self.data, Entries::SortedVec(_))
+    }
+
     pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        if let Some(Some(old_value)) = self.data.get(&key) {
+        self.insert_slot(key, value, None, None);
+    }
+
+    // Like insert, remembering the LSN the write was logged with (see lsn_of)
+    pub fn insert_with_lsn(&mut self, key: Vec<u8>, value: Vec<u8>, lsn: u64) {
+        self.insert_slot(key, value, Some(lsn), None);
+    }
+
+    // Like insert_with_lsn, for a value that reads as removed from `expires_at` on, in
+    // milliseconds since the Unix epoch
+    pub fn insert_with_expiry(&mut self, key: Vec<u8>, value: Vec<u8>, lsn: u64, expires_at: u64) {
+        self.insert_slot(key, value, Some(lsn), Some(expires_at));
+    }
+
+    fn insert_slot(&mut self, key: Vec<u8>, value: Vec<u8>, lsn: Option<u64>, expires_at: Option<u64>) {
+        // Expired values still take up room until overwritten
+        if let Some(Some(old_value)) = self.get_slot(&key).map(|slot| &slot.value) {
             self.size_bytes -= key.len() + old_value.len();
         }
 
         self.size_bytes += key.len() + value.len();
-        self.data.insert(key, Some(value));
+        self.put(key, Slot { value: Some(value), lsn, expires_at });
     }
 
     pub fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
-        match self.data.get(key)? {
+        match self.get_entry(key)? {
             Some(value) => Some(value),
             None => None,
         }
     }
 
+    // Like get, but a tombstone comes back as Some(None) rather than None
+    pub fn get_entry(&self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.get_slot(key).map(Slot::live_value)
+    }
+
+    // When `key`'s current entry expires, or None if it has no entry or no TTL
+    pub fn expiry_of(&self, key: &[u8]) -> Option<u64> {
+        self.get_slot(key)?.expires_at
+    }
+
+    // LSN of the write that left `key`'s current entry (a tombstone included), or None
+    // if there is no entry or it wasn't written with one
+    pub fn lsn_of(&self, key: &[u8]) -> Option<u64> {
+        self.get_slot(key)?.lsn
+    }
+
+    fn get_slot(&self, key: &[u8]) -> Option<&Slot> {
+        match &self.data {
+            Entries::SortedVec(entries) => entries
+                .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                .ok()
+                .map(|idx| &entries[idx].1),
+            Entries::BTree(entries) => entries.get(key),
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, slot: Slot) {
+        match &mut self.data {
+            Entries::SortedVec(entries) => {
+                let appended = entries.last().is_none_or(|(last, _)| *last < key);
+                if appended {
+                    entries.push((key, slot));
+                } else {
+                    match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                        Ok(idx) => entries[idx].1 = slot,
+                        Err(idx) => entries.insert(idx, (key, slot)),
+                    }
+                }
+                if entries.len() > SORTED_VEC_MAX_ENTRIES && !(self.monotonic && appended) {
+                    self.data = Entries::BTree(std::mem::take(entries).into_iter().collect());
+                }
+            }
+            Entries::BTree(entries) => {
+                entries.insert(key, slot);
+            }
+        }
+    }
+
+    // Every entry (tombstones included), in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Option<Vec<u8>>)> {
+        self.range(&[], None)
+    }
+
+    // Like iter, along with the LSN each entry was written at and when it expires
+    pub fn iter_with_metadata(&self) -> impl Iterator<Item = EntryWithMetadata<'_>> {
+        self.slots(&[], None).map(|(key, slot)| (key, slot.live_value(), slot.lsn, slot.expires_at))
+    }
+
+    // Entries (tombstones included) whose key starts with `prefix`, in key order
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)> + 'a {
+        self.range(prefix, None)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    // Entries (tombstones included) with `start <= key < end`, in key order
+    pub fn range<'a>(&'a self, start: &'a [u8], end: Option<&'a [u8]>) -> impl Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)> + 'a {
+        self.slots(start, end).map(|(key, slot)| (key, slot.live_value()))
+    }
+
+    fn slots<'a>(&'a self, start: &'a [u8], end: Option<&'a [u8]>) -> EntryIter<'a> {
+        match &self.data {
+            Entries::SortedVec(entries) => {
+                let from = entries.partition_point(|(key, _)| key.as_slice() < start);
+                let to = match end {
+                    Some(end) => entries.partition_point(|(key, _)| key.as_slice() < end).max(from),
+                    None => entries.len(),
+                };
+                EntryIter::SortedVec(entries[from..to].iter())
+            }
+            Entries::BTree(entries) => {
+                // BTreeMap::range panics on an inverted range rather than returning nothing
+                let end = match end {
+                    Some(end) => Bound::Excluded(end.max(start)),
+                    None => Bound::Unbounded,
+                };
+                EntryIter::BTree(entries.range::<[u8], _>((Bound::Included(start), end)))
+            }
+        }
+    }
+
     pub fn remove(&mut self, key: &[u8]) {
-        self.data.insert(key.to_vec(), None);  // Tombstone
+        self.put(key.to_vec(), Slot { value: None, lsn: None, expires_at: None });  // Tombstone
+    }
+
+    pub fn remove_with_lsn(&mut self, key: &[u8], lsn: u64) {
+        self.put(key.to_vec(), Slot { value: None, lsn: Some(lsn), expires_at: None });
     }
 
     pub fn len(&self) -> usize {
-        self.data.len()
+        match &self.data {
+            Entries::SortedVec(entries) => entries.len(),
+            Entries::BTree(entries) => entries.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn data(&self) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
-        self.data.clone()
+        self.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
     }
 
     pub fn size_byte(&self) -> usize {
@@ -46,9 +244,30 @@ impl MemTable {
     }
 
     pub fn copy(&self) -> MemTable {
+        let data = match &self.data {
+            Entries::SortedVec(entries) => Entries::SortedVec(entries.clone()),
+            Entries::BTree(entries) => Entries::BTree(entries.clone()),
+        };
         MemTable{
-            data: self.data.clone(),
-            size_bytes: self.size_bytes.clone(),
+            data,
+            size_bytes: self.size_bytes,
+            monotonic: self.monotonic,
         }
     }
-}
\ No newline at end of file
+}
+
+enum EntryIter<'a> {
+    SortedVec(std::slice::Iter<'a, Entry>),
+    BTree(std::collections::btree_map::Range<'a, Vec<u8>, Slot>),
+}
+
+impl<'a> Iterator for EntryIter<'a> {
+    type Item = (&'a Vec<u8>, &'a Slot);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            EntryIter::SortedVec(entries) => entries.next().map(|(key, value)| (key, value)),
+            EntryIter::BTree(entries) => entries.next(),
+        }
+    }
+}
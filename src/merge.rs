@@ -0,0 +1,59 @@
+use std::iter::Peekable;
+
+use crate::error::DbexError;
+
+// A lazily read source's entries in key order, None marking a tombstone
+pub(crate) type EntrySource<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Option<Vec<u8>>), DbexError>> + 'a>;
+
+// Merges sorted sources, given oldest to newest, into one sorted stream of live pairs.
+// Unlike DBex::merge_sources it pulls from each source only as far as the next key, so
+// it holds one entry per source at a time; the newest entry of a key wins and tombstones
+// are dropped. A source that fails ends the stream with its error.
+pub(crate) struct MergeIter<'a> {
+    sources: Vec<Peekable<EntrySource<'a>>>,
+    failed: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    pub(crate) fn new(sources: Vec<EntrySource<'a>>) -> Self {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            failed: false,
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), DbexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            for source in self.sources.iter_mut() {
+                if let Some(Err(_)) = source.peek() {
+                    self.failed = true;
+                    return source.next().and_then(Result::err).map(Err);
+                }
+            }
+
+            // There are only a few dozen sources, so a linear pass beats keeping a heap
+            let smallest = self.sources.iter_mut()
+                .filter_map(|source| match source.peek() {
+                    Some(Ok((key, _))) => Some(key.clone()),
+                    _ => None,
+                })
+                .min()?;
+            let mut newest = None;
+            for source in self.sources.iter_mut() {
+                if let Some(Ok((_, value))) = source.next_if(|entry| matches!(entry, Ok((key, _)) if *key == smallest)) {
+                    newest = Some(value);
+                }
+            }
+            if let Some(Some(value)) = newest {
+                return Some(Ok((smallest, value)));
+            }
+        }
+    }
+}
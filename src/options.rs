@@ -0,0 +1,252 @@
+use std::sync::Arc;
+use crate::bloom_filter::BloomHasher;
+use crate::compression::ValueTransform;
+use crate::storage::Storage;
+
+// How SSTable files are fsynced once a flush or compaction finishes writing them.
+//
+// SyncData (fdatasync) persists the file contents plus the metadata needed to read
+// them back (e.g. the file length). SyncAll (fsync) additionally persists metadata
+// such as timestamps and permissions, which the engine never relies on, so it is
+// strictly slower for no extra safety. None leaves write-back to the OS and can lose
+// recently flushed tables on power failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    #[default]
+    SyncData,
+    SyncAll,
+    None,
+}
+
+// How range and prefix scans read values from each SSTable's data file.
+//
+// Point lookups always go through a small buffer, since they touch one entry at a
+// time. Scans read entries in file order, so they can instead use a large buffer and
+// ask the OS to read ahead (posix_fadvise on Linux). Auto does that only once a scan
+// covers enough entries in a table to make opening a dedicated reader worthwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadAhead {
+    #[default]
+    Auto,
+    // Always use the point lookup path
+    Off,
+    // Always use a sequential reader with a buffer of this many bytes
+    Bytes(usize),
+}
+
+// Which entry DBex::bulk_load keeps when its sorted input repeats a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateKeys {
+    // Fail the whole load with DbexError::DuplicateKey
+    Error,
+    // Matches insert: a later write of a key replaces an earlier one
+    #[default]
+    KeepLast,
+    KeepFirst,
+}
+
+// Whose value DBex::merge_from keeps for a key live in both databases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeConflict {
+    #[default]
+    KeepSelf,
+    KeepOther,
+}
+
+// How new WAL records are encoded. Replay reads both, whichever wrote the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecordFormat {
+    // Key and value stored as plain length-prefixed bytes after a fixed header, so
+    // large values are copied once instead of going through rkyv's serializer
+    #[default]
+    Raw,
+    // The whole entry archived with rkyv, readable by releases from before Raw
+    Rkyv,
+}
+
+// What the keys written to a database look like, for DBexOptions::with_key_hint to
+// pick settings by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyHint {
+    // Written in ascending order, e.g. timestamps or sequence numbers
+    Monotonic,
+    // Spread over the key space and mostly read with point lookups, e.g. hashes or UUIDs
+    Random,
+    // Grouped under prefixes of this many bytes (tenants, tables) and read with scan_prefix
+    Prefixed(usize),
+}
+
+// How SSTable values are compressed. Each table records the codec it was written with,
+// so changing this only affects tables written afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    // Every value compressed on its own with LZ4: a lower ratio than Zstd, but several
+    // times faster to compress and decompress
+    Lz4,
+    // Every value compressed on its own
+    Zstd { level: i32 },
+    // Every value compressed against a dictionary of up to `max_dict_len` bytes, trained
+    // on a sample of the values at flush. Pays off for many small, similar values, which
+    // have too little internal redundancy to compress well alone. Compaction output
+    // reuses the newest input's dictionary; bulk loads fall back to plain Zstd.
+    ZstdDictionary { level: i32, max_dict_len: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct DBexOptions {
+    // Applied uniformly to both the data and index file of every SSTable
+    pub sync_policy: SyncPolicy,
+    // When set, every table also gets a Bloom filter over the first N bytes of its
+    // keys so scan_prefix can skip tables. Point lookups always use the whole-key filter.
+    pub prefix_bloom_len: Option<usize>,
+    pub read_ahead: ReadAhead,
+    // Expected number of entries per memtable. Small hints start each memtable as a
+    // sorted vector, which switches to a BTreeMap on its own if it outgrows the hint.
+    pub memtable_size_hint: Option<usize>,
+    pub bulk_load_duplicates: DuplicateKeys,
+    // Catch panics inside the writes (insert, insert_with_ttl, insert_reader, remove,
+    // remove_many, write_batch), flush, maintain, set_compaction_paused and try_find, and
+    // return them as DbexError::Internal, so one failed operation doesn't take the
+    // process down. A stopgap for the remaining unwraps on I/O paths; off by default.
+    pub catch_panics: bool,
+    // How many recently found keys each SSTable remembers the data offset of, so hot
+    // keys skip the index scan in find. 0 disables the cache.
+    pub index_cache_len: usize,
+    pub merge_conflict: MergeConflict,
+    pub compression: Compression,
+    // Lowest LSN a writer hands out, e.g. to continue a replica's sequence. The LSN
+    // recorded by the previous writer still wins if it is higher.
+    pub start_lsn: u64,
+    // When set, WAL replay at open flushes the memtable after every this many entries,
+    // bounding memory use and SSTable size when recovering a large WAL
+    pub replay_batch_size: Option<usize>,
+    // Move the WAL into `<data_dir>/wal_archive/` after each flush instead of truncating
+    // it, keeping the full write history for DBex::recover_to_lsn
+    pub archive_wal: bool,
+    // When set, adjacent L0 tables smaller than this many bytes are merged into one after
+    // each flush, without waiting for L0 to fill up. Keeps bursts of small flushes from
+    // leaving reads to search many tiny tables.
+    pub coalesce_below_bytes: Option<u64>,
+    // Skip the full validation pass (see SSTable::verify) ingest_sstable otherwise makes
+    // over an external table before linking it in. Only for tables from a trusted source.
+    pub trust_ingested_tables: bool,
+    // Check the CRC each value in an SSTable carries on reads, so a corrupted value
+    // fails with DbexError::Corruption. On by default; turning it off trades that
+    // detection for a little less work on hot paths. DBex::verify checks them either way.
+    pub verify_checksums: bool,
+    // When set, up to this many back-to-back writes of the same key (a counter being
+    // bumped, say) are logged as one WAL record holding the last of them, shrinking the
+    // WAL and replay work. recover_to_lsn can't stop partway through such a run.
+    pub wal_coalesce_window: Option<usize>,
+    pub wal_record_format: WalRecordFormat,
+    // fdatasync the WAL on every insert and remove, so a write is durable by the time
+    // the call returns (see DBex::durable_lsn). Without it, each record still reaches the
+    // WAL file before the call returns, so it survives the process dying, but not a power
+    // failure until the next sync or flush. Defeats wal_coalesce_window.
+    pub sync_wal_writes: bool,
+    // Hashes keys for the Bloom filters of new tables; None means Xxh3Hasher. Tables
+    // built with a custom hasher need the same hasher (same id) configured to use their
+    // filters, otherwise lookups just go without them.
+    pub bloom_hasher: Option<Arc<dyn BloomHasher>>,
+    // False positive rate the Bloom filters of new tables are sized for, trading memory
+    // and filter file size (about 4.8 bits per key per tenfold drop) against wasted index
+    // scans on lookups of absent keys. None means about 1%, at 10 bits per key.
+    pub false_positive_rate: Option<f64>,
+    // Inserts of values longer than this many bytes fail with DbexError::ValueTooLarge
+    // instead of landing in the memtable. None (the default) accepts any size.
+    pub max_value_size: Option<usize>,
+    // Applied to every value written to an SSTable, and undone on reads. The WAL and
+    // memtables hold values as inserted. A database whose tables were written with a
+    // transform only opens with one of the same id.
+    pub value_transform: Option<Arc<dyn ValueTransform>>,
+    // Caps the I/O of compaction, counting the tables it reads and the data file it
+    // writes (index entries count once), at this many bytes per second on average, so a
+    // big merge doesn't hog the disk other traffic shares. Compactions take
+    // correspondingly longer. None means no limit.
+    pub compaction_bytes_per_sec: Option<u64>,
+    // Have scan_tenant return keys relative to the tenant, with the tenant prefix cut off.
+    // Off by default, so scan_tenant returns whole keys like scan_prefix.
+    pub strip_tenant_prefix: bool,
+    // How many versions of each key compaction keeps, newest first, for reading back with
+    // DBex::get_all_versions. Reads only ever see the newest. None keeps just the newest,
+    // as does Some(1). A key whose newest version is a tombstone being dropped loses its
+    // older versions with it.
+    pub versions_to_keep: Option<usize>,
+    // Number of levels, L0 included, each compacted into the next once it holds more
+    // than 10 tables. None means 3 (L0 to L2), and values below 2 count as 2. The last
+    // level has no limit, so more levels keep huge datasets from piling up in one.
+    pub level_count: Option<usize>,
+    // Keys between the points of each new table's in-memory sparse index; None means
+    // SPARSE_INDEX_INTERVAL (100). Smaller intervals shorten the index scan of a lookup
+    // at the cost of memory per table.
+    pub sparse_index_interval: Option<usize>,
+    // Start every memtable as one that appends keys arriving in ascending order (see
+    // MemTable::monotonic). Out-of-order keys still work, just without the fast path.
+    pub monotonic_keys: bool,
+    // Have flush write the active memtable straight to an SSTable instead of freezing it
+    // first and starting a new one. Nothing overlaps with a flush when no one else calls
+    // freeze_memtable, so the frozen stage only costs a swap; a memtable frozen by hand
+    // is still flushed first.
+    pub direct_flush: bool,
+    // Where the database's files live; None means the local filesystem
+    pub storage: Option<Arc<dyn Storage>>,
+}
+
+// Everything off or at its type's default, except verify_checksums
+impl Default for DBexOptions {
+    fn default() -> Self {
+        DBexOptions {
+            sync_policy: Default::default(),
+            prefix_bloom_len: Default::default(),
+            read_ahead: Default::default(),
+            memtable_size_hint: Default::default(),
+            bulk_load_duplicates: Default::default(),
+            catch_panics: Default::default(),
+            index_cache_len: Default::default(),
+            merge_conflict: Default::default(),
+            compression: Default::default(),
+            start_lsn: Default::default(),
+            replay_batch_size: Default::default(),
+            archive_wal: Default::default(),
+            coalesce_below_bytes: Default::default(),
+            trust_ingested_tables: Default::default(),
+            verify_checksums: true,
+            wal_coalesce_window: Default::default(),
+            wal_record_format: Default::default(),
+            sync_wal_writes: Default::default(),
+            bloom_hasher: Default::default(),
+            false_positive_rate: Default::default(),
+            max_value_size: Default::default(),
+            value_transform: Default::default(),
+            compaction_bytes_per_sec: Default::default(),
+            strip_tenant_prefix: Default::default(),
+            versions_to_keep: Default::default(),
+            level_count: Default::default(),
+            sparse_index_interval: Default::default(),
+            monotonic_keys: Default::default(),
+            direct_flush: Default::default(),
+            storage: Default::default(),
+        }
+    }
+}
+
+impl DBexOptions {
+    // Sets the sparse index interval, Bloom filters and memtable to suit `hint`,
+    // replacing whatever those options were set to:
+    // - Monotonic: the append fast path of monotonic_keys, and a sparse index point
+    //   every 256 keys, since such keys are mostly read back in ranges
+    // - Random: a sparse index point every 32 keys to cut the index scan of each point
+    //   lookup, whole-key Bloom filters only, and the default BTreeMap memtable
+    // - Prefixed(len): prefix Bloom filters over the first `len` bytes, so scan_prefix
+    //   skips tables without the prefix, and the default sparse index interval
+    pub fn with_key_hint(self, hint: KeyHint) -> Self {
+        let (sparse_index_interval, prefix_bloom_len, monotonic_keys) = match hint {
+            KeyHint::Monotonic => (Some(256), None, true),
+            KeyHint::Random => (Some(32), None, false),
+            KeyHint::Prefixed(len) => (None, Some(len), false),
+        };
+        DBexOptions { sparse_index_interval, prefix_bloom_len, monotonic_keys, ..self }
+    }
+}
@@ -0,0 +1,133 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::error::DbexError;
+use crate::key::{AsKeyBytes, IntoKey};
+use crate::options::DBexOptions;
+use crate::storage::LocalStorage;
+use crate::DBex;
+
+// [shard count: u32][prefix len: u32], with WHOLE_KEY as the prefix len for whole keys
+const LAYOUT_FILE: &str = "SHARDS";
+const WHOLE_KEY: u32 = u32::MAX;
+
+// Spreads keys over several DBex instances, each with its own data directory
+// `<path>/shard_<n>`, so their flushes and compactions run independently. A key goes to
+// the shard picked by a hash of its first `prefix_len` bytes (of the whole key if None or
+// shorter), so keys sharing such a prefix, e.g. a tenant's, stay together. The layout is
+// recorded on disk and has to be given the same on every open.
+pub struct ShardedDb {
+    shards: Vec<DBex>,
+    prefix_len: Option<usize>,
+}
+
+impl ShardedDb {
+    pub fn open<P: AsRef<Path>>(path: P, shard_count: usize, prefix_len: Option<usize>) -> Result<Self, DbexError> {
+        Self::open_with_options(path, shard_count, prefix_len, DBexOptions::default())
+    }
+
+    // Every shard is opened with a copy of `options`
+    pub fn open_with_options<P: AsRef<Path>>(path: P, shard_count: usize, prefix_len: Option<usize>, options: DBexOptions) -> Result<Self, DbexError> {
+        assert!(shard_count > 0, "a ShardedDb needs at least one shard");
+        let path = path.as_ref();
+        let storage = options.storage.clone().unwrap_or_else(|| Arc::new(LocalStorage));
+        storage.create_dir_all(path)?;
+
+        let layout_path = path.join(LAYOUT_FILE);
+        let layout = [shard_count as u32, prefix_len.map_or(WHOLE_KEY, |len| len as u32)];
+        let encoded: Vec<u8> = layout.iter().flat_map(|field| field.to_be_bytes()).collect();
+        if storage.exists(&layout_path) {
+            let stored = storage.read(&layout_path)?;
+            if stored != encoded {
+                let field = |at: usize| stored.get(at..at + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+                let (Some(shard_count), Some(prefix_len)) = (field(0), field(4)) else {
+                    return Err(DbexError::Corruption(format!("{} is not a shard layout", layout_path.display())));
+                };
+                return Err(DbexError::ShardLayoutMismatch {
+                    shard_count: shard_count as usize,
+                    prefix_len: (prefix_len != WHOLE_KEY).then_some(prefix_len as usize),
+                });
+            }
+        } else {
+            let layout_file = storage.create(&layout_path)?;
+            layout_file.write(&encoded)?;
+            layout_file.sync(options.sync_policy)?;
+        }
+
+        let shards = (0..shard_count)
+            .map(|shard| DBex::try_open_with_options(path.join(format!("shard_{:03}", shard)), options.clone()))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedDb { shards, prefix_len })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Index of the shard holding `key`
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        let routed = match self.prefix_len {
+            Some(len) => &key[..len.min(key.len())],
+            None => key,
+        };
+        (xxh3_64(routed) % self.shards.len() as u64) as usize
+    }
+
+    pub fn shard(&mut self, index: usize) -> &mut DBex {
+        &mut self.shards[index]
+    }
+
+    pub fn insert<K: IntoKey>(&mut self, key: K, value: Vec<u8>) -> Result<(), DbexError> {
+        let key = key.into_key();
+        let shard = self.shard_of(&key);
+        self.shards[shard].insert(key, value)
+    }
+
+    pub fn find<K: AsKeyBytes>(&mut self, key: K) -> Result<Option<Vec<u8>>, DbexError> {
+        let key_bytes = key.key_bytes();
+        let shard = self.shard_of(key_bytes.as_ref());
+        self.shards[shard].find(key_bytes.as_ref())
+    }
+
+    pub fn remove<K: AsKeyBytes>(&mut self, key: K) -> Result<(), DbexError> {
+        let key_bytes = key.key_bytes();
+        let shard = self.shard_of(key_bytes.as_ref());
+        self.shards[shard].remove(key_bytes.as_ref())
+    }
+
+    // Live pairs with `start <= key < end` from every shard, merged into key order. Each
+    // key lives in exactly one shard, so there is nothing to deduplicate.
+    pub fn range(&mut self, start: &[u8], end: &[u8]) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError> {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for shard in self.shards.iter_mut() {
+            pairs.extend(shard.range(start, end)?);
+        }
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs.into_iter())
+    }
+
+    // Flushes every shard, each on its own thread. Returns the first error, after all
+    // shards have finished.
+    pub fn flush(&mut self) -> Result<(), DbexError> {
+        thread::scope(|scope| {
+            let flushes: Vec<_> = self.shards.iter_mut()
+                .map(|shard| scope.spawn(move || shard.flush()))
+                .collect();
+            let results: Vec<_> = flushes.into_iter().map(|flush| flush.join().unwrap()).collect();
+            results.into_iter().find_map(Result::err).map_or(Ok(()), Err)
+        })
+    }
+
+    pub fn close(self) -> Result<(), DbexError> {
+        let mut result = Ok(());
+        for shard in self.shards {
+            let closed = shard.close();
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
+    }
+}
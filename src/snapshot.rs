@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::DbexError;
+use crate::key::AsKeyBytes;
+use crate::memtable::MemTable;
+use crate::options::ReadAhead;
+use crate::ss_table::SSTable;
+use crate::storage::Storage;
+use crate::DBex;
+
+// A point-in-time view of a DBex, taken with DBex::snapshot. Reads see the database as
+// it was then; later inserts, removes, flushes and compactions don't show. The snapshot
+// keeps a copy of the memtables and its own handles on the SSTables, whose files stay on
+// disk until it is dropped, so it can outlive the DBex it was taken from.
+pub struct Snapshot {
+    lsn: u64,
+    // Both lists newest first
+    memtables: Vec<MemTable>,
+    ss_tables: Vec<SSTable>,
+    index_cache_len: usize,
+    read_ahead: ReadAhead,
+    // Declared last so the table handles above are closed before a pin removes files
+    _pins: Vec<Arc<TablePin>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(lsn: u64, memtables: Vec<MemTable>, ss_tables: Vec<SSTable>, pins: Vec<Arc<TablePin>>, index_cache_len: usize, read_ahead: ReadAhead) -> Self {
+        Snapshot {
+            lsn,
+            memtables,
+            ss_tables,
+            index_cache_len,
+            read_ahead,
+            _pins: pins,
+        }
+    }
+
+    // The LSN the next write had when the snapshot was taken; it sees every write below it
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    pub fn find<K: AsKeyBytes>(&mut self, key: K) -> Result<Option<Vec<u8>>, DbexError> {
+        let key_bytes = key.key_bytes();
+        let key = key_bytes.as_ref();
+        for table in &self.memtables {
+            if let Some(value) = table.get_entry(key) {
+                return Ok(value.clone());
+            }
+        }
+        for ss_table in self.ss_tables.iter_mut() {
+            if !ss_table.covers(key) {
+                continue;
+            }
+            if let Some(value) = ss_table.get(key, self.index_cache_len)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    // The live entries with `start <= key <= end`, in key order, like DBex::scan
+    pub fn scan(&mut self, start: &[u8], end: &[u8]) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, DbexError> {
+        let mut exclusive_end = end.to_vec();
+        exclusive_end.push(0);
+        let end = exclusive_end.as_slice();
+
+        // Sources from oldest to newest
+        let read_ahead = self.read_ahead;
+        let mut sources: Vec<_> = self.ss_tables.iter_mut()
+            .rev()
+            .filter(|ss_table| ss_table.max_key().as_slice() >= start && ss_table.min_key().as_slice() < end)
+            .map(|ss_table| ss_table.scan_range(start, Some(end), read_ahead))
+            .collect::<Result<_, _>>()?;
+        sources.extend(self.memtables.iter().rev().map(|table| {
+            table.range(start, Some(end))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }));
+
+        Ok(DBex::merge_sources(sources).into_iter().filter_map(|(key, value)| value.map(|value| (key, value))))
+    }
+}
+
+// Keeps a table's files on disk while snapshots read it. When compaction (or anything
+// else) retires a pinned table, the files are removed by the last pin to go instead.
+pub(crate) struct TablePin {
+    storage: Arc<dyn Storage>,
+    data_path: PathBuf,
+    retired: AtomicBool,
+}
+
+impl TablePin {
+    pub(crate) fn new(storage: Arc<dyn Storage>, data_path: PathBuf) -> Self {
+        TablePin {
+            storage,
+            data_path,
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TablePin {
+    fn drop(&mut self) {
+        if self.retired.load(Ordering::SeqCst) {
+            SSTable::remove_files(self.storage.as_ref(), &self.data_path).ok();
+        }
+    }
+}
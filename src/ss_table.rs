@@ -1,89 +1,874 @@
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{ SystemTime, UNIX_EPOCH };
+use crate::bloom_filter::{resolve_hasher, BloomFilter, BloomHasher, Fnv1aHasher, Xxh3Hasher};
+use crate::compression::{ValueCodec, ValueTransform};
+use crate::error::DbexError;
 use crate::memtable::MemTable;
+use crate::options::{ReadAhead, SyncPolicy};
+use crate::storage::{MappedBytes, Storage, StorageReader, StorageWriter};
+use crate::utils::unix_millis;
 
 #[derive(Debug)]
 pub struct SSTable {
+    storage: Arc<dyn Storage>,
     data_path: PathBuf,
-    data_writer: BufWriter<File>,
-    data_reader: BufReader<File>,
+    // Writers are only present on tables that are still being built
+    data_writer: Option<BufWriter<StorageWriter>>,
+    data_reader: BufReader<StorageReader>,
     index_path: PathBuf,
-    index_writer: BufWriter<File>,
-    index_reader: BufReader<File>,
+    index_writer: Option<BufWriter<StorageWriter>>,
+    index_reader: BufReader<StorageReader>,
+    // Length of the index entries, excluding the footer, and the reader's position within them
+    index_len: u64,
+    index_pos: u64,
+    // How many times the index reader has been repositioned
+    index_seeks: u64,
     sparse_index: Vec<(Vec<u8>, u64)>,
+    // Every this many keys get a sparse index point when the index is written
+    sparse_index_interval: usize,
     min_key: Vec<u8>,
     max_key: Vec<u8>,
+    entry_count: u64,
+    size_bytes: u64,
+    // Length of the data file, used to bounds-check offsets and value lengths
+    data_len: u64,
+    filter_path: PathBuf,
+    // Whole-key filter used by point lookups
+    bloom_filter: Option<BloomFilter>,
+    // Filter over the first `len` bytes of each key, used to prune prefix scans
+    prefix_bloom_len: Option<usize>,
+    prefix_bloom_filter: Option<BloomFilter>,
+    // Builds the filters of a new table, and can read those of an existing table that
+    // was built with a custom hasher
+    bloom_hasher: Arc<dyn BloomHasher>,
+    // Rate the filters of a table being written are sized for; None means BloomFilter::new
+    false_positive_rate: Option<f64>,
+    index_cache: IndexCache,
+    // Present only on compressed tables, which carry a .codec sidecar
+    codec_path: PathBuf,
+    codec: Option<ValueCodec>,
+    // Id of the value transform the table's values went through, recorded in a .transform
+    // sidecar, and the transform set to undo it (see set_value_transform)
+    transform_path: PathBuf,
+    transform_id: Option<u8>,
+    transform: Option<Arc<dyn ValueTransform>>,
+    // Mapping of the data file, created by the first get_mapped
+    data_map: Option<MappedBytes>,
+    // (data offset, LSN) of every entry written with an LSN, in offset order. Kept in a
+    // .lsn sidecar; tables opened from disk load it on first use (see lsn_at).
+    lsn_path: PathBuf,
+    lsns: Option<Vec<(u64, u64)>>,
+    // (data offset, expiry in milliseconds since the Unix epoch) of every entry written
+    // with a TTL, in offset order, kept in an .expiry sidecar like the LSNs. An expired
+    // entry reads as a tombstone.
+    expiry_path: PathBuf,
+    expiries: Option<Vec<(u64, u64)>>,
+    // Whether the index footer says the table has an .expiry sidecar. Tables from before
+    // the footer recorded it fall back to treating a missing one as no TTLs.
+    has_expiries: bool,
+    // Persisted sparse index, key range and entry count (see SPARSE_INDEX_MAGIC)
+    sparse_index_path: PathBuf,
+    // CRC of the data file, kept up to date while the table is written and recorded in
+    // the index footer. None on tables from before data checksums.
+    data_hasher: Option<crc32fast::Hasher>,
+    data_crc: Option<u32>,
+    // Whether every value is followed by its own CRC (see ENTRY_CHECKSUMMED_FOOTER_MAGIC),
+    // as in all tables written since, and whether reads check it
+    entry_checksums: bool,
+    verify_checksums: bool,
+    // Versions compaction kept behind each key's newest one (see
+    // DBexOptions::versions_to_keep), in a .versions sidecar loaded on first use
+    versions_path: PathBuf,
+    versions: Option<Vec<OlderVersion>>,
+    // LSN of the next write when the table was created, recorded in the manifest so
+    // incremental backups can tell which tables are new (see DBex::iter_since_checkpoint)
+    created_lsn: u64,
 }
 
+// An uncompressed value read in place from a table's memory-mapped data file. Holding
+// one keeps the mapping alive: compaction only ever unlinks table files, never truncates
+// or rewrites them, and an unlinked file's mapped pages stay valid until the last
+// MappedValue referencing them is dropped.
+#[derive(Debug, Clone)]
+pub struct MappedValue {
+    data_map: MappedBytes,
+    range: Range<usize>,
+}
+
+impl Deref for MappedValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data_map[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for MappedValue {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+// A value returned by get_mapped: borrowed from the mapping when the table stores it as
+// is, decompressed into a Vec otherwise
+#[derive(Debug, Clone)]
+pub enum ValueRef {
+    Mapped(MappedValue),
+    Owned(Vec<u8>),
+}
+
+impl ValueRef {
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, ValueRef::Mapped(_))
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ValueRef::Mapped(value) => value,
+            ValueRef::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+// Data offsets of recently found keys, so repeated lookups skip the index scan.
+// Evicts in insertion order; the table is immutable, so entries never go stale and the
+// cache simply goes away with the table when compaction replaces it.
+#[derive(Debug, Default)]
+struct IndexCache {
+    offsets: HashMap<Vec<u8>, u64>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl IndexCache {
+    fn get(&self, key: &[u8]) -> Option<u64> {
+        self.offsets.get(key).copied()
+    }
+
+    // Each key is held twice, in the map and the eviction order
+    fn size_bytes(&self) -> u64 {
+        self.order.iter().map(|key| 2 * key.len() as u64 + 8).sum()
+    }
+
+    fn insert(&mut self, key: &[u8], offset: u64, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        while self.order.len() >= capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.offsets.remove(&evicted);
+            }
+        }
+        self.offsets.insert(key.to_vec(), offset);
+        self.order.push_back(key.to_vec());
+    }
+}
+
+// First byte of the .filter file, recording which filters the table carries. Files
+// without FILTER_HASHER_RECORDED predate pluggable hashers and used Fnv1aHasher.
+const FILTER_WHOLE_KEY: u8 = 0;
+const FILTER_WHOLE_KEY_AND_PREFIX: u8 = 1;
+const FILTER_HASHER_RECORDED: u8 = 0x80;
+
+// Index file footer: [index_len: u64][crc32 of the index entries: u32][magic: u32]
+const INDEX_FOOTER_LEN: u64 = 16;
+const INDEX_FOOTER_MAGIC: u32 = 0x44425849; // "DBXI"
+// Footer of tables written since data checksums, with the CRC of the whole data file:
+// [index_len: u64][crc32 of the index entries: u32][crc32 of the data file: u32][magic: u32]
+const CHECKSUMMED_FOOTER_LEN: u64 = 20;
+const CHECKSUMMED_FOOTER_MAGIC: u32 = 0x44425843; // "DBXC"
+// The same footer on tables whose data entries each carry a CRC after the value:
+// [value len: u32][value][crc32 of the length and value: u32]. Tombstones don't.
+const ENTRY_CHECKSUMMED_FOOTER_MAGIC: u32 = 0x44425845; // "DBXE"
+// The entry-checksummed footer on a table with entries written with a TTL, so a missing
+// .expiry sidecar is known to be lost rather than never written
+const EXPIRING_FOOTER_MAGIC: u32 = 0x44425858; // "DBXX"
+
+// Sparse index sidecar, saving open() the index scan that would otherwise rebuild it:
+// [magic: u32][index len: u64][index crc32: u32][entry count: u64]
+// [min key len: u32][min key][max key len: u32][max key]
+// [point count: u32], per point: [key len: u32][key][index offset: u64]
+// [crc32 of everything before it: u32]
+// It is only used while its index length and CRC match the index footer.
+const SPARSE_INDEX_MAGIC: u32 = 0x44425853; // "DBXS"
+
+// Keys between sparse index points, unless set_sparse_index_interval says otherwise.
+// Tables without a usable sidecar rebuild their points at open with this interval.
+pub const SPARSE_INDEX_INTERVAL: usize = 100;
+
+// Older versions sidecar: [version count: u32], then in key order and newest first
+// within a key, per version: [key len: u32][key][lsn: u64, u64::MAX if unknown]
+// [value len: u32, u32::MAX for a tombstone][value], then [crc32 of everything before
+// it: u32]. Values go through the table's value transform but aren't compressed.
+const NO_LSN: u64 = u64::MAX;
+const VERSION_TOMBSTONE: u32 = u32::MAX;
+
+// (LSN, value) of a version of some key; the value is None for a tombstone
+pub type KeyVersion = (Option<u64>, Option<Vec<u8>>);
+// (key, LSN, value) of a version shadowed by the table's own entry for the key
+type OlderVersion = (Vec<u8>, Option<u64>, Option<Vec<u8>>);
+// Entries read from the table in key order, None marking a tombstone
+type Entries = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+// What open() would otherwise rebuild by scanning the index
+struct IndexSummary {
+    entry_count: u64,
+    min_key: Vec<u8>,
+    max_key: Vec<u8>,
+    sparse_index: Vec<(Vec<u8>, u64)>,
+}
+
+// A table's files, as suffixes of its data path. Only the data and index files are
+// required; the others are written when a table has something to put in them.
+const TABLE_FILE_SUFFIXES: [&str; 9] = ["", ".index", ".filter", ".codec", ".lsn", ".transform", ".sparse", ".versions", ".expiry"];
+
+// stream_to output: [magic: u32][file count: u32], then per file
+// [suffix len: u32][suffix][file len: u64][file bytes][crc32 of the file bytes: u32]
+const STREAM_MAGIC: u32 = 0x44425854; // "DBXT"
+const STREAM_CHUNK_LEN: usize = 64 * 1024;
+
+// With ReadAhead::Auto, scans covering at least this many entries of a table get a
+// sequential reader with a buffer of SEQUENTIAL_SCAN_BUFFER_LEN bytes
+const SEQUENTIAL_SCAN_MIN_ENTRIES: usize = 64;
+const SEQUENTIAL_SCAN_BUFFER_LEN: usize = 256 * 1024;
+
 impl SSTable {
-    pub fn new() -> Self {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
+    // Creates a new, empty table under `ss_table_dir`, named after the current time. When
+    // `prefix_bloom_len` is set a prefix Bloom filter is built alongside the whole-key one.
+    pub fn new(storage: Arc<dyn Storage>, ss_table_dir: &Path, prefix_bloom_len: Option<usize>) -> Result<Self, DbexError> {
+        Self::numbered(storage, ss_table_dir, timestamp_number(), prefix_bloom_len)
+    }
 
-        let data_path = PathBuf::from(format!("db_data/ss_tables/ss_table_{}.db", timestamp));
-        let index_path = PathBuf::from(format!("db_data/ss_tables/ss_table_{}.db.index", timestamp));
+    // Like new, naming the table `ss_table_<number>.db`. DBex numbers its tables from a
+    // counter kept in the manifest, so a clock that jumps back can't make a new table's
+    // name collide with, or sort before, an older one's.
+    pub fn numbered(storage: Arc<dyn Storage>, ss_table_dir: &Path, number: u64, prefix_bloom_len: Option<usize>) -> Result<Self, DbexError> {
+        let data_path = ss_table_dir.join(format!("ss_table_{}.db", number));
+        let index_path = with_suffix(&data_path, ".index");
+        let filter_path = with_suffix(&data_path, ".filter");
+        let codec_path = with_suffix(&data_path, ".codec");
+        let lsn_path = with_suffix(&data_path, ".lsn");
+        let transform_path = with_suffix(&data_path, ".transform");
+        let sparse_index_path = with_suffix(&data_path, ".sparse");
+        let versions_path = with_suffix(&data_path, ".versions");
+        let expiry_path = with_suffix(&data_path, ".expiry");
 
-        let data_write_file = File::create(&data_path).unwrap();
-        let index_write_file = File::create(&index_path).unwrap();
+        let data_write_file = storage.create(&data_path)?;
+        let index_write_file = storage.create(&index_path)?;
 
-        let data_writer = BufWriter::new(data_write_file);
-        let index_writer = BufWriter::new(index_write_file);
+        let data_writer = BufWriter::new(StorageWriter::new(data_write_file));
+        let index_writer = BufWriter::new(StorageWriter::new(index_write_file));
 
-        let data_read_file = File::open(&data_path).unwrap();
-        let index_read_file = File::open(&index_path).unwrap();
+        let data_read_file = storage.open(&data_path)?;
+        let index_read_file = storage.open(&index_path)?;
 
-        let data_reader = BufReader::new(data_read_file);
-        let index_reader = BufReader::new(index_read_file);
+        let data_reader = BufReader::new(StorageReader::new(data_read_file));
+        let index_reader = BufReader::new(StorageReader::new(index_read_file));
 
-        SSTable {
+        Ok(SSTable {
+            storage,
             data_path,
-            data_writer,
+            data_writer: Some(data_writer),
             data_reader,
             index_path,
-            index_writer,
+            index_writer: Some(index_writer),
             index_reader,
+            index_len: 0,
+            index_pos: 0,
+            index_seeks: 0,
             sparse_index: Vec::new(),
+            sparse_index_interval: SPARSE_INDEX_INTERVAL,
             min_key: Vec::new(),
             max_key: Vec::new(),
+            entry_count: 0,
+            size_bytes: 0,
+            data_len: 0,
+            filter_path,
+            bloom_filter: None,
+            prefix_bloom_len,
+            prefix_bloom_filter: None,
+            bloom_hasher: Arc::new(Xxh3Hasher),
+            false_positive_rate: None,
+            index_cache: IndexCache::default(),
+            codec_path,
+            codec: None,
+            transform_path,
+            transform_id: None,
+            transform: None,
+            data_map: None,
+            lsn_path,
+            lsns: Some(Vec::new()),
+            expiry_path,
+            expiries: Some(Vec::new()),
+            has_expiries: false,
+            sparse_index_path,
+            data_hasher: Some(crc32fast::Hasher::new()),
+            data_crc: None,
+            entry_checksums: true,
+            verify_checksums: true,
+            versions_path,
+            versions: Some(Vec::new()),
+            created_lsn: 0,
+        })
+    }
+
+    // Validates the table at `data_path`, then links (see Storage::link) its files into
+    // `ss_table_dir` as table `number` (see numbered) and opens the result
+    pub fn import(storage: Arc<dyn Storage>, data_path: &Path, ss_table_dir: &Path, number: u64) -> Result<Self, DbexError> {
+        // Flushes and compactions never write an empty table, and the merge relies on it
+        if Self::open(storage.clone(), data_path)?.entry_count() == 0 {
+            return Err(DbexError::Corruption(format!("{}: table has no entries", data_path.display())));
         }
+
+        let new_data_path = ss_table_dir.join(format!("ss_table_{}.db", number));
+
+        for suffix in TABLE_FILE_SUFFIXES {
+            let from = with_suffix(data_path, suffix);
+            let to = with_suffix(&new_data_path, suffix);
+            // Only the data and index files are required
+            if !matches!(suffix, "" | ".index") && !storage.exists(&from) {
+                continue;
+            }
+            storage.link(&from, &to)?;
+        }
+
+        Self::open(storage, &new_data_path)
     }
 
-    pub fn load_from_memtable(&mut self, memtable: &MemTable) {
-        let mut offset = 0u64;
-        let mut index_vec = Vec::new();
+    // Moves whichever files of the table at `data_path` exist into `dir`, keeping their
+    // names, e.g. to set a corrupt table aside without deleting it
+    pub fn move_files(storage: &dyn Storage, data_path: &Path, dir: &Path) -> Result<(), DbexError> {
+        for suffix in TABLE_FILE_SUFFIXES {
+            let from = with_suffix(data_path, suffix);
+            if let (true, Some(file_name)) = (storage.exists(&from), from.file_name()) {
+                storage.rename(&from, &dir.join(file_name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Removes whichever files of the table at `data_path` exist
+    pub fn remove_files(storage: &dyn Storage, data_path: &Path) -> Result<(), DbexError> {
+        for suffix in TABLE_FILE_SUFFIXES {
+            let path = with_suffix(data_path, suffix);
+            if storage.exists(&path) {
+                storage.remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the files of this fully written table to `writer` as they are on disk, for
+    // read_stream to recreate elsewhere byte for byte
+    pub fn stream_to<W: Write>(&self, writer: &mut W) -> Result<(), DbexError> {
+        let files: Vec<(&str, PathBuf)> = TABLE_FILE_SUFFIXES.iter()
+            .map(|suffix| (*suffix, with_suffix(&self.data_path, suffix)))
+            .filter(|(suffix, path)| matches!(*suffix, "" | ".index") || self.storage.exists(path))
+            .collect();
+
+        writer.write_all(&STREAM_MAGIC.to_be_bytes())?;
+        writer.write_all(&(files.len() as u32).to_be_bytes())?;
+        let mut buf = vec![0u8; STREAM_CHUNK_LEN];
+        for (suffix, path) in files {
+            let file = self.storage.open(&path)?;
+            let mut remaining = file.len()?;
+            writer.write_all(&(suffix.len() as u32).to_be_bytes())?;
+            writer.write_all(suffix.as_bytes())?;
+            writer.write_all(&remaining.to_be_bytes())?;
+
+            let mut reader = StorageReader::new(file);
+            let mut hasher = crc32fast::Hasher::new();
+            while remaining > 0 {
+                let chunk = remaining.min(buf.len() as u64) as usize;
+                reader.read_exact(&mut buf[..chunk])?;
+                hasher.update(&buf[..chunk]);
+                writer.write_all(&buf[..chunk])?;
+                remaining -= chunk as u64;
+            }
+            writer.write_all(&hasher.finalize().to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Recreates the files of a table sent by stream_to at `data_path` and opens it. A
+    // malformed stream fails with DbexError::Corruption and leaves no files behind.
+    pub fn read_stream<R: Read>(storage: Arc<dyn Storage>, reader: &mut R, data_path: &Path) -> Result<Self, DbexError> {
+        let result = Self::write_streamed_files(storage.as_ref(), reader, data_path)
+            .and_then(|_| Self::open(storage.clone(), data_path));
+        if result.is_err() {
+            for suffix in TABLE_FILE_SUFFIXES {
+                storage.remove(&with_suffix(data_path, suffix)).ok();
+            }
+        }
+        result
+    }
+
+    fn write_streamed_files<R: Read>(storage: &dyn Storage, reader: &mut R, data_path: &Path) -> Result<(), DbexError> {
+        let corruption = |reason: String| DbexError::Corruption(format!("table stream for {}: {}", data_path.display(), reason));
+        let read_array = |reader: &mut R, len: usize| -> Result<Vec<u8>, DbexError> {
+            let mut bytes = vec![0u8; len];
+            reader.read_exact(&mut bytes).map_err(|err| corruption(err.to_string()))?;
+            Ok(bytes)
+        };
+
+        if u32::from_be_bytes(read_array(reader, 4)?.try_into().unwrap()) != STREAM_MAGIC {
+            return Err(corruption("not a table stream".to_string()));
+        }
+        let file_count = u32::from_be_bytes(read_array(reader, 4)?.try_into().unwrap());
+        let mut buf = vec![0u8; STREAM_CHUNK_LEN];
+        let mut received = Vec::new();
+        for _ in 0..file_count {
+            let suffix_len = u32::from_be_bytes(read_array(reader, 4)?.try_into().unwrap()) as usize;
+            let suffix = read_array(reader, suffix_len.min(16))?;
+            let suffix = TABLE_FILE_SUFFIXES.iter()
+                .find(|known| known.as_bytes() == suffix.as_slice() && suffix_len == suffix.len())
+                .ok_or_else(|| corruption(format!("unknown file {:?}", String::from_utf8_lossy(&suffix))))?;
+            if received.contains(suffix) {
+                return Err(corruption(format!("file {:?} sent twice", suffix)));
+            }
+            received.push(*suffix);
+
+            let file = storage.create(&with_suffix(data_path, suffix))?;
+            let mut remaining = u64::from_be_bytes(read_array(reader, 8)?.try_into().unwrap());
+            let mut hasher = crc32fast::Hasher::new();
+            while remaining > 0 {
+                let chunk = remaining.min(buf.len() as u64) as usize;
+                reader.read_exact(&mut buf[..chunk]).map_err(|err| corruption(err.to_string()))?;
+                hasher.update(&buf[..chunk]);
+                file.write(&buf[..chunk])?;
+                remaining -= chunk as u64;
+            }
+            if u32::from_be_bytes(read_array(reader, 4)?.try_into().unwrap()) != hasher.finalize() {
+                return Err(corruption(format!("checksum mismatch in file {:?}", suffix)));
+            }
+            file.sync(SyncPolicy::SyncAll)?;
+        }
+        Ok(())
+    }
+
+    // Opens an existing, fully written table for reads. The index footer is validated
+    // first, so a truncated or partially written index is rejected with
+    // DbexError::Corruption. The key range and sparse index come from the .sparse sidecar
+    // when it matches the footer; otherwise the index entries are checked against the
+    // footer's CRC and scanned to rebuild them.
+    pub fn open(storage: Arc<dyn Storage>, data_path: &Path) -> Result<Self, DbexError> {
+        let data_path = data_path.to_path_buf();
+        let index_path = with_suffix(&data_path, ".index");
+        let filter_path = with_suffix(&data_path, ".filter");
+        let codec_path = with_suffix(&data_path, ".codec");
+        let lsn_path = with_suffix(&data_path, ".lsn");
+        let transform_path = with_suffix(&data_path, ".transform");
+        let sparse_index_path = with_suffix(&data_path, ".sparse");
+        let versions_path = with_suffix(&data_path, ".versions");
+        let expiry_path = with_suffix(&data_path, ".expiry");
+        let codec = Self::load_codec(storage.as_ref(), &codec_path)?;
+        let transform_id = Self::load_transform_id(storage.as_ref(), &transform_path)?;
+
+        let data_reader = BufReader::new(StorageReader::new(storage.open(&data_path)?));
+        let mut index_reader = BufReader::new(StorageReader::new(storage.open(&index_path)?));
+        let data_len = data_reader.get_ref().file().len()?;
+        let size_bytes = data_len + index_reader.get_ref().file().len()?;
+        let (index_len, index_crc, data_crc, entry_checksums, has_expiries) = Self::read_index_footer(&mut index_reader, &index_path)?;
+        let summary = Self::load_sparse_index(storage.as_ref(), &sparse_index_path, index_len, index_crc);
+        if summary.is_none() {
+            Self::check_index_crc(&mut index_reader, &index_path, index_len, index_crc)?;
+        }
+
+        let mut ss_table = SSTable {
+            storage,
+            data_path,
+            data_writer: None,
+            data_reader,
+            index_path,
+            index_writer: None,
+            index_reader,
+            index_len,
+            index_pos: 0,
+            index_seeks: 0,
+            sparse_index: Vec::new(),
+            sparse_index_interval: SPARSE_INDEX_INTERVAL,
+            min_key: Vec::new(),
+            max_key: Vec::new(),
+            entry_count: 0,
+            size_bytes,
+            data_len,
+            filter_path,
+            bloom_filter: None,
+            prefix_bloom_len: None,
+            prefix_bloom_filter: None,
+            bloom_hasher: Arc::new(Xxh3Hasher),
+            false_positive_rate: None,
+            index_cache: IndexCache::default(),
+            codec_path,
+            codec,
+            transform_path,
+            transform_id,
+            transform: None,
+            data_map: None,
+            lsn_path,
+            lsns: None,
+            expiry_path,
+            expiries: None,
+            has_expiries,
+            sparse_index_path,
+            data_hasher: None,
+            data_crc,
+            entry_checksums,
+            verify_checksums: true,
+            versions_path,
+            versions: None,
+            created_lsn: 0,
+        };
+        // Tables without a readable filter file simply skip Bloom pruning
+        ss_table.load_filters();
+
+        if let Some(summary) = summary {
+            ss_table.entry_count = summary.entry_count;
+            ss_table.min_key = summary.min_key;
+            ss_table.max_key = summary.max_key;
+            ss_table.sparse_index = summary.sparse_index;
+            return Ok(ss_table);
+        }
+
+        ss_table.seek_index(0)?;
+        let mut index_offset = 0u64;
+        while let Some((key, _)) = ss_table.try_next_key_in_index_file()? {
+            if ss_table.entry_count.is_multiple_of(SPARSE_INDEX_INTERVAL as u64) {
+                ss_table.sparse_index.push((key.clone(), index_offset));
+            }
+            if ss_table.entry_count == 0 {
+                ss_table.min_key = key.clone();
+            }
+            index_offset += 4 + key.len() as u64 + 8;
+            ss_table.entry_count += 1;
+            ss_table.max_key = key;
+        }
+
+        Ok(ss_table)
+    }
+
+    // Checks the footer's magic and length and returns the length of the index entries,
+    // their CRC and, on tables that record one, the CRC of the data file
+    // (index length, index CRC, data file CRC, whether entries carry CRCs, whether the
+    // table has an .expiry sidecar)
+    fn read_index_footer(index_reader: &mut BufReader<StorageReader>, index_path: &Path) -> Result<(u64, u32, Option<u32>, bool, bool), DbexError> {
+        let corruption = |reason: &str| DbexError::Corruption(format!("{}: {}", index_path.display(), reason));
+
+        let file_len = index_reader.seek(SeekFrom::End(0))?;
+        if file_len < INDEX_FOOTER_LEN {
+            return Err(corruption("index file too short for footer"));
+        }
+
+        let mut magic = [0u8; 4];
+        index_reader.seek(SeekFrom::Start(file_len - 4))?;
+        index_reader.read_exact(&mut magic)?;
+        let magic = u32::from_be_bytes(magic);
+        let footer_len = match magic {
+            INDEX_FOOTER_MAGIC => INDEX_FOOTER_LEN,
+            CHECKSUMMED_FOOTER_MAGIC | ENTRY_CHECKSUMMED_FOOTER_MAGIC | EXPIRING_FOOTER_MAGIC if file_len >= CHECKSUMMED_FOOTER_LEN => CHECKSUMMED_FOOTER_LEN,
+            CHECKSUMMED_FOOTER_MAGIC | ENTRY_CHECKSUMMED_FOOTER_MAGIC | EXPIRING_FOOTER_MAGIC => return Err(corruption("index file too short for footer")),
+            _ => return Err(corruption("missing index footer")),
+        };
+
+        let mut footer = vec![0u8; footer_len as usize];
+        index_reader.seek(SeekFrom::Start(file_len - footer_len))?;
+        index_reader.read_exact(&mut footer)?;
+        let index_len = u64::from_be_bytes(footer[0..8].try_into().unwrap());
+        let expected_crc = u32::from_be_bytes(footer[8..12].try_into().unwrap());
+        let data_crc = (footer_len == CHECKSUMMED_FOOTER_LEN).then(|| u32::from_be_bytes(footer[12..16].try_into().unwrap()));
+
+        if index_len != file_len - footer_len {
+            return Err(corruption("index length doesn't match footer"));
+        }
+        let entry_checksums = matches!(magic, ENTRY_CHECKSUMMED_FOOTER_MAGIC | EXPIRING_FOOTER_MAGIC);
+        Ok((index_len, expected_crc, data_crc, entry_checksums, magic == EXPIRING_FOOTER_MAGIC))
+    }
+
+    fn check_index_crc(index_reader: &mut BufReader<StorageReader>, index_path: &Path, index_len: u64, expected_crc: u32) -> Result<(), DbexError> {
+        index_reader.seek(SeekFrom::Start(0))?;
+        if crc_of(index_reader, index_len)? != expected_crc {
+            return Err(DbexError::Corruption(format!("{}: index checksum mismatch", index_path.display())));
+        }
+        Ok(())
+    }
 
+    // None if the sidecar is missing, damaged or was written for a different index
+    fn load_sparse_index(storage: &dyn Storage, sparse_index_path: &Path, index_len: u64, index_crc: u32) -> Option<IndexSummary> {
+        let bytes = storage.read(sparse_index_path).ok()?;
+        let (body, crc_bytes) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+        if crc32fast::hash(body) != u32::from_be_bytes(crc_bytes.try_into().ok()?) {
+            return None;
+        }
+
+        let mut pos = 0usize;
+        let mut take = |len: usize| -> Option<&[u8]> {
+            let bytes = body.get(pos..pos.checked_add(len)?)?;
+            pos += len;
+            Some(bytes)
+        };
+        if u32::from_be_bytes(take(4)?.try_into().ok()?) != SPARSE_INDEX_MAGIC {
+            return None;
+        }
+        let recorded_len = u64::from_be_bytes(take(8)?.try_into().ok()?);
+        let recorded_crc = u32::from_be_bytes(take(4)?.try_into().ok()?);
+        if (recorded_len, recorded_crc) != (index_len, index_crc) {
+            return None;
+        }
+        let entry_count = u64::from_be_bytes(take(8)?.try_into().ok()?);
+        let mut keys = Vec::new();
+        for _ in 0..2 {
+            let key_len = u32::from_be_bytes(take(4)?.try_into().ok()?) as usize;
+            keys.push(take(key_len)?.to_vec());
+        }
+        let max_key = keys.pop()?;
+        let min_key = keys.pop()?;
+
+        let point_count = u32::from_be_bytes(take(4)?.try_into().ok()?);
         let mut sparse_index = Vec::new();
-        let mut sparse_offset = 0u64;
+        for _ in 0..point_count {
+            let key_len = u32::from_be_bytes(take(4)?.try_into().ok()?) as usize;
+            let key = take(key_len)?.to_vec();
+            let offset = u64::from_be_bytes(take(8)?.try_into().ok()?);
+            sparse_index.push((key, offset));
+        }
+        Some(IndexSummary { entry_count, min_key, max_key, sparse_index })
+    }
+
+    fn write_sparse_index(&self, index_crc: u32) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&SPARSE_INDEX_MAGIC.to_be_bytes());
+        bytes.extend_from_slice(&self.index_len.to_be_bytes());
+        bytes.extend_from_slice(&index_crc.to_be_bytes());
+        bytes.extend_from_slice(&self.entry_count.to_be_bytes());
+        for key in [&self.min_key, &self.max_key] {
+            bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(key);
+        }
+        bytes.extend_from_slice(&(self.sparse_index.len() as u32).to_be_bytes());
+        for (key, offset) in &self.sparse_index {
+            bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&offset.to_be_bytes());
+        }
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_be_bytes());
+        self.storage.write(&self.sparse_index_path, &bytes)
+    }
+
+    pub fn load_from_memtable(&mut self, memtable: &MemTable, sync_policy: SyncPolicy) -> Result<(), DbexError> {
+        let mut offset = 0u64;
+        let mut index_vec = Vec::new();
 
-        for (i, (key, value)) in memtable.data().iter().enumerate() {
+        for (key, value, lsn, expires_at) in memtable.iter_with_metadata() {
             // Save index entry (key → current offset)
             index_vec.push((key.clone(), offset));
-            offset += self.write_entry(value);
+            if let Some(lsn) = lsn {
+                self.record_lsn(offset, lsn);
+            }
+            // Entries that expired already were written as tombstones
+            if let (Some(expires_at), Some(_)) = (expires_at, value) {
+                self.record_expiry(offset, expires_at);
+            }
+            offset += self.write_entry(value)?;
+        }
+
+        self.write_index(&index_vec)?;
+        self.sync(sync_policy)
+    }
+
+    // Flush buffered writes and persist both files according to the sync policy
+    pub fn sync(&mut self, sync_policy: SyncPolicy) -> Result<(), DbexError> {
+        let data_writer = self.data_writer.as_mut().expect("SSTable is read-only");
+        data_writer.flush()?;
+        let index_writer = self.index_writer.as_mut().expect("SSTable is read-only");
+        index_writer.flush()?;
 
-            if i % 100 == 0 {
-                sparse_index.push((key.clone(), sparse_offset));
+        if sync_policy == SyncPolicy::None {
+            return Ok(());
+        }
+        data_writer.get_ref().file().sync(sync_policy)?;
+        index_writer.get_ref().file().sync(sync_policy)?;
+        for sidecar_path in [&self.filter_path, &self.codec_path, &self.lsn_path, &self.transform_path, &self.sparse_index_path, &self.versions_path, &self.expiry_path] {
+            if let Ok(sidecar_file) = self.storage.open(sidecar_path) {
+                sidecar_file.sync(sync_policy)?;
             }
+        }
+        Ok(())
+    }
 
-            let key_len = key.len() as u32;
-            let entry_size = 4 + key_len as u64 + 8;
-            sparse_offset += entry_size;
+    // Unlike a missing or damaged filter, a damaged codec leaves the values unreadable
+    fn load_codec(storage: &dyn Storage, codec_path: &Path) -> Result<Option<ValueCodec>, DbexError> {
+        let bytes = match storage.read(codec_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        ValueCodec::decode(&bytes)
+            .map(Some)
+            .ok_or_else(|| DbexError::Corruption(format!("{}: malformed codec", codec_path.display())))
+    }
+
+    // [transform id: u8]; like the codec, without it the values can't be read
+    fn load_transform_id(storage: &dyn Storage, transform_path: &Path) -> Result<Option<u8>, DbexError> {
+        let bytes = match storage.read(transform_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        match bytes.as_slice() {
+            [transform_id] => Ok(Some(*transform_id)),
+            _ => Err(DbexError::Corruption(format!("{}: malformed transform marker", transform_path.display()))),
+        }
+    }
+
+    // On a table being written, transforms every value written from here on and must be
+    // set before the first write_entry. An existing table uses it to read back values
+    // written with a transform of the same id.
+    pub fn set_value_transform(&mut self, transform: Arc<dyn ValueTransform>) {
+        if self.data_writer.is_some() {
+            self.transform_id = Some(transform.id());
+        }
+        self.transform = Some(transform);
+    }
+
+    // Fails with DbexError::Corruption if the table's values went through a transform
+    // that set_value_transform hasn't provided
+    pub fn check_value_transform(&self) -> Result<(), DbexError> {
+        self.value_transform().map(|_| ())
+    }
+
+    fn value_transform(&self) -> Result<Option<Arc<dyn ValueTransform>>, DbexError> {
+        match (self.transform_id, &self.transform) {
+            (None, _) => Ok(None),
+            (Some(id), Some(transform)) if transform.id() == id => Ok(Some(transform.clone())),
+            (Some(id), _) => Err(DbexError::Corruption(format!(
+                "{}: values were written with value transform {}, which isn't configured", self.data_path.display(), id
+            ))),
         }
+    }
+
+    // Spaces the sparse index points of a table being written; must be set before
+    // write_index. Denser points mean shorter index scans per lookup for more memory.
+    pub fn set_sparse_index_interval(&mut self, interval: usize) {
+        self.sparse_index_interval = interval.max(1);
+    }
+
+    // Compresses every value written from here on. Must be set before the first write_entry.
+    pub fn set_codec(&mut self, codec: Option<ValueCodec>) {
+        self.codec = codec;
+    }
 
-        let (min_key, max_key) = self.write_index(&index_vec);
-        self.min_key = min_key;
-        self.max_key = max_key;
-        
-        self.sparse_index = sparse_index;
+    // Dictionary the table's values are compressed against, if any
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.codec.as_ref().and_then(ValueCodec::dictionary)
+    }
 
-        self.data_writer.flush().unwrap();
-        self.index_writer.flush().unwrap();
+    // Uses this hasher for the filters written from here on. An existing table whose
+    // filters were skipped at open because they were built with a custom hasher gets
+    // another chance to load them with this one.
+    pub fn set_bloom_hasher(&mut self, bloom_hasher: Arc<dyn BloomHasher>) {
+        self.bloom_hasher = bloom_hasher;
+        if self.data_writer.is_none() && self.bloom_filter.is_none() {
+            self.load_filters();
+        }
+    }
 
-        self.data_writer.get_ref().sync_all().unwrap();
-        self.index_writer.get_ref().sync_data().unwrap();
+    // Sizes the filters written from here on for this false positive rate
+    pub fn set_false_positive_rate(&mut self, false_positive_rate: f64) {
+        self.false_positive_rate = Some(false_positive_rate);
+    }
+
+    // Filters built with a hasher that can't be resolved are left out, like a missing file
+    fn load_filters(&mut self) {
+        let Ok(bytes) = self.storage.read(&self.filter_path) else {
+            return;
+        };
+        let Some((&kind, rest)) = bytes.split_first() else {
+            return;
+        };
+        let (hasher_id, rest) = match kind & FILTER_HASHER_RECORDED {
+            0 => (Fnv1aHasher.id(), rest),
+            _ => match rest.split_first() {
+                Some((&hasher_id, rest)) => (hasher_id, rest),
+                None => return,
+            },
+        };
+        let Some(hasher) = resolve_hasher(hasher_id, Some(&self.bloom_hasher)) else {
+            return;
+        };
+        let Some((bloom_filter, used)) = rest.get(4..).and_then(|filters| BloomFilter::decode(filters, hasher.clone())) else {
+            return;
+        };
+        self.bloom_filter = Some(bloom_filter);
+
+        if kind & !FILTER_HASHER_RECORDED == FILTER_WHOLE_KEY_AND_PREFIX {
+            let prefix_len = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+            if let Some((prefix_bloom_filter, _)) = BloomFilter::decode(&rest[4 + used..], hasher) {
+                self.prefix_bloom_len = Some(prefix_len);
+                self.prefix_bloom_filter = Some(prefix_bloom_filter);
+            }
+        }
+    }
+
+    // [kind: u8][hasher id: u8][prefix_len: u32][whole-key filter][prefix filter, if kind says so]
+    fn write_filters(&self) -> io::Result<()> {
+        let Some(ref bloom_filter) = self.bloom_filter else {
+            return Ok(());
+        };
+
+        let mut bytes = Vec::new();
+        match (self.prefix_bloom_len, &self.prefix_bloom_filter) {
+            (Some(prefix_len), Some(prefix_bloom_filter)) => {
+                bytes.push(FILTER_WHOLE_KEY_AND_PREFIX | FILTER_HASHER_RECORDED);
+                bytes.push(bloom_filter.hasher().id());
+                bytes.extend_from_slice(&(prefix_len as u32).to_be_bytes());
+                bloom_filter.encode(&mut bytes);
+                prefix_bloom_filter.encode(&mut bytes);
+            }
+            _ => {
+                bytes.push(FILTER_WHOLE_KEY | FILTER_HASHER_RECORDED);
+                bytes.push(bloom_filter.hasher().id());
+                bytes.extend_from_slice(&0u32.to_be_bytes());
+                bloom_filter.encode(&mut bytes);
+            }
+        }
+
+        self.storage.write(&self.filter_path, &bytes)
+    }
+
+    // False only if the table definitely doesn't hold `key`
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match self.bloom_filter {
+            Some(ref bloom_filter) => bloom_filter.may_contain(key),
+            None => true,
+        }
+    }
+
+    // False only if no key in the table starts with `prefix`. Prefixes shorter than
+    // the table's prefix Bloom length can't be checked and always return true.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        match (self.prefix_bloom_len, &self.prefix_bloom_filter) {
+            (Some(prefix_len), Some(prefix_bloom_filter)) if prefix.len() >= prefix_len => {
+                prefix_bloom_filter.may_contain(&prefix[..prefix_len])
+            }
+            _ => true,
+        }
+    }
+
+    pub fn prefix_bloom_len(&self) -> Option<usize> {
+        self.prefix_bloom_len
     }
 
     pub fn data_path (&self) -> &PathBuf {
@@ -94,8 +879,81 @@ impl SSTable {
         &self.index_path
     }
 
-    pub fn index_reader_mut(&mut self) -> &mut BufReader<File> {
-        &mut self.index_reader
+    pub fn created_lsn(&self) -> u64 {
+        self.created_lsn
+    }
+
+    pub fn set_created_lsn(&mut self, lsn: u64) {
+        self.created_lsn = lsn;
+    }
+
+    // Every file of the table that exists, starting with the data and index files
+    pub fn files(&self) -> Vec<PathBuf> {
+        TABLE_FILE_SUFFIXES.iter()
+            .map(|suffix| with_suffix(&self.data_path, suffix))
+            .filter(|path| self.storage.exists(path))
+            .collect()
+    }
+
+    // fsyncs the data, index and filter files through fresh handles, so it also works
+    // on tables opened for reading
+    pub fn sync_to_disk(&self) -> Result<(), DbexError> {
+        for path in [&self.data_path, &self.index_path, &self.filter_path] {
+            self.storage.open(path)?.sync(SyncPolicy::SyncAll)?;
+        }
+        if self.codec.is_some() {
+            self.storage.open(&self.codec_path)?.sync(SyncPolicy::SyncAll)?;
+        }
+        for sidecar_path in [&self.lsn_path, &self.transform_path, &self.sparse_index_path, &self.versions_path, &self.expiry_path] {
+            if self.storage.exists(sidecar_path) {
+                self.storage.open(sidecar_path)?.sync(SyncPolicy::SyncAll)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_files(self) {
+        for path in [&self.data_path, &self.index_path, &self.filter_path, &self.codec_path, &self.lsn_path, &self.transform_path, &self.sparse_index_path, &self.versions_path, &self.expiry_path] {
+            self.storage.remove(path).ok();
+        }
+    }
+
+    // Positions the index reader `offset` bytes into the index entries
+    pub fn seek_index(&mut self, offset: u64) -> Result<(), DbexError> {
+        self.index_reader.seek(SeekFrom::Start(offset))?;
+        self.index_pos = offset;
+        self.index_seeks += 1;
+        Ok(())
+    }
+
+    // Bytes held in memory by the sparse index
+    pub fn sparse_index_bytes(&self) -> u64 {
+        self.sparse_index.iter().map(|(key, _)| key.len() as u64 + 8).sum()
+    }
+
+    // Up to `count` keys spread evenly over the table, taken from the sparse index
+    pub fn sample_keys(&self, count: usize) -> Vec<Vec<u8>> {
+        let step = self.sparse_index.len().div_ceil(count.max(1)).max(1);
+        self.sparse_index.iter().step_by(step).map(|(key, _)| key.clone()).collect()
+    }
+
+    // Bytes held in memory by the index cache and loaded LSNs and expiries
+    pub fn cache_bytes(&self) -> u64 {
+        let lsn_bytes = [&self.lsns, &self.expiries].into_iter().flatten().map(|pairs| pairs.len() as u64 * 16).sum::<u64>();
+        let version_bytes: u64 = self.versions.iter().flatten()
+            .map(|(key, _, value)| key.len() as u64 + 8 + value.as_ref().map_or(0, |value| value.len() as u64))
+            .sum();
+        self.index_cache.size_bytes() + lsn_bytes + version_bytes
+    }
+
+    pub fn index_seeks(&self) -> u64 {
+        self.index_seeks
+    }
+
+    // Whether `key` falls within [min_key, max_key]. The bounds are real keys, never
+    // placeholders, so an empty min_key just means the table holds the empty key.
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.entry_count > 0 && self.min_key.as_slice() <= key && key <= self.max_key.as_slice()
     }
 
     pub fn min_key (&self) -> &Vec<u8> {
@@ -106,121 +964,914 @@ impl SSTable {
         &self.max_key
     }
 
-    pub fn get(&mut self, key: &Vec<u8>) -> Option<Vec<u8>> {
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count
+    }
+
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    // Combined size of the data and index files in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+
+    // Like get_entry, remembering up to `index_cache_len` found keys so looking them up
+    // again skips the index
+    pub fn get(&mut self, key: &[u8], index_cache_len: usize) -> Result<Option<Option<Vec<u8>>>, DbexError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        if let Some(offset) = self.index_cache.get(key) {
+            return self.try_read_value_at_offset(offset).map(Some);
+        }
+
+        let Some(offset) = self.find_in_index(key)? else {
+            return Ok(None);
+        };
+        self.index_cache.insert(key, offset, index_cache_len);
+        self.try_read_value_at_offset(offset).map(Some)
+    }
+
+    // Checks the data and index files against the CRCs in the index footer, reading them
+    // front to back without decoding anything: far cheaper than verify, but it can only
+    // say whether something changed, not what. None for tables from before data
+    // checksums, which only verify can check.
+    pub fn checksums_match(&self) -> Result<Option<bool>, DbexError> {
+        let Some(data_crc) = self.data_crc else {
+            return Ok(None);
+        };
+        let mut data_reader = BufReader::new(StorageReader::new(self.storage.open(&self.data_path)?));
+        if crc_of(&mut data_reader, self.data_len)? != data_crc {
+            return Ok(Some(false));
+        }
+
+        let mut index_reader = BufReader::new(StorageReader::new(self.storage.open(&self.index_path)?));
+        let (index_len, index_crc, _, _, _) = Self::read_index_footer(&mut index_reader, &self.index_path)?;
+        index_reader.seek(SeekFrom::Start(0))?;
+        Ok(Some(index_len == self.index_len && crc_of(&mut index_reader, index_len)? == index_crc))
+    }
+
+    // Reads the whole table and checks that its keys are strictly increasing and match
+    // the recorded key range, that its entries lie back to back in the data file and
+    // decode, and that its Bloom filter admits every key
+    pub fn verify(&mut self) -> Result<(), DbexError> {
+        let data_path = self.data_path.clone();
+        let corruption = |reason: String| DbexError::Corruption(format!("{}: {}", data_path.display(), reason));
+
+        self.seek_index(0)?;
+        let mut first_key = None;
+        let mut previous_key: Option<Vec<u8>> = None;
+        let mut expected_offset = 0u64;
+        let mut entry_count = 0u64;
+        while let Some((key, offset)) = self.try_next_key_in_index_file()? {
+            if previous_key.as_ref().is_some_and(|previous_key| *previous_key >= key) {
+                return Err(corruption(format!("key {:?} is out of order", key)));
+            }
+            if offset != expected_offset {
+                return Err(corruption(format!("entry for key {:?} is at offset {}, expected {}", key, offset, expected_offset)));
+            }
+            self.read_value_checked(offset, true)?;
+            expected_offset = self.data_reader.stream_position()?;
+            if !self.may_contain(&key) {
+                return Err(corruption(format!("Bloom filter rejects key {:?}", key)));
+            }
+
+            first_key.get_or_insert_with(|| key.clone());
+            previous_key = Some(key);
+            entry_count += 1;
+        }
+
+        if self.index_pos != self.index_len {
+            return Err(corruption(format!("index entry at offset {} is truncated", self.index_pos)));
+        }
+        if expected_offset != self.data_len {
+            return Err(corruption(format!("{} bytes of the data file aren't referenced by the index", self.data_len - expected_offset)));
+        }
+        if entry_count != self.entry_count || first_key.as_ref() != Some(&self.min_key) || previous_key.as_ref() != Some(&self.max_key) {
+            return Err(corruption("key range doesn't match the index".to_string()));
+        }
+        Ok(())
+    }
+
+    // Data offset of `key`'s entry, if the table has one
+    pub fn offset_of(&mut self, key: &[u8]) -> Result<Option<u64>, DbexError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        self.find_in_index(key)
+    }
+
+    // Notes that the entry being written at `offset` was logged with `lsn`. Offsets have
+    // to be recorded in increasing order, before write_index.
+    pub fn record_lsn(&mut self, offset: u64, lsn: u64) {
+        self.lsns.get_or_insert_with(Vec::new).push((offset, lsn));
+    }
+
+    // LSN of the entry at `offset`, or None if it was written without one (bulk loads,
+    // tables from before LSNs were recorded)
+    pub fn lsn_at(&mut self, offset: u64) -> Option<u64> {
+        let lsns = self.lsns();
+        let idx = lsns.binary_search_by_key(&offset, |(entry_offset, _)| *entry_offset).ok()?;
+        Some(lsns[idx].1)
+    }
+
+    // Highest LSN recorded for any entry of this table
+    pub fn max_lsn(&mut self) -> Option<u64> {
+        self.lsns().iter().map(|(_, lsn)| *lsn).max()
+    }
+
+    // Notes that the entry being written at `offset` expires at `expires_at`, in
+    // milliseconds since the Unix epoch. Like record_lsn, offsets have to be recorded in
+    // increasing order, before write_index.
+    pub fn record_expiry(&mut self, offset: u64, expires_at: u64) {
+        self.expiries.get_or_insert_with(Vec::new).push((offset, expires_at));
+    }
+
+    // When the entry at `offset` expires, or None if it was written without a TTL
+    pub fn expiry_at(&mut self, offset: u64) -> Result<Option<u64>, DbexError> {
+        let expiries = self.expiries()?;
+        let idx = expiries.binary_search_by_key(&offset, |(entry_offset, _)| *entry_offset).ok();
+        Ok(idx.map(|idx| expiries[idx].1))
+    }
+
+    fn is_expired_at(&mut self, offset: u64) -> Result<bool, DbexError> {
+        Ok(self.expiry_at(offset)?.is_some_and(|expires_at| expires_at <= unix_millis()))
+    }
+
+    // Keeps an older version of `key`, which must also get an entry of its own in this
+    // table. Versions have to be recorded in key order, newest first within a key, before
+    // write_index.
+    pub fn record_older_version(&mut self, key: Vec<u8>, lsn: Option<u64>, value: Option<Vec<u8>>) {
+        self.versions.get_or_insert_with(Vec::new).push((key, lsn, value));
+    }
+
+    // (LSN, value) of the versions of `key` this table kept behind its own entry, newest
+    // first, with None for tombstones
+    pub fn older_versions(&mut self, key: &[u8]) -> Result<Vec<KeyVersion>, DbexError> {
+        if self.versions.is_none() {
+            self.versions = Some(self.load_versions()?);
+        }
+        let versions = self.versions.as_deref().unwrap_or_default();
+        let start = versions.partition_point(|(version_key, _, _)| version_key.as_slice() < key);
+        Ok(versions[start..].iter()
+            .take_while(|(version_key, _, _)| version_key.as_slice() == key)
+            .map(|(_, lsn, value)| (*lsn, value.clone()))
+            .collect())
+    }
+
+    fn load_versions(&self) -> Result<Vec<OlderVersion>, DbexError> {
+        let bytes = match self.storage.read(&self.versions_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let corruption = || DbexError::Corruption(format!("{}: damaged versions file", self.versions_path.display()));
+        let body_len = bytes.len().checked_sub(4).ok_or_else(corruption)?;
+        let (body, crc_bytes) = bytes.split_at(body_len);
+        if crc32fast::hash(body) != u32::from_be_bytes(crc_bytes.try_into().unwrap()) {
+            return Err(corruption());
+        }
+
+        let transform = self.value_transform()?;
+        let mut rest = body;
+        let mut take = |len: usize| -> Result<&[u8], DbexError> {
+            let (taken, tail) = rest.split_at_checked(len).ok_or_else(corruption)?;
+            rest = tail;
+            Ok(taken)
+        };
+        let version_count = u32::from_be_bytes(take(4)?.try_into().unwrap());
+        let mut versions = Vec::new();
+        for _ in 0..version_count {
+            let key_len = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+            let key = take(key_len)?.to_vec();
+            let lsn = Some(u64::from_be_bytes(take(8)?.try_into().unwrap())).filter(|lsn| *lsn != NO_LSN);
+            let value = match u32::from_be_bytes(take(4)?.try_into().unwrap()) {
+                VERSION_TOMBSTONE => None,
+                value_len => Some(take(value_len as usize)?.to_vec()),
+            };
+            let value = decode_value(&mut None, transform.as_deref(), &self.versions_path, value)?;
+            versions.push((key, lsn, value));
+        }
+        Ok(versions)
+    }
+
+    fn write_versions(&self) -> io::Result<()> {
+        let Some(versions) = self.versions.as_ref().filter(|versions| !versions.is_empty()) else {
+            return Ok(());
+        };
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(versions.len() as u32).to_be_bytes());
+        for (key, lsn, value) in versions {
+            bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&lsn.unwrap_or(NO_LSN).to_be_bytes());
+            let stored = match (&self.transform, value) {
+                (Some(transform), Some(value)) => Some(transform.on_write(value)),
+                (_, value) => value.clone(),
+            };
+            match stored {
+                Some(stored) => {
+                    bytes.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+                    bytes.extend_from_slice(&stored);
+                }
+                None => bytes.extend_from_slice(&VERSION_TOMBSTONE.to_be_bytes()),
+            }
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        self.storage.write(&self.versions_path, &bytes)
+    }
+
+    fn expiries(&mut self) -> Result<&[(u64, u64)], DbexError> {
+        if self.expiries.is_none() {
+            self.expiries = Some(self.load_expiries()?);
+        }
+        Ok(self.expiries.as_deref().unwrap_or_default())
+    }
+
+    // Laid out like the .lsn sidecar. Losing it would bring expired entries back, so a
+    // missing or damaged one is corruption unless the table was written without TTLs.
+    fn load_expiries(&self) -> Result<Vec<(u64, u64)>, DbexError> {
+        let corruption = |reason: &str| DbexError::Corruption(format!("{}: {}", self.expiry_path.display(), reason));
+        let bytes = match self.storage.read(&self.expiry_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && !self.has_expiries => return Ok(Vec::new()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(corruption("missing expiry file")),
+            Err(err) => return Err(err.into()),
+        };
+        if bytes.is_empty() || !bytes.len().is_multiple_of(16) {
+            return Err(corruption("damaged expiry file"));
+        }
+        Ok(bytes.chunks_exact(16)
+            .map(|pair| (u64::from_be_bytes(pair[..8].try_into().unwrap()), u64::from_be_bytes(pair[8..].try_into().unwrap())))
+            .collect())
+    }
+
+    fn lsns(&mut self) -> &[(u64, u64)] {
+        self.lsns.get_or_insert_with(|| {
+            // [data offset: u64][lsn: u64] per entry; a missing or damaged sidecar only
+            // loses the LSNs
+            let bytes = self.storage.read(&self.lsn_path).unwrap_or_default();
+            bytes.chunks_exact(16)
+                .map(|pair| (u64::from_be_bytes(pair[..8].try_into().unwrap()), u64::from_be_bytes(pair[8..].try_into().unwrap())))
+                .collect()
+        })
+    }
+
+    // Data offset of `key`, scanning the index from the nearest sparse index point
+    fn find_in_index(&mut self, key: &[u8]) -> Result<Option<u64>, DbexError> {
+        self.seek_index(self.index_offset_for(key))?;
+        while let Some((stored_key, offset)) = self.try_next_key_in_index_file()? {
+            if stored_key == key {
+                return Ok(Some(offset));
+            }
+            if stored_key.as_slice() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // Like get_entry, but reads the value through a memory mapping of the data file so an
+    // uncompressed value is returned without being copied
+    pub fn get_mapped(&mut self, key: &[u8], index_cache_len: usize) -> Result<Option<Option<ValueRef>>, DbexError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        let offset = match self.index_cache.get(key) {
+            Some(offset) => offset,
+            None => {
+                let Some(offset) = self.find_in_index(key)? else {
+                    return Ok(None);
+                };
+                self.index_cache.insert(key, offset, index_cache_len);
+                offset
+            }
+        };
+
+        if self.is_expired_at(offset)? {
+            return Ok(Some(None));
+        }
+
+        let data_map = self.data_map()?;
+        let bytes: &[u8] = &data_map;
+        let corruption = |reason: &str| DbexError::Corruption(format!("{}: {} at offset {}", self.data_path.display(), reason, offset));
+
+        let value_start = usize::try_from(offset).ok()
+            .and_then(|offset| offset.checked_add(4))
+            .filter(|value_start| *value_start <= bytes.len())
+            .ok_or_else(|| corruption("entry runs past the end of the data file"))?;
+        let value_len = u32::from_be_bytes(bytes[value_start - 4..value_start].try_into().unwrap());
+        if value_len == 0xFFFFFFFF {
+            return Ok(Some(None));  // This key was deleted
+        }
+        let crc_len = if self.entry_checksums { 4 } else { 0 };
+        let range = value_start..value_start.checked_add(value_len as usize)
+            .filter(|value_end| value_end + crc_len <= bytes.len())
+            .ok_or_else(|| corruption("value runs past the end of the data file"))?;
+        if self.entry_checksums && self.verify_checksums {
+            let stored_crc = u32::from_be_bytes(bytes[range.end..range.end + 4].try_into().unwrap());
+            if entry_crc(&value_len.to_be_bytes(), &bytes[range.clone()]) != stored_crc {
+                return Err(corruption("checksum mismatch for the entry"));
+            }
+        }
+
+        let transform = self.value_transform()?;
+        let value = match (&mut self.codec, transform) {
+            (None, None) => ValueRef::Mapped(MappedValue { data_map, range }),
+            (codec, transform) => ValueRef::Owned(decode_stored(codec, transform.as_deref(), &self.data_path, &bytes[range])?),
+        };
+        Ok(Some(Some(value)))
+    }
+
+    fn data_map(&mut self) -> Result<MappedBytes, DbexError> {
+        if let Some(data_map) = &self.data_map {
+            return Ok(data_map.clone());
+        }
+        if let Some(data_writer) = self.data_writer.as_mut() {
+            data_writer.flush()?;
+        }
+
+        // Table files are never modified once written (see MappedValue), and the mapping is
+        // limited to the entries written so far
+        let data_map = self.storage.open(&self.data_path)?.map(self.data_len)?;
+        self.data_map = Some(data_map.clone());
+        Ok(data_map)
+    }
+
+    // get_entry for each of `keys`, which must be sorted, in a single forward pass over the
+    // index. The reader only seeks when the next key's sparse index point lies beyond
+    // where it already is.
+    pub fn get_entries(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Option<Vec<u8>>>>, DbexError> {
+        let mut entries = vec![None; keys.len()];
+        // An index entry read past the previous key, which may still match a later one
+        let mut pending: Option<(Vec<u8>, u64)> = None;
+        let mut positioned = false;
+
+        for (key, entry) in keys.iter().zip(entries.iter_mut()) {
+            if !self.may_contain(key) {
+                continue;
+            }
+            let sparse_offset = self.index_offset_for(key);
+            if !positioned || sparse_offset > self.index_pos {
+                self.seek_index(sparse_offset)?;
+                pending = None;
+                positioned = true;
+            }
+
+            loop {
+                let next = match pending.take() {
+                    Some(next) => Some(next),
+                    None => self.try_next_key_in_index_file()?,
+                };
+                let Some((stored_key, offset)) = next else {
+                    break;
+                };
+                match stored_key.as_slice().cmp(key) {
+                    std::cmp::Ordering::Less => continue,
+                    std::cmp::Ordering::Equal => *entry = Some(self.try_read_value_at_offset(offset)?),
+                    std::cmp::Ordering::Greater => pending = Some((stored_key, offset)),
+                }
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    // Like get, but a tombstone comes back as Some(None) rather than None
+    pub fn get_entry(&mut self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>, DbexError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+
+        match self.find_in_index(key)? {
+            Some(offset) => self.try_read_value_at_offset(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Returns every entry with `start <= key < end` (no upper bound if `end` is None),
+    // tombstones included
+    pub fn scan_range(&mut self, start: &[u8], end: Option<&[u8]>, read_ahead: ReadAhead) -> Result<Entries, DbexError> {
+        let entries = self.index_entries_in_range(start, end)?;
+        self.read_values(entries, read_ahead)
+    }
+
+    // Every entry, tombstones included, read lazily in key order. Only one index entry
+    // and one value are held at a time, however large the table. Ends after the first
+    // error.
+    pub fn entries(&mut self) -> impl Iterator<Item = Result<(Vec<u8>, Option<Vec<u8>>), DbexError>> + '_ {
+        let mut positioned = Some(self.seek_index(0));
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let entry = positioned.take().unwrap_or(Ok(())).and_then(|_| {
+                let Some((key, offset)) = self.try_next_key_in_index_file()? else {
+                    return Ok(None);
+                };
+                Ok(Some((key, self.try_read_value_at_offset(offset)?)))
+            });
+            failed = entry.is_err();
+            entry.transpose()
+        })
+    }
+
+    // Reads the values for `entries`, which are in key order and therefore in data file order
+    fn read_values(&mut self, entries: Vec<(Vec<u8>, u64)>, read_ahead: ReadAhead) -> Result<Entries, DbexError> {
+        let buffer_len = match read_ahead {
+            ReadAhead::Off => None,
+            ReadAhead::Bytes(buffer_len) => Some(buffer_len),
+            ReadAhead::Auto => (entries.len() >= SEQUENTIAL_SCAN_MIN_ENTRIES).then_some(SEQUENTIAL_SCAN_BUFFER_LEN),
+        };
+        let sequential_reader = match (buffer_len, entries.first()) {
+            (Some(buffer_len), Some((_, first_offset))) => self.open_sequential_reader(buffer_len, *first_offset).ok(),
+            _ => None,
+        };
+        let Some(mut reader) = sequential_reader else {
+            return entries.into_iter()
+                .map(|(key, offset)| Ok((key, self.try_read_value_at_offset(offset)?)))
+                .collect();
+        };
+
+        // Values that fail their checksum or can't be decoded are errors, as is any value
+        // without its transform
+        let transform = self.value_transform()?;
+        let checksum = self.entry_checksums.then_some(self.verify_checksums);
+        let mut reader_pos = entries[0].1;
+        let mut values = Vec::with_capacity(entries.len());
+        for (key, offset) in entries {
+            if self.is_expired_at(offset)? {
+                values.push((key, None));
+                continue;
+            }
+            // seek_relative keeps the buffer when the target is already in it
+            reader.seek_relative(offset as i64 - reader_pos as i64)?;
+            let stored = read_entry(&mut reader, offset, self.data_len, &self.data_path, checksum)?;
+            reader_pos = offset + self.entry_len(stored.as_ref().map(Vec::len));
+            let value = decode_value(&mut self.codec, transform.as_deref(), &self.data_path, stored)?;
+            values.push((key, value));
+        }
+        Ok(values)
+    }
+
+    // A dedicated reader for scans, so the point lookup reader keeps its small buffer
+    fn open_sequential_reader(&self, buffer_len: usize, start: u64) -> std::io::Result<BufReader<StorageReader>> {
+        let file = self.storage.open(&self.data_path)?;
+        file.advise_sequential();
+        let mut reader = StorageReader::new(file);
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(BufReader::with_capacity(buffer_len, reader))
+    }
+
+    // Like scan_range, but only reports whether each key is live rather than reading its value
+    pub fn scan_range_keys(&mut self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<(Vec<u8>, bool)>, DbexError> {
+        self.index_entries_in_range(start, end)?
+            .into_iter()
+            .map(|(key, offset)| Ok((key, !self.is_tombstone_at(offset)?)))
+            .collect()
+    }
+
+    fn index_entries_in_range(&mut self, start: &[u8], end: Option<&[u8]>) -> Result<Vec<(Vec<u8>, u64)>, DbexError> {
+        let mut entries = Vec::new();
+
+        let start_offset = self.index_offset_for(start);
+        self.seek_index(start_offset)?;
+        while let Some((stored_key, offset)) = self.try_next_key_in_index_file()? {
+            if end.is_some_and(|end| stored_key.as_slice() >= end) {
+                break;
+            }
+            if stored_key.as_slice() >= start {
+                entries.push((stored_key, offset));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Index file offset to start scanning from when looking for `key`
+    fn index_offset_for(&self, key: &[u8]) -> u64 {
         // Binary search the sparse index (O(log n) instead of O(n))
         let search_result = self.sparse_index.binary_search_by(|(k, _)| {
             k.as_slice().cmp(key)
         });
 
-        let start_offset = match search_result {
+        match search_result {
             Ok(idx) => {
                 // Exact match in sparse index
-                let start = self.sparse_index[idx].1;
-                start
+                self.sparse_index[idx].1
             }
             Err(idx) => {
                 // Key would be inserted at idx
                 // So it's between sparse_index[idx-1] and sparse_index[idx]
-                let start = if idx == 0 {
+                if idx == 0 {
                     0
                 } else {
                     self.sparse_index[idx - 1].1
-                };
-                start
+                }
             }
-        };
-
-        self.get_from_index_file(key, start_offset)
+        }
     }
 
-    pub fn get_from_index_file(&mut self, key: &[u8], offset: u64) -> Option<Vec<u8>> {
-        self.index_reader.seek(SeekFrom::Start(offset)).unwrap();
-        loop {
-            let maybe_next_key = self.get_next_key_in_index_file();
-
-            if maybe_next_key.is_none() {
-                break;
-            }
-
-            let (stored_key, offset) = maybe_next_key.unwrap();
-
-            if &stored_key == key {
-                // Read offset (8 bytes)
-                return self.read_value_at_offset(offset);
-            }
-            if stored_key.as_slice() > key {
-                return None;
-            }
+    // The next index entry, or None at the end of the entries. An entry cut short before
+    // then is reported as corruption rather than taken for the end.
+    pub fn try_next_key_in_index_file(&mut self) -> Result<Option<(Vec<u8>, u64)>, DbexError> {
+        // Stop at the footer
+        if self.index_pos >= self.index_len {
+            return Ok(None);
         }
-        None
-    }
+        let remaining = self.index_len - self.index_pos;
+        let index_path = &self.index_path;
+        let index_pos = self.index_pos;
+        let truncated = || DbexError::Corruption(format!("{}: index entry at offset {} is truncated", index_path.display(), index_pos));
+        let read_error = |err: std::io::Error| match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => truncated(),
+            _ => err.into(),
+        };
 
-    pub fn get_next_key_in_index_file(&mut self) -> Option<(Vec<u8>, u64)> {
         // Read key length (4 bytes)
         let mut key_len_bytes = [0u8; 4];
-        if self.index_reader.read_exact(&mut key_len_bytes).is_err() {
-            return None;
+        if remaining < 4 + 8 {
+            return Err(truncated());
+        }
+        self.index_reader.read_exact(&mut key_len_bytes).map_err(read_error)?;
+        let key_len = u32::from_be_bytes(key_len_bytes) as u64;
+        // Checked before allocating, so a garbled length can't ask for gigabytes
+        if 4 + key_len + 8 > remaining {
+            return Err(truncated());
         }
-        let key_len = u32::from_be_bytes(key_len_bytes) as usize;
 
         // Read key
-        let mut stored_key = vec![0u8; key_len];
-        self.index_reader.read_exact(&mut stored_key).ok()?;
+        let mut stored_key = vec![0u8; key_len as usize];
+        self.index_reader.read_exact(&mut stored_key).map_err(read_error)?;
 
         let mut offset_bytes = [0u8; 8];
-        self.index_reader.read_exact(&mut offset_bytes).ok()?;
+        self.index_reader.read_exact(&mut offset_bytes).map_err(read_error)?;
         let offset = u64::from_be_bytes(offset_bytes);
 
-        Some((stored_key, offset))
+        self.index_pos += 4 + key_len + 8;
+        Ok(Some((stored_key, offset)))
     }
 
-    pub fn read_value_at_offset(&mut self, offset: u64) -> Option<Vec<u8>> {
+    // The value at `offset`, None for a tombstone. An offset or value length that runs
+    // past the end of the data file is reported as corruption.
+    pub fn try_read_value_at_offset(&mut self, offset: u64) -> Result<Option<Vec<u8>>, DbexError> {
+        self.read_value_checked(offset, self.verify_checksums)
+    }
 
-        self.data_reader.seek(SeekFrom::Start(offset)).unwrap();
+    // try_read_value_at_offset, checking the entry's CRC (if it has one) only when
+    // `verify_checksum` is set
+    fn read_value_checked(&mut self, offset: u64, verify_checksum: bool) -> Result<Option<Vec<u8>>, DbexError> {
+        if offset > self.data_len {
+            return Err(DbexError::Corruption(format!(
+                "{}: entry offset {} is past the end of the data file", self.data_path.display(), offset
+            )));
+        }
 
-        // Read value length
-        let mut len_bytes = [0u8; 4];
-        self.data_reader.read_exact(&mut len_bytes).unwrap();
-        let value_len = u32::from_be_bytes(len_bytes) as usize;
+        if self.is_expired_at(offset)? {
+            return Ok(None);
+        }
 
-        if value_len == 0xFFFFFFFF {
-            return None;  // This key was deleted
+        self.data_reader.seek(SeekFrom::Start(offset))?;
+        let checksum = self.entry_checksums.then_some(verify_checksum);
+        let stored = read_entry(&mut self.data_reader, offset, self.data_len, &self.data_path, checksum)?;
+        let transform = self.value_transform()?;
+        decode_value(&mut self.codec, transform.as_deref(), &self.data_path, stored)
+    }
+
+    // Reads only the length prefix of the entry at `offset`. Expired entries count as
+    // tombstones.
+    pub fn is_tombstone_at(&mut self, offset: u64) -> Result<bool, DbexError> {
+        if self.is_expired_at(offset)? {
+            return Ok(true);
         }
+        self.data_reader.seek(SeekFrom::Start(offset))?;
 
-        // Read value
-        let mut value = vec![0u8; value_len];
-        self.data_reader.read_exact(&mut value).ok()?;
+        let mut len_bytes = [0u8; 4];
+        self.data_reader.read_exact(&mut len_bytes)?;
+        Ok(u32::from_be_bytes(len_bytes) == 0xFFFFFFFF)
+    }
 
-        Some(value)
+    // Bytes an entry takes in the data file, given its stored value's length (None for a
+    // tombstone)
+    fn entry_len(&self, stored_len: Option<usize>) -> u64 {
+        match stored_len {
+            Some(stored_len) if self.entry_checksums => 4 + stored_len as u64 + 4,
+            Some(stored_len) => 4 + stored_len as u64,
+            None => 4,
+        }
     }
 
-    pub fn write_entry(&mut self, value: &Option<Vec<u8>>) -> u64 {
+    // Whether reads check each value's CRC; verify always does
+    pub fn set_verify_checksums(&mut self, verify_checksums: bool) {
+        self.verify_checksums = verify_checksums;
+    }
+
+    pub fn write_entry(&mut self, value: &Option<Vec<u8>>) -> Result<u64, DbexError> {
+        let compressed = match (&mut self.codec, value) {
+            (Some(codec), Some(value)) => Some(codec.compress(value)?),
+            _ => None,
+        };
+        let value = match compressed {
+            Some(_) => &compressed,
+            None => value,
+        };
+        let transformed = match (&self.transform, value) {
+            (Some(transform), Some(value)) => Some(transform.on_write(value)),
+            _ => None,
+        };
+        let value = match transformed {
+            Some(_) => &transformed,
+            None => value,
+        };
+        let data_writer = self.data_writer.as_mut().expect("SSTable is read-only");
 
-        if let Some(value) = value {
-            let value_len = value.len() as u32;
+        let entry_size = if let Some(value) = value {
+            // Compression and transforms can grow a value, and a stored length of
+            // u32::MAX would read back as the tombstone marker
+            let value_len = u32::try_from(value.len()).ok()
+                .filter(|value_len| *value_len != 0xFFFFFFFF)
+                .ok_or(DbexError::ValueTooLarge { len: value.len(), max: 0xFFFFFFFE })?;
 
-            // [value_length][value]
-            self.data_writer.write_all(&value_len.to_be_bytes()).unwrap();
-            self.data_writer.write_all(&value).unwrap();
+            // [value_length][value][crc]
+            let len_bytes = value_len.to_be_bytes();
+            let crc_bytes = entry_crc(&len_bytes, value).to_be_bytes();
+            let crc_bytes = if self.entry_checksums { &crc_bytes[..] } else { &[] };
+            data_writer.write_all(&len_bytes)?;
+            data_writer.write_all(value)?;
+            data_writer.write_all(crc_bytes)?;
+            if let Some(data_hasher) = self.data_hasher.as_mut() {
+                data_hasher.update(&len_bytes);
+                data_hasher.update(value);
+                data_hasher.update(crc_bytes);
+            }
 
-            4 + value.len() as u64
+            self.entry_len(Some(value.len()))
         } else {
             let tombstone_marker = 0xFFFFFFFF_u32;
-            self.data_writer.write_all(&tombstone_marker.to_be_bytes()).unwrap();
+            data_writer.write_all(&tombstone_marker.to_be_bytes())?;
+            if let Some(data_hasher) = self.data_hasher.as_mut() {
+                data_hasher.update(&tombstone_marker.to_be_bytes());
+            }
             4
+        };
+
+        self.size_bytes += entry_size;
+        self.data_len += entry_size;
+        Ok(entry_size)
+    }
+
+    // Writes a value of `len` bytes read from `reader` without holding all of it in memory,
+    // and returns the entry's size like write_entry. The bytes are stored as they are, so
+    // this is only for tables with no codec or value transform, which need whole values.
+    pub fn write_entry_from<R: Read + ?Sized>(&mut self, reader: &mut R, len: u32) -> Result<u64, DbexError> {
+        debug_assert!(self.codec.is_none() && self.transform.is_none());
+        let data_writer = self.data_writer.as_mut().expect("SSTable is read-only");
+
+        // [value_length][value][crc]
+        data_writer.write_all(&len.to_be_bytes())?;
+        if let Some(data_hasher) = self.data_hasher.as_mut() {
+            data_hasher.update(&len.to_be_bytes());
+        }
+        let mut entry_hasher = crc32fast::Hasher::new();
+        entry_hasher.update(&len.to_be_bytes());
+        let mut chunk = vec![0u8; STREAM_CHUNK_LEN.min(len as usize)];
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let chunk = &mut chunk[..remaining.min(STREAM_CHUNK_LEN)];
+            reader.read_exact(chunk)?;
+            data_writer.write_all(chunk)?;
+            if let Some(data_hasher) = self.data_hasher.as_mut() {
+                data_hasher.update(chunk);
+            }
+            entry_hasher.update(chunk);
+            remaining -= chunk.len();
+        }
+        if self.entry_checksums {
+            let crc_bytes = entry_hasher.finalize().to_be_bytes();
+            data_writer.write_all(&crc_bytes)?;
+            if let Some(data_hasher) = self.data_hasher.as_mut() {
+                data_hasher.update(&crc_bytes);
+            }
         }
+
+        let entry_size = self.entry_len(Some(len as usize));
+        self.size_bytes += entry_size;
+        self.data_len += entry_size;
+        Ok(entry_size)
     }
 
-    pub fn write_index(&mut self, index: &Vec<(Vec<u8>, u64)>)
-        -> (Vec<u8>, Vec<u8>) {
-        let min_key = index.first().unwrap().0.clone();
-        let max_key = index.last().unwrap().0.clone();
-        for (key, offset) in index.into_iter() {
+    pub fn write_index(&mut self, index: &[(Vec<u8>, u64)])
+        -> Result<(Vec<u8>, Vec<u8>), DbexError> {
+        let index_writer = self.index_writer.as_mut().expect("SSTable is read-only");
+        // An empty table gets an empty range, which covers() never matches
+        let min_key = index.first().map(|(key, _)| key.clone()).unwrap_or_default();
+        let max_key = index.last().map(|(key, _)| key.clone()).unwrap_or_default();
+
+        let new_filter = || match self.false_positive_rate {
+            Some(rate) => BloomFilter::with_false_positive_rate(index.len(), rate, self.bloom_hasher.clone()),
+            None => BloomFilter::new(index.len(), self.bloom_hasher.clone()),
+        };
+        let mut bloom_filter = new_filter();
+        let mut prefix_bloom_filter = self.prefix_bloom_len.map(|_| new_filter());
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut index_offset = 0u64;
+        for (i, (key, offset)) in index.iter().enumerate() {
+            // Cache every sparse_index_interval-th key in memory, pointing at its index file offset
+            if i.is_multiple_of(self.sparse_index_interval) {
+                self.sparse_index.push((key.clone(), index_offset));
+            }
+
+            bloom_filter.insert(key);
+            if let (Some(prefix_len), Some(ref mut prefix_bloom_filter)) = (self.prefix_bloom_len, &mut prefix_bloom_filter) {
+                // Keys shorter than the prefix can only match shorter prefixes, which skip the filter
+                if key.len() >= prefix_len {
+                    prefix_bloom_filter.insert(&key[..prefix_len]);
+                }
+            }
+
             let key_len = key.len() as u32;
-            self.index_writer.write_all(&key_len.to_be_bytes()).unwrap();  // 4 bytes
-            self.index_writer.write_all(&key).unwrap();
-            self.index_writer.write_all(&offset.to_be_bytes()).unwrap();  // 8 bytes
+            index_writer.write_all(&key_len.to_be_bytes())?;  // 4 bytes
+            index_writer.write_all(key)?;
+            index_writer.write_all(&offset.to_be_bytes())?;  // 8 bytes
+            hasher.update(&key_len.to_be_bytes());
+            hasher.update(key);
+            hasher.update(&offset.to_be_bytes());
+            index_offset += 4 + key.len() as u64 + 8;
+        }
+
+        // Footer lets open() reject a truncated or partially written index without parsing
+        // it, and checksums_match check the whole table without decoding it
+        let index_crc = hasher.finalize();
+        let data_crc = self.data_hasher.take().map_or(0, crc32fast::Hasher::finalize);
+        index_writer.write_all(&index_offset.to_be_bytes())?;
+        index_writer.write_all(&index_crc.to_be_bytes())?;
+        index_writer.write_all(&data_crc.to_be_bytes())?;
+        let has_expiries = self.expiries.as_ref().is_some_and(|expiries| !expiries.is_empty());
+        let magic = match (self.entry_checksums, has_expiries) {
+            (true, true) => EXPIRING_FOOTER_MAGIC,
+            (true, false) => ENTRY_CHECKSUMMED_FOOTER_MAGIC,
+            (false, _) => CHECKSUMMED_FOOTER_MAGIC,
+        };
+        index_writer.write_all(&magic.to_be_bytes())?;
+
+        self.index_len = index_offset;
+        self.data_crc = Some(data_crc);
+        self.has_expiries = has_expiries;
+        self.size_bytes += index_offset + CHECKSUMMED_FOOTER_LEN;
+        self.entry_count = index.len() as u64;
+        self.bloom_filter = Some(bloom_filter);
+        self.prefix_bloom_filter = prefix_bloom_filter;
+        self.write_filters()?;
+        if let Some(codec) = &self.codec {
+            self.storage.write(&self.codec_path, &codec.encode())?;
+        }
+        if let Some(transform_id) = self.transform_id {
+            self.storage.write(&self.transform_path, &[transform_id])?;
+        }
+        if let Some(lsns) = self.lsns.as_ref().filter(|lsns| !lsns.is_empty()) {
+            let bytes: Vec<u8> = lsns.iter()
+                .flat_map(|(offset, lsn)| offset.to_be_bytes().into_iter().chain(lsn.to_be_bytes()))
+                .collect();
+            self.storage.write(&self.lsn_path, &bytes)?;
+        }
+        if let Some(expiries) = self.expiries.as_ref().filter(|expiries| !expiries.is_empty()) {
+            let bytes: Vec<u8> = expiries.iter()
+                .flat_map(|(offset, expires_at)| offset.to_be_bytes().into_iter().chain(expires_at.to_be_bytes()))
+                .collect();
+            self.storage.write(&self.expiry_path, &bytes)?;
+        }
+        self.min_key = min_key.clone();
+        self.max_key = max_key.clone();
+        self.write_sparse_index(index_crc)?;
+        self.write_versions()?;
+        Ok((min_key, max_key))
+    }
+}
+
+// CRC of the next `len` bytes of `reader`
+fn crc_of(reader: &mut impl Read, len: u64) -> io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut remaining = len;
+    let mut buf = [0u8; 8192];
+    while remaining > 0 {
+        let chunk = remaining.min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..chunk])?;
+        hasher.update(&buf[..chunk]);
+        remaining -= chunk as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+// Reads the entry at `offset` from a reader already positioned there, checking it
+// against `data_len` before allocating. `checksum` is None for tables whose entries have
+// no CRC, and otherwise says whether to check it; it's read past either way.
+fn read_entry(reader: &mut impl Read, offset: u64, data_len: u64, data_path: &Path, checksum: Option<bool>) -> Result<Option<Vec<u8>>, DbexError> {
+    let corruption = |reason: String| DbexError::Corruption(format!("{}: {}", data_path.display(), reason));
+
+    let value_start = offset.checked_add(4)
+        .filter(|value_start| *value_start <= data_len)
+        .ok_or_else(|| corruption(format!("entry offset {} is past the end of the data file", offset)))?;
+
+    // Read value length
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let value_len = u32::from_be_bytes(len_bytes);
+
+    if value_len == 0xFFFFFFFF {
+        return Ok(None);  // This key was deleted
+    }
+
+    let crc_len = if checksum.is_some() { 4 } else { 0 };
+    let fits = value_start.checked_add(value_len as u64 + crc_len)
+        .is_some_and(|entry_end| entry_end <= data_len);
+    if !fits {
+        return Err(corruption(format!("value length {} at offset {} runs past the end of the data file", value_len, offset)));
+    }
+
+    // Read value
+    let mut value = vec![0u8; value_len as usize];
+    reader.read_exact(&mut value)?;
+
+    if let Some(verify) = checksum {
+        let mut crc_bytes = [0u8; 4];
+        reader.read_exact(&mut crc_bytes)?;
+        if verify && entry_crc(&len_bytes, &value) != u32::from_be_bytes(crc_bytes) {
+            return Err(corruption(format!("checksum mismatch for the entry at offset {}", offset)));
+        }
+    }
+
+    Ok(Some(value))
+}
+
+// CRC stored after each value of a table with entry checksums
+fn entry_crc(len_bytes: &[u8; 4], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len_bytes);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+// Undoes the table's value transform and compression, if it has them, on a value read
+// by read_entry
+fn decode_value(codec: &mut Option<ValueCodec>, transform: Option<&dyn ValueTransform>, data_path: &Path, stored: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, DbexError> {
+    match stored {
+        Some(stored) if codec.is_some() || transform.is_some() => decode_stored(codec, transform, data_path, &stored).map(Some),
+        stored => Ok(stored),
+    }
+}
+
+fn decode_stored(codec: &mut Option<ValueCodec>, transform: Option<&dyn ValueTransform>, data_path: &Path, stored: &[u8]) -> Result<Vec<u8>, DbexError> {
+    let corruption = |err: std::io::Error| DbexError::Corruption(format!("{}: {}", data_path.display(), err));
+    let transformed;
+    let stored = match transform {
+        Some(transform) => {
+            transformed = transform.on_read(stored).map_err(corruption)?;
+            &transformed[..]
         }
-        (min_key, max_key)
+        None => stored,
+    };
+    match codec {
+        Some(codec) => codec.decompress(stored).map_err(corruption),
+        None => Ok(stored.to_vec()),
     }
-}
\ No newline at end of file
+}
+
+// The number in a table's `ss_table_<number>.db` file name. Tables named by older versions
+// carry their creation time in nanoseconds there, which still orders them among themselves.
+pub fn table_number(data_path: &Path) -> Option<u64> {
+    data_path.file_name()?
+        .to_str()?
+        .strip_prefix("ss_table_")?
+        .strip_suffix(".db")?
+        .parse()
+        .ok()
+}
+
+// The data path of the table that a file like `ss_table_<number>.db.index` belongs to, or
+// None if it isn't a table file
+pub fn owning_data_path(path: &Path) -> Option<PathBuf> {
+    let path_str = path.to_str()?;
+    let data_path = TABLE_FILE_SUFFIXES.iter()
+        .find_map(|suffix| path_str.strip_suffix(suffix).filter(|_| !suffix.is_empty()))
+        .map_or_else(|| path.to_path_buf(), PathBuf::from);
+    table_number(&data_path).map(|_| data_path)
+}
+
+fn timestamp_number() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+// `ss_table_1.db` + `.index` -> `ss_table_1.db.index`
+fn with_suffix(data_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = data_path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
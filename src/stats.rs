@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Figures for a single compaction, or the running total across all of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+    pub input_tables: u64,
+    // Size of the input tables' data and index files
+    pub bytes_read: u64,
+    // Size of the output table's data and index files
+    pub bytes_written: u64,
+    // Older copies of a key shadowed by a newer one
+    pub duplicates_dropped: u64,
+    pub tombstones_dropped: u64,
+    pub duration: Duration,
+    // Part of `duration` spent paused to stay under DBexOptions::compaction_bytes_per_sec
+    pub throttled: Duration,
+}
+
+impl CompactionStats {
+    fn accumulate(&mut self, other: &CompactionStats) {
+        self.input_tables += other.input_tables;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.duplicates_dropped += other.duplicates_dropped;
+        self.tombstones_dropped += other.tombstones_dropped;
+        self.duration += other.duration;
+        self.throttled += other.throttled;
+    }
+
+    // Bytes written per byte read; below 1.0 when the merge dropped dead data
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_read == 0 {
+            0.0
+        } else {
+            self.bytes_written as f64 / self.bytes_read as f64
+        }
+    }
+}
+
+// How many recent flush durations FlushStats keeps for its percentiles
+const FLUSH_WINDOW: usize = 1000;
+
+// Timing of memtable flushes, each measured from starting the new table until its files
+// are written
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlushStats {
+    pub flushes: u64,
+    pub last_duration: Option<Duration>,
+    pub total_duration: Duration,
+    // The most recent FLUSH_WINDOW durations, oldest first
+    recent: VecDeque<Duration>,
+}
+
+impl FlushStats {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        self.flushes += 1;
+        self.last_duration = Some(duration);
+        self.total_duration += duration;
+        if self.recent.len() == FLUSH_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration);
+    }
+
+    // Duration that `percentile` percent of the recent flushes finished within (nearest
+    // rank), or None before the first flush
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.recent.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.recent.iter().copied().collect();
+        sorted.sort();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.saturating_sub(1)])
+    }
+
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(99.0)
+    }
+}
+
+// What happened when the database was opened
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryStats {
+    // The previous writer called DBex::close, so the WAL wasn't replayed
+    pub clean_shutdown: bool,
+    pub wal_entries_replayed: u64,
+    // The manifest was missing while SSTables were on disk, so the levels were rebuilt
+    // from the tables themselves
+    pub manifest_rebuilt: bool,
+}
+
+// What DBex::verify did to check the tables
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyStats {
+    pub tables_checked: u64,
+    // Tables whose checksums were missing or didn't match, and that were read in full
+    pub deep_scans: u64,
+}
+
+// What DBex::backup copied
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackupReport {
+    // Pass this as since_lsn to the next backup to make it incremental
+    pub checkpoint_lsn: u64,
+    // Data paths of the tables copied, in the source database
+    pub tables_copied: Vec<PathBuf>,
+    pub bytes_copied: u64,
+}
+
+// What DBex::repair found and fixed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RepairReport {
+    // The manifest was missing or unreadable, so the levels were rebuilt from the tables
+    pub manifest_rebuilt: bool,
+    pub wal_entries_replayed: u64,
+    // Tables moved into `<data_dir>/quarantine/`; their entries are lost unless the WAL
+    // still held them
+    pub quarantined: Vec<QuarantinedTable>,
+}
+
+// What DBex::maintain did; all zero when there was nothing to do
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaintenanceReport {
+    pub flushed: bool,
+    pub compactions: u64,
+    pub wal_truncated: bool,
+    // Tables on disk that no level refers to, e.g. left behind by an interrupted compaction
+    pub orphaned_tables_removed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedTable {
+    // Where the table's data file was before it was moved
+    pub data_path: PathBuf,
+    pub reason: String,
+    // Smallest and largest key the table held, if it could still be opened to tell
+    pub key_range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// Memory held by a database's in-memory structures, estimated from the bytes of keys,
+// values and offsets they store (allocator and container overhead aren't counted)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryStats {
+    // Keys and values in the active and frozen memtables
+    pub memtable_bytes: u64,
+    // Keys and offsets in every SSTable's sparse index
+    pub sparse_index_bytes: u64,
+    // Every SSTable's index cache and loaded LSNs
+    pub cache_bytes: u64,
+}
+
+impl MemoryStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.memtable_bytes + self.sparse_index_bytes + self.cache_bytes
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DBexStats {
+    pub compactions: u64,
+    pub total_compaction: CompactionStats,
+    pub last_compaction: Option<CompactionStats>,
+    pub recovery: RecoveryStats,
+    pub flush: FlushStats,
+    // Operations served since open or the last truncate
+    pub entries_written: u64,
+    pub entries_deleted: u64,
+    // Calls to find, split by whether they found a live value
+    pub reads_served: u64,
+    pub read_hits: u64,
+    pub read_misses: u64,
+}
+
+impl DBexStats {
+    pub(crate) fn reset_operation_counts(&mut self) {
+        self.entries_written = 0;
+        self.entries_deleted = 0;
+        self.reads_served = 0;
+        self.read_hits = 0;
+        self.read_misses = 0;
+    }
+
+    // Fraction of reads that found a value, 0.0 before the first read
+    pub fn read_hit_rate(&self) -> f64 {
+        if self.reads_served == 0 {
+            0.0
+        } else {
+            self.read_hits as f64 / self.reads_served as f64
+        }
+    }
+
+    pub(crate) fn record_compaction(&mut self, compaction: CompactionStats) {
+        self.compactions += 1;
+        self.total_compaction.accumulate(&compaction);
+        self.last_compaction = Some(compaction);
+    }
+}
@@ -0,0 +1,461 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use memmap2::MmapOptions;
+use crate::options::SyncPolicy;
+
+// The leading bytes of a file, shared by every value read in place from them
+#[derive(Clone)]
+pub struct MappedBytes(Arc<dyn AsRef<[u8]> + Send + Sync>);
+
+impl MappedBytes {
+    pub fn new(bytes: impl AsRef<[u8]> + Send + Sync + 'static) -> Self {
+        MappedBytes(Arc::new(bytes))
+    }
+}
+
+impl Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+impl Debug for MappedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedBytes").field("len", &self.len()).finish()
+    }
+}
+
+// Held for as long as a writer has a data directory open
+pub type StorageLock = Box<dyn Any + Send + Sync>;
+
+// A file opened through a Storage. Handles to the same path see each other's writes, and
+// a handle stays usable after its file is removed.
+pub trait StorageFile: Send + Sync {
+    // Reads into `buf` starting at `offset` and returns how many bytes were read, 0 at the end
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    // Appends `buf` to the end of the file
+    fn write(&self, buf: &[u8]) -> io::Result<()>;
+    // Persists the file according to `sync_policy`
+    fn sync(&self, sync_policy: SyncPolicy) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    // The first `len` bytes of the file, which must not change while mapped
+    fn map(&self, len: u64) -> io::Result<MappedBytes>;
+    // Hints that the file is about to be read front to back
+    fn advise_sequential(&self) {}
+}
+
+// Where a DBex keeps its files. Paths are the ones DBex builds under its data directory:
+// LocalStorage (the default) maps them onto the filesystem, MemoryStorage keeps them in
+// memory, and other backends can map them onto e.g. object storage keys.
+pub trait Storage: Debug + Send + Sync {
+    // Creates an empty file, truncating it if it already exists
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+    // Opens an existing file, failing with ErrorKind::NotFound if there is none
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+    // Opens a file to append to, creating it if it doesn't exist
+    fn open_or_create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    // Atomically replaces `to` with `from`
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    // Gives `from`'s contents a second path, sharing them if the backend can
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    // Paths of the files directly inside `dir`
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    // Removes `dir` and everything under it
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()>;
+    // Persists files created, renamed or removed in `dir`
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    // Takes the exclusive writer lock at `path`, or returns None if it is already held
+    fn try_lock(&self, path: &Path) -> io::Result<Option<StorageLock>>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        StorageReader::new(self.open(path)?).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.create(path)?.write(bytes)
+    }
+
+    // Copies `from` to `to` a chunk at a time and returns the number of bytes copied
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let source = self.open(from)?;
+        let target = self.create(to)?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut copied = 0;
+        loop {
+            let read = source.read_at(copied, &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            target.write(&buf[..read])?;
+            copied += read as u64;
+        }
+        target.sync(SyncPolicy::SyncData)?;
+        Ok(copied)
+    }
+}
+
+// Reads a StorageFile through std::io, for wrapping in a BufReader
+pub struct StorageReader {
+    file: Box<dyn StorageFile>,
+    pos: u64,
+}
+
+impl StorageReader {
+    pub fn new(file: Box<dyn StorageFile>) -> Self {
+        StorageReader { file, pos: 0 }
+    }
+
+    pub fn file(&self) -> &dyn StorageFile {
+        self.file.as_ref()
+    }
+}
+
+impl Debug for StorageReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageReader").field("pos", &self.pos).finish()
+    }
+}
+
+impl Read for StorageReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for StorageReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.file.len()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+// Appends to a StorageFile through std::io, for wrapping in a BufWriter
+pub struct StorageWriter {
+    file: Box<dyn StorageFile>,
+}
+
+impl StorageWriter {
+    pub fn new(file: Box<dyn StorageFile>) -> Self {
+        StorageWriter { file }
+    }
+
+    pub fn file(&self) -> &dyn StorageFile {
+        self.file.as_ref()
+    }
+}
+
+impl Debug for StorageWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageWriter").finish_non_exhaustive()
+    }
+}
+
+impl Write for StorageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Files and directories on the local filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalStorage;
+
+impl StorageFile for File {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<()> {
+        let mut file = self;
+        file.write_all(buf)
+    }
+
+    fn sync(&self, sync_policy: SyncPolicy) -> io::Result<()> {
+        match sync_policy {
+            SyncPolicy::SyncData => self.sync_data(),
+            SyncPolicy::SyncAll => self.sync_all(),
+            SyncPolicy::None => Ok(()),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn map(&self, len: u64) -> io::Result<MappedBytes> {
+        // Safety: callers only map files that are never modified again, and removing a
+        // file leaves an existing mapping intact
+        let map = unsafe { MmapOptions::new().len(len as usize).map(self)? };
+        Ok(MappedBytes::new(map))
+    }
+
+    // Tells the kernel to read ahead more aggressively
+    #[cfg(target_os = "linux")]
+    fn advise_sequential(&self) {
+        use std::os::fd::AsRawFd;
+
+        // Only a hint, so failure is ignored. The fd is valid for the duration of the call.
+        unsafe {
+            libc::posix_fadvise(self.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new().create(true).truncate(true).read(true).write(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_or_create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    // Hard links, falling back to a copy across filesystems
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if fs::hard_link(from, to).is_err() {
+            fs::copy(from, to)?;
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|dir_entry| dir_entry.map(|dir_entry| dir_entry.path()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::remove_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    // An advisory lock on the file, so it also excludes other processes
+    fn try_lock(&self, path: &Path) -> io::Result<Option<StorageLock>> {
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        match lock_file.try_lock() {
+            Ok(()) => Ok(Some(Box::new(lock_file))),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+type MemoryFileData = Arc<RwLock<Vec<u8>>>;
+
+// Keeps every file in memory, for tests and throwaway databases. Clones share the same
+// files, so a database can be reopened from a clone of the storage it was written to.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<PathBuf, MemoryFileData>>>,
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Total size of every file, for checking what a database would have written
+    pub fn size_bytes(&self) -> u64 {
+        let files = self.files.lock().unwrap();
+        files.values().map(|data| data.read().unwrap().len() as u64).sum()
+    }
+}
+
+impl Debug for MemoryStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStorage")
+            .field("files", &self.files.lock().unwrap().len())
+            .finish()
+    }
+}
+
+struct MemoryFile {
+    data: MemoryFileData,
+}
+
+impl StorageFile for MemoryFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = (offset as usize).min(data.len());
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<()> {
+        self.data.write().unwrap().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&self, _sync_policy: SyncPolicy) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    // A copy, made once per table; the file doesn't change afterwards
+    fn map(&self, len: u64) -> io::Result<MappedBytes> {
+        let data = self.data.read().unwrap();
+        let bytes = data.get(..len as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "mapping past the end of the file"))?;
+        Ok(MappedBytes::new(bytes.to_vec()))
+    }
+}
+
+// Unlocks when dropped
+struct MemoryLock {
+    locks: Arc<Mutex<HashSet<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.path);
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let data = MemoryFileData::default();
+        self.files.lock().unwrap().insert(path.to_path_buf(), data.clone());
+        Ok(Box::new(MemoryFile { data }))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let data = self.files.lock().unwrap().get(path).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))?;
+        Ok(Box::new(MemoryFile { data }))
+    }
+
+    fn open_or_create(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let data = self.files.lock().unwrap().entry(path.to_path_buf()).or_default().clone();
+        Ok(Box::new(MemoryFile { data }))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.files.lock().unwrap().remove(path)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.remove(from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", from.display())))?;
+        files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    // Copies, since tables are never modified after being written
+    fn link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let bytes = self.read(from)?;
+        self.write(to, &bytes)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let files = self.files.lock().unwrap();
+        Ok(files.keys().filter(|path| path.parent() == Some(dir)).cloned().collect())
+    }
+
+    // Directories exist implicitly
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.files.lock().unwrap().retain(|path, _| !path.starts_with(dir));
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<StorageLock>> {
+        if !self.locks.lock().unwrap().insert(path.to_path_buf()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemoryLock { locks: self.locks.clone(), path: path.to_path_buf() })))
+    }
+}
@@ -1,3 +1,5 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use rkyv::{Archive, Deserialize, Serialize};
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]
@@ -5,5 +7,65 @@ pub enum Operation {
     Insert,
     Delete,
     StartTxn,
-    CommitTxn
-}
\ No newline at end of file
+    CommitTxn,
+    // An insert with a TTL; the logged value is [expiry: u64][value] (see DBex::insert_with_ttl)
+    InsertWithExpiry,
+}
+// Paces a loop to an average of `bytes_per_sec`: whenever the bytes consumed so far run
+// ahead of the time elapsed, consume sleeps off the difference. None never sleeps.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+    slept: Duration,
+}
+
+// Shorter pauses are left to accumulate, since sleeps that short mostly measure the scheduler
+const MIN_PAUSE: Duration = Duration::from_millis(1);
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.map(|bytes_per_sec| bytes_per_sec.max(1)),
+            start: Instant::now(),
+            bytes: 0,
+            slept: Duration::ZERO,
+        }
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return;
+        };
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / bytes_per_sec as f64);
+        let pause = due.saturating_sub(self.start.elapsed());
+        if pause >= MIN_PAUSE {
+            thread::sleep(pause);
+            self.slept += pause;
+        }
+    }
+
+    // Total time consume has spent sleeping
+    pub fn slept(&self) -> Duration {
+        self.slept
+    }
+}
+
+// The first key after every key starting with `prefix`: the prefix with its last byte
+// below 0xFF incremented and the 0xFF bytes after it dropped. None when no such key
+// exists, i.e. the prefix is empty or all 0xFF, so a scan runs to the end.
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&byte| byte != 0xFF)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+// Milliseconds since the Unix epoch, the unit entry expiries are kept in
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -1,35 +1,53 @@
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use rkyv::{Archive, Deserialize, Serialize};
 use rkyv::rancor::{Error};
 use rkyv::util::AlignedVec;
+use crate::options::{SyncPolicy, WalRecordFormat};
+use crate::storage::{Storage, StorageReader, StorageWriter};
 use crate::utils::Operation;
 
 pub struct WriteAheadLog {
+    storage: Arc<dyn Storage>,
     cur_wal_path: PathBuf,
-    cur_wal_file_writer: BufWriter<File>,
-    prev_wal_files: Vec<PathBuf>
+    cur_wal_file_writer: BufWriter<StorageWriter>,
+    // Up to this many consecutive writes of one key share a single record (see write)
+    coalesce_window: Option<usize>,
+    // The latest of those writes and how many it stands for, not yet encoded
+    pending: Option<(WalEntry, usize)>,
+    record_format: WalRecordFormat,
 }
 
+// Every record starts with [len: u64], the length of the rest. Raw records set this bit
+// in it, which the length of an rkyv record never reaches, and continue with
+// [lsn: u64][operation: u8][key len: u32][key][value len: u32][value], with
+// ABSENT_LEN standing in for the length of a missing key or value.
+const RAW_RECORD_FLAG: u64 = 1 << 63;
+const RAW_HEADER_LEN: usize = 8 + 1;
+const ABSENT_LEN: u32 = u32::MAX;
+
 impl WriteAheadLog {
-    pub fn new() -> Self {
-        let cur_wal_path = PathBuf::from("db_data/wals/cur.wal");
+    pub fn new(storage: Arc<dyn Storage>, wal_dir: &Path, coalesce_window: Option<usize>, record_format: WalRecordFormat) -> io::Result<Self> {
+        let cur_wal_path = wal_dir.join("cur.wal");
 
-        let wal_file: File = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&cur_wal_path)
-            .unwrap();
+        let wal_file = storage.open_or_create(&cur_wal_path)?;
 
-        WriteAheadLog{
+        Ok(WriteAheadLog{
+            storage,
             cur_wal_path,
-            cur_wal_file_writer: BufWriter::new(wal_file),
-            prev_wal_files: Vec::new()
-        }
+            cur_wal_file_writer: BufWriter::new(StorageWriter::new(wal_file)),
+            coalesce_window,
+            pending: None,
+            record_format,
+        })
     }
 
-    pub fn write(&mut self, operation: Operation, lsn: u64, key: Option<Vec<u8>>, value: Option<Vec<u8>>) {
+    // With a coalesce window, a write is held back while the writes after it are to the
+    // same key, and only the latest of a run (up to the window's length) is logged. The
+    // held-back record is written out with the next write to another key, sync, archive
+    // or drop; every other record goes to the file as soon as it's written.
+    pub fn write(&mut self, operation: Operation, lsn: u64, key: Option<Vec<u8>>, value: Option<Vec<u8>>) -> io::Result<()> {
 
         let wal_entry = WalEntry::new(
             lsn,
@@ -38,22 +56,189 @@ impl WriteAheadLog {
             value
         );
 
-        let encoded_wal_entry: AlignedVec = rkyv::to_bytes::<Error>(&wal_entry).unwrap();
-        let data_len = encoded_wal_entry.len();
+        let Some(coalesce_window) = self.coalesce_window else {
+            return self.append(&wal_entry);
+        };
+        match self.pending.take() {
+            Some((pending, coalesced)) if pending.key == wal_entry.key && coalesced < coalesce_window => {
+                self.pending = Some((wal_entry, coalesced + 1));
+            }
+            Some(held) => {
+                // The held-back record goes out first; if it can't, it stays held back
+                // and the new write is rejected
+                self.pending = Some(held);
+                self.write_pending()?;
+                self.pending = Some((wal_entry, 1));
+            }
+            None => self.pending = Some((wal_entry, 1)),
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let Some((pending, coalesced)) = self.pending.take() else {
+            return Ok(());
+        };
+        if let Err(err) = self.append(&pending) {
+            self.pending = Some((pending, coalesced));
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Logs `writes` (a value for an insert, None for a remove) with LSNs counting up from
+    // `first_lsn`, framed by StartTxn and CommitTxn markers so recovery replays all of
+    // them or none. The records go through the buffer in one run and the file is
+    // fdatasynced once at the end.
+    pub fn write_batch(&mut self, first_lsn: u64, writes: &[(Vec<u8>, Option<Vec<u8>>)]) -> io::Result<()> {
+        self.write_pending()?;
+        let last_lsn = first_lsn + writes.len() as u64 - 1;
+        self.encode(&WalEntry::new(first_lsn, Operation::StartTxn, None, None))?;
+        for (lsn, (key, value)) in (first_lsn..).zip(writes) {
+            let operation = if value.is_some() { Operation::Insert } else { Operation::Delete };
+            self.encode(&WalEntry::new(lsn, operation, Some(key.clone()), value.clone()))?;
+        }
+        self.encode(&WalEntry::new(last_lsn, Operation::CommitTxn, None, None))?;
+        self.sync()
+    }
+
+    // Hands the record to the file right away, so it outlives the process even before
+    // the next sync; the buffer just keeps it to one write
+    fn append(&mut self, wal_entry: &WalEntry) -> io::Result<()> {
+        self.encode(wal_entry)?;
+        self.cur_wal_file_writer.flush()
+    }
+
+    fn encode(&mut self, wal_entry: &WalEntry) -> io::Result<()> {
+        match self.record_format {
+            WalRecordFormat::Raw => Self::append_raw(&mut self.cur_wal_file_writer, wal_entry)?,
+            WalRecordFormat::Rkyv => {
+                let encoded_wal_entry: AlignedVec = rkyv::to_bytes::<Error>(wal_entry).unwrap();
+                let data_len = encoded_wal_entry.len();
+
+                // [data_len][encoded_wal_entry]
+                self.cur_wal_file_writer.write_all(&data_len.to_be_bytes())?;
+                self.cur_wal_file_writer.write_all(encoded_wal_entry.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_raw(writer: &mut BufWriter<StorageWriter>, wal_entry: &WalEntry) -> io::Result<()> {
+        let fields = [&wal_entry.key, &wal_entry.value];
+        let data_len = RAW_HEADER_LEN + fields.iter()
+            .map(|field| 4 + field.as_ref().map_or(0, Vec::len))
+            .sum::<usize>();
+
+        writer.write_all(&(data_len as u64 | RAW_RECORD_FLAG).to_be_bytes())?;
+        writer.write_all(&wal_entry.lsn.to_be_bytes())?;
+        writer.write_all(&[operation_tag(&wal_entry.operation)])?;
+        for field in fields {
+            match field {
+                Some(bytes) => {
+                    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+                    writer.write_all(bytes)?;
+                }
+                None => writer.write_all(&ABSENT_LEN.to_be_bytes())?,
+            }
+        }
+        Ok(())
+    }
+
+    // Writes out a record held back for coalescing and fdatasyncs the file
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.cur_wal_file_writer.flush()?;
+        self.cur_wal_file_writer.get_ref().file().sync(SyncPolicy::SyncData)
+    }
+
+    // Whether there are no entries, written out or held back
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.pending.is_none() && self.cur_wal_file_writer.buffer().is_empty() && self.cur_wal_file_writer.get_ref().file().is_empty()?)
+    }
+
+    // Drops every entry, once they're all covered by flushed SSTables
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.pending = None;
+        self.cur_wal_file_writer.flush()?;
+        let wal_file = self.cur_wal_file_writer.get_ref().file();
+        wal_file.set_len(0)?;
+        wal_file.sync(SyncPolicy::SyncData)
+    }
+
+    // Moves every entry into a segment at `archive_path` and starts over with an empty
+    // log. Does nothing if there are no entries.
+    pub fn archive(&mut self, archive_path: &Path) -> io::Result<()> {
+        self.sync()?;
+        if self.cur_wal_file_writer.get_ref().file().is_empty()? {
+            return Ok(());
+        }
+
+        self.storage.rename(&self.cur_wal_path, archive_path)?;
+        let wal_file = self.storage.open_or_create(&self.cur_wal_path)?;
+        self.cur_wal_file_writer = BufWriter::new(StorageWriter::new(wal_file));
+        Ok(())
+    }
+
+    pub fn read(&mut self, start_offset: u64) -> io::Result<Vec<WalEntry>> {
+        Self::read_file(self.storage.as_ref(), &self.cur_wal_path, start_offset)
+    }
+
+    // Reads the entries for replay and cuts what replay skips out of the file: a torn
+    // tail left by a crash, and any batch that never got its commit marker. Records
+    // written from now on then follow the last one replayed; appended after the skipped
+    // bytes instead, they'd be dropped with them on the next replay.
+    pub fn recover(&mut self) -> io::Result<Vec<WalEntry>> {
+        self.write_pending()?;
+        self.cur_wal_file_writer.flush()?;
+        let records = Self::read_records(self.storage.as_ref(), &self.cur_wal_path, 0)?;
+        let ends: Vec<u64> = records.iter().map(|(_, end)| *end).collect();
+        let kept = Self::drop_unterminated_batches(records);
+
+        // Usually only the tail is skipped and truncating the file is enough. A torn
+        // batch with records after it (logged before torn batches were cut out) has the
+        // kept records rewritten to a new file that replaces the log.
+        let kept_len = kept.last().map_or(0, |(_, end)| *end);
+        if kept.is_empty() || ends[kept.len() - 1] == kept_len {
+            let wal_file = self.cur_wal_file_writer.get_ref().file();
+            if kept_len < wal_file.len()? {
+                wal_file.set_len(kept_len)?;
+                wal_file.sync(SyncPolicy::SyncData)?;
+            }
+        } else {
+            let tmp_path = self.cur_wal_path.with_extension("wal.tmp");
+            self.cur_wal_file_writer = BufWriter::new(StorageWriter::new(self.storage.create(&tmp_path)?));
+            for (wal_entry, _) in &kept {
+                self.encode(wal_entry)?;
+            }
+            self.sync()?;
+            self.storage.rename(&tmp_path, &self.cur_wal_path)?;
+            if let Some(wal_dir) = self.cur_wal_path.parent() {
+                self.storage.sync_dir(wal_dir)?;
+            }
+            let wal_file = self.storage.open_or_create(&self.cur_wal_path)?;
+            self.cur_wal_file_writer = BufWriter::new(StorageWriter::new(wal_file));
+        }
+        Ok(kept.into_iter().map(|(wal_entry, _)| wal_entry).collect())
+    }
 
-        // [data_len][encoded_wal_entry]
-        self.cur_wal_file_writer.write(&data_len.to_be_bytes()).unwrap();
-        self.cur_wal_file_writer.write(encoded_wal_entry.as_slice()).unwrap();
+    // Reads the entries of any WAL file, e.g. an archived segment
+    pub fn read_file(storage: &dyn Storage, wal_path: &Path, start_offset: u64) -> io::Result<Vec<WalEntry>> {
+        let records = Self::read_records(storage, wal_path, start_offset)?;
+        Ok(Self::drop_unterminated_batches(records).into_iter().map(|(wal_entry, _)| wal_entry).collect())
     }
 
-    pub fn read(&mut self, start_offset: u64) -> Vec<WalEntry> {
+    // Every record that decodes, up to the first that doesn't, with the offset just past it
+    fn read_records(storage: &dyn Storage, wal_path: &Path, start_offset: u64) -> io::Result<Vec<(WalEntry, u64)>> {
 
-        let mut wal_entries: Vec<WalEntry> = Vec::new();
+        let mut wal_entries: Vec<(WalEntry, u64)> = Vec::new();
 
-        let wal_file = File::open(&self.cur_wal_path).unwrap();
+        let wal_file = storage.open(wal_path)?;
+        let wal_len = wal_file.len()?;
 
-        let mut wal_reader = BufReader::new(wal_file);
-        wal_reader.seek(SeekFrom::Start(start_offset)).unwrap();
+        let mut wal_reader = BufReader::new(StorageReader::new(wal_file));
+        wal_reader.seek(SeekFrom::Start(start_offset))?;
+        let mut pos = start_offset;
 
 
         loop {
@@ -62,23 +247,127 @@ impl WriteAheadLog {
             if wal_reader.read_exact(&mut data_len_bytes).is_err() {
                 break;
             }
-            let data_len = u64::from_be_bytes(data_len_bytes) as usize;
+            let data_len = u64::from_be_bytes(data_len_bytes);
+            let is_raw = data_len & RAW_RECORD_FLAG != 0;
+            let data_len = data_len & !RAW_RECORD_FLAG;
+            // A length running past the end of the file is a torn or garbled record; checked
+            // before allocating, so it can't ask for gigabytes
+            if data_len > wal_len.saturating_sub(pos + 8) {
+                break;
+            }
+            pos += 8 + data_len;
 
             // Read wal_entry
-            let mut encoded_wal_entry_bytes = vec![0u8; data_len];
+            let mut encoded_wal_entry_bytes = vec![0u8; data_len as usize];
             if wal_reader.read_exact(&mut encoded_wal_entry_bytes).is_err() {
                 break;
             }
-            let archived = rkyv::access::<ArchivedWalEntry, Error>(&encoded_wal_entry_bytes).unwrap();
+            if is_raw {
+                // A record that doesn't parse can only be the torn end of the log
+                let Some(wal_entry) = decode_raw(&encoded_wal_entry_bytes) else {
+                    break;
+                };
+                wal_entries.push((wal_entry, pos));
+                continue;
+            }
+            let Ok(archived) = rkyv::access::<ArchivedWalEntry, Error>(&encoded_wal_entry_bytes) else {
+                break;
+            };
             let wal_entry: WalEntry = rkyv::deserialize::<WalEntry, Error>(archived).unwrap();
 
-            wal_entries.push(wal_entry);
+            wal_entries.push((wal_entry, pos));
+        }
+
+        Ok(wal_entries)
+    }
+
+    // A batch logged between StartTxn and CommitTxn markers counts only once its commit
+    // marker is in. One cut off by a crash is dropped whole: its records are the inserts
+    // and removes with LSNs counting up from its StartTxn's, so it ends at the first
+    // record that isn't one of them, whether that's a later batch, a plain write or the
+    // end of the log.
+    fn drop_unterminated_batches(records: Vec<(WalEntry, u64)>) -> Vec<(WalEntry, u64)> {
+        let mut kept = Vec::with_capacity(records.len());
+        // Where the open batch starts in `kept` and the LSN its next record would have
+        let mut open_batch = None;
+        for record in records {
+            let wal_entry = &record.0;
+            if let Some((start, next_lsn)) = open_batch {
+                match wal_entry.operation {
+                    Operation::Insert | Operation::Delete if wal_entry.lsn == next_lsn => {
+                        open_batch = Some((start, next_lsn + 1));
+                        kept.push(record);
+                        continue;
+                    }
+                    Operation::CommitTxn => {
+                        open_batch = None;
+                        kept.push(record);
+                        continue;
+                    }
+                    _ => {
+                        kept.truncate(start);
+                        open_batch = None;
+                    }
+                }
+            }
+            if wal_entry.operation == Operation::StartTxn {
+                open_batch = Some((kept.len(), wal_entry.lsn));
+            }
+            kept.push(record);
+        }
+        if let Some((start, _)) = open_batch {
+            kept.truncate(start);
+        }
+        kept
+    }
+}
+
+fn operation_tag(operation: &Operation) -> u8 {
+    match operation {
+        Operation::Insert => 0,
+        Operation::Delete => 1,
+        Operation::StartTxn => 2,
+        Operation::CommitTxn => 3,
+        Operation::InsertWithExpiry => 4,
+    }
+}
+
+fn decode_raw(bytes: &[u8]) -> Option<WalEntry> {
+    let (header, mut rest) = bytes.split_at_checked(RAW_HEADER_LEN)?;
+    let lsn = u64::from_be_bytes(header[..8].try_into().unwrap());
+    let operation = match header[8] {
+        0 => Operation::Insert,
+        1 => Operation::Delete,
+        2 => Operation::StartTxn,
+        3 => Operation::CommitTxn,
+        4 => Operation::InsertWithExpiry,
+        _ => return None,
+    };
+    let mut fields = [None, None];
+    for field in fields.iter_mut() {
+        let (len, tail) = rest.split_at_checked(4)?;
+        rest = tail;
+        let len = u32::from_be_bytes(len.try_into().unwrap());
+        if len != ABSENT_LEN {
+            let (bytes, tail) = rest.split_at_checked(len as usize)?;
+            rest = tail;
+            *field = Some(bytes.to_vec());
         }
+    }
+    let [key, value] = fields;
+    rest.is_empty().then(|| WalEntry::new(lsn, operation, key, value))
+}
 
-        wal_entries
+impl Drop for WriteAheadLog {
+    // Errors can't be reported from here; sync first to see them
+    fn drop(&mut self) {
+        let _ = self.write_pending();
     }
 }
 
+// A logged write's key, value (None for a remove) and expiry, if it had a TTL
+pub type LoggedWrite = (Vec<u8>, Option<Vec<u8>>, Option<u64>);
+
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq)]pub struct WalEntry {
     lsn: u64,
     operation: Operation,
@@ -95,4 +384,38 @@ impl WalEntry {
             value
         }
     }
+
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    pub fn operation(&self) -> &Operation {
+        &self.operation
+    }
+
+    pub fn into_key_value(self) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        (self.key, self.value)
+    }
+
+    // The key, value (None for a remove) and expiry of the write this entry logged, or
+    // None for transaction markers and entries missing their key
+    pub fn into_write(self) -> Option<LoggedWrite> {
+        match (self.operation, self.key, self.value) {
+            (Operation::Insert, Some(key), Some(value)) => Some((key, Some(value), None)),
+            (Operation::Delete, Some(key), _) => Some((key, None, None)),
+            (Operation::InsertWithExpiry, Some(key), Some(mut value)) if value.len() >= 8 => {
+                let value_bytes = value.split_off(8);
+                Some((key, Some(value_bytes), Some(u64::from_be_bytes(value.try_into().unwrap()))))
+            }
+            _ => None,
+        }
+    }
+}
+
+// The logged value of an Operation::InsertWithExpiry
+pub fn expiring_value(expires_at: u64, value: &[u8]) -> Vec<u8> {
+    let mut logged = Vec::with_capacity(8 + value.len());
+    logged.extend_from_slice(&expires_at.to_be_bytes());
+    logged.extend_from_slice(value);
+    logged
 }
\ No newline at end of file
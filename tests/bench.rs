@@ -3,6 +3,11 @@ mod test_db;
 use test_db::TestDb;
 
 use dbex::DBex;
+use dbex::memtable::MemTable;
+use dbex::options::{Compression, DBexOptions, ReadAhead, SyncPolicy, WalRecordFormat};
+use dbex::storage::LocalStorage;
+use dbex::utils::Operation;
+use dbex::write_ahead_log::WriteAheadLog;
 use std::time::{Duration, Instant, SystemTime};
 use std::fs;
 use std::path::PathBuf;
@@ -10,6 +15,29 @@ use std::sync::OnceLock;
 use rand::Rng;
 use sysinfo::System;
 use std::process;
+use std::alloc::{GlobalAlloc, Layout, System as SystemAlloc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// Counts heap allocations so benches can report allocations per operation
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { SystemAlloc.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { SystemAlloc.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
 
 // Cached bench directory - created once per test run, reused by all benchmarks
 static BENCH_DIR: OnceLock<PathBuf> = OnceLock::new();
@@ -121,9 +149,9 @@ fn bench_sequential_writes(db: &mut DBex, num_keys: usize, value_size: usize) ->
     for i in 0..num_keys {
         let key = i.to_be_bytes().to_vec();
         let value = value.clone();
-        db.insert(key, value);
+        db.insert(key, value).unwrap();
     }
-    db.flush();
+    db.flush().unwrap();
     let total_time = start.elapsed();
 
     let total_bytes = num_keys * (8 + value_size);  // 8 byte keys + value_size
@@ -146,7 +174,7 @@ fn bench_random_reads(db: &mut DBex, num_reads: usize, key_space: usize, value_s
     for _ in 0..num_reads {
         let idx = rng.random_range(0..key_space);
         let key = idx.to_be_bytes().to_vec();
-        let _ = db.find(&key);
+        let _ = db.find(&key).unwrap();
     }
     let total_time = start.elapsed();
 
@@ -167,7 +195,7 @@ fn bench_sequential_reads(db: &mut DBex, num_reads: usize, value_size: usize) ->
     let start = Instant::now();
     for i in 0..num_reads {
         let key = i.to_be_bytes().to_vec();
-        let _ = db.find(&key);
+        let _ = db.find(&key).unwrap();
     }
     let total_time = start.elapsed();
 
@@ -191,7 +219,7 @@ fn bench_zipfian_reads(db: &mut DBex, num_reads: usize, key_space: usize, value_
     for _ in 0..num_reads {
         let idx = zipfian_key(&mut rng, key_space);
         let key = idx.to_be_bytes().to_vec();
-        let _ = db.find(&key);
+        let _ = db.find(&key).unwrap();
     }
     let total_time = start.elapsed();
 
@@ -249,7 +277,7 @@ fn run_benchmark(name: &str, num_keys: usize, value_size: usize, num_reads: usiz
     output.push_str(&format_result(&seq_read_result));
     output.push_str(&format_result(&random_read_result));
     output.push_str(&format_result(&zipfian_result));
-    output.push_str("\n");
+    output.push('\n');
     output.push_str(l0_ss_tables);
     output.push_str(l1_ss_tables);
     output.push_str(l2_ss_tables);
@@ -259,7 +287,7 @@ fn run_benchmark(name: &str, num_keys: usize, value_size: usize, num_reads: usiz
     fs::write(&results_file, output).ok();
 
     // Cleanup database files
-    db.purge();
+    db.purge().unwrap();
 
     println!("Results saved to: {}", results_file.display());
 }
@@ -322,6 +350,645 @@ fn bench_huge() {
     run_benchmark("huge", 10_000_000, 1_000, 10_000);
 }
 
+// Flush latency for each SSTable fsync policy
+#[test]
+fn bench_flush_sync_policies() {
+    let bench_dir = get_bench_dir();
+    let num_flushes: usize = 50;
+    let keys_per_flush = 1_000;
+    let value = vec![0xABu8; 100];
+
+    let mut output = String::new();
+    for sync_policy in [SyncPolicy::None, SyncPolicy::SyncData, SyncPolicy::SyncAll] {
+        let mut test_db = TestDb::with_options(DBexOptions {
+            sync_policy,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+
+        let mut total_time = Duration::ZERO;
+        for flush_num in 0..num_flushes {
+            for i in 0..keys_per_flush {
+                let key = (flush_num * keys_per_flush + i).to_be_bytes().to_vec();
+                db.insert(key, value.clone()).unwrap();
+            }
+
+            let start = Instant::now();
+            db.flush().unwrap();
+            total_time += start.elapsed();
+        }
+
+        let result = BenchResult {
+            operation: format!("flush_{:?}", sync_policy),
+            count: num_flushes,
+            total_time,
+            ops_per_sec: num_flushes as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / num_flushes as f64,
+            throughput_mb_s: None,
+        };
+        result.print();
+        output.push_str(&format_result(&result));
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("flush_sync_policies.txt"), output).ok();
+}
+
+// Skewed reads with and without the per-table index entry cache
+#[test]
+fn bench_index_cache() {
+    let bench_dir = get_bench_dir();
+    let num_keys: usize = 20_000;
+    let num_reads: usize = 200_000;
+    let value_size = 100;
+
+    let mut output = String::new();
+    for index_cache_len in [0, 4096] {
+        let mut test_db = TestDb::with_options(DBexOptions {
+            index_cache_len,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+        bench_sequential_writes(db, num_keys, value_size);
+
+        let mut result = bench_zipfian_reads(db, num_reads, num_keys, value_size);
+        result.operation = format!("zipfian_read_index_cache_{}", index_cache_len);
+        result.print();
+        output.push_str(&format_result(&result));
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("index_cache.txt"), output).ok();
+}
+
+// Building and probing a 1000-entry memtable with each backend
+#[test]
+fn bench_memtable_backends() {
+    let bench_dir = get_bench_dir();
+    let num_entries: usize = 1_000;
+    let rounds = 200;
+    let mut rng = rand::rng();
+    let keys: Vec<Vec<u8>> = (0..num_entries)
+        .map(|_| rng.random::<u64>().to_be_bytes().to_vec())
+        .collect();
+
+    let mut output = String::new();
+    for (name, size_hint) in [("btree", None), ("sorted_vec", Some(num_entries))] {
+        let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..rounds {
+            let mut memtable = match size_hint {
+                Some(size_hint) => MemTable::with_size_hint(size_hint),
+                None => MemTable::new(),
+            };
+            for key in &keys {
+                memtable.insert(key.clone(), vec![0xABu8; 16]);
+            }
+            for key in &keys {
+                assert!(memtable.get(key).is_some());
+            }
+        }
+        let total_time = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+        let count = num_entries * rounds;
+        let result = BenchResult {
+            operation: format!("memtable_{}", name),
+            count,
+            total_time,
+            ops_per_sec: count as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / count as f64,
+            throughput_mb_s: None,
+        };
+        result.print();
+        println!("{:.2} allocs/insert", allocations as f64 / count as f64);
+        output.push_str(&format_result(&result));
+    }
+
+    fs::write(bench_dir.join("memtable_backends.txt"), output).ok();
+}
+
+// Full-range scan throughput with the point lookup reader vs a read-ahead scan reader
+#[test]
+fn bench_scan_read_ahead() {
+    let bench_dir = get_bench_dir();
+    let num_keys: usize = 200_000;
+    let value_size = 100;
+    let num_scans = 5;
+
+    let mut output = String::new();
+    for read_ahead in [ReadAhead::Off, ReadAhead::Auto, ReadAhead::Bytes(1024 * 1024)] {
+        let mut test_db = TestDb::with_options(DBexOptions {
+            read_ahead,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+
+        for i in 0..num_keys {
+            db.insert(i, vec![0xABu8; value_size]).unwrap();
+        }
+        db.flush().unwrap();
+
+        let start = Instant::now();
+        for _ in 0..num_scans {
+            let scanned = db.range(&0usize.to_be_bytes(), &num_keys.to_be_bytes()).unwrap().count();
+            assert_eq!(scanned, num_keys);
+        }
+        let total_time = start.elapsed();
+
+        let count = num_keys * num_scans;
+        let result = BenchResult {
+            operation: format!("scan_read_ahead_{:?}", read_ahead),
+            count,
+            total_time,
+            ops_per_sec: count as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / count as f64,
+            throughput_mb_s: Some((count * value_size) as f64 / (1024.0 * 1024.0) / total_time.as_secs_f64()),
+        };
+        result.print();
+        output.push_str(&format_result(&result));
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("scan_read_ahead.txt"), output).ok();
+}
+
+// Allocations per lookup when integer keys are converted to a Vec vs passed directly
+#[test]
+fn bench_integer_key_allocations() {
+    let bench_dir = get_bench_dir();
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    let num_keys: usize = 10_000;
+    for i in 0..num_keys {
+        db.insert(i, vec![0xABu8; 100]).unwrap();
+    }
+
+    let mut output = String::new();
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..num_keys {
+        let key = i.to_be_bytes().to_vec();
+        let _ = db.find(&key).unwrap();
+    }
+    let vec_time = start.elapsed();
+    let vec_allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..num_keys {
+        let _ = db.find(i).unwrap();
+    }
+    let int_time = start.elapsed();
+    let int_allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    for (operation, time, allocations) in [
+        ("find_vec_key", vec_time, vec_allocations),
+        ("find_integer_key", int_time, int_allocations),
+    ] {
+        let line = format!(
+            "{:<20} {:>10} ops in {:>10.2?} ({:>8.2} µs/op, {:>5.2} allocs/op)\n",
+            operation,
+            num_keys,
+            time,
+            time.as_micros() as f64 / num_keys as f64,
+            allocations as f64 / num_keys as f64,
+        );
+        print!("{}", line);
+        output.push_str(&line);
+    }
+
+    fs::write(bench_dir.join("integer_key_allocations.txt"), output).ok();
+    db.purge().unwrap();
+}
+
+// Allocations and time per SSTable hit for large values, copied by find vs mapped by find_borrowed
+#[test]
+fn bench_find_borrowed() {
+    let bench_dir = get_bench_dir();
+    let mut test_db = TestDb::with_options(DBexOptions {
+        index_cache_len: 1024,
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+
+    let num_keys: usize = 256;
+    let value_size = 64 * 1024;
+    let rounds = 20;
+    for i in 0..num_keys {
+        db.insert(i, vec![0xABu8; value_size]).unwrap();
+    }
+    db.flush().unwrap();
+
+    // Warm the index cache and the mapping so both loops only measure the value reads
+    for i in 0..num_keys {
+        assert!(db.find(i).unwrap().is_some());
+        assert!(db.find_borrowed(i).unwrap().unwrap().is_mapped());
+    }
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for i in 0..num_keys {
+            let value = db.find(i).unwrap().unwrap();
+            assert_eq!(value.len(), value_size);
+        }
+    }
+    let copy_time = start.elapsed();
+    let copy_allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for i in 0..num_keys {
+            let value = db.find_borrowed(i).unwrap().unwrap();
+            assert_eq!(value.len(), value_size);
+        }
+    }
+    let borrowed_time = start.elapsed();
+    let borrowed_allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    let count = num_keys * rounds;
+    let mut output = String::new();
+    for (operation, time, allocations) in [
+        ("find_copied", copy_time, copy_allocations),
+        ("find_borrowed", borrowed_time, borrowed_allocations),
+    ] {
+        let line = format!(
+            "{:<20} {:>10} ops in {:>10.2?} ({:>8.2} µs/op, {:>5.2} allocs/op)\n",
+            operation,
+            count,
+            time,
+            time.as_micros() as f64 / count as f64,
+            allocations as f64 / count as f64,
+        );
+        print!("{}", line);
+        output.push_str(&line);
+    }
+
+    fs::write(bench_dir.join("find_borrowed.txt"), output).ok();
+    db.purge().unwrap();
+}
+
+// Full scan of a leveled database with disjoint tables, through scan_prefix vs range. Both
+// read each table with scan_range and concatenate tables that don't overlap; scan_prefix
+// also collects every pair into a Vec, where range hands them out one at a time.
+#[test]
+fn bench_disjoint_range_scan() {
+    let bench_dir = get_bench_dir();
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    let num_tables: usize = 20;
+    let keys_per_table: usize = 10_000;
+    let value_size = 100;
+    let num_scans = 5;
+    for table in 0..num_tables {
+        let keys = table * keys_per_table..(table + 1) * keys_per_table;
+        db.bulk_load(keys.map(|i| (i.to_be_bytes().to_vec(), vec![0xABu8; value_size]))).unwrap();
+    }
+
+    let num_keys = num_tables * keys_per_table;
+    let start = Instant::now();
+    for _ in 0..num_scans {
+        assert_eq!(db.scan_prefix(&[]).unwrap().len(), num_keys);
+    }
+    let collected_time = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..num_scans {
+        assert_eq!(db.range(&[], &[0xFF; 9]).unwrap().count(), num_keys);
+    }
+    let streamed_time = start.elapsed();
+
+    let count = num_keys * num_scans;
+    let mut output = String::new();
+    for (operation, total_time) in [("scan_prefix", collected_time), ("range", streamed_time)] {
+        let result = BenchResult {
+            operation: operation.to_string(),
+            count,
+            total_time,
+            ops_per_sec: count as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / count as f64,
+            throughput_mb_s: Some((count * value_size) as f64 / (1024.0 * 1024.0) / total_time.as_secs_f64()),
+        };
+        result.print();
+        output.push_str(&format_result(&result));
+    }
+
+    fs::write(bench_dir.join("disjoint_range_scan.txt"), output).ok();
+    db.purge().unwrap();
+}
+
+// Point read latency seen by a reader while the writer compacts, unthrottled vs with
+// compaction_bytes_per_sec set. The reader has its own read-only handle, so the two only
+// compete for the disk.
+#[test]
+fn bench_compaction_throttle() {
+    let bench_dir = get_bench_dir();
+    let num_keys: usize = 50_000;
+    let value_size = 200;
+
+    let mut output = String::new();
+    for limit in [None, Some(16 * 1024 * 1024u64)] {
+        let path = format!("db_data_bench_compaction_throttle_{}", process::id());
+        let mut test_db = TestDb::open_with_options(&path, DBexOptions {
+            compaction_bytes_per_sec: limit,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+        for i in 0..num_keys {
+            db.insert(i, vec![0xABu8; value_size]).unwrap();
+            if i % (num_keys / 10) == 0 {
+                db.flush().unwrap();
+            }
+        }
+        db.flush().unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let mut reader_db = DBex::open_read_only(&path).unwrap();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut rng = rand::rng();
+                let mut latencies = Vec::new();
+                while !stop.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    reader_db.find(rng.random_range(0..num_keys)).unwrap();
+                    latencies.push(start.elapsed());
+                }
+                latencies
+            })
+        };
+
+        // Overwrites that push L0 over its limit and set off compactions
+        let start = Instant::now();
+        for i in 0..num_keys {
+            db.insert(i, vec![0xCDu8; value_size]).unwrap();
+            if i % (num_keys / 12) == 0 {
+                db.flush().unwrap();
+            }
+        }
+        db.flush().unwrap();
+        let write_time = start.elapsed();
+        stop.store(true, Ordering::Relaxed);
+
+        let mut latencies = reader.join().unwrap();
+        latencies.sort();
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+        let compaction = db.stats().total_compaction;
+        let line = format!(
+            "{:<24} {:>8} reads  p50 {:>10.2?}  p99 {:>10.2?}  max {:>10.2?}  writes {:>10.2?}  compaction {:>10.2?} ({:.2?} throttled)\n",
+            format!("compaction_limit_{:?}", limit),
+            latencies.len(),
+            percentile(0.5),
+            percentile(0.99),
+            latencies.last().unwrap(),
+            write_time,
+            compaction.duration,
+            compaction.throttled,
+        );
+        print!("{}", line);
+        output.push_str(&line);
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("compaction_throttle.txt"), output).ok();
+}
+
+// Time to open a database whose tables have their .sparse sidecars vs one that has to
+// scan every index to rebuild its sparse indexes
+#[test]
+fn bench_open_sparse_index() {
+    let bench_dir = get_bench_dir();
+    let path = format!("db_data_bench_open_sparse_index_{}", process::id());
+    let num_keys: usize = 500_000;
+    let num_opens = 5;
+
+    let mut db = DBex::open(&path);
+    for i in 0..num_keys {
+        db.insert(i, vec![0xABu8; 16]).unwrap();
+    }
+    db.close().unwrap();
+
+    let mut output = String::new();
+    for with_sidecars in [true, false] {
+        if !with_sidecars {
+            for entry in fs::read_dir(PathBuf::from(&path).join("ss_tables")).unwrap() {
+                let entry_path = entry.unwrap().path();
+                if entry_path.to_string_lossy().ends_with(".sparse") {
+                    fs::remove_file(entry_path).unwrap();
+                }
+            }
+        }
+
+        let start = Instant::now();
+        for _ in 0..num_opens {
+            let mut db = DBex::open(&path);
+            assert!(db.find(num_keys / 2).unwrap().is_some());
+        }
+        let time = start.elapsed();
+
+        let line = format!(
+            "{:<24} {:>4} opens in {:>10.2?} ({:>10.2?}/open)\n",
+            if with_sidecars { "open_with_sidecars" } else { "open_scanning_index" },
+            num_opens,
+            time,
+            time / num_opens as u32,
+        );
+        print!("{}", line);
+        output.push_str(&line);
+    }
+
+    fs::write(bench_dir.join("open_sparse_index.txt"), output).ok();
+    DBex::open(&path).purge().unwrap();
+}
+
+// WAL write throughput for large values, rkyv-archived records against raw ones
+#[test]
+fn bench_wal_record_format() {
+    let bench_dir = get_bench_dir();
+    let wal_dir = PathBuf::from(format!("db_data_bench_wal_record_format_{}", process::id()));
+    let num_writes: usize = 2_000;
+
+    let mut output = String::new();
+    for value_size in [1_000, 64_000, 1_000_000] {
+        let value = vec![0xABu8; value_size];
+        for record_format in [WalRecordFormat::Rkyv, WalRecordFormat::Raw] {
+            fs::remove_dir_all(&wal_dir).ok();
+            fs::create_dir_all(&wal_dir).unwrap();
+            let mut wal = WriteAheadLog::new(Arc::new(LocalStorage), &wal_dir, None, record_format).unwrap();
+
+            let start = Instant::now();
+            for i in 0..num_writes {
+                wal.write(Operation::Insert, i as u64, Some(i.to_be_bytes().to_vec()), Some(value.clone())).unwrap();
+            }
+            wal.sync().unwrap();
+            let time = start.elapsed();
+
+            let line = format!(
+                "{:<6} {:>9}-byte values: {:>6} writes in {:>10.2?} ({:>8.1} MB/s)\n",
+                format!("{:?}", record_format),
+                value_size,
+                num_writes,
+                time,
+                (num_writes * value_size) as f64 / time.as_secs_f64() / 1_000_000.0,
+            );
+            print!("{}", line);
+            output.push_str(&line);
+        }
+    }
+
+    fs::write(bench_dir.join("wal_record_format.txt"), output).ok();
+    fs::remove_dir_all(&wal_dir).ok();
+}
+
+// Lookups of keys that were never written, at several Bloom filter false positive rates
+#[test]
+fn bench_absent_key_reads() {
+    let bench_dir = get_bench_dir();
+    let num_keys: usize = 200_000;
+    let num_reads: usize = 1_000_000;
+    let value_size = 100;
+
+    let mut output = String::new();
+    for false_positive_rate in [0.5, 0.01, 0.001] {
+        let mut test_db = TestDb::with_options(DBexOptions {
+            false_positive_rate: Some(false_positive_rate),
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+        // Even keys only, so the odd ones probed below fall inside every table's key range
+        let value = vec![0xABu8; value_size];
+        for i in 0..num_keys {
+            db.insert((i * 2).to_be_bytes().to_vec(), value.clone()).unwrap();
+        }
+        db.flush().unwrap();
+
+        let start = Instant::now();
+        for i in 0..num_reads {
+            let key = ((i % num_keys) * 2 + 1).to_be_bytes();
+            assert!(db.find_borrowed(&key[..]).unwrap().is_none());
+        }
+        let total_time = start.elapsed();
+
+        let result = BenchResult {
+            operation: format!("absent_read_fp_{}", false_positive_rate),
+            count: num_reads,
+            total_time,
+            ops_per_sec: num_reads as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / num_reads as f64,
+            throughput_mb_s: None,
+        };
+        result.print();
+        output.push_str(&format_result(&result));
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("absent_key_reads.txt"), output).ok();
+}
+
+// Loading rows one insert at a time against one write_batch per 100k rows
+#[test]
+fn bench_write_batch() {
+    let bench_dir = get_bench_dir();
+    let num_keys: usize = 500_000;
+    let batch_size: usize = 100_000;
+    let value = vec![0xABu8; 100];
+
+    let mut output = String::new();
+    for batched in [false, true] {
+        let mut test_db = TestDb::new();
+        let db = test_db.db();
+
+        let start = Instant::now();
+        if batched {
+            for batch_start in (0..num_keys).step_by(batch_size) {
+                let ops = (batch_start..batch_start + batch_size)
+                    .map(|i| (Operation::Insert, i.to_be_bytes().to_vec(), Some(value.clone())))
+                    .collect();
+                db.write_batch(ops).unwrap();
+            }
+        } else {
+            for i in 0..num_keys {
+                db.insert(i.to_be_bytes().to_vec(), value.clone()).unwrap();
+            }
+            db.sync().unwrap();
+        }
+        let total_time = start.elapsed();
+
+        let result = BenchResult {
+            operation: if batched { "write_batch".to_string() } else { "insert".to_string() },
+            count: num_keys,
+            total_time,
+            ops_per_sec: num_keys as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / num_keys as f64,
+            throughput_mb_s: Some((num_keys * value.len()) as f64 / total_time.as_secs_f64() / 1_000_000.0),
+        };
+        result.print();
+        output.push_str(&format_result(&result));
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("write_batch.txt"), output).ok();
+}
+
+// Flushing 8 KiB repetitive values (like the `large` bench's) raw, with LZ4 and with Zstd,
+// reporting flush throughput and the size of the resulting tables
+#[test]
+fn bench_value_compression() {
+    let bench_dir = get_bench_dir();
+    let num_keys: usize = 5_000;
+    let value_size: usize = 8 * 1024;
+    let value = |i: usize| -> Vec<u8> {
+        format!("record {:08} status=active region=eu-west ", i).into_bytes().into_iter().cycle().take(value_size).collect()
+    };
+
+    let mut output = String::new();
+    for compression in [Compression::None, Compression::Lz4, Compression::Zstd { level: 3 }] {
+        let mut test_db = TestDb::with_options(DBexOptions {
+            compression,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+        for i in 0..num_keys {
+            db.insert(i.to_be_bytes().to_vec(), value(i)).unwrap();
+        }
+
+        let start = Instant::now();
+        db.flush().unwrap();
+        let total_time = start.elapsed();
+        let table_bytes: u64 = db.list_sstables().iter().map(|info| info.size_bytes).sum();
+        assert_eq!(db.find(&1234usize.to_be_bytes()).unwrap(), Some(value(1234)));
+
+        let result = BenchResult {
+            operation: format!("flush_{:?}", compression),
+            count: num_keys,
+            total_time,
+            ops_per_sec: num_keys as f64 / total_time.as_secs_f64(),
+            avg_latency_us: total_time.as_micros() as f64 / num_keys as f64,
+            throughput_mb_s: Some((num_keys * value_size) as f64 / total_time.as_secs_f64() / 1_000_000.0),
+        };
+        result.print();
+        output.push_str(&format_result(&result));
+        let sizes = format!("  tables: {} bytes ({:.1}% of the raw values)\n", table_bytes, table_bytes as f64 * 100.0 / (num_keys * value_size) as f64);
+        print!("{}", sizes);
+        output.push_str(&sizes);
+
+        db.purge().unwrap();
+    }
+
+    fs::write(bench_dir.join("value_compression.txt"), output).ok();
+}
+
 // Dedicated memory stress test with full tracking
 #[test]
 fn bench_memory_stress() {
@@ -349,13 +1016,13 @@ fn bench_memory_stress() {
 
     for i in 0..num_keys {
         let key = i.to_be_bytes().to_vec();
-        db.insert(key, value.clone());
+        db.insert(key, value.clone()).unwrap();
 
         if i % 50000 == 0 {
             mem_tracker.sample();
         }
     }
-    db.flush();
+    db.flush().unwrap();
     mem_tracker.sample();
     let write_time = write_start.elapsed();
 
@@ -383,7 +1050,7 @@ fn bench_memory_stress() {
     for i in 0..num_reads {
         let idx = rng.random_range(0..num_keys);
         let key = idx.to_be_bytes().to_vec();
-        let _ = db.find(&key);
+        let _ = db.find(&key).unwrap();
 
         if i % 5000 == 0 {
             mem_tracker.sample();
@@ -451,5 +1118,5 @@ fn bench_memory_stress() {
     let summary_file = bench_dir.join("memory_stress.txt");
     fs::write(&summary_file, summary).ok();
 
-    db.purge();
+    db.purge().unwrap();
 }
\ No newline at end of file
@@ -1,16 +1,39 @@
 mod test_db;
 use test_db::TestDb;
 
+use dbex::{DBex, FlushInfo, KeyStatus, MemTableState, ReadSource, SSTableInfo};
+use dbex::bloom_filter::{BloomFilter, BloomHasher, Fnv1aHasher, Xxh3Hasher};
+use dbex::compression::ValueTransform;
+use dbex::crash_test::CrashOp;
+use dbex::error::{CasError, DbexError};
+use dbex::sharded::ShardedDb;
+use dbex::ss_table::{table_number, SSTable};
+use dbex::storage::{LocalStorage, MappedBytes, MemoryStorage, Storage, StorageFile, StorageLock};
+use dbex::stats::MaintenanceReport;
+use dbex::utils::Operation;
+use dbex::write_ahead_log::WriteAheadLog;
+use dbex::key::CompositeKey;
+use dbex::memtable::{MemTable, SORTED_VEC_MAX_ENTRIES};
+use dbex::options::{Compression, DBexOptions, DuplicateKeys, KeyHint, MergeConflict, ReadAhead, SyncPolicy, WalRecordFormat};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 #[test]
 fn test_basic_insert_and_find() {
     let mut test_db = TestDb::new();
     let db = test_db.db();
 
-    db.insert(b"key1".to_vec(), b"value1".to_vec());
-    db.insert(b"key2".to_vec(), b"value2".to_vec());
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    db.insert(b"key2".to_vec(), b"value2".to_vec()).unwrap();
 
-    assert_eq!(db.find(&b"key1".to_vec()), Some(b"value1".to_vec()));
-    assert_eq!(db.find(&b"key2".to_vec()), Some(b"value2".to_vec()));
+    assert_eq!(db.find(b"key1").unwrap(), Some(b"value1".to_vec()));
+    assert_eq!(db.find(b"key2").unwrap(), Some(b"value2".to_vec()));
 }
 
 #[test]
@@ -18,9 +41,9 @@ fn test_find_nonexistent_key() {
     let mut test_db = TestDb::new();
     let db = test_db.db();
 
-    db.insert(b"existing".to_vec(), b"value".to_vec());
+    db.insert(b"existing".to_vec(), b"value".to_vec()).unwrap();
 
-    assert_eq!(db.find(&b"nonexistent".to_vec()), None);
+    assert_eq!(db.find(b"nonexistent").unwrap(), None);
 }
 
 #[test]
@@ -29,7 +52,7 @@ fn test_empty_database() {
     let db = test_db.db();
 
     assert_eq!(db.memtable().len(), 0);
-    assert_eq!(db.find(&b"any_key".to_vec()), None);
+    assert_eq!(db.find(b"any_key").unwrap(), None);
 }
 
 #[test]
@@ -37,11 +60,11 @@ fn test_overwrite_key() {
     let mut test_db = TestDb::new();
     let db = test_db.db();
 
-    db.insert(b"key".to_vec(), b"original_value".to_vec());
-    db.insert(b"key".to_vec(), b"new_value".to_vec());
+    db.insert(b"key".to_vec(), b"original_value".to_vec()).unwrap();
+    db.insert(b"key".to_vec(), b"new_value".to_vec()).unwrap();
 
     // Since append-only, latest value should be returned
-    assert_eq!(db.find(&b"key".to_vec()), Some(b"new_value".to_vec()));
+    assert_eq!(db.find(b"key").unwrap(), Some(b"new_value".to_vec()));
 }
 
 #[test]
@@ -50,9 +73,9 @@ fn test_large_values() {
     let db = test_db.db();
 
     let large_value = vec![42u8; 1024 * 1024]; // 1MB value
-    db.insert(b"large".to_vec(), large_value.clone());
+    db.insert(b"large".to_vec(), large_value.clone()).unwrap();
 
-    assert_eq!(db.find(&b"large".to_vec()), Some(large_value));
+    assert_eq!(db.find(b"large").unwrap(), Some(large_value));
 }
 
 #[test]
@@ -60,11 +83,11 @@ fn test_empty_key_and_value() {
     let mut test_db = TestDb::new();
     let db = test_db.db();
 
-    db.insert(b"".to_vec(), b"empty_key".to_vec());
-    db.insert(b"empty_value".to_vec(), b"".to_vec());
+    db.insert(b"".to_vec(), b"empty_key".to_vec()).unwrap();
+    db.insert(b"empty_value".to_vec(), b"".to_vec()).unwrap();
 
-    assert_eq!(db.find(&b"".to_vec()), Some(b"empty_key".to_vec()));
-    assert_eq!(db.find(&b"empty_value".to_vec()), Some(b"".to_vec()));
+    assert_eq!(db.find(b"").unwrap(), Some(b"empty_key".to_vec()));
+    assert_eq!(db.find(b"empty_value").unwrap(), Some(b"".to_vec()));
 }
 
 #[test]
@@ -75,8 +98,8 @@ fn test_binary_data() {
     let binary_key = b"\x00\x01\x02\xFF";
     let binary_value = b"\xDE\xAD\xBE\xEF";
 
-    db.insert(binary_key.to_vec(), binary_value.to_vec());
-    assert_eq!(db.find(&binary_key.to_vec()), Some(binary_value.to_vec()));
+    db.insert(binary_key.to_vec(), binary_value.to_vec()).unwrap();
+    assert_eq!(db.find(binary_key).unwrap(), Some(binary_value.to_vec()));
 }
 
 #[test]
@@ -86,17 +109,17 @@ fn test_multiple_inserts_and_len() {
 
     assert_eq!(db.memtable().len(), 0);
 
-    db.insert(b"key1".to_vec(), b"value1".to_vec());
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
     assert_eq!(db.memtable().len(), 1);
 
-    db.insert(b"key2".to_vec(), b"value2".to_vec());
+    db.insert(b"key2".to_vec(), b"value2".to_vec()).unwrap();
     assert_eq!(db.memtable().len(), 2);
 
-    db.insert(b"key3".to_vec(), b"value3".to_vec());
+    db.insert(b"key3".to_vec(), b"value3".to_vec()).unwrap();
     assert_eq!(db.memtable().len(), 3);
 
     // Overwriting shouldn't increase len
-    db.insert(b"key1".to_vec(), b"new_value".to_vec());
+    db.insert(b"key1".to_vec(), b"new_value".to_vec()).unwrap();
     assert_eq!(db.memtable().len(), 3);
 }
 
@@ -105,12 +128,53 @@ fn test_flush() {
     let mut test_db = TestDb::new();
     let db = test_db.db();
 
-    db.insert(b"key".to_vec(), b"value".to_vec());
-    db.flush();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
 
     // After flush, data should be in an SSTable
     // Verify we can still read it
-    assert_eq!(db.find(&b"key".to_vec()), Some(b"value".to_vec()));
+    assert_eq!(db.find(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_flush_returns_table_info() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    db.insert(b"key_b".to_vec(), b"value_b".to_vec()).unwrap();
+    db.insert(b"key_a".to_vec(), b"value_a".to_vec()).unwrap();
+    db.insert(b"key_c".to_vec(), b"value_c".to_vec()).unwrap();
+
+    let info = db.flush().unwrap().expect("flush of a non-empty memtable should create an SSTable");
+    assert_eq!(info.min_key, b"key_a".to_vec());
+    assert_eq!(info.max_key, b"key_c".to_vec());
+    assert_eq!(info.entry_count, 3);
+    assert!(info.size_bytes > 0);
+
+    // Nothing left to flush
+    assert_eq!(db.flush().unwrap(), None);
+}
+
+#[test]
+fn test_append() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    // A missing key appends onto an empty value
+    db.append(b"log".to_vec(), b"a").unwrap();
+    db.append(b"log".to_vec(), b"b").unwrap();
+    assert_eq!(db.find(b"log").unwrap(), Some(b"ab".to_vec()));
+
+    // The base value can come from an SSTable
+    db.flush().unwrap();
+    db.append(b"log".to_vec(), b"c").unwrap();
+    db.append(b"log".to_vec(), b"").unwrap();
+    assert_eq!(db.find(b"log").unwrap(), Some(b"abc".to_vec()));
+
+    // Keys are taken like insert takes them
+    db.append(7u32, b"x").unwrap();
+    db.append(7u32, b"y").unwrap();
+    assert_eq!(db.find(7u32).unwrap(), Some(b"xy".to_vec()));
 }
 
 #[test]
@@ -122,15 +186,15 @@ fn test_many_small_keys() {
     for i in 0..count {
         let key = format!("key_{}", i);
         let value = format!("value_{}", i);
-        db.insert(key.as_bytes().to_vec(), value.as_bytes().to_vec());
+        db.insert(key.as_bytes().to_vec(), value.as_bytes().to_vec()).unwrap();
     }
 
     assert_eq!(db.memtable().len(), count);
 
     // Verify some random entries
-    assert_eq!(db.find(&b"key_0".to_vec()), Some(b"value_0".to_vec()));
-    assert_eq!(db.find(&b"key_5000".to_vec()), Some(b"value_5000".to_vec()));
-    assert_eq!(db.find(&b"key_9999".to_vec()), Some(b"value_9999".to_vec()));
+    assert_eq!(db.find(b"key_0").unwrap(), Some(b"value_0".to_vec()));
+    assert_eq!(db.find(b"key_5000").unwrap(), Some(b"value_5000".to_vec()));
+    assert_eq!(db.find(b"key_9999").unwrap(), Some(b"value_9999".to_vec()));
 }
 
 #[test]
@@ -139,20 +203,20 @@ fn test_memtable_flush_to_sstable() {
     let db = test_db.db();
 
     // Insert some data
-    db.insert(b"key1".to_vec(), b"value1".to_vec());
-    db.insert(b"key2".to_vec(), b"value2".to_vec());
-    db.insert(b"key3".to_vec(), b"value3".to_vec());
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    db.insert(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+    db.insert(b"key3".to_vec(), b"value3".to_vec()).unwrap();
 
     // Data should be in MemTable
-    assert_eq!(db.find(&b"key1".to_vec()), Some(b"value1".to_vec()));
+    assert_eq!(db.find(b"key1").unwrap(), Some(b"value1".to_vec()));
 
     // Flush to SSTable
-    db.flush();
+    db.flush().unwrap();
 
     // Data should still be readable from SSTable
-    assert_eq!(db.find(&b"key1".to_vec()), Some(b"value1".to_vec()));
-    assert_eq!(db.find(&b"key2".to_vec()), Some(b"value2".to_vec()));
-    assert_eq!(db.find(&b"key3".to_vec()), Some(b"value3".to_vec()));
+    assert_eq!(db.find(b"key1").unwrap(), Some(b"value1".to_vec()));
+    assert_eq!(db.find(b"key2").unwrap(), Some(b"value2".to_vec()));
+    assert_eq!(db.find(b"key3").unwrap(), Some(b"value3".to_vec()));
 }
 
 #[test]
@@ -161,18 +225,18 @@ fn test_read_from_multiple_sstables() {
     let db = test_db.db();
 
     // Insert and flush first batch
-    db.insert(b"batch1_key1".to_vec(), b"batch1_value1".to_vec());
-    db.insert(b"batch1_key2".to_vec(), b"batch1_value2".to_vec());
-    db.flush();
+    db.insert(b"batch1_key1".to_vec(), b"batch1_value1".to_vec()).unwrap();
+    db.insert(b"batch1_key2".to_vec(), b"batch1_value2".to_vec()).unwrap();
+    db.flush().unwrap();
 
     // Insert and flush second batch
-    db.insert(b"batch2_key1".to_vec(), b"batch2_value1".to_vec());
-    db.insert(b"batch2_key2".to_vec(), b"batch2_value2".to_vec());
-    db.flush();
+    db.insert(b"batch2_key1".to_vec(), b"batch2_value1".to_vec()).unwrap();
+    db.insert(b"batch2_key2".to_vec(), b"batch2_value2".to_vec()).unwrap();
+    db.flush().unwrap();
 
     // Should be able to read from both SSTables
-    assert_eq!(db.find(&b"batch1_key1".to_vec()), Some(b"batch1_value1".to_vec()));
-    assert_eq!(db.find(&b"batch2_key1".to_vec()), Some(b"batch2_value1".to_vec()));
+    assert_eq!(db.find(b"batch1_key1").unwrap(), Some(b"batch1_value1".to_vec()));
+    assert_eq!(db.find(b"batch2_key1").unwrap(), Some(b"batch2_value1".to_vec()));
 }
 
 #[test]
@@ -181,12 +245,3517 @@ fn test_newest_value_wins() {
     let db = test_db.db();
 
     // Insert and flush old value
-    db.insert(b"key".to_vec(), b"old_value".to_vec());
-    db.flush();
+    db.insert(b"key".to_vec(), b"old_value".to_vec()).unwrap();
+    db.flush().unwrap();
 
     // Insert new value (in MemTable)
-    db.insert(b"key".to_vec(), b"new_value".to_vec());
+    db.insert(b"key".to_vec(), b"new_value".to_vec()).unwrap();
 
     // Should return newest value from MemTable, not SSTable
-    assert_eq!(db.find(&b"key".to_vec()), Some(b"new_value".to_vec()));
+    assert_eq!(db.find(b"key").unwrap(), Some(b"new_value".to_vec()));
+}
+
+#[test]
+fn test_open_read_only() {
+    let path = "db_data_test_open_read_only";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    db.insert(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"key1".to_vec(), b"new_value1".to_vec()).unwrap();
+    db.flush().unwrap();
+
+    let mut reader = DBex::open_read_only(path).unwrap();
+    assert!(reader.is_read_only());
+
+    // Reads see every flushed table, newest value first
+    assert_eq!(reader.find(b"key1").unwrap(), Some(b"new_value1".to_vec()));
+    assert_eq!(reader.find(b"key2").unwrap(), Some(b"value2".to_vec()));
+    assert_eq!(reader.find(b"missing").unwrap(), None);
+
+    // Writes are rejected
+    assert!(matches!(reader.insert(b"key3".to_vec(), b"value3".to_vec()), Err(DbexError::ReadOnly)));
+    assert!(matches!(reader.remove(b"key1"), Err(DbexError::ReadOnly)));
+    assert!(matches!(reader.flush(), Err(DbexError::ReadOnly)));
+    assert!(matches!(reader.purge(), Err(DbexError::ReadOnly)));
+    assert_eq!(reader.find(b"key3").unwrap(), None);
+
+    // The writer is unaffected
+    db.insert(b"key3".to_vec(), b"value3".to_vec()).unwrap();
+    assert_eq!(db.find(b"key3").unwrap(), Some(b"value3".to_vec()));
+}
+
+#[test]
+fn test_second_writer_is_locked_out() {
+    let path = "db_data_test_second_writer_is_locked_out";
+    let mut test_db = TestDb::open(path);
+    test_db.db().insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+    assert!(matches!(DBex::try_open(path), Err(DbexError::Locked(_))));
+
+    // Readers don't take the lock
+    assert!(DBex::open_read_only(path).is_ok());
+
+    // Dropping the writer releases the lock
+    let other_path = "db_data_test_second_writer_is_locked_out_2";
+    let writer = DBex::try_open(other_path).unwrap();
+    drop(writer);
+    let mut reopened = DBex::try_open(other_path).unwrap();
+    reopened.purge().unwrap();
+}
+
+fn load_prefix_tables(db: &mut DBex) {
+    // Both tables' key ranges cover "bbbb", but only the second holds such keys
+    db.insert(b"aaaa:1".to_vec(), b"a1".to_vec()).unwrap();
+    db.insert(b"cccc:1".to_vec(), b"c1".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"bbbb:1".to_vec(), b"b1".to_vec()).unwrap();
+    db.insert(b"bbbb:2".to_vec(), b"b2".to_vec()).unwrap();
+    db.insert(b"dddd:1".to_vec(), b"d1".to_vec()).unwrap();
+    db.flush().unwrap();
+}
+
+#[test]
+fn test_scan_prefix_bounds() {
+    let mut test_db = TestDb::open("db_data_test_scan_prefix_bounds");
+    let db = test_db.db();
+
+    for key in [&b"a1"[..], b"b1", &[0xFF, 0xFF], &[0xFF, 0xFF, 0x00]] {
+        db.insert(key, b"flushed".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for key in [&b"a2"[..], b"a", b"b", &[0xFF]] {
+        db.insert(key, b"memtable".to_vec()).unwrap();
+    }
+    db.remove(&b"b1"[..]).unwrap();
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    assert_eq!(keys(db.scan_prefix(b"a").unwrap()), vec![b"a".to_vec(), b"a1".to_vec(), b"a2".to_vec()]);
+    assert_eq!(keys(db.scan_prefix(b"b").unwrap()), vec![b"b".to_vec()]);
+    assert_eq!(keys(db.scan_prefix(b"a1").unwrap()), vec![b"a1".to_vec()]);
+    // An all-0xFF prefix has no upper bound, so it runs to the end
+    assert_eq!(keys(db.scan_prefix(&[0xFF, 0xFF]).unwrap()), vec![vec![0xFF, 0xFF], vec![0xFF, 0xFF, 0x00]]);
+    assert_eq!(keys(db.scan_prefix(&[0xFF]).unwrap()).len(), 3);
+    // An empty prefix scans everything
+    assert_eq!(db.scan_prefix(b"").unwrap().len(), 7);
+}
+
+#[test]
+fn test_prefix_bloom_skips_tables() {
+    let expected = vec![
+        (b"bbbb:1".to_vec(), b"b1".to_vec()),
+        (b"bbbb:2".to_vec(), b"b2".to_vec()),
+    ];
+
+    let mut test_db = TestDb::open_with_options("db_data_test_prefix_bloom_skips_tables", DBexOptions {
+        prefix_bloom_len: Some(4),
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+    load_prefix_tables(db);
+
+    assert_eq!(db.scan_prefix(b"bbbb").unwrap(), expected);
+    assert_eq!(db.ss_tables_touched(), 1);
+
+    // Without prefix Blooms both overlapping tables have to be read
+    let mut whole_key_db = TestDb::open("db_data_test_prefix_bloom_skips_tables_whole_key");
+    let db = whole_key_db.db();
+    load_prefix_tables(db);
+
+    assert_eq!(db.scan_prefix(b"bbbb").unwrap(), expected);
+    assert_eq!(db.ss_tables_touched(), 2);
+}
+
+#[test]
+fn test_key_hint() {
+    let mut test_db = TestDb::open_with_options("db_data_test_key_hint_prefixed", DBexOptions::default().with_key_hint(KeyHint::Prefixed(4)));
+    let db = test_db.db();
+    assert_eq!(db.options().prefix_bloom_len, Some(4));
+    load_prefix_tables(db);
+
+    // Prefix Blooms let the scan skip the overlapping table without the prefix
+    assert_eq!(db.scan_prefix(b"bbbb").unwrap().len(), 2);
+    assert_eq!(db.ss_tables_touched(), 1);
+
+    let random = DBexOptions::default().with_key_hint(KeyHint::Random);
+    assert_eq!(random.sparse_index_interval, Some(32));
+    assert_eq!(random.prefix_bloom_len, None);
+    assert!(!random.monotonic_keys);
+
+    // Ascending keys keep the memtable a sorted vector well past its usual limit
+    let mut test_db = TestDb::open_with_options("db_data_test_key_hint_monotonic", DBexOptions::default().with_key_hint(KeyHint::Monotonic));
+    let db = test_db.db();
+    assert!(db.options().monotonic_keys);
+    for i in 0..SORTED_VEC_MAX_ENTRIES * 2 {
+        db.insert(format!("key_{:08}", i).into_bytes(), b"value".to_vec()).unwrap();
+    }
+    assert!(db.memtable().is_sorted_vec());
+    db.insert(b"key_0".to_vec(), b"value".to_vec()).unwrap();
+    assert!(!db.memtable().is_sorted_vec());
+}
+
+#[test]
+fn test_truncated_index_is_flagged_on_open() {
+    let path = "db_data_test_truncated_index_is_flagged_on_open";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+
+    db.insert(b"old_key".to_vec(), b"old_value".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"new_key".to_vec(), b"new_value".to_vec()).unwrap();
+    db.flush().unwrap();
+
+    let mut index_paths: Vec<PathBuf> = fs::read_dir(PathBuf::from(path).join("ss_tables"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".db.index"))
+        .collect();
+    index_paths.sort();
+    assert_eq!(index_paths.len(), 2);
+
+    // Simulate a crash midway through writing the newest index
+    let index_file = OpenOptions::new().write(true).open(&index_paths[1]).unwrap();
+    let index_len = index_file.metadata().unwrap().len();
+    index_file.set_len(index_len - 5).unwrap();
+
+    let mut reader = DBex::open_read_only(path).unwrap();
+    assert_eq!(reader.corrupt_ss_tables().len(), 1);
+    assert!(index_paths[1].to_string_lossy().starts_with(&*reader.corrupt_ss_tables()[0].to_string_lossy()));
+
+    // The intact table is still served
+    assert_eq!(reader.find(b"old_key").unwrap(), Some(b"old_value".to_vec()));
+    assert_eq!(reader.find(b"new_key").unwrap(), None);
+}
+
+#[test]
+fn test_compaction_stats() {
+    let mut test_db = TestDb::open("db_data_test_compaction_stats");
+    let db = test_db.db();
+
+    assert_eq!(db.stats().compactions, 0);
+    assert_eq!(db.stats().last_compaction, None);
+
+    // Overwrite the same keys in every table; the 11th flush triggers L0 -> L1
+    let num_keys = 100;
+    for round in 0..11 {
+        for i in 0..num_keys {
+            let key = format!("key_{:03}", i).into_bytes();
+            let value = format!("value_{}_{}", i, round).into_bytes();
+            db.insert(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        if round == 9 {
+            let (tables, entries, bytes) = db.count_in_level(0);
+            assert_eq!((tables, entries), (10, 10 * num_keys));
+            assert!(bytes > 0);
+            assert_eq!(db.count_in_level(1), (0, 0, 0));
+        }
+    }
+
+    assert_eq!(db.cnt_of_l0_ss_tables(), 0);
+    assert_eq!(db.cnt_of_l1_ss_tables(), 1);
+    assert_eq!(db.count_in_level(0), (0, 0, 0));
+    let (tables, entries, bytes) = db.count_in_level(1);
+    assert_eq!((tables, entries), (1, num_keys));
+    assert_eq!(bytes, db.stats().last_compaction.unwrap().bytes_written);
+    assert_eq!(db.count_in_level(3), (0, 0, 0));
+
+    let stats = db.stats();
+    assert_eq!(stats.compactions, 1);
+    let last = stats.last_compaction.clone().unwrap();
+    assert_eq!(last, stats.total_compaction);
+    assert_eq!(last.input_tables, 11);
+    assert_eq!(last.duplicates_dropped, 10 * num_keys);
+    assert_eq!(last.tombstones_dropped, 0);
+    assert!(last.bytes_read > 0);
+    assert!(last.bytes_written > 0);
+    assert!(last.bytes_written <= last.bytes_read);
+    assert!(last.write_amplification() < 1.0);
+    assert!(last.duration > std::time::Duration::ZERO);
+
+    // The merge kept the newest value of each key
+    assert_eq!(db.find(b"key_000").unwrap(), Some(b"value_0_10".to_vec()));
+    assert_eq!(db.find(b"key_099").unwrap(), Some(b"value_99_10".to_vec()));
+}
+
+#[test]
+fn test_range_keys_matches_range() {
+    let mut test_db = TestDb::open("db_data_test_range_keys_matches_range");
+    let db = test_db.db();
+
+    for i in (0..20).step_by(2) {
+        db.insert(format!("key_{:02}", i).into_bytes(), b"v1".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for i in (1..20).step_by(2) {
+        db.insert(format!("key_{:02}", i).into_bytes(), b"v2".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    db.insert(b"key_04".to_vec(), b"v3".to_vec()).unwrap();
+    db.remove(b"key_05").unwrap();
+
+    let keys: Vec<Vec<u8>> = db.range_keys(b"key_03", b"key_10").unwrap().collect();
+    let from_range: Vec<Vec<u8>> = db.range(b"key_03", b"key_10").unwrap().map(|(k, _)| k).collect();
+    assert_eq!(keys, from_range);
+
+    let expected: Vec<Vec<u8>> = [3, 4, 6, 7, 8, 9]
+        .iter()
+        .map(|i| format!("key_{:02}", i).into_bytes())
+        .collect();
+    assert_eq!(keys, expected);
+
+    assert_eq!(db.range_keys(b"zzz", b"zzzz").unwrap().count(), 0);
+}
+
+#[test]
+fn test_integer_keys() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    db.insert(42u64, b"forty-two".to_vec()).unwrap();
+    assert_eq!(db.find(42u64).unwrap(), Some(b"forty-two".to_vec()));
+    assert_eq!(db.find(&42u64.to_be_bytes()).unwrap(), Some(b"forty-two".to_vec()));
+    assert_eq!(db.find(43u64).unwrap(), None);
+
+    db.remove(42u64).unwrap();
+    assert_eq!(db.find(42u64).unwrap(), None);
+
+    // Signed keys keep numeric order
+    let mut test_db = TestDb::open("db_data_test_integer_keys");
+    let db = test_db.db();
+    for i in [-5i64, 3, -1, 0, 7] {
+        db.insert(i, i.to_string().into_bytes()).unwrap();
+    }
+    let values: Vec<Vec<u8>> = db.range(&[0u8; 8], &[0xFFu8; 8]).unwrap().map(|(_, v)| v).collect();
+    let expected: Vec<Vec<u8>> = ["-5", "-1", "0", "3", "7"].iter().map(|v| v.as_bytes().to_vec()).collect();
+    assert_eq!(values, expected);
+}
+
+fn data_files(path: &str) -> Vec<PathBuf> {
+    let mut data_paths: Vec<PathBuf> = fs::read_dir(PathBuf::from(path).join("ss_tables"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "db"))
+        .collect();
+    data_paths.sort();
+    data_paths
+}
+
+#[test]
+fn test_ingest_sstable() {
+    let source_path = "db_data_test_ingest_sstable_source";
+    let mut source_db = TestDb::open(source_path);
+    let source = source_db.db();
+    source.insert(b"m_key1".to_vec(), b"ingested1".to_vec()).unwrap();
+    source.insert(b"m_key2".to_vec(), b"ingested2".to_vec()).unwrap();
+    source.flush().unwrap();
+    let source_table = data_files(source_path).remove(0);
+
+    let mut target_db = TestDb::open("db_data_test_ingest_sstable_target");
+    let target = target_db.db();
+    target.insert(b"a_key".to_vec(), b"existing".to_vec()).unwrap();
+    target.flush().unwrap();
+
+    // No overlap with the existing table, so it can sit below L0
+    assert_eq!(target.ingest_sstable(&source_table).unwrap(), 2);
+    assert_eq!(target.find(b"m_key1").unwrap(), Some(b"ingested1".to_vec()));
+    assert_eq!(target.find(b"m_key2").unwrap(), Some(b"ingested2".to_vec()));
+    assert_eq!(target.find(b"a_key").unwrap(), Some(b"existing".to_vec()));
+
+    // An overlapping table lands in L0 and shadows older values
+    source.insert(b"a_key".to_vec(), b"replaced".to_vec()).unwrap();
+    source.flush().unwrap();
+    let newest_table = data_files(source_path).pop().unwrap();
+    assert_eq!(target.ingest_sstable(&newest_table).unwrap(), 0);
+    assert_eq!(target.find(b"a_key").unwrap(), Some(b"replaced".to_vec()));
+
+    // The source database is untouched
+    assert_eq!(source.find(b"m_key1").unwrap(), Some(b"ingested1".to_vec()));
+
+    // Anything that isn't a valid table is rejected
+    assert!(target.ingest_sstable(PathBuf::from(source_path).join("LOCK")).is_err());
+}
+
+#[test]
+fn test_compaction_detects_corrupt_value_length() {
+    let path = "db_data_test_compaction_corrupt_length";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+
+    for i in 0..10 {
+        db.insert(format!("key{}", i).into_bytes(), b"value".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+
+    // Claim the first value is ~2GB long, well past the end of its data file
+    let mut data_file = OpenOptions::new().write(true).open(&data_files(path)[0]).unwrap();
+    data_file.write_all(&0x7FFFFFFFu32.to_be_bytes()).unwrap();
+    drop(data_file);
+
+    // The 11th table triggers an L0 compaction, which must refuse the corrupt input
+    db.insert(b"key10".to_vec(), b"value".to_vec()).unwrap();
+    assert!(matches!(db.flush(), Err(DbexError::Corruption(_))));
+
+    // Nothing was lost or half-written: every input table is still in L0
+    assert_eq!(db.cnt_of_l0_ss_tables(), 11);
+    assert_eq!(db.cnt_of_l1_ss_tables(), 0);
+    assert_eq!(data_files(path).len(), 11);
+    assert_eq!(db.find(b"key5").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_scans_match_across_read_ahead_modes() {
+    let mut results = Vec::new();
+    for read_ahead in [ReadAhead::Off, ReadAhead::Auto, ReadAhead::Bytes(16)] {
+        let mut test_db = TestDb::open_with_options("db_data_test_scan_read_ahead", DBexOptions {
+            read_ahead,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+
+        for i in 0..500u32 {
+            db.insert(i, format!("value{}", i).repeat(i as usize % 7).into_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+        // Tombstones and overwrites in a newer table
+        for i in (0..500u32).step_by(3) {
+            db.remove(i).unwrap();
+        }
+        db.insert(10u32, b"overwritten".to_vec()).unwrap();
+        db.flush().unwrap();
+
+        let range: Vec<_> = db.range(&5u32.to_be_bytes(), &400u32.to_be_bytes()).unwrap().collect();
+        let prefix = db.scan_prefix(&[0, 0, 1]).unwrap();
+        results.push((range, prefix));
+    }
+
+    assert_eq!(results[0].0.len(), 263);
+    assert!(results[0].0.contains(&(10u32.to_be_bytes().to_vec(), b"overwritten".to_vec())));
+    assert_eq!(results[0], results[1]);
+    assert_eq!(results[0], results[2]);
+}
+
+#[test]
+fn test_sorted_vec_memtable_matches_btree() {
+    let mut sorted = MemTable::with_size_hint(100);
+    let mut tree = MemTable::new();
+    assert!(sorted.is_sorted_vec());
+    assert!(!tree.is_sorted_vec());
+
+    // Out-of-order inserts, overwrites and tombstones
+    for i in (0..1000u32).rev() {
+        let key = (i * 7 % 1000).to_be_bytes().to_vec();
+        sorted.insert(key.clone(), i.to_be_bytes().to_vec());
+        tree.insert(key, i.to_be_bytes().to_vec());
+    }
+    for i in (0..1000u32).step_by(5) {
+        sorted.remove(&i.to_be_bytes());
+        tree.remove(&i.to_be_bytes());
+    }
+
+    assert_eq!(sorted.len(), tree.len());
+    assert_eq!(sorted.size_byte(), tree.size_byte());
+    assert_eq!(sorted.get(&3u32.to_be_bytes()), tree.get(&3u32.to_be_bytes()));
+    assert_eq!(sorted.get(&5u32.to_be_bytes()), None);
+    assert!(sorted.iter().eq(tree.iter()));
+    assert!(sorted.range(&100u32.to_be_bytes(), Some(&200u32.to_be_bytes()))
+        .eq(tree.range(&100u32.to_be_bytes(), Some(&200u32.to_be_bytes()))));
+    assert!(sorted.scan_prefix(&[0, 0, 1]).eq(tree.scan_prefix(&[0, 0, 1])));
+
+    // Growing past the threshold moves the entries into a BTreeMap
+    for i in 0..SORTED_VEC_MAX_ENTRIES as u32 {
+        sorted.insert((i + 1000).to_be_bytes().to_vec(), b"value".to_vec());
+    }
+    assert!(!sorted.is_sorted_vec());
+    assert_eq!(sorted.len(), 1000 + SORTED_VEC_MAX_ENTRIES);
+    assert_eq!(sorted.get(&3u32.to_be_bytes()), tree.get(&3u32.to_be_bytes()));
+
+    // The option applies to every memtable, including the one started after a flush
+    let mut test_db = TestDb::open_with_options("db_data_test_sorted_vec_memtable", DBexOptions {
+        memtable_size_hint: Some(100),
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    assert!(db.memtable().is_sorted_vec());
+    db.flush().unwrap();
+    assert!(db.memtable().is_sorted_vec());
+    db.insert(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+    assert_eq!(db.find(b"key1").unwrap(), Some(b"value1".to_vec()));
+    assert_eq!(db.find(b"key2").unwrap(), Some(b"value2".to_vec()));
+}
+
+#[test]
+fn test_bulk_load_duplicate_keys() {
+    let entries = || vec![
+        (b"a".to_vec(), b"a1".to_vec()),
+        (b"b".to_vec(), b"b1".to_vec()),
+        (b"b".to_vec(), b"b2".to_vec()),
+        (b"b".to_vec(), b"b3".to_vec()),
+        (b"c".to_vec(), b"c1".to_vec()),
+        (b"c".to_vec(), b"c2".to_vec()),
+    ];
+    let load = |bulk_load_duplicates| {
+        let mut test_db = TestDb::open_with_options("db_data_test_bulk_load_duplicates", DBexOptions {
+            bulk_load_duplicates,
+            ..DBexOptions::default()
+        });
+        let db = test_db.db();
+        let loaded = db.bulk_load(entries());
+        let found = [b"a", b"b", b"c"].map(|key| db.find(key).unwrap());
+        let files = data_files("db_data_test_bulk_load_duplicates").len();
+        (loaded, found, files)
+    };
+
+    let (loaded, found, _) = load(DuplicateKeys::KeepLast);
+    assert_eq!(loaded.unwrap(), 3);
+    assert_eq!(found, [Some(b"a1".to_vec()), Some(b"b3".to_vec()), Some(b"c2".to_vec())]);
+
+    let (loaded, found, _) = load(DuplicateKeys::KeepFirst);
+    assert_eq!(loaded.unwrap(), 3);
+    assert_eq!(found, [Some(b"a1".to_vec()), Some(b"b1".to_vec()), Some(b"c1".to_vec())]);
+
+    // Nothing is loaded and the partial table is removed
+    let (loaded, found, files) = load(DuplicateKeys::Error);
+    assert!(matches!(loaded, Err(DbexError::DuplicateKey(key)) if key == b"b"));
+    assert_eq!(found, [None, None, None]);
+    assert_eq!(files, 0);
+
+    // KeepLast is the default, matching insert
+    assert_eq!(DBexOptions::default().bulk_load_duplicates, DuplicateKeys::KeepLast);
+
+    let mut test_db = TestDb::open("db_data_test_bulk_load_unsorted");
+    let unsorted = vec![(b"b".to_vec(), b"1".to_vec()), (b"a".to_vec(), b"2".to_vec())];
+    assert!(matches!(test_db.db().bulk_load(unsorted), Err(DbexError::UnsortedInput(key)) if key == b"a"));
+}
+
+#[test]
+fn test_clean_close_skips_wal_replay() {
+    let path = "db_data_test_clean_close";
+    fs::remove_dir_all(path).ok();
+
+    // Dropped without close: the next open has to replay the WAL
+    let mut db = DBex::open(path);
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    db.insert(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+    db.remove(b"key1").unwrap();
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert!(!db.stats().recovery.clean_shutdown);
+    assert_eq!(db.stats().recovery.wal_entries_replayed, 3);
+    assert_eq!(db.find(b"key1").unwrap(), None);
+    assert_eq!(db.find(b"key2").unwrap(), Some(b"value2".to_vec()));
+
+    // close() flushes everything, so nothing is left to replay
+    db.insert(b"key3".to_vec(), b"value3".to_vec()).unwrap();
+    db.close().unwrap();
+
+    let mut db = DBex::open(path);
+    assert!(db.stats().recovery.clean_shutdown);
+    assert_eq!(db.stats().recovery.wal_entries_replayed, 0);
+    assert_eq!(db.memtable().len(), 0);
+    assert_eq!(db.find(b"key2").unwrap(), Some(b"value2".to_vec()));
+    assert_eq!(db.find(b"key3").unwrap(), Some(b"value3".to_vec()));
+
+    // The marker only covers one shutdown
+    drop(db);
+    let mut db = DBex::open(path);
+    assert!(!db.stats().recovery.clean_shutdown);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_get_all_versions() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    db.insert(b"key".to_vec(), b"first".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"key".to_vec(), b"second".to_vec()).unwrap();
+    db.flush().unwrap();
+
+    let versions = db.get_all_versions(b"key").unwrap();
+    assert_eq!(versions.len(), 2);
+    assert!(matches!(versions[0].0, ReadSource::SSTable { level: 0, .. }));
+    assert_eq!(versions[0].1, Some(b"second".to_vec()));
+    assert_eq!(versions[1].1, Some(b"first".to_vec()));
+
+    // Tombstones are reported too, ahead of the values they hide
+    db.remove(b"key").unwrap();
+    let versions = db.get_all_versions(b"key").unwrap();
+    assert_eq!(versions.len(), 3);
+    assert_eq!(versions[0], (ReadSource::Memtable, None));
+
+    assert!(db.get_all_versions(b"missing").unwrap().is_empty());
+}
+
+// Panics on every write, standing in for a bug deep in the write path
+#[derive(Debug)]
+struct PanickingTransform;
+
+impl ValueTransform for PanickingTransform {
+    fn id(&self) -> u8 {
+        8
+    }
+
+    fn on_write(&self, _value: &[u8]) -> Vec<u8> {
+        panic!("transform failed");
+    }
+
+    fn on_read(&self, stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(stored.to_vec())
+    }
+}
+
+#[test]
+fn test_catch_panics_returns_internal_error() {
+    let path = "db_data_test_catch_panics";
+    let mut test_db = TestDb::open_with_options(path, DBexOptions {
+        catch_panics: true,
+        value_transform: Some(Arc::new(PanickingTransform)),
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+    db.insert(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+
+    // The transform runs as the SSTable is written, so this panics deep inside flush
+    assert!(matches!(db.flush(), Err(DbexError::Internal(_))));
+
+    // The handle survives and still serves what it holds
+    assert_eq!(db.try_find(b"key1").unwrap(), Some(b"value1".to_vec()));
+}
+
+#[test]
+fn test_index_cache_lookups() {
+    let mut test_db = TestDb::with_options(DBexOptions {
+        index_cache_len: 8,
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+
+    for i in 0..300u32 {
+        db.insert(i, i.to_be_bytes().to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+
+    // Far more keys than the cache holds, each looked up twice
+    for _ in 0..2 {
+        for i in 0..300u32 {
+            assert_eq!(db.find(i).unwrap(), Some(i.to_be_bytes().to_vec()));
+        }
+    }
+
+    // The cache belongs to the old table, so newer values win once they're flushed
+    db.insert(7u32, b"updated".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.find(7u32).unwrap(), Some(b"updated".to_vec()));
+    assert_eq!(db.find(8u32).unwrap(), Some(8u32.to_be_bytes().to_vec()));
+}
+
+#[test]
+fn test_empty_key_survives_pruning() {
+    let path = "db_data_test_empty_key_pruning";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    db.insert(b"".to_vec(), b"empty_key".to_vec()).unwrap();
+    db.insert(b"a".to_vec(), b"a_value".to_vec()).unwrap();
+    db.flush().unwrap();
+    // A second table whose range starts above the empty key
+    db.insert(b"b".to_vec(), b"b_value".to_vec()).unwrap();
+    db.flush().unwrap();
+
+    assert_eq!(db.find(b"").unwrap(), Some(b"empty_key".to_vec()));
+    assert_eq!(db.find(b"a").unwrap(), Some(b"a_value".to_vec()));
+    assert_eq!(db.find(b"b").unwrap(), Some(b"b_value".to_vec()));
+    assert_eq!(db.range(b"", b"b").unwrap().count(), 2);
+
+    // Bounds recomputed from the index on reopen behave the same
+    db.close().unwrap();
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"").unwrap(), Some(b"empty_key".to_vec()));
+    assert_eq!(db.find(b"b").unwrap(), Some(b"b_value".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_merge_from() {
+    let load = |db: &mut DBex, entries: &[(&[u8], &[u8])]| {
+        for (key, value) in entries {
+            db.insert(key.to_vec(), value.to_vec()).unwrap();
+        }
+    };
+    let mut other_db = TestDb::new();
+    let other = other_db.db();
+    load(other, &[(b"a", b"other_a"), (b"b", b"other_b"), (b"d", b"other_d")]);
+    other.flush().unwrap();
+    other.insert(b"c".to_vec(), b"other_c".to_vec()).unwrap();
+    other.remove(b"d").unwrap();
+
+    // Default: values already in self win
+    let mut target_db = TestDb::new();
+    let target = target_db.db();
+    load(target, &[(b"b", b"self_b"), (b"c", b"self_c"), (b"e", b"self_e")]);
+    target.flush().unwrap();
+    assert_eq!(target.merge_from(other).unwrap(), 1);
+    let merged: Vec<_> = target.range(b"a", b"z").unwrap().collect();
+    assert_eq!(merged, vec![
+        (b"a".to_vec(), b"other_a".to_vec()),
+        (b"b".to_vec(), b"self_b".to_vec()),
+        (b"c".to_vec(), b"self_c".to_vec()),
+        (b"e".to_vec(), b"self_e".to_vec()),
+    ]);
+
+    let mut target_db = TestDb::with_options(DBexOptions {
+        merge_conflict: MergeConflict::KeepOther,
+        ..DBexOptions::default()
+    });
+    let target = target_db.db();
+    load(target, &[(b"b", b"self_b"), (b"c", b"self_c"), (b"e", b"self_e")]);
+    assert_eq!(target.merge_from(other).unwrap(), 3);
+    assert_eq!(target.find(b"b").unwrap(), Some(b"other_b".to_vec()));
+    assert_eq!(target.find(b"c").unwrap(), Some(b"other_c".to_vec()));
+    assert_eq!(target.find(b"d").unwrap(), None);
+    assert_eq!(target.find(b"e").unwrap(), Some(b"self_e".to_vec()));
+
+    // The source is untouched
+    assert_eq!(other.find(b"a").unwrap(), Some(b"other_a".to_vec()));
+}
+
+#[test]
+fn test_dictionary_compression_shrinks_similar_values() {
+    let doc = |i: u32| format!(
+        r#"{{"id":{},"user":"user_{}","status":"active","tags":["alpha","beta"],"score":{}}}"#,
+        i, i % 97, i * 7 % 1000
+    ).into_bytes();
+    let table_size = |path: &str, flush_info: &FlushInfo| {
+        let codec_len = data_files(path).iter()
+            .filter_map(|data_path| fs::metadata(format!("{}.codec", data_path.display())).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>();
+        flush_info.size_bytes + codec_len
+    };
+
+    let mut sizes = Vec::new();
+    for (name, compression) in [
+        ("none", Compression::None),
+        ("zstd", Compression::Zstd { level: 3 }),
+        ("dictionary", Compression::ZstdDictionary { level: 3, max_dict_len: 4096 }),
+    ] {
+        let path = format!("db_data_test_compression_{}", name);
+        fs::remove_dir_all(&path).ok();
+        let options = DBexOptions { compression, ..DBexOptions::default() };
+        let mut db = DBex::open_with_options(&path, options.clone());
+
+        for i in 0..2000 {
+            db.insert(i, doc(i)).unwrap();
+        }
+        let flush_info = db.flush().unwrap().unwrap();
+        sizes.push(table_size(&path, &flush_info));
+
+        assert_eq!(db.find(1234u32).unwrap(), Some(doc(1234)));
+        assert_eq!(db.range(&0u32.to_be_bytes(), &2000u32.to_be_bytes()).unwrap().count(), 2000);
+
+        // The codec is read back on open, and compaction output stays readable
+        drop(db);
+        let mut db = DBex::open_with_options(&path, options);
+        for flush in 0..10 {
+            db.insert(5000 + flush, doc(5000 + flush)).unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(db.stats().compactions, 1);
+        assert_eq!(db.find(1234u32).unwrap(), Some(doc(1234)));
+        assert_eq!(db.find(5003u32).unwrap(), Some(doc(5003)));
+        db.purge().unwrap();
+    }
+
+    let [uncompressed, per_value, dictionary] = sizes[..] else { unreachable!() };
+    println!("uncompressed {} bytes, per-value zstd {} bytes, dictionary zstd {} bytes", uncompressed, per_value, dictionary);
+    // Values this small barely compress alone; sizes include the (uncompressed) index
+    assert!(dictionary * 2 < per_value);
+    assert!(dictionary * 3 < uncompressed * 2);
+}
+
+#[test]
+fn test_composite_keys() {
+    let component_lists: Vec<Vec<&[u8]>> = vec![
+        vec![b"", b"z"],
+        vec![b"a", b""],
+        vec![b"a", b"\x00"],
+        vec![b"a", b"\x00\x00"],
+        vec![b"a", b"\x01"],
+        vec![b"a", b"b"],
+        vec![b"a\x00", b""],
+        vec![b"a\x00b", b"a"],
+        vec![b"ab", b""],
+        vec![b"a|b", b"c"],
+        vec![b"b"],
+    ];
+    let keys: Vec<CompositeKey> = component_lists.iter()
+        .map(|components| components.iter().fold(CompositeKey::new(), |key, component| key.push(*component)))
+        .collect();
+
+    // Encoded keys sort component by component, even across separators and escapes
+    let mut sorted = component_lists.clone();
+    sorted.sort();
+    assert_eq!(component_lists, sorted);
+    assert!(keys.windows(2).all(|pair| pair[0].as_bytes() < pair[1].as_bytes()));
+
+    for (key, components) in keys.iter().zip(&component_lists) {
+        let decoded = CompositeKey::decode(key.as_bytes()).unwrap();
+        assert_eq!(decoded, components.iter().map(|c| c.to_vec()).collect::<Vec<_>>());
+    }
+    assert_eq!(CompositeKey::decode(b"a"), None);
+    assert_eq!(CompositeKey::decode(b"a\x00\x07"), None);
+
+    // Integer components keep numeric order, and a leading component works as a scan prefix
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+    for (tenant, timestamp) in [("acme", 300u64), ("acme", 2), ("acme|x", 1), ("ac", 5), ("acme", 10)] {
+        let key = CompositeKey::new().push(tenant).push(timestamp);
+        db.insert(key, timestamp.to_string().into_bytes()).unwrap();
+    }
+    let tenant_prefix = CompositeKey::new().push("acme");
+    let values: Vec<Vec<u8>> = db.scan_prefix(tenant_prefix.as_bytes()).unwrap().into_iter().map(|(_, v)| v).collect();
+    assert_eq!(values, vec![b"2".to_vec(), b"10".to_vec(), b"300".to_vec()]);
+}
+
+#[test]
+fn test_direct_flush() {
+    let path = "db_data_test_direct_flush";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { direct_flush: true, ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+
+    for i in 0..100u32 {
+        db.insert(i, b"value".to_vec()).unwrap();
+    }
+    db.remove(7u32).unwrap();
+
+    // A manifest that can't be written fails the flush; the entries stay in the active
+    // memtable rather than a frozen one
+    fs::create_dir(PathBuf::from(path).join("MANIFEST.tmp")).unwrap();
+    assert!(db.flush().is_err());
+    assert_eq!(db.memtable_state(), MemTableState::Active);
+    assert_eq!(db.memtable().len(), 100);
+    assert_eq!(db.cnt_of_l0_ss_tables(), 0);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value".to_vec()));
+    fs::remove_dir(PathBuf::from(path).join("MANIFEST.tmp")).unwrap();
+
+    let flush_info = db.flush().unwrap().unwrap();
+    assert_eq!(flush_info.entry_count, 100);
+    assert_eq!(db.memtable_state(), MemTableState::Active);
+    assert!(db.memtable().is_empty());
+    assert_eq!(db.cnt_of_l0_ss_tables(), 1);
+    assert_eq!(db.flush().unwrap(), None);
+
+    // A memtable frozen by hand is still written before the active one
+    db.insert(b"shadowed".to_vec(), b"old".to_vec()).unwrap();
+    assert!(db.freeze_memtable().unwrap());
+    db.insert(b"shadowed".to_vec(), b"new".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.cnt_of_l0_ss_tables(), 3);
+    drop(db);
+
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value".to_vec()));
+    assert!(db.find_borrowed(7u32).unwrap().is_none());
+    assert_eq!(db.find(b"shadowed").unwrap(), Some(b"new".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_remove_survives_flush() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    db.insert(b"deleted".to_vec(), b"old".to_vec()).unwrap();
+    db.insert(b"kept".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+
+    // The tombstone in the memtable hides the flushed value
+    db.remove(b"deleted").unwrap();
+    assert_eq!(db.find(b"deleted").unwrap(), None);
+
+    // And so does the tombstone's own table once it is flushed too
+    db.flush().unwrap();
+    assert_eq!(db.cnt_of_l0_ss_tables(), 2);
+    assert_eq!(db.find(b"deleted").unwrap(), None);
+    assert_eq!(db.find(b"kept").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.multi_get(&[&b"deleted"[..], b"kept"]).unwrap(), vec![None, Some(b"value".to_vec())]);
+}
+
+#[test]
+fn test_insert_reader() {
+    let path = "db_data_test_insert_reader";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    let blob: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+    // An earlier write of the key still in the memtable is flushed out of the way
+    db.insert(b"blob".to_vec(), b"small".to_vec()).unwrap();
+    db.insert(b"other".to_vec(), b"value".to_vec()).unwrap();
+    db.insert_reader(b"blob".to_vec(), Cursor::new(&blob), blob.len()).unwrap();
+    assert!(db.memtable().is_empty());
+    assert_eq!(db.cnt_of_l0_ss_tables(), 2);
+    assert_eq!(db.find(b"blob").unwrap(), Some(blob.clone()));
+    assert_eq!(db.lsn_of(b"blob").unwrap(), Some(db.current_lsn() - 1));
+
+    // A reader that runs dry leaves nothing behind
+    let tables = data_files(path).len();
+    let err = db.insert_reader(b"short".to_vec(), Cursor::new(vec![1u8; 10]), 100).unwrap_err();
+    assert!(matches!(err, DbexError::Io(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof), "{:?}", err);
+    assert_eq!(data_files(path).len(), tables);
+    assert!(db.find_borrowed(b"short").unwrap().is_none());
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"blob").unwrap(), Some(blob));
+    assert_eq!(db.find(b"other").unwrap(), Some(b"value".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_len_counts_live_keys() {
+    let path = "db_data_test_len_counts_live_keys";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    // Removing a key that was never there, or twice, neither panics nor counts
+    db.remove(b"missing").unwrap();
+    assert_eq!(db.len().unwrap(), 0);
+    assert!(db.is_empty().unwrap());
+    for i in 0..10u32 {
+        db.insert(i, b"value".to_vec()).unwrap();
+    }
+    db.insert(3u32, b"overwrite".to_vec()).unwrap();
+    db.remove(4u32).unwrap();
+    db.remove(4u32).unwrap();
+    db.remove(b"missing").unwrap();
+    assert_eq!(db.len().unwrap(), 9);
+
+    // The count holds up across flushes, tombstones in tables and a reopen
+    db.flush().unwrap();
+    db.insert(4u32, b"back".to_vec()).unwrap();
+    db.remove(5u32).unwrap();
+    db.flush().unwrap();
+    db.remove(5u32).unwrap();
+    db.insert(0u32, b"overwrite".to_vec()).unwrap();
+    assert_eq!(db.len().unwrap(), 9);
+    assert_eq!(db.remove_many(&[6u32.to_be_bytes().to_vec(), 5u32.to_be_bytes().to_vec()]).unwrap(), 1);
+    assert_eq!(db.len().unwrap(), 8);
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.len().unwrap(), 8);
+    db.bulk_load([(b"bulk".to_vec(), b"value".to_vec())]).unwrap();
+    assert_eq!(db.len().unwrap(), 9);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_frozen_memtable_stays_readable() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    db.insert(b"frozen".to_vec(), b"old".to_vec()).unwrap();
+    db.insert(b"shadowed".to_vec(), b"old".to_vec()).unwrap();
+    assert_eq!(db.memtable_state(), MemTableState::Active);
+
+    // Freezing stands in for a flush that hasn't finished yet
+    assert!(db.freeze_memtable().unwrap());
+    assert_eq!(db.memtable_state(), MemTableState::Frozen);
+    assert!(db.memtable().is_empty());
+    assert_eq!(db.cnt_of_l0_ss_tables(), 0);
+    // Only one memtable can be frozen at a time
+    db.insert(b"shadowed".to_vec(), b"new".to_vec()).unwrap();
+    assert!(!db.freeze_memtable().unwrap());
+
+    assert_eq!(db.find(b"frozen").unwrap(), Some(b"old".to_vec()));
+    assert_eq!(db.find(b"shadowed").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.get_all_versions(b"shadowed").unwrap().len(), 2);
+    assert_eq!(db.scan_prefix(b"").unwrap().len(), 2);
+
+    // The frozen memtable is written first, so the active one's newer value wins
+    db.flush().unwrap();
+    assert_eq!(db.memtable_state(), MemTableState::Active);
+    assert_eq!(db.cnt_of_l0_ss_tables(), 2);
+    assert_eq!(db.find(b"frozen").unwrap(), Some(b"old".to_vec()));
+    assert_eq!(db.find(b"shadowed").unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn test_lsn_survives_restart() {
+    let path = "db_data_test_lsn_survives_restart";
+    fs::remove_dir_all(path).ok();
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.current_lsn(), 0);
+    db.insert(b"a".to_vec(), b"1".to_vec()).unwrap();
+    db.start_txn();
+    db.insert(b"b".to_vec(), b"2".to_vec()).unwrap();
+    db.remove(b"a").unwrap();
+    // Staged writes take their LSNs on commit
+    assert_eq!(db.current_lsn(), 1);
+    db.commit_txn().unwrap();
+    assert_eq!(db.current_lsn(), 3);
+
+    // The flush emptied the WAL, so the manifest carries the LSN over
+    db.flush().unwrap();
+    drop(db);
+    let mut db = DBex::open(path);
+    assert_eq!(db.current_lsn(), 3);
+    db.insert(b"c".to_vec(), b"3".to_vec()).unwrap();
+
+    // Unflushed writes are recovered from the WAL
+    drop(db);
+    let db = DBex::open(path);
+    assert_eq!(db.current_lsn(), 4);
+    db.close().unwrap();
+
+    let db = DBex::open_read_only(path).unwrap();
+    assert_eq!(db.current_lsn(), 4);
+    drop(db);
+
+    // A seed below the recorded LSN is ignored, one above it is honoured
+    let options = DBexOptions { start_lsn: 2, ..DBexOptions::default() };
+    let db = DBex::open_with_options(path, options);
+    assert_eq!(db.current_lsn(), 4);
+    drop(db);
+    let options = DBexOptions { start_lsn: 100, ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.current_lsn(), 100);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_crash_consistency() {
+    let value = |i: usize| format!("value_{:0>90}", i).into_bytes();
+    let mut ops = Vec::new();
+    for i in 0..150 {
+        ops.push(CrashOp::Insert(format!("key_{:03}", i % 60).into_bytes(), value(i)));
+        if i % 7 == 3 {
+            ops.push(CrashOp::Remove(format!("key_{:03}", i % 60).into_bytes()));
+        }
+        if i % 50 == 49 {
+            ops.push(CrashOp::Flush);
+        }
+    }
+
+    let outcomes = DBex::crash_consistency_test("db_data_test_crash_consistency", &ops, 40, 0x5eed).unwrap();
+    assert_eq!(outcomes.len(), 40);
+    assert!(outcomes.iter().any(|outcome| outcome.torn_flush));
+
+    // Unflushed writes come back from the WAL, minus what was still buffered or torn off.
+    // Without the WAL nothing past the last flush would survive.
+    let wal_only: Vec<CrashOp> = (0..200)
+        .map(|i| CrashOp::Insert(format!("key_{:03}", i).into_bytes(), value(i)))
+        .collect();
+    let outcomes = DBex::crash_consistency_test("db_data_test_crash_consistency_wal", &wal_only, 10, 7).unwrap();
+    let late_crashes: Vec<_> = outcomes.iter().filter(|outcome| outcome.crash_after >= 100).collect();
+    assert!(!late_crashes.is_empty());
+    assert!(late_crashes.iter().all(|outcome| outcome.recovered_ops > 0));
+}
+
+#[test]
+fn test_find_borrowed_large_value() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    let large_value: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    db.insert(b"large".to_vec(), large_value.clone()).unwrap();
+    db.insert(b"deleted".to_vec(), b"old".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.remove(b"deleted").unwrap();
+    db.insert(b"in_memory".to_vec(), b"value".to_vec()).unwrap();
+
+    let value = db.find_borrowed(b"large").unwrap().unwrap();
+    assert!(value.is_mapped());
+    assert_eq!(&*value, large_value.as_slice());
+
+    let in_memory = db.find_borrowed(b"in_memory").unwrap().unwrap();
+    assert!(!in_memory.is_mapped());
+    assert_eq!(&*in_memory, b"value");
+    assert!(db.find_borrowed(b"deleted").unwrap().is_none());
+    assert!(db.find_borrowed(b"missing").unwrap().is_none());
+
+    // Compaction deletes the table the value points into; the borrow keeps it readable
+    for flush in 0..10u32 {
+        db.insert(flush, b"filler".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.stats().compactions, 1);
+    assert_eq!(&*value, large_value.as_slice());
+    assert!(db.find_borrowed(b"large").unwrap().unwrap().is_mapped());
+
+    // Compressed tables have to decompress into a Vec
+    let mut test_db = TestDb::with_options(DBexOptions {
+        compression: Compression::Zstd { level: 3 },
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+    db.insert(b"large".to_vec(), large_value.clone()).unwrap();
+    db.flush().unwrap();
+    let value = db.find_borrowed(b"large").unwrap().unwrap();
+    assert!(!value.is_mapped());
+    assert_eq!(&*value, large_value.as_slice());
+}
+
+#[test]
+fn test_wal_replay_batch_size() {
+    let path = "db_data_test_wal_replay_batch_size";
+    fs::remove_dir_all(path).ok();
+
+    let num_keys = 1000u32;
+    let mut db = DBex::open(path);
+    for i in 0..num_keys {
+        db.insert(i, format!("value_{}", i).into_bytes()).unwrap();
+    }
+    db.remove(7u32).unwrap();
+    // Flushes the WAL's buffer without flushing the memtable, like a crash after the writes
+    drop(db);
+
+    let options = DBexOptions { replay_batch_size: Some(300), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+    assert_eq!(db.stats().recovery.wal_entries_replayed, num_keys as u64 + 1);
+    // Three full batches, then the remaining 101 entries flushed at the end of replay
+    assert_eq!(db.cnt_of_l0_ss_tables(), 4);
+    assert!(db.memtable().is_empty());
+    assert_eq!(db.range(&0u32.to_be_bytes(), &num_keys.to_be_bytes()).unwrap().count(), num_keys as usize - 1);
+    assert_eq!(db.find(999u32).unwrap(), Some(b"value_999".to_vec()));
+
+    // Recovery cleared the WAL once everything was in tables, so a second crash has
+    // nothing to replay and loses nothing
+    drop(db);
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.stats().recovery.wal_entries_replayed, 0);
+    assert_eq!(db.range(&0u32.to_be_bytes(), &num_keys.to_be_bytes()).unwrap().count(), num_keys as usize - 1);
+    assert_eq!(db.find(0u32).unwrap(), Some(b"value_0".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_memory_storage() {
+    let path = "db_data_test_memory_storage";
+    let storage = MemoryStorage::new();
+    let options = DBexOptions { storage: Some(Arc::new(storage.clone())), ..DBexOptions::default() };
+
+    let mut db = DBex::try_open_with_options(path, options.clone()).unwrap();
+    for flush in 0..11u32 {
+        for i in 0..50u32 {
+            db.insert(flush * 1000 + i, format!("value_{}_{}", flush, i).into_bytes()).unwrap();
+        }
+        db.remove(flush * 1000).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.stats().compactions, 1);
+    assert_eq!(db.count_in_level(1).0, 1);
+    assert_eq!(db.find(3007u32).unwrap(), Some(b"value_3_7".to_vec()));
+    assert_eq!(db.find_borrowed(10049u32).unwrap().as_deref(), Some(&b"value_10_49"[..]));
+    assert_eq!(db.range(&0u32.to_be_bytes(), &u32::MAX.to_be_bytes()).unwrap().count(), 11 * 49);
+
+    // The storage holds the lock, the WAL and the manifest just like a directory would
+    assert!(matches!(DBex::try_open_with_options(path, options.clone()), Err(DbexError::Locked(_))));
+    db.insert(b"unflushed".to_vec(), b"value".to_vec()).unwrap();
+    db.flush_all_levels_to_disk().unwrap();
+    db.insert(b"in_wal".to_vec(), b"value".to_vec()).unwrap();
+    drop(db);
+
+    assert!(!fs::exists(path).unwrap());
+    assert!(storage.size_bytes() > 0);
+    let mut db = DBex::try_open_with_options(path, options).unwrap();
+    assert_eq!(db.count_in_level(1).0, 1);
+    assert_eq!(db.find(b"unflushed").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.find(b"in_wal").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.find(3007u32).unwrap(), Some(b"value_3_7".to_vec()));
+
+    // Only the empty database's manifest is left
+    db.purge().unwrap();
+    assert_eq!(storage.size_bytes(), storage.read(&Path::new(path).join("MANIFEST")).unwrap().len() as u64);
+}
+
+#[test]
+fn test_multi_get_groups_keys_by_table() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    // Five tables with interleaved key ranges, newer tables overwriting some keys
+    for table in 0..5u32 {
+        for i in (table..2000).step_by(5 + table as usize) {
+            db.insert(i, format!("value_{}_{}", table, i).into_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    db.insert(3u32, b"in_memtable".to_vec()).unwrap();
+
+    let keys: Vec<[u8; 4]> = (0..2500u32).rev().step_by(3).chain([3, 3, 7]).map(u32::to_be_bytes).collect();
+
+    let seeks_before = db.index_seeks();
+    let expected: Vec<Option<Vec<u8>>> = keys.iter().map(|key| db.find(key).unwrap()).collect();
+    let naive_seeks = db.index_seeks() - seeks_before;
+
+    let seeks_before = db.index_seeks();
+    let values = db.multi_get(&keys).unwrap();
+    let batched_seeks = db.index_seeks() - seeks_before;
+
+    assert_eq!(values, expected);
+    assert_eq!(values[values.len() - 3], Some(b"in_memtable".to_vec()));
+    assert!(values.iter().any(Option::is_none));
+    assert!(batched_seeks * 10 < naive_seeks, "{} batched vs {} naive seeks", batched_seeks, naive_seeks);
+}
+
+#[test]
+fn test_recover_to_lsn() {
+    let path = "db_data_test_recover_to_lsn";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { archive_wal: true, ..DBexOptions::default() };
+
+    let mut db = DBex::open_with_options(path, options.clone());
+    for i in 0..200u32 {
+        db.insert(i, format!("value_{}", i).into_bytes()).unwrap();
+        if i % 100 == 99 {
+            db.flush().unwrap();
+        }
+    }
+    let before_delete = db.current_lsn();
+
+    // A bad bulk delete, flushed and followed by more writes
+    for i in 0..150u32 {
+        db.remove(i).unwrap();
+    }
+    db.flush().unwrap();
+    db.insert(500u32, b"after".to_vec()).unwrap();
+    assert!(db.find_borrowed(10u32).unwrap().is_none());
+    assert_eq!(fs::read_dir(Path::new(path).join("wal_archive")).unwrap().count(), 3);
+
+    assert_eq!(db.recover_to_lsn(before_delete).unwrap(), 200);
+    assert_eq!(db.range(&0u32.to_be_bytes(), &1000u32.to_be_bytes()).unwrap().count(), 200);
+    assert_eq!(db.find(10u32).unwrap(), Some(b"value_10".to_vec()));
+    assert_eq!(db.find(500u32).unwrap(), None);
+    // LSNs carry on after the discarded writes
+    assert_eq!(db.current_lsn(), before_delete + 151);
+
+    // The recovered history replaces the archive, so it survives a restart and can be
+    // recovered from again
+    drop(db);
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(199u32).unwrap(), Some(b"value_199".to_vec()));
+    assert_eq!(db.recover_to_lsn(100).unwrap(), 100);
+    assert_eq!(db.find(100u32).unwrap(), None);
+    assert_eq!(db.find(99u32).unwrap(), Some(b"value_99".to_vec()));
+
+    let mut db_without_archive = DBex::open("db_data_test_recover_to_lsn_no_archive");
+    assert!(matches!(db_without_archive.recover_to_lsn(0), Err(DbexError::WalNotArchived)));
+    db_without_archive.purge().unwrap();
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_list_sstables() {
+    let path = "db_data_test_list_sstables";
+    fs::remove_dir_all(path).ok();
+
+    let mut db = DBex::open(path);
+    for flush in 0..13u32 {
+        for i in 0..20u32 {
+            db.insert(flush * 100 + i, vec![b'v'; 32]).unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    // The first 11 flushes were compacted into L1, the last two are still in L0
+    let sstables: Vec<SSTableInfo> = db.list_sstables();
+    let levels: Vec<usize> = sstables.iter().map(|info| info.level).collect();
+    assert_eq!(levels, vec![0, 0, 1]);
+    assert_eq!(sstables[0].min_key, 1100u32.to_be_bytes().to_vec());
+    assert_eq!(sstables[1].max_key, 1219u32.to_be_bytes().to_vec());
+    assert_eq!(sstables[2].min_key, 0u32.to_be_bytes().to_vec());
+    assert_eq!(sstables[2].max_key, 1019u32.to_be_bytes().to_vec());
+    assert_eq!(sstables[2].entry_count, 220);
+
+    let files_on_disk = fs::read_dir(Path::new(path).join("ss_tables")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|file| file.extension().is_some_and(|extension| extension == "db"))
+        .count();
+    assert_eq!(files_on_disk, sstables.len());
+    for info in &sstables {
+        let on_disk = fs::metadata(&info.data_path).unwrap().len() + fs::metadata(&info.index_path).unwrap().len();
+        assert_eq!(info.size_bytes, on_disk);
+    }
+    let l0_entries: u64 = sstables.iter().filter(|info| info.level == 0).map(|info| info.entry_count).sum();
+    assert_eq!(db.count_in_level(0).1, l0_entries);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_open_unwritable_path() {
+    // A regular file where the data directory should go; unlike permission bits, this
+    // can't be bypassed by running as root
+    let blocker = "db_data_test_open_unwritable_path";
+    fs::remove_dir_all(blocker).ok();
+    fs::write(blocker, b"not a directory").unwrap();
+
+    let result = DBex::try_open(Path::new(blocker).join("db"));
+    assert!(matches!(result, Err(DbexError::Io(_))));
+    fs::remove_file(blocker).unwrap();
+}
+
+#[test]
+fn test_coalesce_tiny_tables() {
+    let path = "db_data_test_coalesce_tiny_tables";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { coalesce_below_bytes: Some(16 * 1024), ..DBexOptions::default() };
+
+    let mut db = DBex::open_with_options(path, options);
+    // A large table first, which stays as it is
+    for i in 0..1000u32 {
+        db.insert(i, vec![b'x'; 64]).unwrap();
+    }
+    db.flush().unwrap();
+    for flush in 1..6u32 {
+        for i in 0..5u32 {
+            db.insert(flush * 1000 + i, format!("value_{}", flush).into_bytes()).unwrap();
+        }
+        db.remove(flush).unwrap();
+        db.flush().unwrap();
+    }
+
+    // Well short of the L0 count trigger, the five tiny tables were merged into one
+    let sstables = db.list_sstables();
+    assert_eq!(sstables.len(), 2);
+    assert!(sstables[0].size_bytes >= 16 * 1024);
+    assert_eq!(sstables[1].min_key, 1u32.to_be_bytes().to_vec());
+    assert_eq!(sstables[1].max_key, 5004u32.to_be_bytes().to_vec());
+    assert_eq!(db.stats().compactions, 4);
+
+    // Tombstones still shadow the large table, and the newest value still wins
+    assert!(db.find_borrowed(3u32).unwrap().is_none());
+    assert_eq!(db.find(3001u32).unwrap(), Some(b"value_3".to_vec()));
+    assert_eq!(db.find(999u32).unwrap(), Some(vec![b'x'; 64]));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_reads_during_flush_swap() {
+    let path = "db_data_test_reads_during_flush_swap";
+    fs::remove_dir_all(path).ok();
+
+    let db = Arc::new(Mutex::new(DBex::open(path)));
+    db.lock().unwrap().insert(b"hot".to_vec(), b"0".to_vec()).unwrap();
+
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for round in 1..=30u32 {
+                let mut db = db.lock().unwrap();
+                db.insert(b"hot".to_vec(), round.to_string().into_bytes()).unwrap();
+                db.insert(round, vec![0; 16]).unwrap();
+                if round % 2 == 0 {
+                    db.freeze_memtable().unwrap();
+                } else {
+                    db.flush().unwrap();
+                }
+            }
+        })
+    };
+
+    let mut last_seen = 0;
+    while !writer.is_finished() {
+        let value = db.lock().unwrap().find(&b"hot"[..]).unwrap().expect("hot key missing mid-flush");
+        let round: u32 = String::from_utf8(value).unwrap().parse().unwrap();
+        assert!(round >= last_seen);
+        last_seen = round;
+    }
+    writer.join().unwrap();
+
+    let mut db = db.lock().unwrap();
+    assert_eq!(db.find(&b"hot"[..]).unwrap(), Some(b"30".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_truncate() {
+    let path = "db_data_test_truncate";
+    let sibling_path = "db_data_test_truncate/sibling";
+    fs::remove_dir_all(path).ok();
+
+    let mut sibling = DBex::open(sibling_path);
+    sibling.insert(1u32, b"kept".to_vec()).unwrap();
+    sibling.flush().unwrap();
+
+    let mut db = DBex::open(path);
+    for i in 0..50u32 {
+        db.insert(i, b"flushed".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for i in 50..60u32 {
+        db.insert(i, b"in memory".to_vec()).unwrap();
+    }
+
+    db.truncate().unwrap();
+    assert_eq!(db.range(&0u32.to_be_bytes(), &100u32.to_be_bytes()).unwrap().count(), 0);
+    assert!(db.list_sstables().is_empty());
+    assert_eq!(db.find(55u32).unwrap(), None);
+
+    db.insert(7u32, b"after".to_vec()).unwrap();
+    assert_eq!(db.find(7u32).unwrap(), Some(b"after".to_vec()));
+
+    // Nothing from before the truncate comes back from the WAL after a crash
+    drop(db);
+    let mut db = DBex::open(path);
+    assert_eq!(db.range(&0u32.to_be_bytes(), &100u32.to_be_bytes()).unwrap().count(), 1);
+    assert_eq!(sibling.find(1u32).unwrap(), Some(b"kept".to_vec()));
+
+    drop(sibling);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_purge_leaves_usable_database() {
+    let path = "db_data_test_purge_leaves_usable_database";
+    fs::remove_dir_all(path).ok();
+
+    let mut db = DBex::open(path);
+    for i in 0..50u32 {
+        db.insert(i, b"flushed".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    // One in the active memtable and one frozen mid-flush
+    db.insert(50u32, b"in memory".to_vec()).unwrap();
+    db.freeze_memtable().unwrap();
+    db.insert(51u32, b"in memory".to_vec()).unwrap();
+
+    db.purge().unwrap();
+    assert_eq!(db.find(0u32).unwrap(), None);
+    assert_eq!(db.find(50u32).unwrap(), None);
+    assert_eq!(db.find(51u32).unwrap(), None);
+    assert_eq!(db.len().unwrap(), 0);
+    assert!(db.list_sstables().is_empty());
+    // Another handle still can't open it
+    assert!(Path::new(path).join("LOCK").exists());
+    assert!(DBex::try_open(path).is_err());
+
+    db.insert(7u32, b"after".to_vec()).unwrap();
+    assert_eq!(db.len().unwrap(), 1);
+    db.flush().unwrap();
+    assert_eq!(db.list_sstables().len(), 1);
+    assert_eq!(db.find(7u32).unwrap(), Some(b"after".to_vec()));
+
+    // A crash brings back only what was written after the purge
+    db.insert(8u32, b"after".to_vec()).unwrap();
+    drop(db);
+    let mut db = DBex::open(path);
+    assert_eq!(db.len().unwrap(), 2);
+    assert_eq!(db.find(0u32).unwrap(), None);
+    assert_eq!(db.find(8u32).unwrap(), Some(b"after".to_vec()));
+    drop(db);
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_lsn_of() {
+    let path = "db_data_test_lsn_of";
+    fs::remove_dir_all(path).ok();
+
+    let mut db = DBex::open(path);
+    let first_lsn = db.current_lsn();
+    db.insert(1u32, b"first".to_vec()).unwrap();
+    db.insert(2u32, b"other".to_vec()).unwrap();
+    assert_eq!(db.lsn_of(1u32).unwrap(), Some(first_lsn));
+    assert_eq!(db.lsn_of(3u32).unwrap(), None);
+
+    db.insert(1u32, b"second".to_vec()).unwrap();
+    let overwritten_lsn = db.lsn_of(1u32).unwrap().unwrap();
+    assert!(overwritten_lsn > first_lsn);
+
+    // The LSN is kept through a flush, compaction into L1 and a restart
+    for round in 0..11u32 {
+        db.insert(100 + round, vec![0; 8]).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.cnt_of_l1_ss_tables(), 1);
+    assert_eq!(db.lsn_of(1u32).unwrap(), Some(overwritten_lsn));
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.lsn_of(1u32).unwrap(), Some(overwritten_lsn));
+    assert_eq!(db.lsn_of(2u32).unwrap(), Some(first_lsn + 1));
+
+    db.insert(1u32, b"third".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert!(db.lsn_of(1u32).unwrap().unwrap() > overwritten_lsn);
+    db.remove(1u32).unwrap();
+    assert_eq!(db.lsn_of(1u32).unwrap(), None);
+    db.flush().unwrap();
+    assert_eq!(db.lsn_of(1u32).unwrap(), None);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_compare_and_swap() {
+    let path = "db_data_test_compare_and_swap";
+    fs::remove_dir_all(path).ok();
+
+    let mut db = DBex::open(path);
+    // Absent as expected
+    let created_lsn = db.compare_and_swap(1u32, None, b"v1".to_vec()).unwrap();
+    assert_eq!(db.lsn_of(1u32).unwrap(), Some(created_lsn));
+
+    // Present when absence was expected
+    match db.compare_and_swap(1u32, None, b"clobbered".to_vec()) {
+        Err(CasError::Conflict { current_lsn }) => assert_eq!(current_lsn, Some(created_lsn)),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+
+    // Matching LSN, also once the key is only in an SSTable
+    db.flush().unwrap();
+    let updated_lsn = db.compare_and_swap(1u32, Some(created_lsn), b"v2".to_vec()).unwrap();
+    assert!(updated_lsn > created_lsn);
+
+    // Stale LSN
+    match db.compare_and_swap(1u32, Some(created_lsn), b"lost update".to_vec()) {
+        Err(CasError::Conflict { current_lsn }) => assert_eq!(current_lsn, Some(updated_lsn)),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(db.find(1u32).unwrap(), Some(b"v2".to_vec()));
+
+    // A removed key counts as absent again
+    db.remove(1u32).unwrap();
+    assert!(db.compare_and_swap(1u32, Some(updated_lsn), b"v3".to_vec()).is_err());
+    assert!(db.compare_and_swap(1u32, None, b"v3".to_vec()).is_ok());
+    assert_eq!(db.find(1u32).unwrap(), Some(b"v3".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_memory_usage() {
+    let path = "db_data_test_memory_usage";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { index_cache_len: 16, ..DBexOptions::default() };
+
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.memory_usage().total_bytes(), 0);
+
+    let mut last_memtable_bytes = 0;
+    for i in 0..1000u32 {
+        db.insert(i, vec![b'v'; 100]).unwrap();
+        if i % 100 == 99 {
+            let memtable_bytes = db.memory_usage().memtable_bytes;
+            assert!(memtable_bytes > last_memtable_bytes);
+            last_memtable_bytes = memtable_bytes;
+        }
+    }
+    assert!(last_memtable_bytes >= 1000 * 104);
+
+    db.flush().unwrap();
+    let usage = db.memory_usage();
+    assert_eq!(usage.memtable_bytes, 0);
+    // One sparse index point per 100 keys
+    assert_eq!(usage.sparse_index_bytes, 10 * (4 + 8));
+
+    db.find(5u32).unwrap();
+    assert!(db.memory_usage().cache_bytes > 0);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_ingest_rejects_unsorted_table() {
+    let source_dir = "db_data_test_ingest_rejects_unsorted_table_source";
+    fs::remove_dir_all(source_dir).ok();
+    fs::create_dir_all(source_dir).unwrap();
+
+    // Written by hand, with the index listing "b" before "a"
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage);
+    let mut unsorted = SSTable::new(storage, Path::new(source_dir), None).unwrap();
+    let b_len = unsorted.write_entry(&Some(b"value_b".to_vec())).unwrap();
+    unsorted.write_entry(&Some(b"value_a".to_vec())).unwrap();
+    unsorted.write_index(&[(b"b".to_vec(), 0), (b"a".to_vec(), b_len)]).unwrap();
+    unsorted.sync(SyncPolicy::None).unwrap();
+    let unsorted_path = unsorted.data_path().clone();
+
+    let mut target_db = TestDb::open("db_data_test_ingest_rejects_unsorted_table_target");
+    let target = target_db.db();
+    match target.ingest_sstable(&unsorted_path) {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("out of order"), "{}", reason),
+        other => panic!("expected the unsorted table to be rejected, got {:?}", other),
+    }
+    assert!(target.list_sstables().is_empty());
+    assert!(data_files("db_data_test_ingest_rejects_unsorted_table_target").is_empty());
+
+    // A well-formed table still goes in
+    let mut sorted = SSTable::new(Arc::new(LocalStorage), Path::new(source_dir), None).unwrap();
+    let a_len = sorted.write_entry(&Some(b"value_a".to_vec())).unwrap();
+    sorted.write_entry(&Some(b"value_b".to_vec())).unwrap();
+    sorted.write_index(&[(b"a".to_vec(), 0), (b"b".to_vec(), a_len)]).unwrap();
+    sorted.sync(SyncPolicy::None).unwrap();
+    target.ingest_sstable(sorted.data_path()).unwrap();
+    assert_eq!(target.find(&b"b"[..]).unwrap(), Some(b"value_b".to_vec()));
+
+    drop(target_db);
+    fs::remove_dir_all(source_dir).unwrap();
+}
+
+#[test]
+fn test_ingest_rejects_empty_table_even_when_trusted() {
+    let source_dir = "db_data_test_ingest_rejects_empty_table_source";
+    fs::remove_dir_all(source_dir).ok();
+    fs::create_dir_all(source_dir).unwrap();
+
+    let mut empty = SSTable::new(Arc::new(LocalStorage), Path::new(source_dir), None).unwrap();
+    empty.write_index(&[]).unwrap();
+    empty.sync(SyncPolicy::None).unwrap();
+    let empty_path = empty.data_path().clone();
+
+    // Skipping verification still doesn't let a table with nothing to merge in
+    let options = DBexOptions { trust_ingested_tables: true, ..DBexOptions::default() };
+    let target_path = "db_data_test_ingest_rejects_empty_table_target";
+    let mut target_db = TestDb::open_with_options(target_path, options);
+    let target = target_db.db();
+    target.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    target.flush().unwrap();
+    match target.ingest_sstable(&empty_path) {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("no entries"), "{}", reason),
+        other => panic!("expected the empty table to be rejected, got {:?}", other),
+    }
+    assert_eq!(target.list_sstables().len(), 1);
+    assert_eq!(data_files(target_path).len(), 1);
+    assert_eq!(target.find(&b"key"[..]).unwrap(), Some(b"value".to_vec()));
+
+    drop(target_db);
+    fs::remove_dir_all(source_dir).unwrap();
+}
+
+#[test]
+fn test_iter_tombstones() {
+    let path = "db_data_test_iter_tombstones";
+    fs::remove_dir_all(path).ok();
+
+    let mut db = DBex::open(path);
+    for i in 0..20u32 {
+        db.insert(i, b"value".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for i in [3u32, 7, 11] {
+        db.remove(i).unwrap();
+    }
+    // Reinserting a deleted key makes it live again
+    db.remove(15u32).unwrap();
+    db.insert(15u32, b"back".to_vec()).unwrap();
+
+    let expected: Vec<Vec<u8>> = [3u32, 7, 11].iter().map(|i| i.to_be_bytes().to_vec()).collect();
+    assert_eq!(db.iter_tombstones().unwrap().collect::<Vec<_>>(), expected);
+
+    // Still there once flushed, while L0 holds older copies beneath them
+    db.flush().unwrap();
+    assert_eq!(db.iter_tombstones().unwrap().collect::<Vec<_>>(), expected);
+
+    // Compacting into the bottom level drops them
+    for round in 0..9u32 {
+        db.insert(100 + round, b"filler".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.cnt_of_l1_ss_tables(), 1);
+    assert_eq!(db.iter_tombstones().unwrap().count(), 0);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_wal_coalesces_overwrites() {
+    let path = "db_data_test_wal_coalesces_overwrites";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { wal_coalesce_window: Some(100), ..DBexOptions::default() };
+
+    let mut db = DBex::open_with_options(path, options.clone());
+    db.insert(b"other".to_vec(), b"before".to_vec()).unwrap();
+    for i in 0..1000u32 {
+        db.insert(b"counter".to_vec(), i.to_string().into_bytes()).unwrap();
+    }
+    db.insert(b"other".to_vec(), b"after".to_vec()).unwrap();
+    // Crash without flushing
+    drop(db);
+
+    let wal_entries = WriteAheadLog::read_file(&LocalStorage, &Path::new(path).join("wals").join("cur.wal"), 0).unwrap();
+    assert_eq!(wal_entries.len(), 12);
+    let (key, value) = wal_entries.into_iter().nth(10).unwrap().into_key_value();
+    assert_eq!(key, Some(b"counter".to_vec()));
+    assert_eq!(value, Some(b"999".to_vec()));
+
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(&b"counter"[..]).unwrap(), Some(b"999".to_vec()));
+    assert_eq!(db.find(&b"other"[..]).unwrap(), Some(b"after".to_vec()));
+    assert_eq!(db.current_lsn(), 1002);
+    db.purge().unwrap();
+}
+
+// Every read path over the whole key space, with an inverted and an empty range thrown in
+fn assert_no_live_data(db: &mut DBex) {
+    let max_key = [0xFFu8; 8];
+    assert_eq!(db.range(&[], &max_key).unwrap().count(), 0);
+    assert_eq!(db.range(&max_key, &[]).unwrap().count(), 0);
+    assert_eq!(db.range(&[], &[]).unwrap().count(), 0);
+    assert_eq!(db.range_keys(&[], &max_key).unwrap().count(), 0);
+    assert_eq!(db.range_keys(&max_key, &[]).unwrap().count(), 0);
+    assert!(db.scan_prefix(&[]).unwrap().is_empty());
+    assert!(db.scan_prefix(b"k").unwrap().is_empty());
+    assert_eq!(db.multi_get(&[b"k1", b"k2"]).unwrap(), vec![None, None]);
+    assert_eq!(db.find(&b"k1"[..]).unwrap(), None);
+    assert!(db.find_borrowed(&b"k1"[..]).unwrap().is_none());
+    assert_eq!(db.lsn_of(&b"k1"[..]).unwrap(), None);
+}
+
+#[test]
+fn test_iterators_on_degenerate_databases() {
+    let path = "db_data_test_iterators_on_degenerate_databases";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    // Brand new
+    assert_no_live_data(&mut db);
+    assert_eq!(db.iter_tombstones().unwrap().count(), 0);
+    assert_eq!(db.flush().unwrap(), None);
+
+    // Only tombstones, in the memtable and then in an SSTable
+    db.remove(&b"k1"[..]).unwrap();
+    db.remove(&b"k2"[..]).unwrap();
+    assert_no_live_data(&mut db);
+    assert_eq!(db.iter_tombstones().unwrap().count(), 2);
+    db.flush().unwrap();
+    assert_eq!(db.list_sstables().len(), 1);
+    assert_no_live_data(&mut db);
+    assert_eq!(db.iter_tombstones().unwrap().count(), 2);
+
+    // Live data in the memtable only, then in SSTables only, all deleted again
+    db.insert(b"k1".to_vec(), b"v".to_vec()).unwrap();
+    db.remove(&b"k1"[..]).unwrap();
+    assert_no_live_data(&mut db);
+    db.insert(b"k2".to_vec(), b"v".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.remove(&b"k2"[..]).unwrap();
+    db.flush().unwrap();
+    assert_no_live_data(&mut db);
+
+    // Compacting nothing but tombstones into the bottom level leaves no table at all
+    assert_eq!(db.cnt_of_l0_ss_tables(), 3);
+    for _ in 0..8 {
+        db.remove(&b"k3"[..]).unwrap();
+        db.flush().unwrap();
+    }
+    assert!(db.list_sstables().is_empty());
+    assert_no_live_data(&mut db);
+    assert_eq!(db.iter_tombstones().unwrap().count(), 0);
+    db.purge().unwrap();
+}
+
+// SipHash with fixed keys, standing in for a user's own hasher
+#[derive(Debug)]
+struct SipBloomHasher;
+
+impl BloomHasher for SipBloomHasher {
+    fn id(&self) -> u8 {
+        200
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        let mut hasher = std::hash::DefaultHasher::new();
+        std::hash::Hasher::write(&mut hasher, key);
+        std::hash::Hasher::finish(&hasher)
+    }
+}
+
+#[test]
+fn test_bloom_hashers() {
+    let hashers: [Arc<dyn BloomHasher>; 3] = [Arc::new(Xxh3Hasher), Arc::new(Fnv1aHasher), Arc::new(SipBloomHasher)];
+    for hasher in hashers {
+        let mut bloom_filter = BloomFilter::new(10_000, hasher.clone());
+        for i in 0..10_000 {
+            bloom_filter.insert(format!("member_{}", i).as_bytes());
+        }
+        assert!((0..10_000).all(|i| bloom_filter.may_contain(format!("member_{}", i).as_bytes())));
+
+        // Sized for about 1% false positives
+        let false_positives = (0..10_000).filter(|i| bloom_filter.may_contain(format!("stranger_{}", i).as_bytes())).count();
+        assert!(false_positives < 200, "{:?}: {} false positives", hasher, false_positives);
+    }
+
+    // The hasher is recorded with the table, so reopening with it finds the filter again
+    let path = "db_data_test_bloom_hashers";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { bloom_hasher: Some(Arc::new(SipBloomHasher)), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+    for i in (0..200u32).step_by(2) {
+        db.insert(i, b"value".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    drop(db);
+
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value".to_vec()));
+    let seeks = db.index_seeks();
+    assert!(db.index_seeks() - seeks < 10, "{}", db.index_seeks() - seeks);
+    assert!(db.index_seeks() - seeks < 10);
+    drop(db);
+
+    // Without it the filter is skipped, which costs index reads but not correctness
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value".to_vec()));
+    let seeks = db.index_seeks();
+    assert!((1..200u32).step_by(2).all(|i| db.find(i).unwrap().is_none()));
+    assert!(db.index_seeks() - seeks >= 99, "{}", db.index_seeks() - seeks);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_false_positive_rate() {
+    let false_positives = |false_positive_rate: f64| {
+        let mut bloom_filter = BloomFilter::with_false_positive_rate(10_000, false_positive_rate, Arc::new(Xxh3Hasher));
+        for i in 0..10_000 {
+            bloom_filter.insert(format!("member_{}", i).as_bytes());
+        }
+        assert!((0..10_000).all(|i| bloom_filter.may_contain(format!("member_{}", i).as_bytes())));
+        (0..10_000).filter(|i| bloom_filter.may_contain(format!("stranger_{}", i).as_bytes())).count()
+    };
+    assert!(false_positives(0.1) > 500, "{}", false_positives(0.1));
+    assert!(false_positives(0.001) < 50, "{}", false_positives(0.001));
+
+    // Tables size their filters by the option, and absent keys still skip the index
+    let filter_len = |false_positive_rate: Option<f64>| {
+        let path = "db_data_test_false_positive_rate";
+        fs::remove_dir_all(path).ok();
+        let mut db = DBex::open_with_options(path, DBexOptions { false_positive_rate, ..DBexOptions::default() });
+        for i in (0..2_000u32).step_by(2) {
+            db.insert(i, b"value".to_vec()).unwrap();
+        }
+        db.flush().unwrap();
+        let seeks = db.index_seeks();
+        assert!((1..2_000u32).step_by(2).all(|i| db.find_borrowed(i).unwrap().is_none()));
+        assert!(db.index_seeks() - seeks < 100, "{}", db.index_seeks() - seeks);
+
+        let mut filter_path = data_files(path)[0].clone().into_os_string();
+        filter_path.push(".filter");
+        let filter_len = fs::metadata(filter_path).unwrap().len();
+        db.purge().unwrap();
+        filter_len
+    };
+    assert!(filter_len(Some(0.0001)) > filter_len(None));
+    assert!(filter_len(None) > filter_len(Some(0.05)));
+}
+
+#[test]
+fn test_operation_counters() {
+    let path = "db_data_test_operation_counters";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    for i in 0..10u32 {
+        db.insert(i, b"value".to_vec()).unwrap();
+    }
+    db.remove(3u32).unwrap();
+    db.remove(4u32).unwrap();
+
+    // Counters survive the memtable being flushed
+    db.flush().unwrap();
+    assert!(db.find(0u32).unwrap().is_some());
+    assert!(db.find(9u32).unwrap().is_some());
+    assert!(db.find(42u32).unwrap().is_none());
+    db.append(b"k".to_vec(), b"v").unwrap();
+
+    let stats = db.stats();
+    assert_eq!(stats.entries_written, 11);
+    assert_eq!(stats.entries_deleted, 2);
+    assert_eq!(stats.reads_served, 3);
+    assert_eq!(stats.read_hits, 2);
+    assert_eq!(stats.read_misses, 1);
+    assert!((stats.read_hit_rate() - 2.0 / 3.0).abs() < f64::EPSILON);
+
+    db.truncate().unwrap();
+    let stats = db.stats();
+    assert_eq!((stats.entries_written, stats.entries_deleted), (0, 0));
+    assert_eq!((stats.reads_served, stats.read_hits, stats.read_misses), (0, 0, 0));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_max_value_size() {
+    let path = "db_data_test_max_value_size";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { max_value_size: Some(16), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options);
+
+    db.insert(b"fits".to_vec(), vec![b'x'; 16]).unwrap();
+    let lsn = db.current_lsn();
+    match db.insert(b"big".to_vec(), vec![b'x'; 17]) {
+        Err(DbexError::ValueTooLarge { len: 17, max: 16 }) => {}
+        other => panic!("expected ValueTooLarge, got {:?}", other),
+    }
+    // Appends go through the same check once the value grows past the limit
+    assert!(matches!(db.append(b"fits".to_vec(), b"y"), Err(DbexError::ValueTooLarge { .. })));
+
+    // Rejected writes leave no trace, not even a used-up LSN
+    assert!(db.find_borrowed(&b"big"[..]).unwrap().is_none());
+    assert_eq!(db.find(&b"fits"[..]).unwrap(), Some(vec![b'x'; 16]));
+    assert_eq!(db.stats().entries_written, 1);
+    assert_eq!(db.current_lsn(), lsn);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_scan_includes_end() {
+    let mut test_db = TestDb::open("db_data_test_scan_includes_end");
+    let db = test_db.db();
+
+    // Interleaved keys across two flushes, with an older key deleted in the newer table
+    for i in (0..40u32).step_by(2) {
+        db.insert(i, b"old".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for i in (1..40u32).step_by(2).chain([10, 20]) {
+        db.insert(i, b"new".to_vec()).unwrap();
+    }
+    db.remove(12u32).unwrap();
+    db.flush().unwrap();
+    db.insert(30u32, b"memtable".to_vec()).unwrap();
+
+    let (start, end) = (5u32.to_be_bytes(), 30u32.to_be_bytes());
+    let scanned: Vec<_> = db.scan(&start, &end).unwrap().collect();
+    let keys: Vec<u32> = scanned.iter().map(|(key, _)| u32::from_be_bytes(key[..].try_into().unwrap())).collect();
+    assert_eq!(keys, (5..=30).filter(|&i| i != 12).collect::<Vec<_>>());
+    assert_eq!(scanned[5], (10u32.to_be_bytes().to_vec(), b"new".to_vec()));
+    assert_eq!(scanned.last().unwrap().1, b"memtable".to_vec());
+
+    // range leaves the end out
+    assert_eq!(db.range(&start, &end).unwrap().count(), scanned.len() - 1);
+    assert_eq!(db.scan(&end, &end).unwrap().count(), 1);
+}
+
+#[test]
+fn test_range_over_disjoint_and_overlapping_tables() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    // Disjoint bulk-loaded tables land below L0 and are concatenated by range
+    for chunk in 0..5u32 {
+        db.bulk_load((chunk * 100..chunk * 100 + 50).map(|i| (i.to_be_bytes().to_vec(), b"bulk".to_vec()))).unwrap();
+    }
+    assert_eq!(db.cnt_of_l0_ss_tables(), 0);
+
+    // Overlapping L0 tables and memtable entries still have to be merged, newest winning
+    for i in (120..140u32).chain(310..320) {
+        db.insert(i, b"l0".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for i in (130..135u32).chain(600..610) {
+        db.insert(i, b"l0_newer".to_vec()).unwrap();
+    }
+    db.remove(10u32).unwrap();
+    db.flush().unwrap();
+    db.insert(315u32, b"memtable".to_vec()).unwrap();
+    db.remove(&220u32.to_be_bytes()[..]).unwrap();
+
+    // scan_prefix merges every source the long way round
+    let expected = db.scan_prefix(&[]).unwrap();
+    assert_eq!(expected.len(), 250 - 2 + 10);
+    assert_eq!(db.range(&[], &[0xFF; 5]).unwrap().collect::<Vec<_>>(), expected);
+
+    let (start, end) = (125u32.to_be_bytes(), 317u32.to_be_bytes());
+    let in_range: Vec<_> = expected.iter()
+        .filter(|(key, _)| key.as_slice() >= &start[..] && key.as_slice() < &end[..])
+        .cloned()
+        .collect();
+    assert_eq!(db.range(&start, &end).unwrap().collect::<Vec<_>>(), in_range);
+    assert_eq!(db.find(132u32).unwrap(), Some(b"l0_newer".to_vec()));
+    assert_eq!(db.find(315u32).unwrap(), Some(b"memtable".to_vec()));
+}
+
+#[test]
+fn test_open_rebuilds_missing_manifest() {
+    let path = "db_data_test_open_rebuilds_missing_manifest";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    // Two tables over the same keys, the newer one deleting and overwriting some of them,
+    // and one table of its own
+    for i in 0..100u32 {
+        db.insert(i, b"old".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    for i in 0..50u32 {
+        db.insert(i, b"new".to_vec()).unwrap();
+    }
+    db.remove(99u32).unwrap();
+    db.flush().unwrap();
+    for i in 1000..1100u32 {
+        db.insert(i, b"apart".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    let next_lsn = db.current_lsn();
+    db.close().unwrap();
+
+    fs::remove_file(Path::new(path).join("MANIFEST")).unwrap();
+    let mut db = DBex::open(path);
+    assert!(db.stats().recovery.manifest_rebuilt);
+    assert_eq!(db.cnt_of_l0_ss_tables(), 2);
+    assert_eq!(db.cnt_of_l2_ss_tables(), 1);
+    assert_eq!(db.current_lsn(), next_lsn);
+
+    assert_eq!(db.find(10u32).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.find(60u32).unwrap(), Some(b"old".to_vec()));
+    assert!(db.find_borrowed(99u32).unwrap().is_none());
+    assert_eq!(db.find(1050u32).unwrap(), Some(b"apart".to_vec()));
+    assert_eq!(db.range(&[], &[0xFF; 5]).unwrap().count(), 199);
+    drop(db);
+
+    // The rebuilt manifest is written back, so the next open is an ordinary one
+    let mut db = DBex::open(path);
+    assert!(!db.stats().recovery.manifest_rebuilt);
+    assert_eq!(db.find(1050u32).unwrap(), Some(b"apart".to_vec()));
+    db.purge().unwrap();
+}
+
+// XORs every byte with a fixed key, standing in for a user's encryption
+#[derive(Debug)]
+struct XorTransform(u8);
+
+impl ValueTransform for XorTransform {
+    fn id(&self) -> u8 {
+        7
+    }
+
+    fn on_write(&self, value: &[u8]) -> Vec<u8> {
+        value.iter().map(|byte| byte ^ self.0).collect()
+    }
+
+    fn on_read(&self, stored: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(self.on_write(stored))
+    }
+}
+
+#[test]
+fn test_value_transform() {
+    let path = "db_data_test_value_transform";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { value_transform: Some(Arc::new(XorTransform(0x5A))), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+
+    // Enough flushes to compact L0, so values also pass through a merge
+    for round in 0..11u32 {
+        for i in 0..20u32 {
+            db.insert(i, format!("secret_{}_{}", round, i).into_bytes()).unwrap();
+        }
+        db.insert(1000 + round, b"secret_kept".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    assert!(db.cnt_of_l1_ss_tables() > 0);
+
+    // No table holds the plain value
+    for entry in fs::read_dir(Path::new(path).join("ss_tables")).unwrap() {
+        let entry_path = entry.unwrap().path();
+        if entry_path.extension().is_some_and(|ext| ext == "db") {
+            let bytes = fs::read(&entry_path).unwrap();
+            assert!(!bytes.windows(6).any(|window| window == b"secret"), "{}", entry_path.display());
+        }
+    }
+
+    assert_eq!(db.find(3u32).unwrap(), Some(b"secret_10_3".to_vec()));
+    assert_eq!(db.find(1004u32).unwrap(), Some(b"secret_kept".to_vec()));
+    assert_eq!(db.find_borrowed(5u32).unwrap().unwrap().to_vec(), b"secret_10_5".to_vec());
+    assert_eq!(db.range(&[], &[0xFF; 5]).unwrap().count(), 31);
+    db.close().unwrap();
+
+    // The tables record the transform, so they won't open without it
+    assert!(matches!(DBex::try_open(path), Err(DbexError::Corruption(_))));
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(3u32).unwrap(), Some(b"secret_10_3".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_scan_with_filter() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    for i in 0..300u32 {
+        let key = format!("{}_{:03}", if i % 3 == 0 { "user" } else { "item" }, i);
+        db.insert(key.into_bytes(), format!("value_{}", i % 7).into_bytes()).unwrap();
+        if i == 150 {
+            db.flush().unwrap();
+        }
+    }
+    // A newer non-matching value must hide an older matching one, and the other way round
+    db.insert(b"user_000".to_vec(), b"value_none".to_vec()).unwrap();
+    db.insert(b"user_003".to_vec(), b"value_3".to_vec()).unwrap();
+    db.remove(&b"user_021"[..]).unwrap();
+
+    let (start, end) = (&b"item_100"[..], &b"user_250"[..]);
+    let by_key = |key: &[u8], _: &[u8]| key.starts_with(b"user");
+    let by_value = |_: &[u8], value: &[u8]| value.ends_with(b"_3") || value.ends_with(b"_0");
+    let expected_by_key: Vec<_> = db.range(start, end).unwrap().filter(|(key, value)| by_key(key, value)).collect();
+    let expected_by_value: Vec<_> = db.range(start, end).unwrap().filter(|(key, value)| by_value(key, value)).collect();
+    assert!(!expected_by_key.is_empty() && !expected_by_value.is_empty());
+
+    assert_eq!(db.scan_with_filter(start, end, by_key).unwrap().collect::<Vec<_>>(), expected_by_key);
+    assert_eq!(db.scan_with_filter(start, end, by_value).unwrap().collect::<Vec<_>>(), expected_by_value);
+    let all: Vec<_> = db.scan_with_filter(&[], &[0xFF], by_value).unwrap().collect();
+    assert!(all.iter().any(|(key, _)| key == b"user_003"));
+    assert!(!all.iter().any(|(key, _)| key == b"user_000" || key == b"user_021"));
+}
+
+#[test]
+fn test_compaction_throttle() {
+    let bytes_per_sec = 2 * 1024 * 1024;
+    let mut test_db = TestDb::with_options(DBexOptions {
+        compaction_bytes_per_sec: Some(bytes_per_sec),
+        ..DBexOptions::default()
+    });
+    let db = test_db.db();
+
+    for round in 0..11u32 {
+        for i in 0..100u32 {
+            db.insert(round * 100 + i, vec![round as u8; 500]).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let compaction = db.stats().last_compaction.expect("L0 should have been compacted");
+
+    // Reading the inputs and writing the output moves about bytes_read + bytes_written,
+    // which can't go faster than the limit
+    let bytes_moved = compaction.bytes_read + compaction.bytes_written;
+    let min_duration = Duration::from_secs_f64(bytes_moved as f64 / bytes_per_sec as f64);
+    assert!(compaction.duration >= min_duration.mul_f64(0.9), "{:?} for {} bytes", compaction.duration, bytes_moved);
+    assert!(compaction.throttled > Duration::ZERO);
+    assert!(compaction.throttled <= compaction.duration);
+    assert_eq!(db.find(1050u32).unwrap(), Some(vec![10u8; 500]));
+}
+
+#[test]
+fn test_table_names_ignore_clock() {
+    let path = "db_data_test_table_names_ignore_clock";
+    fs::remove_dir_all(path).ok();
+    let ss_table_dir = Path::new(path).join("ss_tables");
+    fs::create_dir_all(&ss_table_dir).unwrap();
+
+    // A table named by an older version while the clock ran a day ahead
+    let skewed_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 + 86_400 * 1_000_000_000;
+    let mut skewed = SSTable::numbered(Arc::new(LocalStorage), &ss_table_dir, skewed_timestamp, None).unwrap();
+    skewed.write_entry(&Some(b"stale".to_vec())).unwrap();
+    skewed.write_index(&[(b"k".to_vec(), 0)]).unwrap();
+    skewed.sync(SyncPolicy::None).unwrap();
+    drop(skewed);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(&b"k"[..]).unwrap(), Some(b"stale".to_vec()));
+    db.insert(b"k".to_vec(), b"fresh".to_vec()).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.find(&b"k"[..]).unwrap(), Some(b"fresh".to_vec()));
+    let numbers: Vec<u64> = db.list_sstables().iter().map(|info| table_number(&info.data_path).unwrap()).collect();
+    assert!(numbers.contains(&(skewed_timestamp + 1)), "{:?}", numbers);
+    db.close().unwrap();
+
+    // Without the manifest, recency comes from the table numbers, which still put the
+    // new table after the skewed one
+    fs::remove_file(Path::new(path).join("MANIFEST")).unwrap();
+    let mut db = DBex::open(path);
+    assert!(db.stats().recovery.manifest_rebuilt);
+    assert_eq!(db.find(&b"k"[..]).unwrap(), Some(b"fresh".to_vec()));
+
+    // The counter carries on from the manifest rather than the clock
+    db.insert(b"k".to_vec(), b"fresher".to_vec()).unwrap();
+    db.flush().unwrap();
+    let newest = db.list_sstables().iter().filter_map(|info| table_number(&info.data_path)).max().unwrap();
+    assert_eq!(newest, skewed_timestamp + 2);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_remove_many() {
+    let path = "db_data_test_remove_many";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    for i in 0..1500u32 {
+        db.insert(i.to_be_bytes().to_vec(), b"value".to_vec()).unwrap();
+        if i == 700 {
+            db.flush().unwrap();
+        }
+    }
+    // 900 live keys, a duplicate and 100 that were never written
+    let mut keys: Vec<Vec<u8>> = (100..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    keys.push(150u32.to_be_bytes().to_vec());
+    keys.extend((5000..5099u32).map(|i| i.to_be_bytes().to_vec()));
+    assert_eq!(keys.len(), 1000);
+
+    let lsn = db.current_lsn();
+    assert_eq!(db.remove_many(&keys).unwrap(), 900);
+    assert_eq!(db.current_lsn(), lsn + 1000);
+    assert_eq!(db.stats().entries_deleted, 1000);
+    assert!(keys.iter().all(|key| db.find_borrowed(key.as_slice()).unwrap().is_none()));
+    assert_eq!(db.range(&[], &[0xFF; 5]).unwrap().count(), 600);
+    drop(db);
+
+    // The batch was synced as a whole, so replay brings all of it back
+    let mut db = DBex::open(path);
+    assert!(keys.iter().all(|key| db.find_borrowed(key.as_slice()).unwrap().is_none()));
+    assert_eq!(db.find(99u32.to_be_bytes().as_slice()).unwrap(), Some(b"value".to_vec()));
+    drop(db);
+
+    // A batch whose commit marker never reached the log isn't replayed at all, whether
+    // another batch follows it or the log ends inside it
+    let mut wal = WriteAheadLog::new(Arc::new(LocalStorage), &Path::new(path).join("wals"), None, WalRecordFormat::default()).unwrap();
+    wal.write(Operation::StartTxn, 5000, None, None).unwrap();
+    wal.write(Operation::Delete, 5000, Some(0u32.to_be_bytes().to_vec()), None).unwrap();
+    wal.write_batch(5001, &[(1u32.to_be_bytes().to_vec(), None)]).unwrap();
+    wal.write(Operation::StartTxn, 5002, None, None).unwrap();
+    wal.write(Operation::Delete, 5002, Some(2u32.to_be_bytes().to_vec()), None).unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(0u32.to_be_bytes().as_slice()).unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.find(1u32.to_be_bytes().as_slice()).unwrap(), None);
+    assert_eq!(db.find(2u32.to_be_bytes().as_slice()).unwrap(), Some(b"value".to_vec()));
+    assert!(db.find_borrowed(150u32.to_be_bytes().as_slice()).unwrap().is_none());
+
+    // Inside a transaction the removes are staged, and a repeated key still counts once
+    let len = db.len().unwrap();
+    db.start_txn();
+    let keys = vec![0u32.to_be_bytes().to_vec(), 0u32.to_be_bytes().to_vec(), 1u32.to_be_bytes().to_vec()];
+    assert_eq!(db.remove_many(&keys).unwrap(), 1);
+    assert_eq!(db.find(0u32.to_be_bytes().as_slice()).unwrap(), None);
+    db.commit_txn().unwrap();
+    assert_eq!(db.len().unwrap(), len - 1);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_write_after_torn_batch_survives_crash() {
+    let path = "db_data_test_write_after_torn_batch";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"old_key".to_vec(), b"old_value".to_vec()).unwrap();
+    db.sync().unwrap();
+    let lsn = db.current_lsn();
+    drop(db);
+
+    // A crash inside a batch leaves its StartTxn and an insert, but no commit marker
+    let wal_dir = Path::new(path).join("wals");
+    let mut wal = WriteAheadLog::new(Arc::new(LocalStorage), &wal_dir, None, WalRecordFormat::default()).unwrap();
+    wal.write(Operation::StartTxn, lsn, None, None).unwrap();
+    wal.write(Operation::Insert, lsn, Some(b"torn_key".to_vec()), Some(b"value".to_vec())).unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+
+    // The next write takes the LSN the batch's second record would have had. Opening
+    // cuts the batch out, so the write isn't read back as part of it and dropped.
+    let mut db = DBex::open(path);
+    db.insert(b"new_key".to_vec(), b"new_value".to_vec()).unwrap();
+    db.sync().unwrap();
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"old_key").unwrap(), Some(b"old_value".to_vec()));
+    assert_eq!(db.find(b"new_key").unwrap(), Some(b"new_value".to_vec()));
+    assert!(db.find_borrowed(b"torn_key").unwrap().is_none());
+    drop(db);
+
+    // A log that already has a plain write after a torn batch keeps the write, and the
+    // batch is cut out from between them
+    let mut wal = WriteAheadLog::new(Arc::new(LocalStorage), &wal_dir, None, WalRecordFormat::default()).unwrap();
+    wal.write(Operation::StartTxn, 6000, None, None).unwrap();
+    wal.write(Operation::Insert, 6000, Some(b"torn_key".to_vec()), Some(b"value".to_vec())).unwrap();
+    wal.write(Operation::Insert, 7000, Some(b"after_key".to_vec()), Some(b"value".to_vec())).unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    let mut db = DBex::open(path);
+    db.insert(b"last_key".to_vec(), b"value".to_vec()).unwrap();
+    db.sync().unwrap();
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"new_key").unwrap(), Some(b"new_value".to_vec()));
+    assert_eq!(db.find(b"after_key").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.find(b"last_key").unwrap(), Some(b"value".to_vec()));
+    assert!(db.find_borrowed(b"torn_key").unwrap().is_none());
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_sparse_index_sidecar() {
+    let path = "db_data_test_sparse_index_sidecar";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    for i in 0..5000u32 {
+        db.insert(i, format!("value_{}", i).into_bytes()).unwrap();
+        if i % 2000 == 1999 {
+            db.flush().unwrap();
+        }
+    }
+    db.close().unwrap();
+
+    let sidecars = || -> Vec<PathBuf> {
+        fs::read_dir(Path::new(path).join("ss_tables")).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|entry_path| entry_path.to_string_lossy().ends_with(".db.sparse"))
+            .collect()
+    };
+    assert_eq!(sidecars().len(), 3);
+    let check_reads = |db: &mut DBex| {
+        assert_eq!(db.find(0u32).unwrap(), Some(b"value_0".to_vec()));
+        assert_eq!(db.find(3333u32).unwrap(), Some(b"value_3333".to_vec()));
+        assert_eq!(db.find(4999u32).unwrap(), Some(b"value_4999".to_vec()));
+        assert!(db.find(5000u32).unwrap().is_none());
+        assert_eq!(db.range(&[], &[0xFF; 5]).unwrap().count(), 5000);
+    };
+
+    // With the sidecars, open doesn't touch the index files at all
+    let mut db = DBex::open(path);
+    assert_eq!(db.index_seeks(), 0);
+    check_reads(&mut db);
+    drop(db);
+
+    // A damaged sidecar is ignored, and a missing one too, in favour of scanning the index
+    let sidecar_paths = sidecars();
+    fs::write(&sidecar_paths[0], b"garbage").unwrap();
+    fs::remove_file(&sidecar_paths[1]).unwrap();
+    let mut db = DBex::open(path);
+    assert_eq!(db.index_seeks(), 2);
+    check_reads(&mut db);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_stream_table_between_databases() {
+    let source_path = "db_data_test_stream_table_source";
+    let mut source_db = TestDb::open(source_path);
+    let source = source_db.db();
+    for i in 0..500 {
+        source.insert(format!("key_{:04}", i).into_bytes(), format!("value_{}", i).into_bytes()).unwrap();
+    }
+    source.flush().unwrap();
+    let source_table = data_files(source_path).remove(0);
+
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage);
+    let mut stream = Vec::new();
+    SSTable::open(storage, &source_table).unwrap().stream_to(&mut stream).unwrap();
+
+    let target_path = "db_data_test_stream_table_target";
+    let mut target_db = TestDb::open(target_path);
+    let target = target_db.db();
+    // Nothing to overlap with in the empty target, so the table goes to the bottom level
+    assert_eq!(target.ingest_stream(&mut stream.as_slice()).unwrap(), 2);
+    assert_eq!(target.find(b"key_0000").unwrap(), Some(b"value_0".to_vec()));
+    assert_eq!(target.find(b"key_0499").unwrap(), Some(b"value_499".to_vec()));
+    assert_eq!(target.scan_prefix(b"key_").unwrap().len(), 500);
+
+    // The received files match the sent ones byte for byte, and the staged copy is gone
+    let target_table = data_files(target_path).remove(0);
+    for suffix in ["", ".index"] {
+        let sent = fs::read(format!("{}{}", source_table.display(), suffix)).unwrap();
+        let received = fs::read(format!("{}{}", target_table.display(), suffix)).unwrap();
+        assert_eq!(sent, received);
+    }
+    assert_eq!(fs::read_dir(Path::new(target_path).join("ingest")).unwrap().count(), 0);
+
+    // A damaged stream is rejected without leaving anything behind
+    let last = stream.len() - 10;
+    stream[last] ^= 0xff;
+    assert!(matches!(target.ingest_stream(&mut stream.as_slice()), Err(DbexError::Corruption(_))));
+    assert!(target.ingest_stream(&mut &stream[..stream.len() / 2]).is_err());
+    assert_eq!(fs::read_dir(Path::new(target_path).join("ingest")).unwrap().count(), 0);
+    assert_eq!(data_files(target_path).len(), 1);
+}
+
+#[test]
+fn test_scan_tenant() {
+    let options = DBexOptions { strip_tenant_prefix: true, ..DBexOptions::default() };
+    let mut test_db = TestDb::open_with_options("db_data_test_scan_tenant", options);
+    let db = test_db.db();
+    for tenant in ["acme/", "globex/"] {
+        for i in 0..3 {
+            db.insert(format!("{}user_{}", tenant, i).into_bytes(), format!("{}{}", tenant, i).into_bytes()).unwrap();
+        }
+    }
+    db.flush().unwrap();
+    db.insert(b"acme/user_3".to_vec(), b"acme/3".to_vec()).unwrap();
+    db.remove(b"acme/user_0").unwrap();
+
+    let acme = db.scan_tenant(b"acme/").unwrap();
+    assert_eq!(acme, vec![
+        (b"user_1".to_vec(), b"acme/1".to_vec()),
+        (b"user_2".to_vec(), b"acme/2".to_vec()),
+        (b"user_3".to_vec(), b"acme/3".to_vec()),
+    ]);
+    assert_eq!(db.scan_tenant(b"globex/").unwrap().len(), 3);
+    assert!(db.scan_tenant(b"initech/").unwrap().is_empty());
+}
+
+#[test]
+fn test_durable_lsn() {
+    let mut test_db = TestDb::open("db_data_test_durable_lsn");
+    let db = test_db.db();
+    assert_eq!(db.durable_lsn(), db.current_lsn());
+
+    // Plain writes sit in the WAL's buffer until something syncs it
+    for i in 0..10 {
+        db.insert(format!("key_{}", i).into_bytes(), b"value".to_vec()).unwrap();
+    }
+    assert!(db.durable_lsn() < db.current_lsn());
+    db.sync().unwrap();
+    assert_eq!(db.durable_lsn(), db.current_lsn());
+
+    db.insert(b"key_10".to_vec(), b"value".to_vec()).unwrap();
+    assert!(db.durable_lsn() < db.current_lsn());
+    db.flush().unwrap();
+    assert_eq!(db.durable_lsn(), db.current_lsn());
+
+    // A batch of deletes is synced as part of the call
+    db.insert(b"key_11".to_vec(), b"value".to_vec()).unwrap();
+    db.remove_many(&[b"key_0".to_vec(), b"key_1".to_vec()]).unwrap();
+    assert_eq!(db.durable_lsn(), db.current_lsn());
+}
+
+#[test]
+fn test_get_or_insert_with() {
+    let mut test_db = TestDb::open("db_data_test_get_or_insert_with");
+    let db = test_db.db();
+
+    // Absent: the value is computed, stored and returned
+    let value = db.get_or_insert_with(b"cache_key".to_vec(), || b"computed".to_vec()).unwrap();
+    assert_eq!(value, b"computed".to_vec());
+    assert_eq!(db.find(b"cache_key").unwrap(), Some(b"computed".to_vec()));
+
+    // Present, in the memtable or on disk: the initializer doesn't run
+    for flushed in [false, true] {
+        if flushed {
+            db.flush().unwrap();
+        }
+        let value = db.get_or_insert_with(b"cache_key".to_vec(), || panic!("initializer ran for a present key")).unwrap();
+        assert_eq!(value, b"computed".to_vec());
+    }
+
+    // A removed key counts as absent
+    db.remove(b"cache_key").unwrap();
+    let value = db.get_or_insert_with(b"cache_key".to_vec(), || b"recomputed".to_vec()).unwrap();
+    assert_eq!(value, b"recomputed".to_vec());
+
+    // Threads sharing the handle initialize the key once between them
+    let shared = Arc::new(Mutex::new(DBex::open("db_data_test_get_or_insert_with_shared")));
+    let init_calls = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..8).map(|_| {
+        let shared = shared.clone();
+        let init_calls = init_calls.clone();
+        thread::spawn(move || {
+            shared.lock().unwrap().get_or_insert_with(b"lazy".to_vec(), || {
+                *init_calls.lock().unwrap() += 1;
+                b"initialized".to_vec()
+            }).unwrap()
+        })
+    }).collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), b"initialized".to_vec());
+    }
+    assert_eq!(*init_calls.lock().unwrap(), 1);
+    drop(shared);
+    fs::remove_dir_all("db_data_test_get_or_insert_with_shared").ok();
+}
+
+#[test]
+fn test_verify_uses_checksums() {
+    let path = "db_data_test_verify_quick_pass";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+    for batch in 0..3 {
+        for i in 0..100 {
+            db.insert(format!("key_{}_{:03}", batch, i).into_bytes(), format!("value_{}", i).into_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    // Clean tables pass on their checksums alone
+    let verify_stats = db.verify().unwrap();
+    assert_eq!(verify_stats.tables_checked, 3);
+    assert_eq!(verify_stats.deep_scans, 0);
+
+    // A changed value byte is only caught by the checksum
+    let table = data_files(path).remove(0);
+    let mut data = fs::read(&table).unwrap();
+    data[4] ^= 0xff;
+    fs::write(&table, &data).unwrap();
+    match db.verify() {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("checksum mismatch"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+
+    // A mangled value length escalates to the full scan, which says what's wrong
+    data[0..4].copy_from_slice(&u32::MAX.wrapping_sub(1).to_be_bytes());
+    fs::write(&table, &data).unwrap();
+    match db.verify() {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("runs past the end"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_repair() {
+    let path = "db_data_test_repair";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    for batch in 0..3 {
+        for i in 0..100 {
+            db.insert(format!("key_{}_{:03}", batch, i).into_bytes(), b"value".to_vec()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    db.insert(b"wal_key".to_vec(), b"unflushed".to_vec()).unwrap();
+    drop(db);
+
+    // A flipped value byte, a torn index and a mangled manifest
+    let tables = data_files(path);
+    let mut data = fs::read(&tables[0]).unwrap();
+    data[4] ^= 0xff;
+    fs::write(&tables[0], &data).unwrap();
+    let mut index_path = tables[1].clone().into_os_string();
+    index_path.push(".index");
+    let index_file = OpenOptions::new().write(true).open(&index_path).unwrap();
+    index_file.set_len(index_file.metadata().unwrap().len() - 5).unwrap();
+    fs::write(PathBuf::from(path).join("MANIFEST"), b"garbage").unwrap();
+    assert!(matches!(DBex::try_open(path), Err(DbexError::Corruption(_))));
+
+    let report = DBex::repair(path, DBexOptions::default()).unwrap();
+    assert!(report.manifest_rebuilt);
+    assert_eq!(report.wal_entries_replayed, 1);
+    let quarantined: Vec<_> = report.quarantined.iter().map(|table| (table.data_path.clone(), table.key_range.clone())).collect();
+    assert_eq!(quarantined, vec![
+        (tables[1].clone(), None),
+        (tables[0].clone(), Some((b"key_0_000".to_vec(), b"key_0_099".to_vec()))),
+    ]);
+    assert!(report.quarantined[1].reason.contains("checksum mismatch"), "{}", report.quarantined[1].reason);
+    assert!(PathBuf::from(path).join("quarantine").join(tables[0].file_name().unwrap()).exists());
+
+    // Nothing is left to fix, and the database opens cleanly with what survived
+    assert_eq!(DBex::repair(path, DBexOptions::default()).unwrap().quarantined, vec![]);
+    let mut db = DBex::open(path);
+    assert!(db.corrupt_ss_tables().is_empty());
+    assert_eq!(db.verify().unwrap().tables_checked, 2);
+    assert_eq!(db.find(b"key_2_050").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.find(b"wal_key").unwrap(), Some(b"unflushed".to_vec()));
+    assert!(db.find_borrowed(b"key_0_050").unwrap().is_none());
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_versions_to_keep() {
+    let path = "db_data_test_versions_to_keep";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { versions_to_keep: Some(3), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+
+    for version in 1..=5 {
+        db.insert(b"key".to_vec(), format!("version_{}", version).into_bytes()).unwrap();
+        db.insert(format!("other_{}", version).into_bytes(), b"value".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    // Fill L0 past its limit so it's compacted into one L1 table
+    for i in 0..6 {
+        db.insert(format!("filler_{}", i).into_bytes(), b"value".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.cnt_of_l0_ss_tables(), 0);
+    assert_eq!(db.cnt_of_l1_ss_tables(), 1);
+
+    let expected: Vec<Option<Vec<u8>>> = (3..=5).rev().map(|version| Some(format!("version_{}", version).into_bytes())).collect();
+    let versions: Vec<Option<Vec<u8>>> = db.get_all_versions(b"key").unwrap().into_iter().map(|(_, value)| value).collect();
+    assert_eq!(versions, expected);
+    assert_eq!(db.find(b"key").unwrap(), Some(b"version_5".to_vec()));
+    assert_eq!(db.get_all_versions(b"other_1").unwrap().len(), 1);
+    assert_eq!(db.stats().total_compaction.duplicates_dropped, 2);
+
+    // The versions are kept on disk with the table
+    drop(db);
+    let mut db = DBex::open_with_options(path, options);
+    let versions: Vec<Option<Vec<u8>>> = db.get_all_versions(b"key").unwrap().into_iter().map(|(_, value)| value).collect();
+    assert_eq!(versions, expected);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_writes_reach_wal_before_returning() {
+    let path = "db_data_test_writes_reach_wal";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+    let first_lsn = db.current_lsn();
+    for key in [b"key_a", b"key_b", b"key_c"] {
+        db.insert(key.to_vec(), b"value".to_vec()).unwrap();
+    }
+    db.remove(b"key_b").unwrap();
+
+    // Readable from the file while the database is still open, with nothing synced
+    let mut wal = WriteAheadLog::new(Arc::new(LocalStorage), &Path::new(path).join("wals"), None, WalRecordFormat::default()).unwrap();
+    let logged: Vec<_> = wal.read(0).unwrap().into_iter()
+        .map(|wal_entry| {
+            let (lsn, is_insert) = (wal_entry.lsn(), *wal_entry.operation() == Operation::Insert);
+            let (key, value) = wal_entry.into_key_value();
+            (lsn, is_insert, key, value)
+        })
+        .collect();
+    assert_eq!(logged, vec![
+        (first_lsn, true, Some(b"key_a".to_vec()), Some(b"value".to_vec())),
+        (first_lsn + 1, true, Some(b"key_b".to_vec()), Some(b"value".to_vec())),
+        (first_lsn + 2, true, Some(b"key_c".to_vec()), Some(b"value".to_vec())),
+        (first_lsn + 3, false, Some(b"key_b".to_vec()), None),
+    ]);
+
+    // With sync_wal_writes, each write is durable when the call returns
+    let options = DBexOptions { sync_wal_writes: true, ..DBexOptions::default() };
+    let mut synced_db = TestDb::open_with_options("db_data_test_writes_reach_wal_synced", options);
+    let synced = synced_db.db();
+    synced.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    assert_eq!(synced.durable_lsn(), synced.current_lsn());
+    synced.remove(b"key").unwrap();
+    assert_eq!(synced.durable_lsn(), synced.current_lsn());
+}
+
+#[test]
+fn test_wal_record_formats() {
+    let path = "db_data_test_wal_record_formats";
+    fs::remove_dir_all(path).ok();
+    let with_format = |wal_record_format| DBexOptions { wal_record_format, ..DBexOptions::default() };
+    let large_value = vec![0x5Au8; 200_000];
+
+    // Records in the old format, left in the WAL by a crash
+    let mut db = DBex::open_with_options(path, with_format(WalRecordFormat::Rkyv));
+    db.insert(b"old_key".to_vec(), b"old_value".to_vec()).unwrap();
+    db.insert(b"old_large".to_vec(), large_value.clone()).unwrap();
+    db.remove(b"old_key").unwrap();
+    drop(db);
+
+    // Raw records appended after them
+    let mut db = DBex::open_with_options(path, with_format(WalRecordFormat::Raw));
+    assert_eq!(db.stats().recovery.wal_entries_replayed, 3);
+    db.insert(b"new_key".to_vec(), Vec::new()).unwrap();
+    db.insert(b"new_large".to_vec(), large_value.clone()).unwrap();
+    db.remove(b"old_large").unwrap();
+    drop(db);
+
+    // Either setting replays the mixed log
+    for record_format in [WalRecordFormat::Rkyv, WalRecordFormat::Raw] {
+        let mut db = DBex::open_with_options(path, with_format(record_format));
+        assert_eq!(db.stats().recovery.wal_entries_replayed, 6);
+        assert!(db.find_borrowed(b"old_key").unwrap().is_none());
+        assert!(db.find_borrowed(b"old_large").unwrap().is_none());
+        assert_eq!(db.find(b"new_key").unwrap(), Some(Vec::new()));
+        assert_eq!(db.find(b"new_large").unwrap(), Some(large_value.clone()));
+    }
+
+    // A raw record torn by a crash is dropped, along with nothing before it
+    let wal_path = Path::new(path).join("wals").join("cur.wal");
+    let len = fs::metadata(&wal_path).unwrap().len();
+    OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(len - 10).unwrap();
+    let mut db = DBex::open(path);
+    assert_eq!(db.stats().recovery.wal_entries_replayed, 5);
+    assert!(db.find_borrowed(b"old_large").unwrap().is_some());
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_write_after_torn_wal_tail_survives_crash() {
+    let path = "db_data_test_write_after_torn_wal_tail";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"old_key".to_vec(), b"old_value".to_vec()).unwrap();
+    db.sync().unwrap();
+    drop(db);
+
+    // A crash mid-append leaves half a record at the end of the WAL
+    let wal_path = Path::new(path).join("wals").join("cur.wal");
+    let mut wal = OpenOptions::new().append(true).open(&wal_path).unwrap();
+    wal.write_all(&64u64.to_le_bytes()).unwrap();
+    wal.write_all(b"partial").unwrap();
+    drop(wal);
+
+    // Opening cuts the torn bytes off, so the next write lands where replay finds it
+    let mut db = DBex::open(path);
+    db.insert(b"new_key".to_vec(), b"new_value".to_vec()).unwrap();
+    db.sync().unwrap();
+    drop(db);
+
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"old_key").unwrap(), Some(b"old_value".to_vec()));
+    assert_eq!(db.find(b"new_key").unwrap(), Some(b"new_value".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_manifest_table_with_missing_files() {
+    let path = "db_data_test_manifest_table_missing_files";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"old_key".to_vec(), b"old_value".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert(b"new_key".to_vec(), b"new_value".to_vec()).unwrap();
+    // Keep the WAL as it was before the flush clears it
+    let wal_path = Path::new(path).join("wals").join("cur.wal");
+    let wal = fs::read(&wal_path).unwrap();
+    db.flush().unwrap();
+    // Crash without closing
+    drop(db);
+
+    // The manifest lists both tables, but the newest lost its index
+    let newest = data_files(path).pop().unwrap();
+    let newest_index = format!("{}.index", newest.display());
+    fs::remove_file(&newest_index).unwrap();
+
+    // Its writes are gone with the cleared WAL, so opening fails
+    match DBex::try_open(path) {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("missing"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+    }
+
+    // As if the crash came between the manifest write and clearing the WAL: the table
+    // is set aside and its writes replayed
+    fs::write(&wal_path, &wal).unwrap();
+    let mut db = DBex::open(path);
+    assert_eq!(db.corrupt_ss_tables(), std::slice::from_ref(&newest));
+    assert_eq!(db.find(b"old_key").unwrap(), Some(b"old_value".to_vec()));
+    assert_eq!(db.find(b"new_key").unwrap(), Some(b"new_value".to_vec()));
+    db.close().unwrap();
+
+    // A table in a deeper level is never covered by the WAL
+    let mut db = DBex::open(path);
+    for flush in 0..9u32 {
+        db.insert(flush, b"value".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!((db.cnt_of_l0_ss_tables(), db.cnt_of_l1_ss_tables()), (0, 1));
+    db.insert(b"logged".to_vec(), b"value".to_vec()).unwrap();
+    drop(db);
+    let l1_table = data_files(path).into_iter().find(|data_path| *data_path != newest).unwrap();
+    fs::remove_file(format!("{}.index", l1_table.display())).unwrap();
+    assert!(matches!(DBex::try_open(path), Err(DbexError::Corruption(_))));
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_flush_stats() {
+    let mut test_db = TestDb::open("db_data_test_flush_stats");
+    let db = test_db.db();
+    assert_eq!(db.flush_stats().flushes, 0);
+    assert_eq!(db.flush_stats().p99(), None);
+
+    for round in 0..5 {
+        for i in 0..200 {
+            db.insert(format!("key_{}_{}", round, i).into_bytes(), vec![0xABu8; 100]).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    // Nothing to write, so not a flush
+    db.flush().unwrap();
+
+    let flush_stats = db.stats().flush;
+    assert_eq!(flush_stats.flushes, 5);
+    let last_duration = flush_stats.last_duration.unwrap();
+    assert!(last_duration > Duration::ZERO);
+    assert!(flush_stats.total_duration >= last_duration);
+    let p99 = flush_stats.p99().unwrap();
+    assert!(p99 >= last_duration && p99 <= flush_stats.total_duration);
+    assert!(flush_stats.percentile(0.0).unwrap() <= p99);
+}
+
+#[test]
+fn test_torn_index_entry_is_reported() {
+    let path = "db_data_test_torn_index_entry";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+    for i in 0..3 {
+        db.insert(format!("key_{}", i).into_bytes(), b"value".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+
+    // Give the first entry a key length running past the end of the index entries,
+    // leaving the footer as it was
+    let index_path = format!("{}.index", data_files(path)[0].display());
+    let mut index = fs::read(&index_path).unwrap();
+    index[0..4].copy_from_slice(&1000u32.to_be_bytes());
+    fs::write(&index_path, &index).unwrap();
+
+    match db.verify() {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("truncated"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+
+    // Compaction fails rather than merging the table as if it were empty
+    let mut compaction_error = None;
+    for i in 0..10 {
+        db.insert(format!("other_{}", i).into_bytes(), b"value".to_vec()).unwrap();
+        if let Err(err) = db.flush() {
+            compaction_error = Some(err);
+        }
+    }
+    match compaction_error {
+        Some(DbexError::Corruption(reason)) => assert!(reason.contains("truncated"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert_eq!(db.cnt_of_l0_ss_tables(), 11);
+    assert_eq!(db.find(b"other_0").unwrap(), Some(b"value".to_vec()));
+}
+
+// MemoryStorage whose files reject every write once `full` is set, like a full disk
+#[derive(Debug, Clone)]
+struct FullDiskStorage {
+    inner: MemoryStorage,
+    full: Arc<AtomicBool>,
+}
+
+struct FullDiskFile {
+    inner: Box<dyn StorageFile>,
+    full: Arc<AtomicBool>,
+}
+
+impl FullDiskStorage {
+    fn wrap(&self, inner: Box<dyn StorageFile>) -> Box<dyn StorageFile> {
+        Box::new(FullDiskFile { inner, full: self.full.clone() })
+    }
+}
+
+impl StorageFile for FullDiskFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> { self.inner.read_at(offset, buf) }
+    fn write(&self, buf: &[u8]) -> std::io::Result<()> {
+        if self.full.load(AtomicOrdering::SeqCst) {
+            return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "disk full"));
+        }
+        self.inner.write(buf)
+    }
+    fn sync(&self, sync_policy: SyncPolicy) -> std::io::Result<()> { self.inner.sync(sync_policy) }
+    fn len(&self) -> std::io::Result<u64> { self.inner.len() }
+    fn set_len(&self, len: u64) -> std::io::Result<()> { self.inner.set_len(len) }
+    fn map(&self, len: u64) -> std::io::Result<MappedBytes> { self.inner.map(len) }
+}
+
+impl Storage for FullDiskStorage {
+    fn create(&self, path: &Path) -> std::io::Result<Box<dyn StorageFile>> { Ok(self.wrap(self.inner.create(path)?)) }
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn StorageFile>> { Ok(self.wrap(self.inner.open(path)?)) }
+    fn open_or_create(&self, path: &Path) -> std::io::Result<Box<dyn StorageFile>> { Ok(self.wrap(self.inner.open_or_create(path)?)) }
+    fn remove(&self, path: &Path) -> std::io::Result<()> { self.inner.remove(path) }
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> { self.inner.rename(from, to) }
+    fn link(&self, from: &Path, to: &Path) -> std::io::Result<()> { self.inner.link(from, to) }
+    fn exists(&self, path: &Path) -> bool { self.inner.exists(path) }
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> { self.inner.list(dir) }
+    fn create_dir_all(&self, dir: &Path) -> std::io::Result<()> { self.inner.create_dir_all(dir) }
+    fn remove_dir_all(&self, dir: &Path) -> std::io::Result<()> { self.inner.remove_dir_all(dir) }
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()> { self.inner.sync_dir(dir) }
+    fn try_lock(&self, path: &Path) -> std::io::Result<Option<StorageLock>> { self.inner.try_lock(path) }
+}
+
+#[test]
+fn test_write_errors_are_returned() {
+    let path = "db_data_test_write_errors_are_returned";
+    let storage = FullDiskStorage { inner: MemoryStorage::new(), full: Arc::new(AtomicBool::new(false)) };
+    let options = DBexOptions { storage: Some(Arc::new(storage.clone())), ..DBexOptions::default() };
+    let mut db = DBex::try_open_with_options(path, options).unwrap();
+    for i in 0..100u32 {
+        db.insert(i, format!("value_{}", i).into_bytes()).unwrap();
+    }
+
+    // Neither the WAL nor the flush panics on a failed write; both hand back the error
+    storage.full.store(true, AtomicOrdering::SeqCst);
+    assert!(matches!(db.insert(b"rejected".to_vec(), b"value".to_vec()), Err(DbexError::Io(_))));
+    assert!(db.flush().is_err());
+    assert_eq!(db.count_in_level(0).0, 0);
+
+    // Once there is room again the same database carries on
+    storage.full.store(false, AtomicOrdering::SeqCst);
+    db.flush().unwrap();
+    assert_eq!(db.count_in_level(0).0, 1);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value_42".to_vec()));
+}
+
+// MemoryStorage that logs directory syncs and renames, in order
+#[derive(Debug, Clone)]
+struct SyncLogStorage {
+    inner: MemoryStorage,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Storage for SyncLogStorage {
+    fn create(&self, path: &Path) -> std::io::Result<Box<dyn StorageFile>> { self.inner.create(path) }
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn StorageFile>> { self.inner.open(path) }
+    fn open_or_create(&self, path: &Path) -> std::io::Result<Box<dyn StorageFile>> { self.inner.open_or_create(path) }
+    fn remove(&self, path: &Path) -> std::io::Result<()> { self.inner.remove(path) }
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.log.lock().unwrap().push(format!("rename {}", to.display()));
+        self.inner.rename(from, to)
+    }
+    fn link(&self, from: &Path, to: &Path) -> std::io::Result<()> { self.inner.link(from, to) }
+    fn exists(&self, path: &Path) -> bool { self.inner.exists(path) }
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> { self.inner.list(dir) }
+    fn create_dir_all(&self, dir: &Path) -> std::io::Result<()> { self.inner.create_dir_all(dir) }
+    fn remove_dir_all(&self, dir: &Path) -> std::io::Result<()> { self.inner.remove_dir_all(dir) }
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()> {
+        self.log.lock().unwrap().push(format!("sync_dir {}", dir.display()));
+        self.inner.sync_dir(dir)
+    }
+    fn try_lock(&self, path: &Path) -> std::io::Result<Option<StorageLock>> { self.inner.try_lock(path) }
+}
+
+#[test]
+fn test_flush_syncs_table_directory_before_manifest() {
+    let path = "db_data_test_flush_syncs_table_directory_before_manifest";
+    let storage = SyncLogStorage { inner: MemoryStorage::new(), log: Arc::new(Mutex::new(Vec::new())) };
+    let options = DBexOptions { storage: Some(Arc::new(storage.clone())), ..DBexOptions::default() };
+    let mut db = DBex::try_open_with_options(path, options).unwrap();
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    storage.log.lock().unwrap().clear();
+
+    db.flush().unwrap();
+    let log = storage.log.lock().unwrap().clone();
+    let manifest = Path::new(path).join("MANIFEST");
+    let committed = log.iter().position(|event| *event == format!("rename {}", manifest.display())).unwrap();
+    let ss_tables = Path::new(path).join("ss_tables");
+    assert!(log[..committed].contains(&format!("sync_dir {}", ss_tables.display())), "{:?}", log);
+}
+
+#[test]
+fn test_failed_wal_write_keeps_coalesced_record() {
+    let path = "db_data_test_failed_wal_write_keeps_coalesced_record";
+    let storage = FullDiskStorage { inner: MemoryStorage::new(), full: Arc::new(AtomicBool::new(false)) };
+    let options = DBexOptions {
+        storage: Some(Arc::new(storage.clone())),
+        wal_coalesce_window: Some(100),
+        ..DBexOptions::default()
+    };
+    let mut db = DBex::try_open_with_options(path, options.clone()).unwrap();
+    db.insert(b"held".to_vec(), b"value".to_vec()).unwrap();
+
+    // Writing out the held-back record fails, so the write after it is rejected and the
+    // held-back record stays queued
+    storage.full.store(true, AtomicOrdering::SeqCst);
+    assert!(matches!(db.insert(b"rejected".to_vec(), b"value".to_vec()), Err(DbexError::Io(_))));
+    storage.full.store(false, AtomicOrdering::SeqCst);
+    // Crash without flushing
+    drop(db);
+
+    let mut db = DBex::try_open_with_options(path, options).unwrap();
+    assert_eq!(db.find(b"held").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.find(b"rejected").unwrap(), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_read_only_directory_errors_are_returned() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = "db_data_test_read_only_directory_errors_are_returned";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+    for i in 0..100u32 {
+        db.insert(i, format!("value_{}", i).into_bytes()).unwrap();
+    }
+
+    let ss_tables_dir = PathBuf::from(path).join("ss_tables");
+    fs::set_permissions(&ss_tables_dir, fs::Permissions::from_mode(0o555)).unwrap();
+    // Permissions don't bind root, so there is nothing to check when running as root
+    let probe = ss_tables_dir.join("probe");
+    if fs::write(&probe, b"").is_ok() {
+        fs::remove_file(&probe).unwrap();
+        fs::set_permissions(&ss_tables_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        return;
+    }
+
+    // The flush can't create its table and reports that instead of panicking
+    assert!(matches!(db.flush(), Err(DbexError::Io(_))));
+    assert_eq!(db.count_in_level(0).0, 0);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value_42".to_vec()));
+
+    fs::set_permissions(&ss_tables_dir, fs::Permissions::from_mode(0o755)).unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.count_in_level(0).0, 1);
+    assert_eq!(db.find(42u32).unwrap(), Some(b"value_42".to_vec()));
+}
+
+#[test]
+fn test_maintain() {
+    let path = "db_data_test_maintain";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+
+    // Overlapping bulk loads fill L2 and L1, then pile up in L0 without triggering a
+    // compaction
+    for batch in 0..13u32 {
+        db.bulk_load((0..20u32).map(|i| (i.to_be_bytes().to_vec(), format!("value_{}_{}", batch, i).into_bytes()))).unwrap();
+    }
+    assert_eq!(db.cnt_of_l0_ss_tables(), 11);
+    db.insert(b"logged".to_vec(), b"value".to_vec()).unwrap();
+    let orphan = PathBuf::from(path).join("ss_tables").join("ss_table_999999.db.index");
+    fs::write(&orphan, b"left behind").unwrap();
+
+    let report = db.maintain().unwrap();
+    assert!(report.flushed && report.wal_truncated);
+    assert_eq!(report.compactions, 1);
+    assert_eq!(report.orphaned_tables_removed, 1);
+    assert!(db.cnt_of_l0_ss_tables() <= 10);
+    assert_eq!(fs::metadata(PathBuf::from(path).join("wals").join("cur.wal")).unwrap().len(), 0);
+    assert!(!orphan.exists());
+    assert_eq!(db.find(7u32).unwrap(), Some(b"value_12_7".to_vec()));
+    assert_eq!(db.find(b"logged").unwrap(), Some(b"value".to_vec()));
+
+    // Nothing left to do
+    assert_eq!(db.maintain().unwrap(), MaintenanceReport::default());
+}
+
+#[test]
+fn test_snapshot() {
+    let path = "db_data_test_snapshot";
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+    for i in 0..50u32 {
+        db.insert(i, b"old".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    db.insert(b"unflushed".to_vec(), b"old".to_vec()).unwrap();
+
+    let mut snapshot = db.snapshot().unwrap();
+    assert_eq!(snapshot.lsn(), db.current_lsn());
+    let old_tables = data_files(path);
+
+    // Overwrites, removes and new keys, flushed often enough to compact the old table away
+    for flush in 0..11u32 {
+        db.insert(flush, b"new".to_vec()).unwrap();
+        db.remove(49 - flush).unwrap();
+        db.insert(1000 + flush, b"new".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    db.insert(b"unflushed".to_vec(), b"new".to_vec()).unwrap();
+    assert_eq!(db.stats().compactions, 1);
+    assert!(old_tables.iter().all(|table| !db.list_sstables().iter().any(|info| &info.data_path == table)));
+    assert_eq!(db.maintain().unwrap().orphaned_tables_removed, 0);
+
+    assert_eq!(db.find(3u32).unwrap(), Some(b"new".to_vec()));
+    assert_eq!(snapshot.find(3u32).unwrap(), Some(b"old".to_vec()));
+    assert_eq!(snapshot.find(45u32).unwrap(), Some(b"old".to_vec()));
+    assert_eq!(snapshot.find(1003u32).unwrap(), None);
+    assert_eq!(snapshot.find(b"unflushed").unwrap(), Some(b"old".to_vec()));
+    let scanned: Vec<_> = snapshot.scan(&0u32.to_be_bytes(), &2000u32.to_be_bytes()).unwrap().collect();
+    assert_eq!(scanned.len(), 50);
+    assert!(scanned.iter().all(|(_, value)| value == b"old"));
+
+    // The retired tables' files outlive the compaction until the snapshot is dropped
+    assert!(old_tables.iter().all(|table| table.exists()));
+    drop(snapshot);
+    assert!(old_tables.iter().all(|table| !table.exists()));
+}
+
+#[test]
+fn test_sharded_db() {
+    let path = "db_data_test_sharded_db";
+    fs::remove_dir_all(path).ok();
+    // Routed by the first two bytes, so each tenant's keys share a shard
+    let key = |tenant: u16, i: u32| [&tenant.to_be_bytes()[..], &i.to_be_bytes()].concat();
+    let mut db = ShardedDb::open(path, 4, Some(2)).unwrap();
+    for tenant in 0..16u16 {
+        for i in 0..50u32 {
+            db.insert(key(tenant, i), format!("value_{}_{}", tenant, i).into_bytes()).unwrap();
+        }
+    }
+    db.remove(&key(3, 7)).unwrap();
+    db.flush().unwrap();
+
+    let mut per_shard = Vec::new();
+    for shard in 0..db.shard_count() {
+        per_shard.push(db.shard(shard).range_keys(&[], &[0xff]).unwrap().count());
+    }
+    assert_eq!(per_shard.iter().sum::<usize>(), 16 * 50 - 1);
+    assert!(per_shard.iter().all(|&count| count > 0), "{:?}", per_shard);
+    for tenant in 0..16u16 {
+        let shard = db.shard_of(&tenant.to_be_bytes());
+        assert_eq!(db.shard(shard).scan_prefix(&tenant.to_be_bytes()).unwrap().len(), if tenant == 3 { 49 } else { 50 });
+    }
+
+    assert_eq!(db.find(&key(9, 42)).unwrap(), Some(b"value_9_42".to_vec()));
+    assert_eq!(db.find(&key(3, 7)).unwrap(), None);
+
+    // Range scans merge the shards back into key order
+    let start = 2u16.to_be_bytes();
+    let end = 6u16.to_be_bytes();
+    let scanned: Vec<_> = db.range(&start, &end).unwrap().collect();
+    assert_eq!(scanned.len(), 4 * 50 - 1);
+    assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(scanned[0].1, b"value_2_0".to_vec());
+    db.close().unwrap();
+
+    // The layout is fixed once created
+    assert!(matches!(ShardedDb::open(path, 8, Some(2)), Err(DbexError::ShardLayoutMismatch { shard_count: 4, prefix_len: Some(2) })));
+    let mut db = ShardedDb::open(path, 4, Some(2)).unwrap();
+    assert_eq!(db.find(&key(15, 49)).unwrap(), Some(b"value_15_49".to_vec()));
+    drop(db);
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_txn_rollback() {
+    let path = "db_data_test_txn_rollback";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"kept".to_vec(), b"before".to_vec()).unwrap();
+
+    // Staged writes are visible to the transaction's own reads only
+    db.start_txn();
+    db.insert(b"new".to_vec(), b"staged".to_vec()).unwrap();
+    db.insert(b"kept".to_vec(), b"staged".to_vec()).unwrap();
+    assert_eq!(db.find(b"new").unwrap(), Some(b"staged".to_vec()));
+    assert_eq!(db.find(b"kept").unwrap(), Some(b"staged".to_vec()));
+    assert!(db.memtable().get(b"new").is_none());
+    db.rollback_txn();
+    assert!(!db.in_txn());
+    assert_eq!(db.find(b"new").unwrap(), None);
+    assert_eq!(db.find(b"kept").unwrap(), Some(b"before".to_vec()));
+    assert_eq!(db.scan(b"a", b"z").unwrap().count(), 1);
+
+    db.start_txn();
+    db.insert(b"new".to_vec(), b"committed".to_vec()).unwrap();
+    db.remove(b"kept").unwrap();
+    assert_eq!(db.scan(b"a", b"z").unwrap().collect::<Vec<_>>(), vec![(b"new".to_vec(), b"committed".to_vec())]);
+    assert_eq!(db.scan_prefix(b"ke").unwrap(), Vec::new());
+    assert_eq!(db.range_keys(b"a", b"z").unwrap().collect::<Vec<_>>(), vec![b"new".to_vec()]);
+    assert_eq!(db.len().unwrap(), 1);
+    assert_eq!(db.iter_tombstones().unwrap().collect::<Vec<_>>(), vec![b"kept".to_vec()]);
+    assert_eq!(db.get_all_versions(b"kept").unwrap()[0], (ReadSource::Transaction, None));
+    // Staged writes have no LSN until the commit, so there is nothing to compare against
+    assert_eq!(db.lsn_of(b"new").unwrap(), None);
+    assert!(matches!(db.compare_and_swap(b"new".to_vec(), None, b"swapped".to_vec()), Err(CasError::Dbex(DbexError::CasInTransaction))));
+    db.commit_txn().unwrap();
+    assert_eq!(db.len().unwrap(), 1);
+    assert!(db.lsn_of(b"new").unwrap().is_some());
+    assert_eq!(db.find(b"kept").unwrap(), None);
+
+    // A transaction cut off before its commit marker is dropped whole on recovery
+    db.start_txn();
+    db.insert(b"lost".to_vec(), b"staged".to_vec()).unwrap();
+    drop(db);
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"new").unwrap(), Some(b"committed".to_vec()));
+    assert_eq!(db.find(b"kept").unwrap(), None);
+    assert_eq!(db.find(b"lost").unwrap(), None);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_incremental_backup() {
+    let path = "db_data_test_incremental_backup";
+    let backup_path = "db_data_test_incremental_backup_copy";
+    fs::remove_dir_all(backup_path).ok();
+    let mut test_db = TestDb::open(path);
+    let db = test_db.db();
+    for flush in 0..3u32 {
+        for i in 0..100u32 {
+            db.insert(flush * 1000 + i, b"first".to_vec()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    db.insert(b"unflushed".to_vec(), b"first".to_vec()).unwrap();
+
+    let full = db.backup(backup_path, None).unwrap();
+    assert_eq!(full.tables_copied.len(), 4);
+    assert_eq!(full.checkpoint_lsn, db.current_lsn());
+
+    // Only the table flushed after the checkpoint is new
+    for i in 0..100u32 {
+        db.insert(5000 + i, b"second".to_vec()).unwrap();
+    }
+    db.flush().unwrap();
+    let new_tables: Vec<PathBuf> = db.iter_since_checkpoint(full.checkpoint_lsn).map(|info| info.data_path).collect();
+    assert!(!new_tables.is_empty());
+    let incremental = db.backup(backup_path, Some(full.checkpoint_lsn)).unwrap();
+    assert_eq!(incremental.tables_copied.len(), 1);
+    assert!(new_tables.contains(&incremental.tables_copied[0]));
+    assert!(incremental.bytes_copied < full.bytes_copied);
+
+    // The backup opens as a database with everything written before it
+    let mut copy = DBex::open(backup_path);
+    assert_eq!(copy.find(2042u32).unwrap(), Some(b"first".to_vec()));
+    assert_eq!(copy.find(5099u32).unwrap(), Some(b"second".to_vec()));
+    assert_eq!(copy.find(b"unflushed").unwrap(), Some(b"first".to_vec()));
+    assert_eq!(copy.cnt_of_l0_ss_tables(), db.cnt_of_l0_ss_tables());
+    copy.purge().unwrap();
+}
+
+#[test]
+fn test_write_batch() {
+    let path = "db_data_test_write_batch";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"removed".to_vec(), b"before".to_vec()).unwrap();
+
+    let mut ops: Vec<_> = (0..1000u32)
+        .map(|i| (Operation::Insert, i.to_be_bytes().to_vec(), Some(format!("value_{}", i).into_bytes())))
+        .collect();
+    ops.push((Operation::Delete, b"removed".to_vec(), None));
+    ops.push((Operation::Insert, 7u32.to_be_bytes().to_vec(), Some(b"rewritten".to_vec())));
+    db.write_batch(ops).unwrap();
+    assert_eq!(db.current_lsn(), 1 + 1002);
+    assert_eq!(db.durable_lsn(), db.current_lsn());
+    assert_eq!(db.find(7u32).unwrap(), Some(b"rewritten".to_vec()));
+    assert_eq!(db.find(b"removed").unwrap(), None);
+
+    // Nothing of a rejected batch is applied
+    let invalid = vec![
+        (Operation::Insert, b"first".to_vec(), Some(b"value".to_vec())),
+        (Operation::Insert, b"second".to_vec(), None),
+    ];
+    assert!(matches!(db.write_batch(invalid), Err(DbexError::InvalidBatch(_))));
+    assert_eq!(db.find(b"first").unwrap(), None);
+
+    // The batch is replayed whole from the WAL
+    drop(db);
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(999u32).unwrap(), Some(b"value_999".to_vec()));
+    assert_eq!(db.find(7u32).unwrap(), Some(b"rewritten".to_vec()));
+    assert_eq!(db.find(b"removed").unwrap(), None);
+    assert_eq!(db.len().unwrap(), 1000);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_garbled_lengths_are_rejected() {
+    let path = "db_data_test_garbled_lengths";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { compression: Compression::Zstd { level: 3 }, ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+    db.insert(b"key".to_vec(), vec![b'x'; 1000]).unwrap();
+    db.flush().unwrap();
+    db.insert(b"logged".to_vec(), b"value".to_vec()).unwrap();
+    drop(db);
+
+    // A compressed value claiming to be 4 GiB, well past what its frame can hold
+    let table = data_files(path).remove(0);
+    let mut data = fs::read(&table).unwrap();
+    data[4..8].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+    fs::write(&table, &data).unwrap();
+    // A WAL record whose length runs far past the end of the file
+    let mut wal = OpenOptions::new().append(true).open(PathBuf::from(path).join("wals").join("cur.wal")).unwrap();
+    wal.write_all(&(u64::MAX >> 2).to_be_bytes()).unwrap();
+    wal.write_all(b"garbage").unwrap();
+    drop(wal);
+
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(b"logged").unwrap(), Some(b"value".to_vec()));
+    assert!(matches!(db.find_borrowed(b"key"), Err(DbexError::Corruption(_))));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_iter_walks_every_live_key() {
+    let mut test_db = TestDb::open("db_data_test_iter_walks_every_live_key");
+    let db = test_db.db();
+    let key = |i: u32| format!("key_{:06}", i).into_bytes();
+    let mut expected = BTreeMap::new();
+
+    // Interleaved rounds, so every flushed table overlaps the others
+    for round in 0..4 {
+        for i in (round..100_000).step_by(4) {
+            db.insert(key(i), i.to_be_bytes().to_vec()).unwrap();
+            expected.insert(key(i), i.to_be_bytes().to_vec());
+        }
+        db.flush().unwrap();
+    }
+    for i in (0..100_000).step_by(10) {
+        db.insert(key(i), b"updated".to_vec()).unwrap();
+        expected.insert(key(i), b"updated".to_vec());
+    }
+    db.flush().unwrap();
+    for i in (0..100_000).step_by(7) {
+        db.remove(&key(i)).unwrap();
+        expected.remove(&key(i));
+    }
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = db.iter().collect::<Result<_, _>>().unwrap();
+    assert_eq!(pairs.len(), expected.len());
+    assert!(pairs.into_iter().eq(expected));
+}
+
+#[test]
+fn test_compaction_paused() {
+    let mut test_db = TestDb::open("db_data_test_compaction_paused");
+    let db = test_db.db();
+    db.set_compaction_paused(true).unwrap();
+    assert!(db.is_compaction_paused());
+
+    // Well past the L0 threshold, and nothing compacts
+    for round in 0..15u32 {
+        for i in 0..50u32 {
+            db.insert(i, format!("value_{}_{}", round, i).into_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    assert_eq!(db.cnt_of_l0_ss_tables(), 15);
+    assert_eq!(db.stats().compactions, 0);
+    assert_eq!(db.find(7u32).unwrap(), Some(b"value_14_7".to_vec()));
+
+    // Resuming works off the backlog
+    db.set_compaction_paused(false).unwrap();
+    assert!(db.stats().compactions >= 1);
+    assert!(db.cnt_of_l0_ss_tables() <= 10);
+    assert_eq!(db.find(7u32).unwrap(), Some(b"value_14_7".to_vec()));
+    assert_eq!(db.range(&[], &[0xff]).unwrap().count(), 50);
+}
+
+#[test]
+fn test_insert_with_ttl() {
+    let path = "db_data_test_insert_with_ttl";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+
+    // Expired in the memtable, before any flush or compaction
+    db.insert_with_ttl(b"short".to_vec(), b"value".to_vec(), Duration::from_millis(1)).unwrap();
+    // An expired copy in L0 under a newer plain write
+    db.insert_with_ttl(b"rewritten".to_vec(), b"old".to_vec(), Duration::from_millis(1)).unwrap();
+    // An older plain write under an expired copy
+    db.insert(b"masked".to_vec(), b"old".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert_with_ttl(b"masked".to_vec(), b"new".to_vec(), Duration::from_millis(1)).unwrap();
+    thread::sleep(Duration::from_millis(5));
+    db.insert(b"rewritten".to_vec(), b"new".to_vec()).unwrap();
+
+    assert_eq!(db.find(b"short").unwrap(), None);
+    assert_eq!(db.find(b"rewritten").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(db.find(b"masked").unwrap(), None);
+    assert_eq!(db.range(b"a", b"z").unwrap().count(), 1);
+
+    // Compaction into an empty L1 drops expired entries outright
+    db.flush().unwrap();
+    for i in 0..10u32 {
+        db.insert(i, b"filler".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.stats().compactions, 1);
+    assert!(db.get_all_versions(b"short").unwrap().is_empty());
+    assert!(db.get_all_versions(b"masked").unwrap().is_empty());
+    assert_eq!(db.find(b"rewritten").unwrap(), Some(b"new".to_vec()));
+
+    // The expiry is logged, so it still applies after replay
+    db.insert_with_ttl(b"logged".to_vec(), b"value".to_vec(), Duration::from_millis(500)).unwrap();
+    drop(db);
+    let mut db = DBex::open(path);
+    assert_eq!(db.find(b"logged").unwrap(), Some(b"value".to_vec()));
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(db.find(b"logged").unwrap(), None);
+
+    db.start_txn();
+    assert!(matches!(db.insert_with_ttl(b"staged".to_vec(), b"value".to_vec(), Duration::from_secs(1)), Err(DbexError::TtlInTransaction)));
+    db.rollback_txn();
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_missing_expiry_sidecar_is_corruption() {
+    let path = "db_data_test_missing_expiry_sidecar";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"plain".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.insert_with_ttl(b"expiring".to_vec(), b"value".to_vec(), Duration::from_secs(3600)).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    // Without its sidecar the entry would never expire
+    let tables = data_files(path);
+    assert!(!Path::new(&format!("{}.expiry", tables[0].display())).exists());
+    fs::remove_file(format!("{}.expiry", tables[1].display())).unwrap();
+    let mut db = DBex::open(path);
+    match db.find(b"expiring") {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("missing expiry file"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    // A table written without TTLs never had one
+    assert_eq!(db.find(b"plain").unwrap(), Some(b"value".to_vec()));
+
+    // A cut-off sidecar is caught too
+    drop(db);
+    fs::write(format!("{}.expiry", tables[1].display()), [0u8; 10]).unwrap();
+    let mut db = DBex::open(path);
+    assert!(matches!(db.find(b"expiring"), Err(DbexError::Corruption(_))));
+    drop(db);
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn test_get_status() {
+    let mut test_db = TestDb::new();
+    let db = test_db.db();
+
+    // In an SSTable: a live key, a tombstone over an older table's value, and a tombstone
+    // of a key never written
+    db.insert(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+    db.insert(b"flushed_removed".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    db.remove(b"flushed_removed").unwrap();
+    db.remove(b"never_written").unwrap();
+    db.flush().unwrap();
+    // In the memtable: a live key and a removed one
+    db.insert(b"buffered".to_vec(), b"value".to_vec()).unwrap();
+    db.insert(b"buffered_removed".to_vec(), b"value".to_vec()).unwrap();
+    db.remove(b"buffered_removed").unwrap();
+
+    assert_eq!(db.get_status(b"flushed").unwrap(), KeyStatus::Present(b"value".to_vec()));
+    assert_eq!(db.get_status(b"buffered").unwrap(), KeyStatus::Present(b"value".to_vec()));
+    assert_eq!(db.get_status(b"flushed_removed").unwrap(), KeyStatus::Deleted);
+    assert_eq!(db.get_status(b"never_written").unwrap(), KeyStatus::Deleted);
+    assert_eq!(db.get_status(b"buffered_removed").unwrap(), KeyStatus::Deleted);
+    assert_eq!(db.get_status(b"missing").unwrap(), KeyStatus::Absent);
+    assert_eq!(db.find(b"buffered_removed").unwrap(), None);
+    assert_eq!(db.find(b"missing").unwrap(), None);
+
+    // A memtable write shadows a tombstone in a table, and the other way round
+    db.insert(b"flushed_removed".to_vec(), b"again".to_vec()).unwrap();
+    db.remove(b"flushed").unwrap();
+    assert_eq!(db.get_status(b"flushed_removed").unwrap(), KeyStatus::Present(b"again".to_vec()));
+    assert_eq!(db.get_status(b"flushed").unwrap(), KeyStatus::Deleted);
+}
+
+#[test]
+fn test_lz4_compression() {
+    let path = "db_data_test_lz4_compression";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { compression: Compression::Lz4, ..DBexOptions::default() };
+    let value = |i: u32| format!("value {} ", i).repeat(1000).into_bytes();
+    let mut db = DBex::open_with_options(path, options.clone());
+    for i in 0..100u32 {
+        db.insert(i, value(i)).unwrap();
+    }
+    db.remove(7u32).unwrap();
+    let flush_info = db.flush().unwrap().unwrap();
+    let raw_len: usize = (0..100).map(|i| value(i).len()).sum();
+    assert!((flush_info.size_bytes as usize) * 10 < raw_len);
+    assert_eq!(db.find(42u32).unwrap(), Some(value(42)));
+    assert_eq!(db.find(7u32).unwrap(), None);
+    drop(db);
+
+    // The codec is read back on open, and compaction output stays readable
+    let mut db = DBex::open_with_options(path, options);
+    assert_eq!(db.find(42u32).unwrap(), Some(value(42)));
+    for flush in 0..10 {
+        db.insert(1000 + flush, value(1000 + flush)).unwrap();
+        db.flush().unwrap();
+    }
+    assert_eq!(db.stats().compactions, 1);
+    assert_eq!(db.find(42u32).unwrap(), Some(value(42)));
+    assert_eq!(db.find(1005u32).unwrap(), Some(value(1005)));
+    assert_eq!(db.range(&0u32.to_be_bytes(), &2000u32.to_be_bytes()).unwrap().count(), 109);
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_entry_checksums() {
+    let path = "db_data_test_entry_checksums";
+    fs::remove_dir_all(path).ok();
+    let mut db = DBex::open(path);
+    db.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    // Flip a byte of the value, leaving its length alone
+    let table = data_files(path).remove(0);
+    let mut data = fs::read(&table).unwrap();
+    data[4] ^= 0xff;
+    fs::write(&table, &data).unwrap();
+
+    let mut db = DBex::open(path);
+    match db.find_borrowed(b"key") {
+        Err(DbexError::Corruption(reason)) => assert!(reason.contains("checksum mismatch"), "{}", reason),
+        other => panic!("expected corruption, got {:?}", other.map(|value| value.map(|value| value.to_vec()))),
+    }
+    assert!(matches!(db.find(b"key"), Err(DbexError::Corruption(_))));
+    assert!(matches!(db.verify(), Err(DbexError::Corruption(_))));
+    drop(db);
+
+    // Scans report it too, including through the sequential reader
+    let options = DBexOptions { read_ahead: ReadAhead::Bytes(4096), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options);
+    assert!(matches!(db.scan_prefix(b"key"), Err(DbexError::Corruption(_))));
+    drop(db);
+
+    // Without verification the damaged value reads back as it is
+    let options = DBexOptions { verify_checksums: false, ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options);
+    let mut damaged = b"value".to_vec();
+    damaged[0] ^= 0xff;
+    assert_eq!(db.find(b"key").unwrap(), Some(damaged));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_level_count() {
+    let path = "db_data_test_level_count";
+    fs::remove_dir_all(path).ok();
+    let options = DBexOptions { level_count: Some(5), ..DBexOptions::default() };
+    let mut db = DBex::open_with_options(path, options.clone());
+    assert_eq!(db.level_count(), 5);
+
+    // Bulk loads land in the deepest level they fit: L4, then L3 over its key range
+    let deep: Vec<(Vec<u8>, Vec<u8>)> = (0..100u32).map(|i| (format!("deep_{:03}", i).into_bytes(), b"l4".to_vec())).collect();
+    db.bulk_load(deep).unwrap();
+    let shadowing: Vec<(Vec<u8>, Vec<u8>)> = (0..10u32).map(|i| (format!("deep_{:03}", i).into_bytes(), b"l3".to_vec())).collect();
+    db.bulk_load(shadowing).unwrap();
+    // 121 flushes fill L1 eleven times over, spilling one table into L2
+    for flush in 0..124u32 {
+        db.insert(flush, format!("value_{}", flush).into_bytes()).unwrap();
+        db.flush().unwrap();
+    }
+    let counts: Vec<usize> = (0..5).map(|level| db.cnt_of_ss_tables_in_level(level)).collect();
+    assert_eq!(counts, vec![3, 0, 1, 1, 1]);
+    assert_eq!(db.cnt_of_l2_ss_tables(), 1);
+
+    assert_eq!(db.find(5u32).unwrap(), Some(b"value_5".to_vec()));
+    assert_eq!(db.find(122u32).unwrap(), Some(b"value_122".to_vec()));
+    assert_eq!(db.find(b"deep_005").unwrap(), Some(b"l3".to_vec()));
+    assert_eq!(db.find(b"deep_050").unwrap(), Some(b"l4".to_vec()));
+    drop(db);
+
+    // The manifest keeps the deeper levels even when opened with fewer configured
+    let mut db = DBex::open(path);
+    assert_eq!(db.level_count(), 5);
+    assert_eq!(db.cnt_of_ss_tables_in_level(4), 1);
+    assert_eq!(db.find(b"deep_050").unwrap(), Some(b"l4".to_vec()));
+    assert_eq!(db.find(b"deep_005").unwrap(), Some(b"l3".to_vec()));
+    assert_eq!(db.find(5u32).unwrap(), Some(b"value_5".to_vec()));
+    db.purge().unwrap();
+}
+
+#[test]
+fn test_estimate_live_data_size() {
+    let options = DBexOptions { sparse_index_interval: Some(10), ..DBexOptions::default() };
+    let mut test_db = TestDb::with_options(options);
+    let db = test_db.db();
+    let key = |i: u32| format!("key_{:04}", i).into_bytes();
+    let value = |round: u32| vec![round as u8; 100];
+    let live_bytes = 200 * (key(0).len() + value(0).len()) as u64;
+    let disk_bytes = |db: &DBex| (0..db.level_count()).map(|level| db.count_in_level(level).2).sum::<u64>();
+
+    // The memtable is counted exactly, and overwrites there replace rather than pile up
+    for round in 0..3 {
+        for i in 0..200 {
+            db.insert(key(i), value(round)).unwrap();
+        }
+    }
+    assert_eq!(db.estimate_live_data_size().unwrap(), live_bytes);
+
+    // Ten flushes of the same keys leave nine dead copies of each on disk
+    for round in 0..10 {
+        for i in 0..200 {
+            db.insert(key(i), value(round)).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let estimate = db.estimate_live_data_size().unwrap();
+    assert!(estimate.abs_diff(live_bytes) * 10 < live_bytes, "{} vs {}", estimate, live_bytes);
+    assert!(disk_bytes(db) > 8 * estimate, "{} vs {}", disk_bytes(db), estimate);
+
+    // Compaction merges the copies away, bringing the two together
+    for i in 0..200 {
+        db.insert(key(i), value(10)).unwrap();
+    }
+    db.flush().unwrap();
+    assert_eq!(db.stats().compactions, 1);
+    let estimate = db.estimate_live_data_size().unwrap();
+    assert!(estimate.abs_diff(live_bytes) * 10 < live_bytes, "{} vs {}", estimate, live_bytes);
+    assert!(disk_bytes(db) < 2 * estimate, "{} vs {}", disk_bytes(db), estimate);
 }
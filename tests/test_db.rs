@@ -1,16 +1,62 @@
 // Integration tests for DBex functionality
 use dbex::DBex;
+use dbex::options::DBexOptions;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DB_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Tests run in parallel and a data directory can only have one writer, so every
+// database that doesn't ask for a path gets a fresh one
+fn unique_path() -> String {
+    format!("db_data_{}_{}", process::id(), NEXT_DB_ID.fetch_add(1, Ordering::Relaxed))
+}
 
 // Test guard that ensures cleanup happens even if test panics
 pub struct TestDb {
     db: DBex,
+    path: String,
+}
+
+impl Default for TestDb {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TestDb {
     pub fn new() -> Self {
-        let db = DBex::new();
+        let path = unique_path();
+        TestDb {
+            db: DBex::open(&path),
+            path,
+        }
+    }
+
+    // Roots the database at its own directory so it can't collide with other tests
+    #[allow(dead_code)]
+    pub fn open(path: &str) -> Self {
+        TestDb {
+            db: DBex::open(path),
+            path: path.to_string(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_options(options: DBexOptions) -> Self {
+        let path = unique_path();
+        TestDb {
+            db: DBex::open_with_options(&path, options),
+            path,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn open_with_options(path: &str, options: DBexOptions) -> Self {
         TestDb {
-            db
+            db: DBex::open_with_options(path, options),
+            path: path.to_string(),
         }
     }
 
@@ -22,7 +68,9 @@ impl TestDb {
 
 impl Drop for TestDb {
     fn drop(&mut self) {
-        // This runs even if the test panics!
-        self.db.purge();
+        // This runs even if the test panics! Purging keeps the directory, so it goes
+        // separately.
+        self.db.purge().ok();
+        fs::remove_dir_all(&self.path).ok();
     }
 }
\ No newline at end of file